@@ -14,13 +14,16 @@ fn main() {
             .expect("Failed to convert boot_cfg/main.conf to PathBuf")
     };
     let _ = fs::create_dir("boot_images");
-    kbuild::build(&Config::new(
-        "target/artifacts"
-            .parse()
-            .expect("Failed to parse target/artifacts"),
-        PathBuf::try_from(kernel_dir).expect("Failed to convert kernel_dir to PathBuf"),
-        limine_cfg,
-        PathBuf::try_from("boot_images").expect("Failed to convert  to PathBuf"),
-        "novaos.iso".to_string(),
-    ));
+    kbuild::build(
+        &Config::new(
+            "target/artifacts"
+                .parse()
+                .expect("Failed to parse target/artifacts"),
+            PathBuf::try_from(kernel_dir).expect("Failed to convert kernel_dir to PathBuf"),
+            limine_cfg,
+            PathBuf::try_from("boot_images").expect("Failed to convert  to PathBuf"),
+            "novaos.iso".to_string(),
+        )
+        .with_initramfs(PathBuf::from("initramfs")),
+    );
 }
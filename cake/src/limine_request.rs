@@ -47,6 +47,7 @@ impl_lim_req! {
     MemoryMapRequest => MemoryMapResponse,
     ExecutableFileRequest => ExecutableFileResponse,
     MpRequest => MpResponse,
+    ModuleRequest => ModuleResponse,
 }
 
 /// A Limine request paired with kernel data initialized from the Limine response.
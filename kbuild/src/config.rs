@@ -16,6 +16,8 @@ pub struct Config {
     pub iso_root: PathBuf,
     /// Name of the output ISO file.
     pub iso_name: String,
+    /// Directory packed into the initramfs loaded alongside the kernel, if any.
+    pub initramfs: Option<PathBuf>,
 }
 
 fn val_or_env<T, E>(val: T, env: &str) -> T
@@ -45,9 +47,16 @@ impl Config {
             limine_config: val_or_env(limine_config, "LIMINE_CONFIG"),
             iso_root: val_or_env(iso_root, "ISO_ROOT"),
             iso_name: val_or_env(iso_name, "ISO_NAME"),
+            initramfs: env::var("INITRAMFS_DIR").ok().map(PathBuf::from),
         }
     }
 
+    /// Packs `dir` into the initramfs, unless overridden by the environment.
+    pub fn with_initramfs(mut self, dir: PathBuf) -> Config {
+        self.initramfs = Some(val_or_env(dir, "INITRAMFS_DIR"));
+        self
+    }
+
     /// Joins `path` with the output directory.
     pub fn a(&self, path: &str) -> PathBuf {
         self.artifact_dir.join(path)
@@ -0,0 +1,104 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::Config, macros::cargo_warn};
+
+/// Where the archive is put in the ISO, relative to its root.
+const ARCHIVE_PATH: &str = "boot/initramfs.cpio";
+/// The command line the kernel looks for to tell the initramfs from other modules.
+const CMDLINE: &str = "initramfs";
+
+/// Packs the initramfs directory into a cpio archive in the ISO, and lists it as a module in `limine.conf`.
+pub fn make_initramfs(cfg: &Config) {
+    let Some(dir) = &cfg.initramfs else {
+        return;
+    };
+    if !dir.is_dir() {
+        cargo_warn!("Initramfs directory not found, booting without one");
+        return;
+    }
+
+    let mut paths = Vec::new();
+    collect(dir, &mut paths);
+    let mut archive = Vec::new();
+    for (inode, path) in paths.iter().enumerate() {
+        let name = path
+            .strip_prefix(dir)
+            .unwrap()
+            .to_str()
+            .expect("Initramfs paths must be UTF-8")
+            .replace('\\', "/");
+        let metadata = fs::symlink_metadata(path).expect("Failed to read initramfs entry");
+        let (mode, links, data) = if metadata.is_symlink() {
+            let target = fs::read_link(path).expect("Failed to read initramfs symlink");
+            let target = target.to_str().expect("Initramfs paths must be UTF-8");
+            (0o120777, 1, target.as_bytes().to_vec())
+        } else if metadata.is_dir() {
+            (0o040755, 2, Vec::new())
+        } else {
+            let data = fs::read(path).expect("Failed to read initramfs file");
+            (0o100644, 1, data)
+        };
+        push(&mut archive, inode as u32 + 1, mode, links, &name, &data);
+    }
+    push(&mut archive, 0, 0, 1, "TRAILER!!!", &[]);
+    fs::write(cfg.iso(ARCHIVE_PATH), archive).expect("Failed to write the initramfs");
+
+    let conf = cfg.iso("boot/limine.conf");
+    let contents = fs::read_to_string(&conf).expect("Failed to read limine.conf");
+    fs::write(&conf, with_module(&contents)).expect("Failed to write limine.conf");
+}
+
+/// Adds the paths of the entries below `dir` to `paths`, parents first and in a stable order.
+fn collect(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .expect("Failed to read initramfs directory")
+        .map(|entry| entry.expect("Failed to read initramfs directory").path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        paths.push(path.clone());
+        if !path.is_symlink() && path.is_dir() {
+            collect(&path, paths);
+        }
+    }
+}
+
+/// Appends an entry in the newc format to `archive`.
+fn push(archive: &mut Vec<u8>, inode: u32, mode: u32, links: u32, name: &str, data: &[u8]) {
+    let size = u32::try_from(data.len()).expect("Initramfs file too large");
+    // The owner, group, modification time, devices and checksum are left to zero.
+    let mut fields = [0; 13];
+    fields[0] = inode;
+    fields[1] = mode;
+    fields[4] = links;
+    fields[6] = size;
+    fields[11] = name.len() as u32 + 1;
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+/// Returns `conf` with the archive loaded as a module by every entry booting a kernel.
+fn with_module(conf: &str) -> String {
+    let mut result = String::new();
+    for line in conf.lines() {
+        result.push_str(line);
+        result.push('\n');
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("kernel_path:") {
+            let indent = &line[..line.len() - trimmed.len()];
+            result.push_str(&format!("{indent}module_path: boot():/{ARCHIVE_PATH}\n"));
+            result.push_str(&format!("{indent}module_string: {CMDLINE}\n"));
+        }
+    }
+    result
+}
@@ -4,6 +4,7 @@ use std::{fs, path::Path};
 use config::Config;
 
 pub mod config;
+mod initramfs;
 mod iso;
 mod limine;
 
@@ -12,6 +13,7 @@ pub fn build(cfg: &Config) {
     fs::create_dir_all(&cfg.a("iso")).ok();
     limine::update_limine(&cfg);
     limine::copy_limine_boot(&cfg);
+    initramfs::make_initramfs(&cfg);
     iso::make_iso(&cfg);
 }
 
@@ -59,6 +59,33 @@ pub enum ElfError {
         /// The expected architecture.
         expected: u8,
     },
+    /// The ELF file has an invalid data encoding.
+    #[error("Invalid data encoding: {actual} != {expected}")]
+    InvalidEncoding {
+        /// The actual data encoding.
+        actual: u8,
+        /// The expected data encoding.
+        expected: u8,
+    },
+    /// The ELF file targets a different machine.
+    #[error("Unsupported machine: {actual} != {expected}")]
+    UnsupportedMachine {
+        /// The actual machine.
+        actual: u16,
+        /// The expected machine.
+        expected: u16,
+    },
+    /// The ELF file is not an executable or a shared object.
+    #[error("Unsupported object type: {0}")]
+    UnsupportedType(u16),
+    /// The program header table doesn't fit in the ELF file.
+    #[error("Program header table out of bounds: {offset:#x} + {count} entries")]
+    InvalidProgramHeaders {
+        /// The offset of the program header table.
+        offset: u64,
+        /// The number of program headers.
+        count: u16,
+    },
 }
 
 impl<'a> Elf<'a> {
@@ -89,6 +116,49 @@ impl<'a> Elf<'a> {
         }
     }
 
+    /// Returns the ELF header.
+    pub fn header(&self) -> &'a header::Header {
+        self.header
+    }
+
+    /// Checks that the ELF file is a little endian executable (`ET_EXEC`) or shared object (`ET_DYN`) for `machine`,
+    /// and that its program header table is in bounds, so it can be loaded.
+    pub fn check_executable(&self, machine: u16) -> Result<(), ElfError> {
+        let header = self.header;
+        if header.e_ident[header::EI_DATA] != header::ELFDATA2LSB {
+            return Err(ElfError::InvalidEncoding {
+                actual: header.e_ident[header::EI_DATA],
+                expected: header::ELFDATA2LSB,
+            });
+        }
+        if header.e_machine != machine {
+            return Err(ElfError::UnsupportedMachine {
+                actual: header.e_machine,
+                expected: machine,
+            });
+        }
+        if header.e_type != header::ET_EXEC && header.e_type != header::ET_DYN {
+            return Err(ElfError::UnsupportedType(header.e_type));
+        }
+
+        let table_size = header.e_phnum as u64 * size_of::<ProgramHeader>() as u64;
+        let in_bounds = header.e_phentsize as usize == size_of::<ProgramHeader>()
+            && header
+                .e_phoff
+                .checked_add(table_size)
+                .is_some_and(|end| end <= self.data.len() as u64)
+            && (self.data.as_ptr() as u64)
+                .wrapping_add(header.e_phoff)
+                .is_multiple_of(align_of::<ProgramHeader>() as u64);
+        if !in_bounds {
+            return Err(ElfError::InvalidProgramHeaders {
+                offset: header.e_phoff,
+                count: header.e_phnum,
+            });
+        }
+        Ok(())
+    }
+
     /// Iterate over the sections in the ELF file.
     pub fn sections(&'a self) -> ElfSections<'a> {
         unsafe { ElfSections::new(self.data, self.header) }
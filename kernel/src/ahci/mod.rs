@@ -0,0 +1,547 @@
+//! AHCI SATA controllers.
+//!
+//! The controller is reset when it is probed, then every implemented port with a drive attached is started and the
+//! drive identified. ATA drives are registered as block devices named `sda`, `sdb`, ... and transfer data through DMA,
+//! one command at a time per port. The thread issuing a command blocks until the controller raises its interrupt, or
+//! polls the port if the controller has no MSI or the scheduler isn't running yet.
+use core::{
+    convert::Infallible,
+    ops::Range,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use cake::{
+    Mutex,
+    log::{info, warn},
+};
+
+use crate::{
+    block::{self, BlockDevice, BlockError},
+    declare_module,
+    interrupts::{InterruptMutex, IrqReturn},
+    memory::dma::DmaBuffer,
+    pci::{self, DriverEntry, MappedBar, PciDevice, PciDeviceId, PciDriver, PciHandle, ProbeError},
+    proc::{self, ThreadID},
+    time::{self, Duration, Instant},
+};
+
+mod port;
+
+use port::{CommandFis, Port, PortRegisters, SIGNATURE_ATA, SIGNATURE_ATAPI, irq};
+
+/// The generic registers of the controller.
+mod reg {
+    /// The capabilities of the controller.
+    pub const CAP: usize = 0x00;
+    /// The global control register.
+    pub const GHC: usize = 0x04;
+    /// The ports with pending interrupts.
+    pub const IS: usize = 0x08;
+    /// The ports implemented.
+    pub const PI: usize = 0x0c;
+    /// The version of the specification the controller implements.
+    pub const VS: usize = 0x10;
+}
+
+/// The controller can reach memory above 4 GiB.
+const CAP_S64A: u32 = 1 << 31;
+/// The controller supports staggered spin-up.
+const CAP_SSS: u32 = 1 << 27;
+/// Resets the controller, cleared once the reset is done.
+const GHC_HR: u32 = 1 << 0;
+/// The controller raises interrupts.
+const GHC_IE: u32 = 1 << 1;
+/// The controller is driven through AHCI rather than the legacy IDE interface.
+const GHC_AE: u32 = 1 << 31;
+
+/// The ATA commands issued.
+mod command {
+    pub const READ_DMA: u8 = 0xc8;
+    pub const READ_DMA_EXT: u8 = 0x25;
+    pub const WRITE_DMA: u8 = 0xca;
+    pub const WRITE_DMA_EXT: u8 = 0x35;
+    pub const FLUSH_CACHE: u8 = 0xe7;
+    pub const FLUSH_CACHE_EXT: u8 = 0xea;
+    pub const IDENTIFY_DEVICE: u8 = 0xec;
+}
+
+/// How long the controller may take to reset.
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a command may take before the port is restarted.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most bytes transferred by a single command.
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// The number of ATA drives found, used to name them.
+static DRIVE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+declare_module!("ahci", init);
+
+fn init() -> Result<(), Infallible> {
+    pci::register_driver(&PCI_DRIVER);
+    Ok(())
+}
+
+/// Spins until `condition` holds, for up to `timeout`. Returns `false` if it never did.
+fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if condition() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// The driver for AHCI controllers, matched by their class.
+#[derive(Debug)]
+pub struct AhciDriver;
+
+/// A bound controller and the drives registered through it.
+#[derive(Debug)]
+pub struct AhciState {
+    controller: Arc<AhciController>,
+    drives: Vec<Arc<AhciDrive>>,
+}
+
+impl AhciState {
+    /// Returns the controller.
+    pub fn controller(&self) -> &Arc<AhciController> {
+        &self.controller
+    }
+}
+
+impl PciDriver for AhciDriver {
+    const NAME: &'static str = "ahci";
+    const IDS: &'static [PciDeviceId] = &[PciDeviceId::class(0x01, 0x06).with_prog_if(0x01)];
+    type State = AhciState;
+
+    fn probe(handle: PciHandle, _: &PciDeviceId) -> Result<AhciState, ProbeError> {
+        let controller = Arc::new(AhciController::new(handle)?);
+        let mut drives = Vec::new();
+        for index in 0..32 {
+            if controller.ports & (1 << index) == 0 {
+                continue;
+            }
+            match AhciDrive::new(&controller, index) {
+                Ok(Some(drive)) => {
+                    let drive = Arc::new(drive);
+                    block::register(drive.clone());
+                    drives.push(drive);
+                }
+                Ok(None) => {}
+                Err(err) => warn!("AHCI port {index}: {err}"),
+            }
+        }
+        Ok(AhciState { controller, drives })
+    }
+
+    fn remove(state: AhciState) {
+        for drive in state.drives {
+            block::unregister(drive.name());
+        }
+    }
+}
+
+/// The PCI driver entry of [AhciDriver].
+pub static PCI_DRIVER: DriverEntry = DriverEntry::new::<AhciDriver>();
+
+/// The completion state of the command of a port.
+#[derive(Debug)]
+struct PortEvents {
+    /// The interrupts raised since the command was issued.
+    status: AtomicU32,
+    waiter: InterruptMutex<Option<ThreadID>>,
+}
+
+impl PortEvents {
+    /// Acknowledges the interrupts of the port `regs` and records them.
+    fn collect(&self, regs: PortRegisters) {
+        let status = regs.take_interrupts();
+        if status != 0 {
+            self.status.fetch_or(status, Ordering::SeqCst);
+        }
+    }
+}
+
+/// The state shared with the interrupt handler.
+#[derive(Debug)]
+struct Shared {
+    hba: MappedBar,
+    ports: [PortEvents; 32],
+    interrupts: bool,
+}
+
+fn handle_irq(_: u8, cookie: usize) -> IrqReturn {
+    // SAFETY: The cookie points to the shared state of the controller, which outlives the registration of the
+    // handler.
+    let shared = unsafe { &*(cookie as *const Shared) };
+    // SAFETY: The handler is unregistered before the handle that mapped the registers is dropped.
+    let pending = unsafe { shared.hba.read::<u32>(reg::IS) };
+    if pending == 0 {
+        return IrqReturn::NotMine;
+    }
+    for index in (0..32).filter(|index| pending & (1 << index) != 0) {
+        let events = &shared.ports[index];
+        events.collect(PortRegisters::new(shared.hba, index));
+        if let Some(pid) = events.waiter.lock().take() {
+            proc::wake(pid);
+        }
+    }
+    // SAFETY: Acknowledging interrupts has no other effect.
+    unsafe { shared.hba.write(reg::IS, pending) };
+    IrqReturn::Handled
+}
+
+/// An AHCI controller.
+#[derive(Debug)]
+pub struct AhciController {
+    // Dropped first, so the interrupt handler is unregistered before the state it uses is freed.
+    handle: PciHandle,
+    shared: Arc<Shared>,
+    /// The ports implemented.
+    ports: u32,
+    capabilities: u32,
+}
+
+impl AhciController {
+    fn new(mut handle: PciHandle) -> Result<Self, ProbeError> {
+        let hba = handle.map_bar(5)?;
+        // SAFETY: The registers are only read here while `handle` is alive.
+        let read = |offset| unsafe { hba.read::<u32>(offset) };
+        // SAFETY: Resetting the controller stops every port, so it no longer accesses memory.
+        unsafe {
+            hba.write(reg::GHC, GHC_AE);
+            hba.write(reg::GHC, GHC_AE | GHC_HR);
+        }
+        if !wait_for(RESET_TIMEOUT, || read(reg::GHC) & GHC_HR == 0) {
+            return Err(ProbeError::Device("AHCI controller reset timed out"));
+        }
+        // SAFETY: The reset cleared the AHCI enable bit.
+        unsafe { hba.write(reg::GHC, GHC_AE) };
+        let capabilities = read(reg::CAP);
+        let ports = read(reg::PI);
+        let version = read(reg::VS);
+
+        let interrupts = handle.enable_interrupts(1).is_ok();
+        let shared = Arc::new(Shared {
+            hba,
+            ports: core::array::from_fn(|_| PortEvents {
+                status: AtomicU32::new(0),
+                waiter: InterruptMutex::new(None),
+            }),
+            interrupts,
+        });
+        // Bound again so it is dropped before the shared state if probing fails, unregistering the handler first.
+        let mut handle = handle;
+        if interrupts {
+            handle.request_irq(0, handle_irq, Arc::as_ptr(&shared) as usize)?;
+            // SAFETY: The handler is registered.
+            unsafe { hba.write(reg::GHC, GHC_AE | GHC_IE) };
+        }
+        handle.enable_bus_master();
+        info!(
+            "AHCI {}.{} controller with {} ports{}",
+            version >> 16,
+            (version >> 8) & 0xff,
+            ports.count_ones(),
+            if interrupts { "" } else { ", polled" }
+        );
+        Ok(AhciController {
+            handle,
+            shared,
+            ports,
+            capabilities,
+        })
+    }
+
+    /// Returns the PCI function of the controller.
+    pub fn pci_device(&self) -> &'static PciDevice {
+        self.handle.device()
+    }
+
+    /// Issues `fis` on `port`, transferring `len` bytes from or to the start of `buffer`, and waits for it to complete.
+    fn execute(
+        &self,
+        port: &Port,
+        fis: CommandFis,
+        buffer: Option<&DmaBuffer>,
+        len: usize,
+        write: bool,
+    ) -> Result<(), BlockError> {
+        let data = buffer.map_or(0, DmaBuffer::phys_addr);
+        if self.capabilities & CAP_S64A == 0 && data + len as u64 > u32::MAX as u64 {
+            // The controller can't reach the buffer.
+            return Err(BlockError::OutOfMemory);
+        }
+        let events = &self.shared.ports[port.index()];
+        events.status.store(0, Ordering::SeqCst);
+        // SAFETY: The buffer outlives the command, which is waited for below or aborted by restarting the port.
+        unsafe { port.issue(fis, data, len, write) };
+
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        let failed = |events: &PortEvents| events.status.load(Ordering::SeqCst) & irq::ERRORS != 0;
+        loop {
+            // The interrupt may not be delivered yet, if interrupts are disabled on this core.
+            events.collect(port.registers());
+            if failed(events) || Instant::now() >= deadline {
+                port.recover();
+                return Err(BlockError::Io);
+            }
+            if !port.is_busy() {
+                return if port.has_error() {
+                    Err(BlockError::Io)
+                } else {
+                    Ok(())
+                };
+            }
+            let mut timer = None;
+            let blocked = self.shared.interrupts
+                && proc::block(|pid| {
+                    *events.waiter.lock() = Some(pid);
+                    timer = Some(time::wheel::at(deadline, move || {
+                        proc::wake(pid);
+                    }));
+                    // The command may have completed before the thread was registered as its waiter.
+                    if !port.is_busy() || failed(events) {
+                        proc::wake(pid);
+                    }
+                });
+            if let Some(timer) = timer {
+                timer.cancel();
+            }
+            if !blocked {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl Drop for AhciController {
+    fn drop(&mut self) {
+        // SAFETY: Disabling interrupts has no other effect.
+        unsafe { self.shared.hba.write(reg::GHC, GHC_AE) };
+    }
+}
+
+/// What a drive reports about itself in response to IDENTIFY DEVICE.
+#[derive(Debug, Clone)]
+struct Identity {
+    model: String,
+    sectors: u64,
+    sector_size: usize,
+    lba48: bool,
+}
+
+impl Identity {
+    fn parse(data: &[u8; 512]) -> Self {
+        let word = |index: usize| u16::from_le_bytes([data[2 * index], data[2 * index + 1]]);
+        let dword = |index: usize| word(index) as u32 | (word(index + 1) as u32) << 16;
+        // Strings hold two characters per word, the first in the high byte.
+        let model: String = (27..47)
+            .flat_map(|index| word(index).to_be_bytes())
+            .map(char::from)
+            .collect();
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            dword(100) as u64 | (dword(102) as u64) << 32
+        } else {
+            dword(60) as u64
+        };
+        // Valid, and the logical sectors are longer than 256 words.
+        let sector_size = if word(106) & 0xc000 == 0x4000 && word(106) & (1 << 12) != 0 {
+            dword(117) as usize * 2
+        } else {
+            512
+        };
+        Identity {
+            model: String::from(model.trim()),
+            sectors,
+            sector_size,
+            lba48,
+        }
+    }
+}
+
+/// An ATA drive attached to a port of an AHCI controller.
+#[derive(Debug)]
+pub struct AhciDrive {
+    // Dropped before the controller, so the port is stopped while its registers are still mapped.
+    port: Mutex<Port>,
+    controller: Arc<AhciController>,
+    name: String,
+    identity: Identity,
+}
+
+impl AhciDrive {
+    /// Starts the port `index` and identifies the drive attached to it. Returns `None` if there is no ATA drive.
+    fn new(controller: &Arc<AhciController>, index: usize) -> Result<Option<Self>, ProbeError> {
+        let capabilities = controller.capabilities;
+        let Some(port) = Port::new(
+            controller.shared.hba,
+            index,
+            capabilities & CAP_SSS != 0,
+            capabilities & CAP_S64A != 0,
+        )?
+        else {
+            return Ok(None);
+        };
+        match port.signature() {
+            SIGNATURE_ATA => {}
+            SIGNATURE_ATAPI => {
+                info!("AHCI port {index}: ATAPI drive, not supported");
+                return Ok(None);
+            }
+            signature => {
+                info!("AHCI port {index}: unknown drive signature {signature:#x}");
+                return Ok(None);
+            }
+        }
+        port.set_interrupts(controller.shared.interrupts);
+        if !port.start() {
+            return Err(ProbeError::Device("AHCI drive stayed busy"));
+        }
+
+        let buffer = DmaBuffer::new(512).map_err(|_| ProbeError::Device("Out of memory"))?;
+        let fis = CommandFis {
+            command: command::IDENTIFY_DEVICE,
+            ..Default::default()
+        };
+        controller
+            .execute(&port, fis, Some(&buffer), 512, false)
+            .map_err(|_| ProbeError::Device("AHCI IDENTIFY DEVICE failed"))?;
+        let mut data = [0; 512];
+        buffer.read_bytes(0, &mut data);
+        let identity = Identity::parse(&data);
+        if identity.sectors == 0 || !identity.sector_size.is_power_of_two() {
+            return Err(ProbeError::Device(
+                "AHCI drive reported an invalid geometry",
+            ));
+        }
+
+        let number = DRIVE_COUNT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("sd{}", (b'a' + (number % 26) as u8) as char);
+        info!("AHCI port {index}: {name} is {}", identity.model);
+        Ok(Some(AhciDrive {
+            port: Mutex::new(port),
+            controller: controller.clone(),
+            name,
+            identity,
+        }))
+    }
+
+    /// Returns the model of the drive.
+    pub fn model(&self) -> &str {
+        &self.identity.model
+    }
+
+    /// Transfers the `len` bytes starting at `sector` in commands of up to [MAX_TRANSFER] bytes, through a DMA buffer
+    /// that `copy` fills with the range of the data before a write, or drains after a read.
+    fn transfer(
+        &self,
+        sector: u64,
+        len: usize,
+        write: bool,
+        mut copy: impl FnMut(Range<usize>, &DmaBuffer),
+    ) -> Result<(), BlockError> {
+        let sector_size = self.identity.sector_size;
+        let max_transfer = MAX_TRANSFER.max(sector_size);
+        let buffer = DmaBuffer::new(len.min(max_transfer)).map_err(|_| BlockError::OutOfMemory)?;
+        let port = self.port.lock();
+        for offset in (0..len).step_by(max_transfer) {
+            let range = offset..(offset + max_transfer).min(len);
+            let fis = CommandFis {
+                command: match (write, self.identity.lba48) {
+                    (false, true) => command::READ_DMA_EXT,
+                    (false, false) => command::READ_DMA,
+                    (true, true) => command::WRITE_DMA_EXT,
+                    (true, false) => command::WRITE_DMA,
+                },
+                lba: sector + (offset / sector_size) as u64,
+                count: (range.len() / sector_size) as u16,
+            };
+            if write {
+                copy(range.clone(), &buffer);
+            }
+            self.controller
+                .execute(&port, fis, Some(&buffer), range.len(), write)?;
+            if !write {
+                copy(range, &buffer);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.identity.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(sector, buf.len())?;
+        self.transfer(sector, buf.len(), false, |range, buffer| {
+            buffer.read_bytes(0, &mut buf[range]);
+        })
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(sector, buf.len())?;
+        self.transfer(sector, buf.len(), true, |range, buffer| {
+            buffer.write_bytes(0, &buf[range]);
+        })
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let fis = CommandFis {
+            command: if self.identity.lba48 {
+                command::FLUSH_CACHE_EXT
+            } else {
+                command::FLUSH_CACHE
+            },
+            ..Default::default()
+        };
+        self.controller
+            .execute(&self.port.lock(), fis, None, 0, false)
+    }
+}
+
+#[kproc::test("AHCI read and write")]
+fn ahci_read_write() {
+    // The test runner attaches a scratch drive, see `krun::QemuConfig::disks`.
+    let disk = block::devices()
+        .into_iter()
+        .find(|disk| disk.name().starts_with("sd") && !disk.is_read_only())
+        .expect("No writable SATA drive, the test runner attaches one");
+    let sector_size = disk.sector_size();
+    // Spans several commands, to exercise splitting.
+    let len = MAX_TRANSFER + 2 * sector_size;
+    let sectors = (len / sector_size) as u64;
+    assert!(disk.sector_count() >= sectors, "The test disk is too small");
+    let first = disk.sector_count() - sectors;
+    let mut original = alloc::vec![0; len];
+    disk.read(first, &mut original).unwrap();
+    let pattern: Vec<u8> = (0..len).map(|i| (i * 13 + i / sector_size) as u8).collect();
+    disk.write(first, &pattern).unwrap();
+    disk.flush().unwrap();
+    let mut read = alloc::vec![0; len];
+    disk.read(first, &mut read).unwrap();
+    assert!(
+        read == pattern,
+        "Data read back differs from the data written"
+    );
+    disk.write(first, &original).unwrap();
+    disk.flush().unwrap();
+}
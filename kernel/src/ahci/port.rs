@@ -0,0 +1,346 @@
+//! The ports of an AHCI controller and the commands issued through them.
+//!
+//! Every port gets one DMA buffer holding its command list, the area the controller copies the FISes received from the
+//! drive to, and the command table of its first command slot, which is the only one used: commands are issued one at
+//! a time.
+use crate::{memory::dma::DmaBuffer, pci::MappedBar, pci::ProbeError, time::Duration};
+
+use super::wait_for;
+
+/// The registers of a port, relative to its register block.
+mod reg {
+    /// The low half of the address of the command list.
+    pub const CLB: usize = 0x00;
+    /// The high half of the address of the command list.
+    pub const CLBU: usize = 0x04;
+    /// The low half of the address of the received FIS area.
+    pub const FB: usize = 0x08;
+    /// The high half of the address of the received FIS area.
+    pub const FBU: usize = 0x0c;
+    /// The interrupt status, see [super::irq].
+    pub const IS: usize = 0x10;
+    /// The interrupts enabled, see [super::irq].
+    pub const IE: usize = 0x14;
+    /// The command and status register, see [super::cmd].
+    pub const CMD: usize = 0x18;
+    /// The task file data: the status and error registers of the drive.
+    pub const TFD: usize = 0x20;
+    /// The signature of the attached drive.
+    pub const SIG: usize = 0x24;
+    /// The SATA status.
+    pub const SSTS: usize = 0x28;
+    /// The SATA error register.
+    pub const SERR: usize = 0x30;
+    /// The command slots issued and not completed yet.
+    pub const CI: usize = 0x38;
+}
+
+/// The bits of the command and status register.
+mod cmd {
+    /// The port processes the command list.
+    pub const ST: u32 = 1 << 0;
+    /// Spins up the drive, with staggered spin-up.
+    pub const SUD: u32 = 1 << 1;
+    /// The port copies received FISes to memory.
+    pub const FRE: u32 = 1 << 4;
+    /// The FIS receive engine is running.
+    pub const FR: u32 = 1 << 14;
+    /// The command list engine is running.
+    pub const CR: u32 = 1 << 15;
+}
+
+/// The bits of the interrupt status and interrupt enable registers.
+pub(super) mod irq {
+    /// A register FIS from the drive was received, which ends most commands.
+    const DHRS: u32 = 1 << 0;
+    /// A PIO setup FIS was received, which ends PIO data-in commands.
+    const PSS: u32 = 1 << 1;
+    /// A DMA setup FIS was received.
+    const DSS: u32 = 1 << 2;
+    /// A set device bits FIS was received.
+    const SDBS: u32 = 1 << 3;
+    /// An interface error the port can't recover from.
+    const IFS: u32 = 1 << 27;
+    /// A data error on the host bus.
+    const HBDS: u32 = 1 << 28;
+    /// A fatal error on the host bus.
+    const HBFS: u32 = 1 << 29;
+    /// The drive reported an error in its status register.
+    const TFES: u32 = 1 << 30;
+    /// The interrupts that mean the command failed.
+    pub const ERRORS: u32 = IFS | HBDS | HBFS | TFES;
+    /// The interrupts raised by the port.
+    pub const ENABLED: u32 = DHRS | PSS | DSS | SDBS | ERRORS;
+}
+
+/// The drive reported an error.
+const TFD_ERR: u32 = 1 << 0;
+/// The drive expects a data transfer.
+const TFD_DRQ: u32 = 1 << 3;
+/// The drive is busy.
+const TFD_BSY: u32 = 1 << 7;
+/// The device detection field of the SATA status, when a drive is present and communicating.
+const SSTS_DET_PRESENT: u32 = 3;
+
+/// The signature of an ATA drive.
+pub(super) const SIGNATURE_ATA: u32 = 0x0000_0101;
+/// The signature of an ATAPI drive, such as an optical drive.
+pub(super) const SIGNATURE_ATAPI: u32 = 0xeb14_0101;
+
+/// The offset of the received FIS area in the memory of a port.
+const RECEIVED_FIS: usize = 0x400;
+/// The offset of the command table of the first slot in the memory of a port.
+const COMMAND_TABLE: usize = 0x500;
+/// The offset of the PRDT in a command table.
+const PRDT: usize = 0x80;
+/// The number of entries of the PRDT.
+const PRDT_ENTRIES: usize = 8;
+/// The size of the memory of a port.
+const MEMORY_SIZE: usize = COMMAND_TABLE + PRDT + PRDT_ENTRIES * 16;
+/// The most bytes a PRDT entry describes.
+const PRD_MAX: usize = 4 * 1024 * 1024;
+
+/// How long the drive may take to establish communication with the port.
+const LINK_TIMEOUT: Duration = Duration::from_millis(10);
+/// How long the engines of the port may take to start or stop.
+const ENGINE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the drive may stay busy before commands are issued.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The registers of one port.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PortRegisters {
+    hba: MappedBar,
+    base: usize,
+}
+
+impl PortRegisters {
+    /// Returns the registers of the port `index` of the controller whose registers are in `hba`.
+    pub(super) fn new(hba: MappedBar, index: usize) -> Self {
+        PortRegisters {
+            hba,
+            base: 0x100 + 0x80 * index,
+        }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        // SAFETY: Ports belong to drives, which keep the controller and its handle alive, and the interrupt handler is
+        // unregistered before the handle is dropped.
+        unsafe { self.hba.read(self.base + reg) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        // SAFETY: The registers written only point the port to memory it owns, or start and stop it.
+        unsafe { self.hba.write(self.base + reg, value) };
+    }
+
+    /// Reads and acknowledges the pending interrupts.
+    pub(super) fn take_interrupts(&self) -> u32 {
+        let status = self.read(reg::IS);
+        if status != 0 {
+            self.write(reg::IS, status);
+        }
+        status
+    }
+}
+
+/// A command FIS sent from the host to the drive.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct CommandFis {
+    /// The ATA command.
+    pub command: u8,
+    /// The first sector, with up to 48 bits.
+    pub lba: u64,
+    /// The number of sectors.
+    pub count: u16,
+}
+
+impl CommandFis {
+    fn to_bytes(self) -> [u8; 20] {
+        let lba = self.lba.to_le_bytes();
+        let count = self.count.to_le_bytes();
+        let mut fis = [0; 20];
+        // A register FIS from the host, updating the command register.
+        fis[0] = 0x27;
+        fis[1] = 0x80;
+        fis[2] = self.command;
+        fis[4..7].copy_from_slice(&lba[..3]);
+        // LBA addressing, with the top nibble of 28-bit addresses, which commands with 48-bit addresses ignore.
+        fis[7] = 0x40 | (lba[3] & 0x0f);
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[12..14].copy_from_slice(&count);
+        fis
+    }
+}
+
+/// A port with a drive attached.
+#[derive(Debug)]
+pub(super) struct Port {
+    regs: PortRegisters,
+    index: usize,
+    memory: DmaBuffer,
+}
+
+impl Port {
+    /// Stops the port `index`, points it to its memory and starts receiving FISes. Returns `None` if no drive is
+    /// attached. `spin_up` is set if the controller supports staggered spin-up, and `addr64` if it can reach memory
+    /// above 4 GiB.
+    pub(super) fn new(
+        hba: MappedBar,
+        index: usize,
+        spin_up: bool,
+        addr64: bool,
+    ) -> Result<Option<Self>, ProbeError> {
+        let regs = PortRegisters::new(hba, index);
+        if !stop(regs) {
+            return Err(ProbeError::Device("AHCI port didn't stop"));
+        }
+        if spin_up {
+            regs.write(reg::CMD, regs.read(reg::CMD) | cmd::SUD);
+        }
+        if !wait_for(LINK_TIMEOUT, || {
+            regs.read(reg::SSTS) & 0xf == SSTS_DET_PRESENT
+        }) {
+            return Ok(None);
+        }
+
+        let memory =
+            DmaBuffer::new(MEMORY_SIZE).map_err(|_| ProbeError::Device("Out of memory"))?;
+        if !addr64 && memory.phys_addr() + MEMORY_SIZE as u64 > u32::MAX as u64 {
+            return Err(ProbeError::Device(
+                "AHCI port memory out of the controller's reach",
+            ));
+        }
+        let fis = memory.phys_addr() + RECEIVED_FIS as u64;
+        regs.write(reg::CLB, memory.phys_addr() as u32);
+        regs.write(reg::CLBU, (memory.phys_addr() >> 32) as u32);
+        regs.write(reg::FB, fis as u32);
+        regs.write(reg::FBU, (fis >> 32) as u32);
+        regs.write(reg::SERR, !0);
+        regs.write(reg::IS, !0);
+        regs.write(reg::CMD, regs.read(reg::CMD) | cmd::FRE);
+        // The drive sends its signature once it is ready.
+        wait_for(BUSY_TIMEOUT, || regs.read(reg::TFD) & TFD_BSY == 0);
+        Ok(Some(Port {
+            regs,
+            index,
+            memory,
+        }))
+    }
+
+    /// Returns the index of the port.
+    pub(super) fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the registers of the port.
+    pub(super) fn registers(&self) -> PortRegisters {
+        self.regs
+    }
+
+    /// Returns the signature of the drive.
+    pub(super) fn signature(&self) -> u32 {
+        self.regs.read(reg::SIG)
+    }
+
+    /// Enables the interrupts of the port, or disables them.
+    pub(super) fn set_interrupts(&self, enabled: bool) {
+        self.regs
+            .write(reg::IE, if enabled { irq::ENABLED } else { 0 });
+    }
+
+    /// Starts processing the command list once the drive isn't busy. Returns `false` if it stays busy.
+    pub(super) fn start(&self) -> bool {
+        if !wait_for(BUSY_TIMEOUT, || {
+            self.regs.read(reg::TFD) & (TFD_BSY | TFD_DRQ) == 0
+        }) {
+            return false;
+        }
+        self.regs
+            .write(reg::CMD, self.regs.read(reg::CMD) | cmd::ST);
+        true
+    }
+
+    /// Restarts the port after an error, which the port halts on.
+    pub(super) fn recover(&self) {
+        self.regs
+            .write(reg::CMD, self.regs.read(reg::CMD) & !cmd::ST);
+        wait_for(ENGINE_TIMEOUT, || self.regs.read(reg::CMD) & cmd::CR == 0);
+        self.regs.write(reg::SERR, !0);
+        self.regs.write(reg::IS, !0);
+        self.start();
+    }
+
+    /// Issues `fis` in the first command slot, transferring the `len` bytes at the physical address `data` from the
+    /// drive, or to it if `write` is set.
+    ///
+    /// # Safety
+    /// The data must stay valid until the command completes or the port is stopped.
+    pub(super) unsafe fn issue(&self, fis: CommandFis, data: u64, len: usize, write: bool) {
+        let entries = len.div_ceil(PRD_MAX);
+        assert!(
+            entries <= PRDT_ENTRIES,
+            "AHCI transfer of {len} bytes is too large"
+        );
+        let table = self.memory.phys_addr() + COMMAND_TABLE as u64;
+        self.memory.write_bytes(COMMAND_TABLE, &fis.to_bytes());
+        for i in 0..entries {
+            let addr = data + (i * PRD_MAX) as u64;
+            let size = (len - i * PRD_MAX).min(PRD_MAX);
+            let mut entry = [0; 16];
+            entry[0..8].copy_from_slice(&addr.to_le_bytes());
+            entry[12..16].copy_from_slice(&(size as u32 - 1).to_le_bytes());
+            self.memory
+                .write_bytes(COMMAND_TABLE + PRDT + 16 * i, &entry);
+        }
+        // The length of the FIS in dwords, the direction and the length of the PRDT.
+        let flags = 5 | if write { 1 << 6 } else { 0 } | (entries as u32) << 16;
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&flags.to_le_bytes());
+        header[8..16].copy_from_slice(&table.to_le_bytes());
+        self.memory.write_bytes(0, &header);
+        self.regs.write(reg::CI, 1);
+    }
+
+    /// Returns `true` while the command issued hasn't completed.
+    pub(super) fn is_busy(&self) -> bool {
+        self.regs.read(reg::CI) & 1 != 0
+    }
+
+    /// Returns `true` if the drive reported an error for the last command.
+    pub(super) fn has_error(&self) -> bool {
+        self.regs.read(reg::TFD) & TFD_ERR != 0
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        // The port must stop using its memory before it is freed.
+        self.set_interrupts(false);
+        stop(self.regs);
+    }
+}
+
+/// Stops the command list and FIS receive engines of a port. Returns `false` if they don't stop in time.
+fn stop(regs: PortRegisters) -> bool {
+    regs.write(reg::CMD, regs.read(reg::CMD) & !cmd::ST);
+    if !wait_for(ENGINE_TIMEOUT, || regs.read(reg::CMD) & cmd::CR == 0) {
+        return false;
+    }
+    regs.write(reg::CMD, regs.read(reg::CMD) & !cmd::FRE);
+    wait_for(ENGINE_TIMEOUT, || regs.read(reg::CMD) & cmd::FR == 0)
+}
+
+#[kproc::test("AHCI command FIS")]
+fn command_fis() {
+    let fis = CommandFis {
+        command: 0x25,
+        lba: 0x0605_0403_0201,
+        count: 0x0102,
+    }
+    .to_bytes();
+    assert_eq!(&fis[..4], &[0x27, 0x80, 0x25, 0]);
+    assert_eq!(&fis[4..8], &[0x01, 0x02, 0x03, 0x44]);
+    assert_eq!(&fis[8..11], &[0x04, 0x05, 0x06]);
+    assert_eq!(&fis[12..14], &[0x02, 0x01]);
+}
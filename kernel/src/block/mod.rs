@@ -0,0 +1,157 @@
+//! Block devices and the registry of the ones found.
+//!
+//! Every storage driver exposes its disks through [BlockDevice], which reads and writes whole sectors, and registers
+//! them with [register] under a name such as `vda`. Filesystems then look them up with [find] without knowing which
+//! driver is behind them.
+use core::fmt::Debug;
+
+use alloc::{sync::Arc, vec::Vec};
+use cake::{RwLock, log::info};
+
+/// An error reported by a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum BlockError {
+    /// The request reaches beyond the last sector of the device.
+    #[error("Sectors {0}..{1} are out of range")]
+    OutOfRange(u64, u64),
+    /// The buffer isn't a whole number of sectors.
+    #[error("Buffer of {0} bytes isn't a multiple of the sector size")]
+    UnalignedBuffer(usize),
+    /// The device is read-only.
+    #[error("Device is read-only")]
+    ReadOnly,
+    /// The device doesn't support the operation.
+    #[error("Operation not supported")]
+    Unsupported,
+    /// The device failed to complete the request.
+    #[error("I/O error")]
+    Io,
+    /// Memory couldn't be allocated for the request.
+    #[error("Out of memory")]
+    OutOfMemory,
+}
+
+/// A device storing data in fixed-size sectors.
+pub trait BlockDevice: Debug + Send + Sync {
+    /// Returns the name the device is registered under.
+    fn name(&self) -> &str;
+
+    /// Returns the size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// Returns the number of sectors of the device.
+    fn sector_count(&self) -> u64;
+
+    /// Returns `true` if the device can't be written to.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads the sectors starting at `sector` into `buf`, whose length must be a multiple of the sector size.
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf`, whose length must be a multiple of the sector size, to the sectors starting at `sector`.
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Waits until every completed write is stored persistently.
+    fn flush(&self) -> Result<(), BlockError>;
+
+    /// Returns the size of the device in bytes.
+    fn size(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+
+    /// Checks that a request of `len` bytes starting at `sector` is a whole number of sectors within the device, and
+    /// returns the number of sectors it covers.
+    fn check_request(&self, sector: u64, len: usize) -> Result<u64, BlockError> {
+        if !len.is_multiple_of(self.sector_size()) {
+            return Err(BlockError::UnalignedBuffer(len));
+        }
+        let count = (len / self.sector_size()) as u64;
+        match sector.checked_add(count) {
+            Some(end) if end <= self.sector_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange(sector, sector.saturating_add(count))),
+        }
+    }
+}
+
+/// Block devices are what the filesystems of [kfs] live on.
+impl kfs::SectorDevice for dyn BlockDevice {
+    fn sector_size(&self) -> usize {
+        BlockDevice::sector_size(self)
+    }
+
+    fn sector_count(&self) -> u64 {
+        BlockDevice::sector_count(self)
+    }
+
+    fn is_read_only(&self) -> bool {
+        BlockDevice::is_read_only(self)
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), kfs::DeviceError> {
+        self.read(sector, buf).map_err(Into::into)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), kfs::DeviceError> {
+        self.write(sector, buf).map_err(Into::into)
+    }
+
+    fn flush(&self) -> Result<(), kfs::DeviceError> {
+        BlockDevice::flush(self).map_err(Into::into)
+    }
+}
+
+impl From<BlockError> for kfs::DeviceError {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::OutOfRange(..) => kfs::DeviceError::OutOfRange,
+            BlockError::UnalignedBuffer(_) => kfs::DeviceError::UnalignedBuffer,
+            BlockError::ReadOnly => kfs::DeviceError::ReadOnly,
+            BlockError::Unsupported | BlockError::Io | BlockError::OutOfMemory => {
+                kfs::DeviceError::Io
+            }
+        }
+    }
+}
+
+/// The registered devices.
+static DEVICES: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+
+/// Registers `device` so it can be found by name.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    info!(
+        "Block device {}: {} sectors of {} bytes{}",
+        device.name(),
+        device.sector_count(),
+        device.sector_size(),
+        if device.is_read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    DEVICES.write().push(device);
+}
+
+/// Removes the device registered as `name`. Returns `false` if there was none.
+pub fn unregister(name: &str) -> bool {
+    let mut devices = DEVICES.write();
+    let count = devices.len();
+    devices.retain(|device| device.name() != name);
+    devices.len() != count
+}
+
+/// Returns the device registered as `name`.
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .read()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Returns every registered device.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.read().clone()
+}
@@ -4,7 +4,8 @@ use core::fmt::Display;
 use x86_64::structures::idt::{InterruptStackFrameValue, PageFaultErrorCode};
 
 /// Represents the CPU context during an interrupt.
-/// Contains the values of all general-purpose registers.
+/// Contains the values of all general-purpose registers. The x87/SSE/AVX registers are kept separately in a
+/// [FpuState](super::FpuState), since the kernel itself never touches them.
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 #[allow(missing_docs)]
@@ -24,7 +25,6 @@ pub struct ContextValue {
     pub rcx: u64,
     pub rbx: u64,
     pub rax: u64,
-    // TODO float regs
 }
 
 impl ContextValue {
@@ -87,10 +87,10 @@ impl Display for ContextValue {
 pub struct PageFaultInterruptContextValue {
     /// The general CPU context at the time of the page fault.
     pub context: ContextValue,
-    /// The interrupt stack frame at the time of the page fault.
-    pub int_frame: InterruptStackFrameValue,
     /// The page fault error code associated with the page fault.
     pub error_code: PageFaultErrorCode,
+    /// The interrupt stack frame at the time of the page fault.
+    pub int_frame: InterruptStackFrameValue,
 }
 
 impl PageFaultInterruptContextValue {
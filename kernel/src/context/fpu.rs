@@ -0,0 +1,328 @@
+//! x87/SSE/AVX register state.
+//!
+//! The kernel is built without SSE, so only user threads ever touch these registers. Each user thread owns an
+//! [FpuState], which the scheduler saves and restores eagerly on every switch. The save area uses `XSAVE`/`XRSTOR` with
+//! every component enabled in XCR0 where the CPU supports it, sized from CPUID leaf 0xD, and falls back to
+//! `FXSAVE`/`FXRSTOR` otherwise.
+use core::{alloc::Layout, ptr::NonNull};
+
+use alloc::alloc::{Allocator, Global};
+use cake::{Once, log::info};
+use raw_cpuid::CpuId;
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+/// The size of the legacy `FXSAVE` area.
+const FXSAVE_SIZE: usize = 512;
+/// The alignment `XSAVE` requires of its save area. `FXSAVE` only needs 16 bytes.
+const SAVE_AREA_ALIGN: usize = 64;
+/// The default x87 control word: every exception masked, 64-bit precision, round to nearest.
+const DEFAULT_FCW: u16 = 0x037f;
+/// The default MXCSR: every SSE exception masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// How register state is saved on this machine. The same on every core.
+#[derive(Debug, Clone, Copy)]
+struct SaveFormat {
+    /// The size of a save area in bytes.
+    size: usize,
+    /// The components saved with `XSAVE`, or `None` if the CPU only supports `FXSAVE`.
+    xsave: Option<XCr0Flags>,
+}
+
+static FORMAT: Once<SaveFormat> = Once::new();
+
+/// Enables the x87 FPU, SSE and, where supported, `XSAVE` with AVX and AVX-512 on the current core. Must run on every
+/// core before any user thread is switched to.
+pub fn init_core() {
+    let cpuid = CpuId::with_cpuid_reader(raw_cpuid::CpuIdReaderNative);
+    let features = cpuid
+        .get_feature_info()
+        .expect("CPUID leaf 1 is always present");
+    assert!(
+        features.has_fxsave_fxstor(),
+        "The CPU doesn't support FXSAVE"
+    );
+
+    // SAFETY: Enabling the FPU and SSE doesn't change the behavior of kernel code, which uses neither.
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let xsave = features.has_xsave().then(|| {
+        // SAFETY: The CPU supports XSAVE, and only supported components are enabled below.
+        unsafe {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(xcr0_components());
+        }
+        XCr0::read()
+    });
+
+    let format = FORMAT.call_once(|| {
+        let format = SaveFormat {
+            size: match xsave {
+                // EBX of leaf 0xD is the save area size for the components currently enabled in XCR0.
+                Some(_) => core::arch::x86_64::__cpuid_count(0xd, 0).ebx as usize,
+                None => FXSAVE_SIZE,
+            },
+            xsave,
+        };
+        info!("FPU save area: {:?}", format);
+        format
+    });
+    assert_eq!(
+        format.xsave.map(|c| c.bits()),
+        xsave.map(|c| c.bits()),
+        "Cores disagree on the enabled XSAVE components"
+    );
+
+    // SAFETY: The FPU was just enabled.
+    unsafe { core::arch::asm!("fninit", options(nomem, nostack)) };
+}
+
+/// Returns the XSAVE components to enable: x87, SSE, and AVX and AVX-512 where the CPU supports them.
+fn xcr0_components() -> XCr0Flags {
+    let leaf = core::arch::x86_64::__cpuid_count(0xd, 0);
+    let supported = XCr0Flags::from_bits_truncate(leaf.eax as u64 | (leaf.edx as u64) << 32);
+
+    let avx512 = XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+    let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+    if supported.contains(XCr0Flags::AVX) {
+        components |= XCr0Flags::AVX;
+        if supported.contains(avx512) {
+            components |= avx512;
+        }
+    }
+    components
+}
+
+/// A save area for the x87/SSE/AVX registers of a thread.
+#[derive(Debug)]
+pub struct FpuState {
+    area: NonNull<u8>,
+    format: SaveFormat,
+}
+
+// SAFETY: The save area is owned by the state and only accessed through it.
+unsafe impl Send for FpuState {}
+// SAFETY: See above, shared references never access the save area mutably.
+unsafe impl Sync for FpuState {}
+
+impl FpuState {
+    /// Allocates a save area holding the initial register state: empty registers, all exceptions masked.
+    ///
+    /// # Panics
+    /// Panics if [init_core] hasn't run yet.
+    pub fn new() -> Self {
+        let format = *FORMAT.get().expect("FPU state used before init_core");
+        let area = Global
+            .allocate_zeroed(Self::layout(format))
+            .expect("Failed to allocate FPU save area")
+            .cast::<u8>();
+
+        // SAFETY: The area is at least `FXSAVE_SIZE` bytes, and FCW and MXCSR sit at offsets 0 and 24 of the legacy
+        // region. A zeroed XSAVE header marks every other component as being in its initial state.
+        unsafe {
+            area.cast::<u16>().write(DEFAULT_FCW);
+            area.add(24).cast::<u32>().write(DEFAULT_MXCSR);
+        }
+        Self { area, format }
+    }
+
+    fn layout(format: SaveFormat) -> Layout {
+        Layout::from_size_align(format.size, SAVE_AREA_ALIGN).expect("Invalid FPU save area size")
+    }
+
+    /// Saves the registers of the current core into this area.
+    ///
+    /// # Safety
+    /// Must run on a core where [init_core] ran.
+    pub unsafe fn save(&mut self) {
+        let ptr = self.area.as_ptr();
+        match self.format.xsave {
+            // SAFETY: The area is large enough and aligned for the enabled components.
+            Some(components) => unsafe {
+                core::arch::asm!(
+                    "xsave64 [{}]",
+                    in(reg) ptr,
+                    in("eax") components.bits() as u32,
+                    in("edx") (components.bits() >> 32) as u32,
+                    options(nostack, preserves_flags),
+                )
+            },
+            // SAFETY: The area is at least 512 bytes and 16 byte aligned.
+            None => unsafe {
+                core::arch::asm!("fxsave64 [{}]", in(reg) ptr, options(nostack, preserves_flags))
+            },
+        }
+    }
+
+    /// Loads the registers of the current core from this area.
+    ///
+    /// # Safety
+    /// Must run on a core where [init_core] ran.
+    pub unsafe fn restore(&self) {
+        let ptr = self.area.as_ptr();
+        match self.format.xsave {
+            // SAFETY: The area holds a valid XSAVE image, either saved by `save` or built by `new`.
+            Some(components) => unsafe {
+                core::arch::asm!(
+                    "xrstor64 [{}]",
+                    in(reg) ptr,
+                    in("eax") components.bits() as u32,
+                    in("edx") (components.bits() >> 32) as u32,
+                    options(nostack, preserves_flags, readonly),
+                )
+            },
+            // SAFETY: The area holds a valid FXSAVE image, either saved by `save` or built by `new`.
+            None => unsafe {
+                core::arch::asm!(
+                    "fxrstor64 [{}]",
+                    in(reg) ptr,
+                    options(nostack, preserves_flags, readonly),
+                )
+            },
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for FpuState {
+    fn clone(&self) -> Self {
+        let clone = Self::new();
+        // SAFETY: Both areas have the same size and don't overlap.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.area.as_ptr(),
+                clone.area.as_ptr(),
+                self.format.size,
+            )
+        };
+        clone
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // SAFETY: The area was allocated by `new` with the same layout.
+        unsafe { Global.deallocate(self.area, Self::layout(self.format)) };
+    }
+}
+
+#[kproc::test("FPU state survives context switches")]
+fn fpu_state_switches() {
+    use alloc::{sync::Arc, vec::Vec};
+    use nmm::{AddressSpace, MapFlags, MapSource, arch::L1_PAGE_SIZE, paging::Address};
+    use x86_64::VirtAddr;
+
+    use crate::{
+        interrupts, mp,
+        proc::{self, CpuMask},
+        requests::PHYSICAL_MEMORY_OFFSET,
+        syscall::Syscall,
+        time::{Duration, Instant},
+    };
+
+    const CODE: u64 = 0x40_0000;
+    const DATA: u64 = 0x40_1000;
+    const VALUES: [u64; 2] = [0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210];
+
+    /// Returns code that loads `value` into `xmm0`, yields 100 times, then stores `xmm0` at `result` and exits.
+    fn program(value: u64, result: u64) -> Vec<u8> {
+        let mut code = Vec::new();
+        // mov rax, value; movq xmm0, rax; mov ebx, 100
+        code.extend([0x48, 0xb8]);
+        code.extend(value.to_le_bytes());
+        code.extend([0x66, 0x48, 0x0f, 0x6e, 0xc0, 0xbb, 100, 0, 0, 0]);
+        // 1: mov eax, Yield; syscall; dec ebx; jnz 1b
+        code.extend([
+            0xb8,
+            Syscall::Yield as u8,
+            0,
+            0,
+            0,
+            0x0f,
+            0x05,
+            0xff,
+            0xcb,
+            0x75,
+            0xf5,
+        ]);
+        // movq rax, xmm0; mov rdx, result; mov [rdx], rax
+        code.extend([0x66, 0x48, 0x0f, 0x7e, 0xc0, 0x48, 0xba]);
+        code.extend(result.to_le_bytes());
+        code.extend([0x48, 0x89, 0x02]);
+        // mov eax, Exit; syscall
+        code.extend([0xb8, Syscall::Exit as u8, 0, 0, 0, 0x0f, 0x05]);
+        code
+    }
+
+    let space = Arc::new(AddressSpace::new().unwrap());
+    let page = L1_PAGE_SIZE as usize;
+    let user = MapFlags::USER_ACCESSIBLE;
+    let anon = MapSource::Anon { zero: true };
+    space
+        .map(
+            nmm::paging::VirtAddr::new(CODE),
+            anon,
+            page,
+            user | MapFlags::EXECUTABLE,
+        )
+        .unwrap();
+    space
+        .map(
+            nmm::paging::VirtAddr::new(DATA),
+            anon,
+            page,
+            user | MapFlags::WRITABLE,
+        )
+        .unwrap();
+    let hhdm = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
+    let frame = |addr: u64| {
+        let (phys, _) = space.translate(nmm::paging::VirtAddr::new(addr)).unwrap();
+        (hhdm + phys.as_u64()) as *mut u8
+    };
+    let results = frame(DATA).cast::<u64>();
+    let result = |index: usize| {
+        // SAFETY: The data page stays mapped while `space` is alive.
+        unsafe { results.add(index).read_volatile() }
+    };
+
+    // Both threads run on this core, so every yield switches from one to the other.
+    let core = CpuMask::single(mp::current_core_id() as u32);
+    for (index, value) in VALUES.into_iter().enumerate() {
+        let entry = CODE + index as u64 * 0x100;
+        let code = program(value, DATA + index as u64 * 8);
+        // SAFETY: The code page was just mapped, and the thread isn't spawned yet.
+        unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), frame(entry), code.len()) };
+        // SAFETY: The code is mapped above, and never touches the stack, which is left at the top of the data page.
+        let pid = unsafe {
+            proc::spawn_user(
+                "test-fpu",
+                space.clone(),
+                VirtAddr::new(entry),
+                VirtAddr::new(DATA + L1_PAGE_SIZE),
+            )
+        };
+        assert!(proc::set_affinity(pid, core));
+    }
+    interrupts::enable();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while result(0) == 0 || result(1) == 0 {
+        assert!(Instant::now() < deadline, "The user threads didn't finish");
+        proc::yield_now();
+    }
+    interrupts::disable();
+    assert_eq!([result(0), result(1)], VALUES);
+}
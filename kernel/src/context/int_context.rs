@@ -47,6 +47,25 @@ impl InterruptContextValue {
         ctx
     }
 
+    /// Creates a new interrupt context value that returns to user mode.
+    /// # Safety
+    /// The caller must ensure that `rip` and `rsp` are valid user addresses and that `cs` and `ss` are ring 3 selectors.
+    pub unsafe fn new_user(
+        rip: VirtAddr,
+        rsp: VirtAddr,
+        cs: SegmentSelector,
+        ss: SegmentSelector,
+    ) -> InterruptContextValue {
+        let mut ctx = Self::zero();
+        ctx.int_frame.instruction_pointer = rip;
+        ctx.int_frame.stack_pointer = rsp;
+        ctx.int_frame.code_segment = cs;
+        ctx.int_frame.stack_segment = ss;
+        // User code must not be allowed to do port IO, so IOPL stays 0.
+        ctx.int_frame.cpu_flags = RFlags::INTERRUPT_FLAG;
+        ctx
+    }
+
     /// Switches the current context with another. Replaces `to` with the current context.
     /// # Safety
     /// The caller must ensure that the context switch is valid and that the `to` context is properly initialized.
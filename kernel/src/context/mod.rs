@@ -1,6 +1,7 @@
 //! Various processor context representations. Used for context switching and interrupt handling.
 #![allow(private_bounds)] // Don't let implementations on arbitrary types
 mod contexts;
+pub mod fpu;
 mod int_context;
 
 use core::fmt::Debug;
@@ -9,6 +10,7 @@ use core::ops::Deref;
 
 pub use contexts::ContextValue;
 pub use contexts::PageFaultInterruptContextValue;
+pub use fpu::FpuState;
 pub use int_context::InterruptCodeContextValue;
 pub use int_context::InterruptContextValue;
 
@@ -0,0 +1,264 @@
+//! The cache of directory entries, and the mounts they belong to.
+//!
+//! Every entry looked up stays cached under its parent until [Dentry::prune] finds nothing else refers to it, and
+//! keeps its inode alive meanwhile. Each mount also remembers the inodes handed out by its filesystem, so that a file
+//! reached through several entries, such as hard links, is always the same inode.
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use cake::{Mutex, Once, RwLock};
+
+use super::{FileSystem, FileType, FsError, Inode};
+
+/// A filesystem mounted over a directory.
+#[derive(Debug)]
+pub struct Mount {
+    fs: Arc<dyn FileSystem>,
+    /// The entry the filesystem is mounted over, `None` for the root filesystem and once unmounted.
+    mountpoint: RwLock<Option<Arc<Dentry>>>,
+    /// The inodes handed out by the filesystem, by number.
+    inodes: Mutex<BTreeMap<u64, Weak<dyn Inode>>>,
+}
+
+impl Mount {
+    /// Returns the mounted filesystem.
+    pub fn fs(&self) -> &Arc<dyn FileSystem> {
+        &self.fs
+    }
+
+    /// Returns the inode already handed out with the number of `inode` if there is one, and `inode` otherwise.
+    fn cache(&self, inode: Arc<dyn Inode>) -> Arc<dyn Inode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(cached) = inodes.get(&inode.id()).and_then(Weak::upgrade) {
+            return cached;
+        }
+        inodes.insert(inode.id(), Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Forgets the inodes no longer in use.
+    pub fn prune_inodes(&self) {
+        self.inodes
+            .lock()
+            .retain(|_, inode| inode.strong_count() > 0);
+    }
+}
+
+/// A cached directory entry.
+#[derive(Debug)]
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    /// The directory holding the entry, `None` for the root of a mount.
+    parent: Option<Arc<Dentry>>,
+    mount: Arc<Mount>,
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    /// The root of the filesystem mounted over the entry.
+    mounted: RwLock<Option<Arc<Dentry>>>,
+}
+
+/// The root of the filesystem mounted at `/`.
+static ROOT: Once<Arc<Dentry>> = Once::new();
+/// The root of every mounted filesystem, in the order they were mounted.
+static MOUNTS: Mutex<Vec<Arc<Dentry>>> = Mutex::new(Vec::new());
+
+/// Mounts `fs` at `/`, which can only be done once.
+pub fn mount_root(fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let root = Dentry::new_root(fs, None)?;
+    if ROOT.get().is_some() {
+        return Err(FsError::Busy);
+    }
+    ROOT.call_once(|| root.clone());
+    MOUNTS.lock().insert(0, root);
+    Ok(())
+}
+
+/// Returns the root directory of the tree.
+pub fn root() -> Arc<Dentry> {
+    ROOT.get()
+        .expect("The root filesystem isn't mounted")
+        .clone()
+}
+
+/// Returns the root of every mounted filesystem, starting with `/`.
+pub fn mounts() -> Vec<Arc<Dentry>> {
+    MOUNTS.lock().clone()
+}
+
+/// Mounts `fs` over the directory `mountpoint`.
+pub fn attach(mountpoint: &Arc<Dentry>, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    if mountpoint.inode.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    let root = Dentry::new_root(fs, Some(mountpoint.clone()))?;
+    let mut mounted = mountpoint.mounted.write();
+    if mounted.is_some() {
+        return Err(FsError::Busy);
+    }
+    *mounted = Some(root.clone());
+    MOUNTS.lock().push(root);
+    Ok(())
+}
+
+/// Unmounts the filesystem whose root is `root`.
+pub fn detach(root: &Arc<Dentry>) -> Result<(), FsError> {
+    if root.parent.is_some() {
+        return Err(FsError::InvalidPath);
+    }
+    let mut mounts = MOUNTS.lock();
+    let nested = mounts.iter().any(|other| {
+        other
+            .mount
+            .mountpoint
+            .read()
+            .as_ref()
+            .is_some_and(|mountpoint| Arc::ptr_eq(&mountpoint.mount, &root.mount))
+    });
+    if nested {
+        return Err(FsError::Busy);
+    }
+    // The root filesystem has no mount point.
+    let mountpoint = root.mount.mountpoint.write().take().ok_or(FsError::Busy)?;
+    *mountpoint.mounted.write() = None;
+    mounts.retain(|other| !Arc::ptr_eq(other, root));
+    Ok(())
+}
+
+impl Dentry {
+    fn new_root(
+        fs: Arc<dyn FileSystem>,
+        mountpoint: Option<Arc<Dentry>>,
+    ) -> Result<Arc<Self>, FsError> {
+        let inode = fs.root()?;
+        let mount = Arc::new(Mount {
+            fs,
+            mountpoint: RwLock::new(mountpoint),
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+        Ok(Arc::new(Dentry {
+            name: String::new(),
+            inode: mount.cache(inode),
+            parent: None,
+            mount,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: RwLock::new(None),
+        }))
+    }
+
+    /// Returns the inode the entry refers to.
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// Returns the mount the entry belongs to.
+    pub fn mount(&self) -> &Arc<Mount> {
+        &self.mount
+    }
+
+    /// Returns `true` if a filesystem is mounted over the entry.
+    pub fn is_mountpoint(&self) -> bool {
+        self.mounted.read().is_some()
+    }
+
+    /// Returns the root of the filesystems mounted over the entry, the last one if they are stacked, or the entry
+    /// itself if there is none.
+    pub fn follow_mounts(self: Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self;
+        loop {
+            let root = dentry.mounted.read().clone();
+            match root {
+                Some(root) => dentry = root,
+                None => return dentry,
+            }
+        }
+    }
+
+    /// Returns the directory `..` refers to, which may belong to another mount. The parent of `/` is itself.
+    pub fn parent(self: &Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self.clone();
+        loop {
+            if let Some(parent) = &dentry.parent {
+                return parent.clone();
+            }
+            let mountpoint = dentry.mount.mountpoint.read().clone();
+            match mountpoint {
+                Some(mountpoint) => dentry = mountpoint,
+                None => return dentry,
+            }
+        }
+    }
+
+    /// Returns the entry `name` of the directory, without crossing into a filesystem mounted over it.
+    pub fn lookup(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        if let Some(child) = self.children.lock().get(name) {
+            return Ok(child.clone());
+        }
+        // Not looked up under the lock, since filesystems may need to read their device.
+        let inode = self.inode.lookup(name)?;
+        Ok(self.insert(name, inode))
+    }
+
+    /// Caches `inode` as the entry `name` of the directory, unless another thread already did.
+    pub fn insert(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        let inode = self.mount.cache(inode);
+        self.children
+            .lock()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(Dentry {
+                    name: name.to_string(),
+                    inode,
+                    parent: Some(self.clone()),
+                    mount: self.mount.clone(),
+                    children: Mutex::new(BTreeMap::new()),
+                    mounted: RwLock::new(None),
+                })
+            })
+            .clone()
+    }
+
+    /// Forgets the entry `child` of the directory once it was removed from the filesystem, along with the other names
+    /// it is cached under, since filesystems like FAT find the same file under several names.
+    pub fn forget(&self, child: &Dentry) {
+        self.children
+            .lock()
+            .retain(|_, cached| !Arc::ptr_eq(&cached.inode, &child.inode));
+    }
+
+    /// Returns the absolute path of the entry, through the mount points of the filesystems it is on.
+    pub fn path(self: &Arc<Self>) -> String {
+        let mut names = Vec::new();
+        let mut dentry = self.clone();
+        loop {
+            if let Some(parent) = &dentry.parent {
+                names.push(dentry.name.clone());
+                dentry = parent.clone();
+                continue;
+            }
+            let mountpoint = dentry.mount.mountpoint.read().clone();
+            match mountpoint {
+                Some(mountpoint) => dentry = mountpoint,
+                None => break,
+            }
+        }
+        if names.is_empty() {
+            return String::from("/");
+        }
+        names
+            .iter()
+            .rev()
+            .fold(String::new(), |path, name| path + "/" + name)
+    }
+
+    /// Drops the cached entries below the directory that nothing refers to, other than the cache itself.
+    pub fn prune(&self) {
+        self.children.lock().retain(|_, child| {
+            child.prune();
+            // Cached children and open files hold references, and mount points must stay to be crossed.
+            Arc::strong_count(child) > 1 || child.is_mountpoint()
+        });
+    }
+}
@@ -0,0 +1,235 @@
+//! Read-only ext2 volumes, through the driver of [kfs::ext2].
+use core::fmt::{self, Debug};
+
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::{Arc, Weak},
+};
+use kfs::{
+    DeviceError, SectorDevice,
+    ext2::{self, Ext2Error, Ext2Fs},
+};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{BlockDevice, BlockError};
+
+impl From<Ext2Error> for FsError {
+    fn from(err: Ext2Error) -> Self {
+        match err {
+            Ext2Error::NotFound => FsError::NotFound,
+            Ext2Error::NotADirectory => FsError::NotADirectory,
+            Ext2Error::IsADirectory => FsError::IsADirectory,
+            Ext2Error::NotASymlink => FsError::InvalidPath,
+            Ext2Error::TooManyLinks => FsError::TooManyLinks,
+            Ext2Error::Unsupported(_) => FsError::Unsupported,
+            Ext2Error::InvalidSuperblock(_) | Ext2Error::Corrupted(_) => FsError::Corrupted,
+            Ext2Error::Device(DeviceError::ReadOnly) => FsError::ReadOnly,
+            Ext2Error::Device(_) => FsError::Device(BlockError::Io),
+        }
+    }
+}
+
+/// A device an ext2 volume can live on.
+type Device = Box<dyn SectorDevice + Send + Sync>;
+
+/// An ext2 volume, which can't be modified.
+pub struct Ext2FileSystem {
+    this: Weak<Ext2FileSystem>,
+    ext2: Ext2Fs<Device>,
+}
+
+impl Ext2FileSystem {
+    /// Opens the volume on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        Self::with_device(Box::new(device))
+    }
+
+    /// Opens the volume on `device`, which may be a partition or a device in memory.
+    pub fn with_device(device: Device) -> Result<Arc<Self>, FsError> {
+        let ext2 = Ext2Fs::new(device)?;
+        Ok(Arc::new_cyclic(|this| Ext2FileSystem {
+            this: this.clone(),
+            ext2,
+        }))
+    }
+
+    /// Returns the label of the volume, if it has one.
+    pub fn label(&self) -> Option<String> {
+        self.ext2.label()
+    }
+}
+
+impl Debug for Ext2FileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ext2FileSystem")
+            .field("block_size", &self.ext2.block_size())
+            .field("label", &self.ext2.label())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileSystem for Ext2FileSystem {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(Arc::new(Ext2Inode {
+            fs: self.this.upgrade().expect("ext2 filesystem dropped"),
+            inode: self.ext2.root()?,
+        }))
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+/// A file of an [Ext2FileSystem]. Inodes are numbered by the volume, so the VFS shares a single one per file.
+#[derive(Debug)]
+struct Ext2Inode {
+    fs: Arc<Ext2FileSystem>,
+    inode: ext2::Inode,
+}
+
+impl Ext2Inode {
+    /// Returns the inode of a file of the same volume.
+    fn sibling(&self, inode: ext2::Inode) -> Arc<dyn Inode> {
+        Arc::new(Ext2Inode {
+            fs: self.fs.clone(),
+            inode,
+        })
+    }
+}
+
+/// Returns the type of a file in the VFS, where devices, pipes and sockets are empty regular files.
+fn file_type(file_type: ext2::FileType) -> FileType {
+    match file_type {
+        ext2::FileType::Regular | ext2::FileType::Other => FileType::Regular,
+        ext2::FileType::Directory => FileType::Directory,
+        ext2::FileType::Symlink => FileType::Symlink,
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn id(&self) -> u64 {
+        u64::from(self.inode.number())
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.id(),
+            file_type: file_type(self.inode.file_type()),
+            size: self.inode.size(),
+            links: u32::from(self.inode.links()),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.inode.file_type() == ext2::FileType::Other {
+            return Ok(0);
+        }
+        Ok(self.fs.ext2.read(&self.inode, offset, buf)?)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(self.sibling(self.fs.ext2.lookup(&self.inode, name)?))
+    }
+
+    fn read_dir(&self, offset: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        // Offsets are indices in the entries of the directory.
+        let entries = self.fs.ext2.read_dir(&self.inode)?;
+        let Some(entry) = entries.into_iter().nth(offset as usize) else {
+            return Ok(None);
+        };
+        let entry = DirEntry {
+            name: entry.name,
+            inode: u64::from(entry.inode),
+            file_type: file_type(entry.file_type),
+        };
+        Ok(Some((entry, offset + 1)))
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        Ok(self.fs.ext2.read_link(&self.inode)?)
+    }
+}
+
+#[kproc::test("ext2 filesystem")]
+fn ext2_files() {
+    use super::{OpenFlags, metadata, mount, open, read, read_link, remove_dir, unmount, write};
+    use alloc::vec::Vec;
+    use kfs::MemoryDevice;
+
+    // A volume of 1 KiB blocks: the superblock in block 1, the group descriptor in block 2, the inodes in blocks 3
+    // and 4, the root directory in block 5 and the data of its only file in block 6.
+    let mut image = alloc::vec![0; 64 * 1024];
+    let mut put =
+        |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    for (field, value) in [
+        (0, 16),
+        (4, 64),
+        (20, 1),
+        (32, 8192),
+        (40, 16),
+        (76, 1),
+        (96, 2),
+    ] {
+        put(1024 + field, &u32::to_le_bytes(value));
+    }
+    put(1024 + 56, &0xef53u16.to_le_bytes());
+    put(1024 + 88, &128u16.to_le_bytes());
+    put(2048 + 8, &3u32.to_le_bytes());
+    let inode = |number: usize| 3 * 1024 + (number - 1) * 128;
+    for (number, mode, size, links, block) in [
+        (2, 0x41ed, 1024, 2, &5u32.to_le_bytes()[..]),
+        (12, 0x81a4, 10, 1, &6u32.to_le_bytes()[..]),
+        (13, 0xa1ff, 5, 1, b"hello"),
+    ] {
+        put(inode(number), &u16::to_le_bytes(mode));
+        put(inode(number) + 4, &u32::to_le_bytes(size));
+        put(inode(number) + 26, &u16::to_le_bytes(links));
+        put(inode(number) + 40, block);
+    }
+    let mut offset = 5 * 1024;
+    for (number, name, kind, len) in [
+        (2u32, &b"."[..], 2, 12u16),
+        (2, b"..", 2, 12),
+        (12, b"hello", 1, 16),
+        (13, b"link", 7, 1024 - 40),
+    ] {
+        let mut entry = Vec::from(number.to_le_bytes());
+        entry.extend_from_slice(&len.to_le_bytes());
+        entry.extend_from_slice(&[name.len() as u8, kind]);
+        entry.extend_from_slice(name);
+        put(offset, &entry);
+        offset += usize::from(len);
+    }
+    put(6 * 1024, b"hello ext2");
+
+    let fs = Ext2FileSystem::with_device(Box::new(MemoryDevice::from_bytes(512, image))).unwrap();
+    super::create_dir("/ext2-test").unwrap();
+    mount("/ext2-test", fs).unwrap();
+
+    assert_eq!(read("/ext2-test/hello").unwrap(), b"hello ext2");
+    assert_eq!(read("/ext2-test/link").unwrap(), b"hello ext2");
+    assert_eq!(read_link("/ext2-test/link").unwrap(), "hello");
+    assert_eq!(metadata("/ext2-test").unwrap().links, 2);
+    let dir = open("/ext2-test", OpenFlags::READ).unwrap();
+    let mut names = Vec::new();
+    while let Some(entry) = dir.read_dir().unwrap() {
+        names.push((entry.name, entry.file_type));
+    }
+    assert_eq!(
+        names,
+        [
+            (String::from("hello"), FileType::Regular),
+            (String::from("link"), FileType::Symlink)
+        ]
+    );
+    assert_eq!(write("/ext2-test/new", b"data"), Err(FsError::ReadOnly));
+
+    unmount("/ext2-test").unwrap();
+    remove_dir("/ext2-test").unwrap();
+}
@@ -0,0 +1,358 @@
+//! FAT volumes, through the driver of [kfs::fat], and the mount of the EFI system partition.
+use core::{
+    fmt::{self, Debug},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::{
+    boxed::Box,
+    collections::btree_map::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+};
+use cake::{
+    Mutex, RwLock,
+    log::{info, warn},
+};
+use kfs::{
+    DeviceError, SectorDevice, Slice,
+    fat::{FatError, FatFs, Node},
+};
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{self, BlockDevice, BlockError};
+
+/// Where the EFI system partition is mounted.
+pub const ESP_PATH: &str = "/boot/efi";
+
+impl From<FatError> for FsError {
+    fn from(err: FatError) -> Self {
+        match err {
+            FatError::NotFound => FsError::NotFound,
+            FatError::AlreadyExists => FsError::AlreadyExists,
+            FatError::NotADirectory => FsError::NotADirectory,
+            FatError::IsADirectory => FsError::IsADirectory,
+            FatError::DirectoryNotEmpty => FsError::DirectoryNotEmpty,
+            FatError::InvalidName => FsError::InvalidPath,
+            FatError::NoSpace => FsError::NoSpace,
+            FatError::FileTooLarge => FsError::InvalidOffset,
+            FatError::ReadOnly | FatError::Device(DeviceError::ReadOnly) => FsError::ReadOnly,
+            FatError::Unsupported(_) => FsError::Unsupported,
+            FatError::InvalidBootSector(_) | FatError::Corrupted(_) => FsError::Corrupted,
+            FatError::Device(_) => FsError::Device(BlockError::Io),
+        }
+    }
+}
+
+/// A device a FAT volume can live on.
+type Device = Box<dyn SectorDevice + Send + Sync>;
+
+/// A FAT12, FAT16 or FAT32 volume.
+pub struct FatFileSystem {
+    this: Weak<FatFileSystem>,
+    fat: FatFs<Device>,
+    /// The source of the numbers of the inodes.
+    ids: AtomicU64,
+    /// The number and inode of every file seen so far, by the location of its entry. Locations are reused once a
+    /// file is removed, so inodes get numbers of their own.
+    inodes: Mutex<BTreeMap<u64, (u64, Weak<FatInode>)>>,
+}
+
+impl FatFileSystem {
+    /// Opens the volume on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, FsError> {
+        Self::with_device(Box::new(device))
+    }
+
+    /// Opens the volume on `device`, which may be a partition or a device in memory.
+    pub fn with_device(device: Device) -> Result<Arc<Self>, FsError> {
+        let fat = FatFs::new(device)?;
+        Ok(Arc::new_cyclic(|this| FatFileSystem {
+            this: this.clone(),
+            fat,
+            ids: AtomicU64::new(1),
+            inodes: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    /// Returns the label of the volume, if it has one.
+    pub fn label(&self) -> Option<String> {
+        self.fat.label()
+    }
+
+    /// Returns the inode of `node`, which is only created if the file has none yet.
+    fn inode(&self, node: Node) -> Arc<FatInode> {
+        let mut inodes = self.inodes.lock();
+        let (id, inode) = inodes
+            .entry(node.id())
+            .or_insert_with(|| (self.ids.fetch_add(1, Ordering::Relaxed), Weak::new()));
+        if let Some(inode) = inode.upgrade() {
+            return inode;
+        }
+        let new = Arc::new(FatInode {
+            id: *id,
+            fs: self.this.upgrade().expect("FAT filesystem dropped"),
+            node: RwLock::new(node),
+            removed: AtomicBool::new(false),
+        });
+        *inode = Arc::downgrade(&new);
+        new
+    }
+}
+
+impl Debug for FatFileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FatFileSystem")
+            .field("fat_type", &self.fat.fat_type())
+            .field("label", &self.fat.label())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn name(&self) -> &str {
+        "fat"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(self.inode(self.fat.root()))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.fat.is_read_only()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(self.fat.flush()?)
+    }
+}
+
+/// A file of a [FatFileSystem].
+#[derive(Debug)]
+struct FatInode {
+    id: u64,
+    fs: Arc<FatFileSystem>,
+    node: RwLock<Node>,
+    /// Whether the file was removed, which frees its clusters at once.
+    removed: AtomicBool,
+}
+
+impl FatInode {
+    /// Returns the node of the file, unless it was removed.
+    fn node(&self) -> Result<Node, FsError> {
+        if self.removed.load(Ordering::Relaxed) {
+            return Err(FsError::NotFound);
+        }
+        Ok(self.node.read().clone())
+    }
+
+    fn file_type(node: &Node) -> FileType {
+        if node.is_dir() {
+            FileType::Directory
+        } else {
+            FileType::Regular
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn metadata(&self) -> Metadata {
+        let node = self.node.read();
+        Metadata {
+            inode: self.id,
+            file_type: Self::file_type(&node),
+            size: node.size(),
+            links: if self.removed.load(Ordering::Relaxed) {
+                0
+            } else {
+                1
+            },
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let node = self.node()?;
+        Ok(self.fs.fat.read(&node, offset, buf)?)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        self.node()?;
+        Ok(self.fs.fat.write(&mut self.node.write(), offset, buf)?)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.node()?;
+        Ok(self.fs.fat.truncate(&mut self.node.write(), size)?)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let node = self.fs.fat.lookup(&self.node()?, name)?;
+        Ok(self.fs.inode(node))
+    }
+
+    fn read_dir(&self, offset: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        // Offsets are indices, so entries added or removed meanwhile may shift the ones after them.
+        let entries = self.fs.fat.read_dir(&self.node()?)?;
+        let Some(entry) = entries.into_iter().nth(offset as usize) else {
+            return Ok(None);
+        };
+        let file_type = Self::file_type(&entry.node);
+        let inode = self.fs.inode(entry.node).id;
+        let entry = DirEntry {
+            name: entry.name,
+            inode,
+            file_type,
+        };
+        Ok(Some((entry, offset + 1)))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let directory = match file_type {
+            FileType::Regular => false,
+            FileType::Directory => true,
+            FileType::Symlink => return Err(FsError::Unsupported),
+        };
+        let node = self.fs.fat.create(&self.node()?, name, directory)?;
+        Ok(self.fs.inode(node))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let dir = self.node()?;
+        // The registry stays locked so the location isn't reused before its inode is forgotten.
+        let mut inodes = self.fs.inodes.lock();
+        let location = self.fs.fat.lookup(&dir, name)?.id();
+        self.fs.fat.remove(&dir, name)?;
+        if let Some((_, inode)) = inodes.remove(&location)
+            && let Some(inode) = inode.upgrade()
+        {
+            inode.removed.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Mounts the first EFI system partition of the block devices at [ESP_PATH], if there is one.
+pub fn mount_esp() -> Result<(), FsError> {
+    for device in block::devices() {
+        let partitions = match kfs::partition::partitions(&device) {
+            Ok(partitions) => partitions,
+            Err(err) => {
+                warn!("Failed to read the partitions of {}: {err}", device.name());
+                continue;
+            }
+        };
+        for partition in partitions.into_iter().filter(|partition| partition.is_esp) {
+            let Ok(slice) = Slice::new(device.clone(), partition.start, partition.sectors) else {
+                continue;
+            };
+            let fs = match FatFileSystem::with_device(Box::new(slice)) {
+                Ok(fs) => fs,
+                Err(err) => {
+                    warn!("EFI system partition of {} isn't FAT: {err}", device.name());
+                    continue;
+                }
+            };
+            super::create_dir_all(ESP_PATH)?;
+            super::mount(ESP_PATH, fs)?;
+            info!(
+                "Mounted the EFI system partition of {} at {ESP_PATH}",
+                device.name()
+            );
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+#[kproc::test("EFI system partition")]
+fn esp_mounted() {
+    // The test runner boots with UEFI, which krun attaches the boot image for as a disk holding the partition.
+    assert!(
+        *crate::requests::UEFI_BOOT.get().unwrap(),
+        "The tests must boot with UEFI"
+    );
+    assert!(
+        super::mounts()
+            .iter()
+            .any(|(path, fs)| path == ESP_PATH && fs.name() == "fat"),
+        "No EFI system partition mounted at {ESP_PATH}"
+    );
+    assert_eq!(
+        super::metadata(&alloc::format!("{ESP_PATH}/EFI/BOOT")).map(|metadata| metadata.file_type),
+        Ok(FileType::Directory)
+    );
+}
+
+#[kproc::test("FAT filesystem")]
+fn fat_files() {
+    use super::{
+        OpenFlags, SeekFrom, create_dir, metadata, mount, open, read, remove_dir, remove_file,
+        unmount, write,
+    };
+    use kfs::{MemoryDevice, fat::FatType};
+
+    let device = MemoryDevice::new(512, 8192);
+    kfs::fat::format(&device, FatType::Fat16, "KFS").unwrap();
+    let fs = FatFileSystem::with_device(Box::new(device)).unwrap();
+    assert_eq!(fs.label().as_deref(), Some("KFS"));
+    create_dir("/fat-test").unwrap();
+    mount("/fat-test", fs).unwrap();
+
+    write("/fat-test/A long file name.txt", b"hello fat").unwrap();
+    assert_eq!(
+        read("/fat-test/a long file name.TXT").unwrap(),
+        b"hello fat"
+    );
+    create_dir("/fat-test/dir").unwrap();
+    let file = open(
+        "/fat-test/dir/file",
+        OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+    )
+    .unwrap();
+    file.write_all(&[7; 5000]).unwrap();
+    assert_eq!(metadata("/fat-test/dir/file").unwrap().size, 5000);
+    file.set_len(10).unwrap();
+    assert_eq!(read("/fat-test/dir/file").unwrap(), [7; 10]);
+    assert_eq!(
+        metadata("/fat-test/dir").unwrap().file_type,
+        FileType::Directory
+    );
+
+    let dir = open("/fat-test", OpenFlags::READ).unwrap();
+    let mut names = alloc::vec::Vec::new();
+    while let Some(entry) = dir.read_dir().unwrap() {
+        names.push(entry.name);
+    }
+    names.sort();
+    assert_eq!(names, ["A long file name.txt", "dir"]);
+
+    assert_eq!(remove_dir("/fat-test/dir"), Err(FsError::DirectoryNotEmpty));
+    remove_file("/fat-test/dir/file").unwrap();
+    // The clusters of a removed file are freed at once, so it can't be read anymore.
+    file.seek(SeekFrom::Start(0)).unwrap();
+    assert_eq!(file.read(&mut [0; 4]), Err(FsError::NotFound));
+    remove_dir("/fat-test/dir").unwrap();
+    remove_file("/fat-test/A long file name.txt").unwrap();
+    assert_eq!(
+        read("/fat-test/a long file name.txt"),
+        Err(FsError::NotFound)
+    );
+
+    // Removing a file under one spelling of its name forgets the others, so it can be created again under any.
+    write("/fat-test/A.TXT", b"upper").unwrap();
+    assert_eq!(read("/fat-test/A.TXT").unwrap(), b"upper");
+    remove_file("/fat-test/a.txt").unwrap();
+    assert_eq!(read("/fat-test/A.TXT"), Err(FsError::NotFound));
+    write("/fat-test/A.TXT", b"again").unwrap();
+    assert_eq!(read("/fat-test/a.txt").unwrap(), b"again");
+    remove_file("/fat-test/A.TXT").unwrap();
+    create_dir("/fat-test/A.TXT").unwrap();
+    remove_dir("/fat-test/a.txt").unwrap();
+
+    unmount("/fat-test").unwrap();
+    remove_dir("/fat-test").unwrap();
+}
@@ -0,0 +1,186 @@
+//! Open files.
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use cake::Mutex;
+
+use super::{DirEntry, FileType, FsError, Metadata, dentry::Dentry, path};
+
+bitflags! {
+    /// How [File::open] opens a file.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: u32 {
+        /// The file can be read.
+        const READ = 1 << 0;
+        /// The file can be written.
+        const WRITE = 1 << 1;
+        /// Every write goes to the end of the file, with [OpenFlags::WRITE].
+        const APPEND = 1 << 2;
+        /// The file is created if it doesn't exist, in an existing directory.
+        const CREATE = 1 << 3;
+        /// Opening fails if the file exists, with [OpenFlags::CREATE].
+        const EXCLUSIVE = 1 << 4;
+        /// The file is emptied when it is opened, with [OpenFlags::WRITE].
+        const TRUNCATE = 1 << 5;
+        /// Opening fails unless the file is a directory.
+        const DIRECTORY = 1 << 6;
+    }
+}
+
+/// Where [File::seek] moves the offset from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// From the start of the file.
+    Start(u64),
+    /// From the current offset.
+    Current(i64),
+    /// From the end of the file.
+    End(i64),
+}
+
+/// An open file or directory, read and written at an offset that each access moves forward.
+#[derive(Debug)]
+pub struct File {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+impl File {
+    /// Opens the file at `path`, following symbolic links.
+    pub fn open(path: &str, flags: OpenFlags) -> Result<Self, FsError> {
+        let dentry = match path::resolve(path, true) {
+            Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+                return Err(FsError::AlreadyExists);
+            }
+            Ok(dentry) => dentry,
+            Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                path::create(path, |parent, name| parent.create(name, FileType::Regular))?
+            }
+            Err(err) => return Err(err),
+        };
+        let modifies = flags.contains(OpenFlags::WRITE);
+        if flags.intersects(OpenFlags::APPEND | OpenFlags::TRUNCATE) && !modifies {
+            return Err(FsError::BadAccess);
+        }
+        match dentry.inode().metadata().file_type {
+            FileType::Directory if modifies => return Err(FsError::IsADirectory),
+            FileType::Regular if flags.contains(OpenFlags::DIRECTORY) => {
+                return Err(FsError::NotADirectory);
+            }
+            _ => {}
+        }
+        if modifies && dentry.mount().fs().is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        if flags.contains(OpenFlags::TRUNCATE) {
+            dentry.inode().truncate(0)?;
+        }
+        Ok(File {
+            dentry,
+            flags,
+            offset: Mutex::new(0),
+        })
+    }
+
+    /// Returns the flags the file was opened with.
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    /// Returns the absolute path of the file, after following symbolic links.
+    pub fn path(&self) -> String {
+        self.dentry.path()
+    }
+
+    /// Returns the metadata of the file.
+    pub fn metadata(&self) -> Metadata {
+        self.dentry.inode().metadata()
+    }
+
+    /// Checks that the file is a regular file opened with `access`.
+    fn check_access(&self, access: OpenFlags) -> Result<(), FsError> {
+        if !self.flags.contains(access) {
+            return Err(FsError::BadAccess);
+        }
+        match self.metadata().file_type {
+            FileType::Directory => Err(FsError::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads from the offset into `buf`. Returns the number of bytes read, 0 at the end of the file.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.check_access(OpenFlags::READ)?;
+        let mut offset = self.offset.lock();
+        let len = self.dentry.inode().read_at(*offset, buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// Reads from the offset to the end of the file.
+    pub fn read_to_end(&self) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::new();
+        let mut chunk = [0; 512];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(data),
+                len => data.extend_from_slice(&chunk[..len]),
+            }
+        }
+    }
+
+    /// Writes `buf` at the offset, or at the end of the file if it was opened with [OpenFlags::APPEND]. Returns the
+    /// number of bytes written.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        self.check_access(OpenFlags::WRITE)?;
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.metadata().size;
+        }
+        let len = self.dentry.inode().write_at(*offset, buf)?;
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// Writes the whole of `buf`, see [File::write].
+    pub fn write_all(&self, mut buf: &[u8]) -> Result<(), FsError> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(FsError::NoSpace),
+                len => buf = &buf[len..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the offset, which may go past the end of the file, and returns it.
+    pub fn seek(&self, position: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let new = match position {
+            SeekFrom::Start(new) => Some(new),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.metadata().size.checked_add_signed(delta),
+        };
+        *offset = new.ok_or(FsError::InvalidOffset)?;
+        Ok(*offset)
+    }
+
+    /// Truncates or extends the file to `size` bytes, filling the extension with zeroes. The offset doesn't move.
+    pub fn set_len(&self, size: u64) -> Result<(), FsError> {
+        self.check_access(OpenFlags::WRITE)?;
+        self.dentry.inode().truncate(size)
+    }
+
+    /// Returns the next entry of a directory, or `None` past the last one. `.` and `..` aren't included.
+    pub fn read_dir(&self) -> Result<Option<DirEntry>, FsError> {
+        if self.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let mut offset = self.offset.lock();
+        let Some((entry, next)) = self.dentry.inode().read_dir(*offset)? else {
+            return Ok(None);
+        };
+        *offset = next;
+        Ok(Some(entry))
+    }
+}
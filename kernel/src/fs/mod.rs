@@ -0,0 +1,331 @@
+//! The virtual filesystem.
+//!
+//! Filesystems expose their files as [Inode]s, and the VFS ties them into a single tree of paths: each mounted
+//! filesystem hangs its root directory over a directory of the filesystem it is mounted on, starting with a [tmpfs]
+//! mounted at `/` during initialization. Paths are resolved through a cache of the directory entries looked up so far,
+//! which follows symbolic links and handles `.` and `..` across mount points, and the inodes of each mount are cached
+//! so every path to the same file shares a single one.
+//!
+//! Files are accessed through the [File] handles returned by [open]. Threads have no working directory, so relative
+//! paths are resolved from the root.
+use core::{convert::Infallible, fmt::Debug};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use cake::log::warn;
+
+use crate::{block::BlockError, declare_module};
+
+mod dentry;
+pub mod ext2;
+pub mod fat;
+mod file;
+mod path;
+pub mod tmpfs;
+
+pub use file::{File, OpenFlags, SeekFrom};
+
+/// An error reported by the VFS or a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum FsError {
+    /// No file exists at the path.
+    #[error("No such file or directory")]
+    NotFound,
+    /// A file already exists at the path.
+    #[error("File exists")]
+    AlreadyExists,
+    /// A directory was expected.
+    #[error("Not a directory")]
+    NotADirectory,
+    /// The operation can't be applied to a directory.
+    #[error("Is a directory")]
+    IsADirectory,
+    /// The directory to remove still has entries.
+    #[error("Directory not empty")]
+    DirectoryNotEmpty,
+    /// The path or a name in it is empty or otherwise invalid.
+    #[error("Invalid path")]
+    InvalidPath,
+    /// Too many symbolic links were followed, likely because they form a loop.
+    #[error("Too many levels of symbolic links")]
+    TooManyLinks,
+    /// The file wasn't opened for the access attempted.
+    #[error("Bad file access mode")]
+    BadAccess,
+    /// The offset is beyond what the file supports.
+    #[error("Invalid offset")]
+    InvalidOffset,
+    /// The filesystem is read-only.
+    #[error("Read-only filesystem")]
+    ReadOnly,
+    /// The directory is a mount point, or the filesystem has others mounted in it.
+    #[error("Resource busy")]
+    Busy,
+    /// The filesystem has no space left.
+    #[error("No space left on device")]
+    NoSpace,
+    /// The filesystem doesn't support the operation.
+    #[error("Operation not supported")]
+    Unsupported,
+    /// The data of the filesystem is inconsistent.
+    #[error("Corrupted filesystem")]
+    Corrupted,
+    /// The device holding the filesystem failed.
+    #[error("Device error: {0}")]
+    Device(#[from] BlockError),
+}
+
+/// The type of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// A regular file, holding data.
+    Regular,
+    /// A directory, holding named entries.
+    Directory,
+    /// A symbolic link, holding a path.
+    Symlink,
+}
+
+/// What [metadata] and [File::metadata] report about a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// The number of the inode, unique within its filesystem.
+    pub inode: u64,
+    /// The type of the file.
+    pub file_type: FileType,
+    /// The size of the file in bytes, or the length of the target of a symbolic link.
+    pub size: u64,
+    /// The number of directory entries referring to the file.
+    pub links: u32,
+}
+
+/// An entry of a directory, as returned by [File::read_dir].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name of the entry.
+    pub name: String,
+    /// The number of the inode it refers to.
+    pub inode: u64,
+    /// The type of the file it refers to.
+    pub file_type: FileType,
+}
+
+/// A mounted filesystem.
+pub trait FileSystem: Debug + Send + Sync {
+    /// Returns the name of the type of the filesystem, such as `tmpfs`.
+    fn name(&self) -> &str;
+
+    /// Returns the root directory.
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError>;
+
+    /// Returns `true` if the filesystem can't be modified. The VFS then rejects every modification before it reaches
+    /// the inodes.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Writes every modification to the device holding the filesystem.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A file of a filesystem.
+///
+/// The VFS checks the type of the file before calling the operations specific to it, so filesystems only implement
+/// the ones their files support. Names given to directory operations are never empty, `.` or `..`, and never contain
+/// `/`.
+pub trait Inode: Debug + Send + Sync {
+    /// Returns the number of the inode, unique within its filesystem.
+    fn id(&self) -> u64;
+
+    /// Returns the metadata of the file.
+    fn metadata(&self) -> Metadata;
+
+    /// Reads the data of a regular file starting at `offset` into `buf`. Returns the number of bytes read, 0 at the
+    /// end of the file.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _ = (offset, buf);
+        Err(FsError::Unsupported)
+    }
+
+    /// Writes `buf` to a regular file starting at `offset`, extending it if needed. Returns the number of bytes
+    /// written.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let _ = (offset, buf);
+        Err(FsError::Unsupported)
+    }
+
+    /// Truncates or extends a regular file to `size` bytes, filling the extension with zeroes.
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let _ = size;
+        Err(FsError::Unsupported)
+    }
+
+    /// Returns the entry `name` of a directory.
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _ = name;
+        Err(FsError::Unsupported)
+    }
+
+    /// Returns the entry of a directory at `offset` and the offset of the next one, or `None` past the last entry.
+    /// Offsets are opaque to the VFS, which starts at 0.
+    fn read_dir(&self, offset: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let _ = offset;
+        Err(FsError::Unsupported)
+    }
+
+    /// Creates an empty regular file or directory named `name` in a directory.
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let _ = (name, file_type);
+        Err(FsError::Unsupported)
+    }
+
+    /// Creates a symbolic link named `name` to `target` in a directory.
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let _ = (name, target);
+        Err(FsError::Unsupported)
+    }
+
+    /// Removes the entry `name` from a directory. Directories may only be removed once empty.
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let _ = name;
+        Err(FsError::Unsupported)
+    }
+
+    /// Returns the target of a symbolic link.
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+declare_module!("fs", init);
+
+fn init() -> Result<(), Infallible> {
+    dentry::mount_root(tmpfs::TmpFs::new()).expect("Failed to mount the root filesystem");
+    if let Err(err) = fat::mount_esp() {
+        warn!("Failed to mount the EFI system partition: {err}");
+    }
+    Ok(())
+}
+
+/// Opens the file at `path`, see [OpenFlags] for the options.
+pub fn open(path: &str, flags: OpenFlags) -> Result<File, FsError> {
+    File::open(path, flags)
+}
+
+/// Returns the metadata of the file at `path`, following symbolic links.
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    Ok(path::resolve(path, true)?.inode().metadata())
+}
+
+/// Returns the metadata of the file at `path`, or of the symbolic link itself if it is one.
+pub fn symlink_metadata(path: &str) -> Result<Metadata, FsError> {
+    Ok(path::resolve(path, false)?.inode().metadata())
+}
+
+/// Reads the whole file at `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    open(path, OpenFlags::READ)?.read_to_end()
+}
+
+/// Writes `data` to the file at `path`, creating it if needed and replacing its previous contents.
+pub fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let file = open(
+        path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+    )?;
+    file.write_all(data)
+}
+
+/// Creates the directory `path`. Its parent must exist.
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    path::create(path, |parent, name| {
+        parent.create(name, FileType::Directory)
+    })?;
+    Ok(())
+}
+
+/// Creates the directory `path` and any of its missing parents. Directories already there are left as they are.
+pub fn create_dir_all(path: &str) -> Result<(), FsError> {
+    if path.trim_matches('/').is_empty() {
+        return Ok(());
+    }
+    match metadata(path) {
+        Ok(metadata) if metadata.file_type == FileType::Directory => return Ok(()),
+        Ok(_) => return Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => {}
+        Err(err) => return Err(err),
+    }
+    let path = path.trim_end_matches('/');
+    if let Some(index) = path.rfind('/') {
+        create_dir_all(&path[..index])?;
+    }
+    match create_dir(path) {
+        // Another thread may have created it meanwhile.
+        Err(FsError::AlreadyExists) if metadata(path)?.file_type == FileType::Directory => Ok(()),
+        result => result,
+    }
+}
+
+/// Creates a symbolic link at `path` pointing to `target`, which isn't checked.
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    if target.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    path::create(path, |parent, name| parent.symlink(name, target))?;
+    Ok(())
+}
+
+/// Returns the target of the symbolic link at `path`.
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    let dentry = path::resolve(path, false)?;
+    if dentry.inode().metadata().file_type != FileType::Symlink {
+        return Err(FsError::InvalidPath);
+    }
+    dentry.inode().read_link()
+}
+
+/// Removes the file or symbolic link at `path`.
+pub fn remove_file(path: &str) -> Result<(), FsError> {
+    path::remove(path, false)
+}
+
+/// Removes the empty directory at `path`.
+pub fn remove_dir(path: &str) -> Result<(), FsError> {
+    path::remove(path, true)
+}
+
+/// Mounts `fs` over the directory at `path`, hiding its entries until it is unmounted.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    dentry::attach(&path::resolve(path, true)?, fs)
+}
+
+/// Unmounts the filesystem mounted at `path`, which must have no other filesystem mounted in it. Files still open
+/// on it stay usable.
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    dentry::detach(&path::resolve(path, true)?)
+}
+
+/// Returns the path of every mount point and the filesystem mounted on it, from the root.
+pub fn mounts() -> Vec<(String, Arc<dyn FileSystem>)> {
+    dentry::mounts()
+        .into_iter()
+        .map(|root| (root.path(), root.mount().fs().clone()))
+        .collect()
+}
+
+/// Writes the modifications of every mounted filesystem to their devices.
+pub fn sync() -> Result<(), FsError> {
+    for root in dentry::mounts() {
+        root.mount().fs().sync()?;
+    }
+    Ok(())
+}
+
+/// Drops the cached directory entries and inodes that nothing uses.
+pub fn prune_caches() {
+    for root in dentry::mounts() {
+        root.prune();
+        root.mount().prune_inodes();
+    }
+}
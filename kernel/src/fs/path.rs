@@ -0,0 +1,193 @@
+//! Path resolution.
+use alloc::sync::Arc;
+
+use super::{
+    FileType, FsError, Inode,
+    dentry::{self, Dentry},
+};
+
+/// The most symbolic links followed while resolving a path.
+const MAX_LINKS: usize = 40;
+
+/// Returns the entry at `path`. A symbolic link at the end of the path is returned as is unless `follow`, the ones
+/// before it are always followed.
+pub fn resolve(path: &str, follow: bool) -> Result<Arc<Dentry>, FsError> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    walk(dentry::root(), path, follow, &mut 0)
+}
+
+/// Resolves `path` from `start`, or from the root if it is absolute, counting the symbolic links followed in `links`.
+fn walk(
+    start: Arc<Dentry>,
+    path: &str,
+    follow: bool,
+    links: &mut usize,
+) -> Result<Arc<Dentry>, FsError> {
+    let mut current = if path.starts_with('/') {
+        dentry::root()
+    } else {
+        start
+    };
+    let mut names = path.split('/').filter(|name| !name.is_empty()).peekable();
+    while let Some(name) = names.next() {
+        if current.inode().metadata().file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        match name {
+            "." => continue,
+            ".." => {
+                current = current.parent();
+                continue;
+            }
+            _ => {}
+        }
+        let child = current.lookup(name)?.follow_mounts();
+        let last = names.peek().is_none();
+        if child.inode().metadata().file_type == FileType::Symlink && (follow || !last) {
+            *links += 1;
+            if *links > MAX_LINKS {
+                return Err(FsError::TooManyLinks);
+            }
+            let target = child.inode().read_link()?;
+            // Relative targets start from the directory holding the link.
+            current = walk(current, &target, true, links)?;
+        } else {
+            current = child;
+        }
+    }
+    Ok(current)
+}
+
+/// Splits `path` into the path of its parent directory and its last name.
+fn split(path: &str) -> Result<(&str, &str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(index) => (&path[..=index], &path[index + 1..]),
+        None => ("/", path),
+    };
+    if matches!(name, "" | "." | "..") {
+        return Err(FsError::InvalidPath);
+    }
+    Ok((parent, name))
+}
+
+/// Resolves the parent directory of `path`, which must be writable, and returns it with the last name of the path.
+fn resolve_parent(path: &str) -> Result<(Arc<Dentry>, &str), FsError> {
+    let (parent, name) = split(path)?;
+    let parent = resolve(parent, true)?;
+    if parent.inode().metadata().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    if parent.mount().fs().is_read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    Ok((parent, name))
+}
+
+/// Creates the file at `path` with `create`, called with its parent directory and its name, and returns its entry.
+pub fn create(
+    path: &str,
+    create: impl FnOnce(&dyn Inode, &str) -> Result<Arc<dyn Inode>, FsError>,
+) -> Result<Arc<Dentry>, FsError> {
+    let (parent, name) = resolve_parent(path)?;
+    match parent.lookup(name) {
+        Ok(_) => return Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => {}
+        Err(err) => return Err(err),
+    }
+    let inode = create(parent.inode().as_ref(), name)?;
+    Ok(parent.insert(name, inode))
+}
+
+/// Removes the file at `path`, which must be a directory if `directory` and mustn't be one otherwise.
+pub fn remove(path: &str, directory: bool) -> Result<(), FsError> {
+    let (parent, name) = resolve_parent(path)?;
+    let child = parent.lookup(name)?;
+    if child.is_mountpoint() {
+        return Err(FsError::Busy);
+    }
+    match (directory, child.inode().metadata().file_type) {
+        (true, FileType::Directory) | (false, FileType::Regular | FileType::Symlink) => {}
+        (true, _) => return Err(FsError::NotADirectory),
+        (false, FileType::Directory) => return Err(FsError::IsADirectory),
+    }
+    parent.inode().unlink(name)?;
+    parent.forget(&child);
+    Ok(())
+}
+
+#[kproc::test("VFS path resolution")]
+fn vfs_path_resolution() {
+    use super::{
+        create_dir, metadata, mount, mounts, prune_caches, read, read_link, remove_dir,
+        remove_file, symlink, symlink_metadata, tmpfs::TmpFs, unmount, write,
+    };
+
+    create_dir("/vfs-test").unwrap();
+    create_dir("/vfs-test/a").unwrap();
+    create_dir("/vfs-test/a/b").unwrap();
+    write("/vfs-test/a/b/file", b"data").unwrap();
+    assert_eq!(read("/vfs-test/./a/../a/b/file").unwrap(), b"data");
+    assert_eq!(read("//vfs-test/a/b/../../a/b//file").unwrap(), b"data");
+    // `..` stops at the root, and relative paths start from it.
+    assert_eq!(read("/../../vfs-test/a/b/file").unwrap(), b"data");
+    assert_eq!(read("vfs-test/a/b/file").unwrap(), b"data");
+    assert_eq!(read("/vfs-test/a/b/file/.."), Err(FsError::NotADirectory));
+    assert_eq!(read("/vfs-test/a/missing"), Err(FsError::NotFound));
+    assert_eq!(create_dir("/vfs-test/a/b"), Err(FsError::AlreadyExists));
+    assert_eq!(create_dir("/vfs-test/a/.."), Err(FsError::InvalidPath));
+
+    // Relative targets start from the directory of the link, and links may point to other links.
+    symlink("b/file", "/vfs-test/a/relative").unwrap();
+    symlink("/vfs-test/a/b", "/vfs-test/absolute").unwrap();
+    symlink("../absolute/./file", "/vfs-test/a/chained").unwrap();
+    assert_eq!(read("/vfs-test/a/relative").unwrap(), b"data");
+    assert_eq!(read("/vfs-test/absolute/file").unwrap(), b"data");
+    assert_eq!(read("/vfs-test/a/chained").unwrap(), b"data");
+    assert_eq!(
+        read_link("/vfs-test/a/chained").unwrap(),
+        "../absolute/./file"
+    );
+    assert_eq!(
+        metadata("/vfs-test/a/relative").unwrap().file_type,
+        FileType::Regular
+    );
+    assert_eq!(
+        symlink_metadata("/vfs-test/a/relative").unwrap().file_type,
+        FileType::Symlink
+    );
+    // `..` after a link to a directory leaves the target, not the directory holding the link.
+    assert_eq!(read("/vfs-test/absolute/../b/file").unwrap(), b"data");
+    symlink("loop-b", "/vfs-test/loop-a").unwrap();
+    symlink("loop-a", "/vfs-test/loop-b").unwrap();
+    assert_eq!(read("/vfs-test/loop-a"), Err(FsError::TooManyLinks));
+
+    // Mounts hide the directory they are mounted over, and `..` leads back out of them.
+    mount("/vfs-test/a", TmpFs::new()).unwrap();
+    assert_eq!(read("/vfs-test/a/b/file"), Err(FsError::NotFound));
+    write("/vfs-test/a/mounted", b"mounted").unwrap();
+    assert_eq!(read("/vfs-test/a/../a/mounted").unwrap(), b"mounted");
+    assert_eq!(read("/vfs-test/a/../absolute/file"), Err(FsError::NotFound));
+    assert!(
+        mounts()
+            .iter()
+            .any(|(path, fs)| path == "/vfs-test/a" && fs.name() == "tmpfs")
+    );
+    assert_eq!(remove_dir("/vfs-test/a"), Err(FsError::Busy));
+    prune_caches();
+    assert_eq!(read("/vfs-test/a/mounted").unwrap(), b"mounted");
+    unmount("/vfs-test/a").unwrap();
+    assert_eq!(read("/vfs-test/a/b/file").unwrap(), b"data");
+    assert_eq!(unmount("/vfs-test/a"), Err(FsError::InvalidPath));
+
+    for link in ["a/relative", "a/chained", "absolute", "loop-a", "loop-b"] {
+        remove_file(&alloc::format!("/vfs-test/{link}")).unwrap();
+    }
+    remove_file("/vfs-test/a/b/file").unwrap();
+    remove_dir("/vfs-test/a/b").unwrap();
+    remove_dir("/vfs-test/a").unwrap();
+    remove_dir("/vfs-test").unwrap();
+    assert_eq!(metadata("/vfs-test"), Err(FsError::NotFound));
+}
@@ -0,0 +1,326 @@
+//! A filesystem keeping its files in memory, which needs no device and is lost once nothing refers to it anymore.
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use alloc::{
+    collections::btree_map::{BTreeMap, Entry},
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use cake::RwLock;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+
+/// A filesystem held in memory.
+#[derive(Debug)]
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    /// Creates an empty filesystem.
+    pub fn new() -> Arc<Self> {
+        let ids = Arc::new(AtomicU64::new(1));
+        Arc::new(TmpFs {
+            root: TmpInode::new(&ids, Node::Directory(BTreeMap::new())),
+        })
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Result<Arc<dyn Inode>, FsError> {
+        Ok(self.root.clone())
+    }
+}
+
+/// The contents of a file.
+#[derive(Debug)]
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+    Symlink(String),
+}
+
+/// A file of a [TmpFs].
+#[derive(Debug)]
+struct TmpInode {
+    id: u64,
+    /// The source of the numbers of the inodes of the filesystem.
+    ids: Arc<AtomicU64>,
+    links: AtomicU32,
+    node: RwLock<Node>,
+}
+
+impl TmpInode {
+    fn new(ids: &Arc<AtomicU64>, node: Node) -> Arc<Self> {
+        // Directories are also referred to by their own `.` entry.
+        let links = if matches!(node, Node::Directory(_)) {
+            2
+        } else {
+            1
+        };
+        Arc::new(TmpInode {
+            id: ids.fetch_add(1, Ordering::Relaxed),
+            ids: ids.clone(),
+            links: AtomicU32::new(links),
+            node: RwLock::new(node),
+        })
+    }
+
+    fn file_type(node: &Node) -> FileType {
+        match node {
+            Node::File(_) => FileType::Regular,
+            Node::Directory(_) => FileType::Directory,
+            Node::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    /// Adds `node` to the directory as `name`.
+    fn add(&self, name: &str, node: Node) -> Result<Arc<dyn Inode>, FsError> {
+        let is_directory = matches!(node, Node::Directory(_));
+        let mut parent = self.node.write();
+        let Node::Directory(entries) = &mut *parent else {
+            return Err(FsError::NotADirectory);
+        };
+        let Entry::Vacant(entry) = entries.entry(name.to_string()) else {
+            return Err(FsError::AlreadyExists);
+        };
+        let inode = TmpInode::new(&self.ids, node);
+        entry.insert(inode.clone());
+        // The `..` entry of a subdirectory refers to its parent.
+        if is_directory {
+            self.links.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(inode)
+    }
+}
+
+impl Inode for TmpInode {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn metadata(&self) -> Metadata {
+        let node = self.node.read();
+        Metadata {
+            inode: self.id,
+            file_type: Self::file_type(&node),
+            size: match &*node {
+                Node::File(data) => data.len() as u64,
+                Node::Directory(entries) => entries.len() as u64,
+                Node::Symlink(target) => target.len() as u64,
+            },
+            links: self.links.load(Ordering::Relaxed),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let Node::File(data) = &*self.node.read() else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = offset.min(data.len() as u64) as usize;
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let Node::File(data) = &mut *self.node.write() else {
+            return Err(FsError::IsADirectory);
+        };
+        let end = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| offset.checked_add(buf.len()))
+            .ok_or(FsError::InvalidOffset)?;
+        if end > data.len() {
+            data.try_reserve(end - data.len())
+                .map_err(|_| FsError::NoSpace)?;
+            data.resize(end, 0);
+        }
+        data[end - buf.len()..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let Node::File(data) = &mut *self.node.write() else {
+            return Err(FsError::IsADirectory);
+        };
+        let size = usize::try_from(size).map_err(|_| FsError::InvalidOffset)?;
+        if size > data.len() {
+            data.try_reserve(size - data.len())
+                .map_err(|_| FsError::NoSpace)?;
+        }
+        data.resize(size, 0);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let Node::Directory(entries) = &*self.node.read() else {
+            return Err(FsError::NotADirectory);
+        };
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+        Ok(inode.clone())
+    }
+
+    fn read_dir(&self, offset: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let Node::Directory(entries) = &*self.node.read() else {
+            return Err(FsError::NotADirectory);
+        };
+        // Offsets are indices, so entries added or removed meanwhile may shift the ones after them.
+        let Some((name, inode)) = entries.iter().nth(offset as usize) else {
+            return Ok(None);
+        };
+        let entry = DirEntry {
+            name: name.clone(),
+            inode: inode.id,
+            file_type: Self::file_type(&inode.node.read()),
+        };
+        Ok(Some((entry, offset + 1)))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let node = match file_type {
+            FileType::Regular => Node::File(Vec::new()),
+            FileType::Directory => Node::Directory(BTreeMap::new()),
+            FileType::Symlink => return Err(FsError::Unsupported),
+        };
+        self.add(name, node)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.add(name, Node::Symlink(target.to_string()))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut parent = self.node.write();
+        let Node::Directory(entries) = &mut *parent else {
+            return Err(FsError::NotADirectory);
+        };
+        let inode = entries.get(name).ok_or(FsError::NotFound)?;
+        let is_directory = match &*inode.node.read() {
+            Node::Directory(children) if !children.is_empty() => {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+            node => matches!(node, Node::Directory(_)),
+        };
+        let inode = entries.remove(name).unwrap();
+        if is_directory {
+            self.links.fetch_sub(1, Ordering::Relaxed);
+            inode.links.store(0, Ordering::Relaxed);
+        } else {
+            inode.links.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &*self.node.read() {
+            Node::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidPath),
+        }
+    }
+}
+
+#[kproc::test("tmpfs files and directories")]
+fn tmpfs_files() {
+    use super::{
+        OpenFlags, SeekFrom, create_dir, metadata, mount, open, read, remove_dir, remove_file,
+        unmount,
+    };
+
+    create_dir("/tmpfs-test").unwrap();
+    mount("/tmpfs-test", TmpFs::new()).unwrap();
+
+    let file = open(
+        "/tmpfs-test/file",
+        OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+    )
+    .unwrap();
+    assert_eq!(file.write(b"hello world").unwrap(), 11);
+    assert_eq!(file.seek(SeekFrom::Start(6)).unwrap(), 6);
+    file.write_all(b"tmpfs").unwrap();
+    assert_eq!(file.seek(SeekFrom::Current(-5)).unwrap(), 6);
+    let mut buf = [0; 16];
+    assert_eq!(file.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"tmpfs");
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+    assert_eq!(
+        file.seek(SeekFrom::Current(-20)),
+        Err(FsError::InvalidOffset)
+    );
+    // Writing past the end fills the gap with zeroes.
+    file.seek(SeekFrom::End(2)).unwrap();
+    file.write_all(b"!").unwrap();
+    assert_eq!(read("/tmpfs-test/file").unwrap(), b"hello tmpfs\0\0!");
+    file.set_len(5).unwrap();
+    let metadata_of_file = file.metadata();
+    assert_eq!(metadata_of_file.size, 5);
+    assert_eq!(metadata_of_file.file_type, FileType::Regular);
+    assert_eq!(metadata_of_file.links, 1);
+
+    let appender = open("/tmpfs-test/file", OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+    appender.write_all(b", again").unwrap();
+    assert_eq!(read("/tmpfs-test/file").unwrap(), b"hello, again");
+    assert_eq!(appender.read(&mut buf), Err(FsError::BadAccess));
+    let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+    assert_eq!(
+        open("/tmpfs-test/file", flags).unwrap_err(),
+        FsError::AlreadyExists
+    );
+    assert_eq!(
+        open("/tmpfs-test/missing", OpenFlags::READ).unwrap_err(),
+        FsError::NotFound
+    );
+    assert_eq!(
+        open("/tmpfs-test/file", OpenFlags::READ | OpenFlags::DIRECTORY).unwrap_err(),
+        FsError::NotADirectory
+    );
+
+    create_dir("/tmpfs-test/dir").unwrap();
+    create_dir("/tmpfs-test/dir/sub").unwrap();
+    assert_eq!(metadata("/tmpfs-test/dir").unwrap().links, 3);
+    assert_eq!(
+        open("/tmpfs-test/dir", OpenFlags::WRITE).unwrap_err(),
+        FsError::IsADirectory
+    );
+    let dir = open("/tmpfs-test", OpenFlags::READ).unwrap();
+    assert_eq!(dir.read(&mut buf), Err(FsError::IsADirectory));
+    let mut entries = Vec::new();
+    while let Some(entry) = dir.read_dir().unwrap() {
+        entries.push((entry.name, entry.file_type));
+    }
+    assert_eq!(
+        entries,
+        [
+            (String::from("dir"), FileType::Directory),
+            (String::from("file"), FileType::Regular)
+        ]
+    );
+    dir.seek(SeekFrom::Start(0)).unwrap();
+    assert_eq!(dir.read_dir().unwrap().unwrap().name, "dir");
+
+    assert_eq!(
+        remove_dir("/tmpfs-test/dir"),
+        Err(FsError::DirectoryNotEmpty)
+    );
+    assert_eq!(
+        remove_file("/tmpfs-test/dir/sub"),
+        Err(FsError::IsADirectory)
+    );
+    assert_eq!(remove_dir("/tmpfs-test/file"), Err(FsError::NotADirectory));
+    remove_dir("/tmpfs-test/dir/sub").unwrap();
+    remove_dir("/tmpfs-test/dir").unwrap();
+    remove_file("/tmpfs-test/file").unwrap();
+    // Open files outlive their last link.
+    file.seek(SeekFrom::Start(0)).unwrap();
+    assert_eq!(file.read(&mut buf).unwrap(), 12);
+    assert_eq!(file.metadata().links, 0);
+    assert_eq!(read("/tmpfs-test/file"), Err(FsError::NotFound));
+
+    unmount("/tmpfs-test").unwrap();
+    remove_dir("/tmpfs-test").unwrap();
+}
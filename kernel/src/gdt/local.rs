@@ -41,7 +41,7 @@ pub struct DescriptorState {
     /// The segment selectors for this GDT.
     pub selectors: Selectors,
     /// The TSS for this GDT.
-    pub tss: &'static TaskStateSegment,
+    tss: *mut TaskStateSegment,
     /// The interrupt stack pointer.
     interrupt_stack: *mut u8,
 }
@@ -64,10 +64,11 @@ impl DescriptorState {
         tss.interrupt_stack_table[IST_FAULT_INDEX as usize] = VirtAddr::from_ptr(interrupt_stack);
         unsafe { CORE0_TSS = tss };
 
+        // `sysret` loads the user selectors relative to one base, with the data segment before the code segment.
         let k_code = gdt.append(Descriptor::kernel_code_segment());
         let k_data = gdt.append(Descriptor::kernel_data_segment());
-        let u_code = gdt.append(Descriptor::user_code_segment());
         let u_data = gdt.append(Descriptor::user_data_segment());
+        let u_code = gdt.append(Descriptor::user_code_segment());
 
         let tss_selector = gdt.append(Descriptor::tss_segment(unsafe { &CORE0_TSS }));
 
@@ -80,7 +81,7 @@ impl DescriptorState {
                 user_data: u_data,
                 tss_selector,
             },
-            tss: unsafe { &mut CORE0_TSS },
+            tss: &raw mut CORE0_TSS,
             interrupt_stack,
         }
     }
@@ -102,19 +103,20 @@ impl DescriptorState {
             .as_ptr()
             .cast::<TaskStateSegment>();
 
-        let tss = unsafe {
+        let tss_ref = unsafe {
             tss.write(TaskStateSegment::new());
             &mut *tss
         };
 
-        tss.interrupt_stack_table[IST_FAULT_INDEX as usize] =
+        tss_ref.interrupt_stack_table[IST_FAULT_INDEX as usize] =
             VirtAddr::from_ptr(unsafe { stack.add(crate::STACK_SIZE as usize) });
 
+        // `sysret` loads the user selectors relative to one base, with the data segment before the code segment.
         let k_code = gdt.append(Descriptor::kernel_code_segment());
         let k_data = gdt.append(Descriptor::kernel_data_segment());
-        let u_code = gdt.append(Descriptor::user_code_segment());
         let u_data = gdt.append(Descriptor::user_data_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+        let u_code = gdt.append(Descriptor::user_code_segment());
+        let tss_selector = gdt.append(Descriptor::tss_segment(tss_ref));
 
         DescriptorState {
             gdt,
@@ -131,6 +133,29 @@ impl DescriptorState {
     }
 }
 
+impl DescriptorState {
+    /// Returns the TSS of this core.
+    pub fn tss(&self) -> &TaskStateSegment {
+        // SAFETY: The TSS lives for the lifetime of the kernel and is only mutated through `set_kernel_stack`.
+        unsafe { &*self.tss }
+    }
+
+    /// Returns a raw pointer to the TSS of this core. Used by the syscall entry stub to find the kernel stack.
+    pub fn tss_ptr(&self) -> *const TaskStateSegment {
+        self.tss
+    }
+
+    /// Sets the stack the CPU switches to when entering ring 0 from ring 3 (RSP0).
+    ///
+    /// # Safety
+    /// Must be called on the core that owns this TSS with interrupts disabled, and `stack` must be the top of a valid
+    /// kernel stack that stays mapped while it is installed.
+    pub unsafe fn set_kernel_stack(&self, stack: VirtAddr) {
+        // SAFETY: The TSS is only written to by its own core with interrupts disabled, so there are no concurrent writes.
+        unsafe { (*self.tss).privilege_stack_table[0] = stack };
+    }
+}
+
 /// A per-core Global Descriptor Table (GDT).
 #[derive(Debug)]
 pub struct LocalGdt {
@@ -12,6 +12,7 @@ pub static LGDT: Lazy<LocalGdt> = Lazy::new(LocalGdt::new);
 
 fn init() -> Result<(), Infallible> {
     unsafe { LGDT.load() };
+    crate::context::fpu::init_core();
     Ok(())
 }
 
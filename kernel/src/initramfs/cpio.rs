@@ -0,0 +1,238 @@
+//! Reading of cpio archives in the "newc" format written by `cpio -H newc`, which Linux uses for its initramfs.
+//!
+//! Every entry is a 110 byte header of ASCII hexadecimal fields, followed by the name of the entry and its data, both
+//! padded to 4 bytes. The archive ends with an entry named `TRAILER!!!`.
+use alloc::borrow::Cow;
+
+use super::{ArchiveError, Entry, EntryKind};
+
+/// The magic of archives without checksums.
+const MAGIC: &[u8] = b"070701";
+/// The magic of archives with checksums of the data, which aren't checked.
+const MAGIC_CRC: &[u8] = b"070702";
+/// The size of a header.
+const HEADER_SIZE: usize = 110;
+/// The name of the entry ending the archive.
+const TRAILER: &str = "TRAILER!!!";
+
+/// The index of each field of the header, after the magic.
+mod field {
+    pub const INODE: usize = 0;
+    pub const MODE: usize = 1;
+    pub const LINKS: usize = 4;
+    pub const FILE_SIZE: usize = 6;
+    pub const DEV_MAJOR: usize = 7;
+    pub const DEV_MINOR: usize = 8;
+    pub const NAME_SIZE: usize = 11;
+}
+
+/// The bits of the mode holding the type of file, and the types.
+mod mode {
+    pub const TYPE: u32 = 0o170000;
+    pub const DIRECTORY: u32 = 0o040000;
+    pub const REGULAR: u32 = 0o100000;
+    pub const SYMLINK: u32 = 0o120000;
+}
+
+/// Returns `true` if `data` starts like a newc cpio archive.
+pub fn is_cpio(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || data.starts_with(MAGIC_CRC)
+}
+
+/// Returns the entries of the archive `data`.
+pub fn entries(data: &[u8]) -> Entries<'_> {
+    Entries {
+        data,
+        offset: 0,
+        done: false,
+    }
+}
+
+/// An entry as stored in the archive.
+#[derive(Debug, Clone, Copy)]
+struct RawEntry<'a> {
+    /// The device and inode numbers, shared by hard links to the same file.
+    id: (u32, u32, u32),
+    mode: u32,
+    links: u32,
+    name: &'a str,
+    data: &'a [u8],
+    /// The offset of the entry after this one.
+    next: usize,
+}
+
+/// Parses the entry at `offset` of `data`.
+fn parse(data: &[u8], offset: usize) -> Result<RawEntry<'_>, ArchiveError> {
+    let header = data
+        .get(offset..offset + HEADER_SIZE)
+        .ok_or(ArchiveError::Truncated)?;
+    if !is_cpio(header) {
+        return Err(ArchiveError::InvalidHeader("magic"));
+    }
+    let field = |index: usize| {
+        let start = MAGIC.len() + index * 8;
+        core::str::from_utf8(&header[start..start + 8])
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or(ArchiveError::InvalidHeader("field"))
+    };
+    let name_size = field(field::NAME_SIZE)? as usize;
+    let file_size = field(field::FILE_SIZE)? as usize;
+    let name_start = offset + HEADER_SIZE;
+    let name = data
+        .get(name_start..name_start + name_size)
+        .ok_or(ArchiveError::Truncated)?;
+    // The size of the name counts its terminating NUL.
+    let name = name
+        .strip_suffix(&[0])
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .ok_or(ArchiveError::InvalidHeader("name"))?;
+    let data_start = (name_start + name_size).next_multiple_of(4);
+    let file = data
+        .get(data_start..data_start + file_size)
+        .ok_or(ArchiveError::Truncated)?;
+    Ok(RawEntry {
+        id: (
+            field(field::DEV_MAJOR)?,
+            field(field::DEV_MINOR)?,
+            field(field::INODE)?,
+        ),
+        mode: field(field::MODE)?,
+        links: field(field::LINKS)?,
+        name,
+        data: file,
+        next: (data_start + file_size).next_multiple_of(4),
+    })
+}
+
+/// The entries of a cpio archive.
+#[derive(Debug, Clone)]
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Entries<'a> {
+    /// Returns the data of the hard link to `entry` which holds it. `cpio` only stores it with the last link to a file.
+    fn linked_data(&self, entry: &RawEntry<'a>) -> &'a [u8] {
+        let mut offset = entry.next;
+        while let Ok(other) = parse(self.data, offset) {
+            if other.name == TRAILER {
+                break;
+            }
+            if other.id == entry.id && !other.data.is_empty() {
+                return other.data;
+            }
+            offset = other.next;
+        }
+        entry.data
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = match parse(self.data, self.offset) {
+            Ok(entry) => entry,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        if entry.name == TRAILER {
+            self.done = true;
+            return None;
+        }
+        self.offset = entry.next;
+        let kind = match entry.mode & mode::TYPE {
+            mode::DIRECTORY => EntryKind::Directory,
+            mode::REGULAR if entry.links > 1 && entry.data.is_empty() => {
+                EntryKind::File(self.linked_data(&entry))
+            }
+            mode::REGULAR => EntryKind::File(entry.data),
+            mode::SYMLINK => match core::str::from_utf8(entry.data) {
+                Ok(target) => EntryKind::Symlink(target),
+                Err(_) => {
+                    self.done = true;
+                    return Some(Err(ArchiveError::InvalidHeader("symlink target")));
+                }
+            },
+            _ => EntryKind::Other,
+        };
+        Some(Ok(Entry {
+            path: Cow::Borrowed(entry.name),
+            kind,
+        }))
+    }
+}
+
+/// Appends an entry to the archive `archive`, with the fields not given set to zero.
+pub(super) fn push(
+    archive: &mut alloc::vec::Vec<u8>,
+    inode: u32,
+    mode: u32,
+    links: u32,
+    name: &str,
+    data: &[u8],
+) {
+    let mut fields = [0; 13];
+    fields[field::INODE] = inode;
+    fields[field::MODE] = mode;
+    fields[field::LINKS] = links;
+    fields[field::FILE_SIZE] = data.len() as u32;
+    fields[field::NAME_SIZE] = name.len() as u32 + 1;
+    archive.extend_from_slice(MAGIC);
+    for field in fields {
+        archive.extend_from_slice(alloc::format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+#[kproc::test("cpio archive parsing")]
+fn cpio_parsing() {
+    use alloc::vec::Vec;
+
+    let mut archive = Vec::new();
+    push(&mut archive, 1, 0o040755, 2, ".", &[]);
+    push(&mut archive, 2, 0o040755, 2, "etc", &[]);
+    push(&mut archive, 3, 0o100644, 1, "etc/motd", b"hello");
+    push(&mut archive, 4, 0o120777, 1, "motd", b"etc/motd");
+    // Only the last hard link to a file holds its data.
+    push(&mut archive, 5, 0o100644, 2, "first", &[]);
+    push(&mut archive, 5, 0o100644, 2, "second", b"linked");
+    push(&mut archive, 6, 0o020644, 1, "dev/null", &[]);
+    push(&mut archive, 0, 0, 1, TRAILER, &[]);
+    archive.extend_from_slice(&[0; 512]);
+    assert!(is_cpio(&archive));
+
+    let parsed: Vec<_> = entries(&archive).map(Result::unwrap).collect();
+    let names: Vec<_> = parsed.iter().map(|entry| entry.path.as_ref()).collect();
+    assert_eq!(
+        names,
+        [
+            ".", "etc", "etc/motd", "motd", "first", "second", "dev/null"
+        ]
+    );
+    assert_eq!(parsed[1].kind, EntryKind::Directory);
+    assert_eq!(parsed[2].kind, EntryKind::File(b"hello"));
+    assert_eq!(parsed[3].kind, EntryKind::Symlink("etc/motd"));
+    assert_eq!(parsed[4].kind, EntryKind::File(b"linked"));
+    assert_eq!(parsed[5].kind, EntryKind::File(b"linked"));
+    assert_eq!(parsed[6].kind, EntryKind::Other);
+
+    // Cutting the archive anywhere in an entry is noticed.
+    let truncated = &archive[..HEADER_SIZE * 2 + 4];
+    assert_eq!(
+        entries(truncated).last(),
+        Some(Err(ArchiveError::Truncated))
+    );
+}
@@ -0,0 +1,207 @@
+//! The initial ramdisk, an archive the bootloader loads as a module whose files are unpacked into the root filesystem
+//! at boot. Both newc cpio archives and ustar archives are understood.
+use core::convert::Infallible;
+
+use alloc::{borrow::Cow, string::String};
+use cake::log::{info, warn};
+
+use crate::{
+    declare_module,
+    fs::{self, FsError},
+    requests::MODULES,
+};
+
+mod cpio;
+pub mod req_data;
+mod ustar;
+
+/// The command line marking a boot module as an initramfs in `limine.conf`.
+pub const CMDLINE: &str = "initramfs";
+
+declare_module!("initramfs", init);
+
+fn init() -> Result<(), Infallible> {
+    for module in MODULES.get().get() {
+        if module.cmdline() != CMDLINE {
+            continue;
+        }
+        match unpack(module.data(), "/") {
+            Ok(count) => info!("Unpacked {count} entries of {}", module.path()),
+            Err(err) => warn!("Failed to unpack {}: {err}", module.path()),
+        }
+    }
+    Ok(())
+}
+
+/// An error found in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ArchiveError {
+    /// The archive is neither a cpio nor a ustar archive.
+    #[error("Unknown archive format")]
+    UnknownFormat,
+    /// The archive ends in the middle of an entry.
+    #[error("Archive is truncated")]
+    Truncated,
+    /// A field of a header is invalid.
+    #[error("Invalid {0} in header")]
+    InvalidHeader(&'static str),
+}
+
+/// An entry of an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// The path of the entry, as stored in the archive.
+    pub path: Cow<'a, str>,
+    /// What the entry is.
+    pub kind: EntryKind<'a>,
+}
+
+/// What an entry of an archive is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind<'a> {
+    /// A regular file with its contents.
+    File(&'a [u8]),
+    /// A directory.
+    Directory,
+    /// A symbolic link to the given target.
+    Symlink(&'a str),
+    /// Another name for the file at the given path of the archive.
+    HardLink(&'a str),
+    /// A device, pipe or anything else which can't be unpacked.
+    Other,
+}
+
+/// An error that occurred while unpacking an archive.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum UnpackError {
+    /// The archive is invalid.
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+    /// An entry couldn't be written to the filesystem.
+    #[error("Failed to unpack {path}: {err}")]
+    Fs {
+        /// The path the entry was unpacked to.
+        path: String,
+        /// The error of the filesystem.
+        err: FsError,
+    },
+}
+
+/// Returns the path of `path` of the archive once unpacked into `target`, or `None` for the root of the archive and
+/// paths leaving it.
+fn unpacked_path(target: &str, path: &str) -> Option<String> {
+    let mut unpacked = String::from(target.trim_end_matches('/'));
+    for name in path.split('/').filter(|name| !matches!(*name, "" | ".")) {
+        if name == ".." {
+            return None;
+        }
+        unpacked.push('/');
+        unpacked.push_str(name);
+    }
+    (unpacked.len() > target.trim_end_matches('/').len()).then_some(unpacked)
+}
+
+/// Unpacks the entry `kind` to `path`, creating its missing parent directories.
+fn unpack_entry(target: &str, path: &str, kind: EntryKind) -> Result<(), FsError> {
+    if let Some(index) = path.rfind('/') {
+        fs::create_dir_all(&path[..index])?;
+    }
+    match kind {
+        EntryKind::File(data) => fs::write(path, data),
+        EntryKind::Directory => fs::create_dir_all(path),
+        EntryKind::Symlink(link) => fs::symlink(link, path),
+        EntryKind::HardLink(link) => {
+            // Hard links are unpacked as copies, which is the best filesystems without them can do.
+            let source = unpacked_path(target, link).ok_or(FsError::InvalidPath)?;
+            fs::write(path, &fs::read(&source)?)
+        }
+        EntryKind::Other => Ok(()),
+    }
+}
+
+/// Unpacks the archive `data` into the directory `target`, returning the number of entries unpacked. Devices and other
+/// special files are skipped, as are entries whose paths would leave `target`.
+pub fn unpack(data: &[u8], target: &str) -> Result<usize, UnpackError> {
+    if cpio::is_cpio(data) {
+        unpack_entries(cpio::entries(data), target)
+    } else if ustar::is_ustar(data) {
+        unpack_entries(ustar::entries(data), target)
+    } else {
+        Err(ArchiveError::UnknownFormat.into())
+    }
+}
+
+/// Unpacks `entries` into `target`, see [unpack].
+fn unpack_entries<'a>(
+    entries: impl Iterator<Item = Result<Entry<'a>, ArchiveError>>,
+    target: &str,
+) -> Result<usize, UnpackError> {
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.kind == EntryKind::Other {
+            continue;
+        }
+        let Some(path) = unpacked_path(target, &entry.path) else {
+            continue;
+        };
+        unpack_entry(target, &path, entry.kind).map_err(|err| UnpackError::Fs { path, err })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[kproc::test("initramfs unpacking")]
+fn initramfs_unpacking() {
+    use alloc::vec::Vec;
+
+    fs::create_dir("/initramfs-test").unwrap();
+    fs::mount("/initramfs-test", fs::tmpfs::TmpFs::new()).unwrap();
+
+    // Parents missing from the archive are created, and paths leaving it are skipped.
+    let mut cpio_archive = Vec::new();
+    cpio::push(&mut cpio_archive, 1, 0o040755, 2, ".", &[]);
+    cpio::push(&mut cpio_archive, 2, 0o100644, 1, "etc/conf/motd", b"hello");
+    cpio::push(&mut cpio_archive, 3, 0o120777, 1, "motd", b"etc/conf/motd");
+    cpio::push(&mut cpio_archive, 4, 0o100644, 1, "../escape", b"out");
+    cpio::push(&mut cpio_archive, 5, 0o020644, 1, "dev/null", &[]);
+    cpio::push(&mut cpio_archive, 0, 0, 1, "TRAILER!!!", &[]);
+    assert_eq!(unpack(&cpio_archive, "/initramfs-test"), Ok(2));
+    assert_eq!(fs::read("/initramfs-test/motd").unwrap(), b"hello");
+    assert_eq!(fs::read("/escape"), Err(FsError::NotFound));
+    assert_eq!(fs::metadata("/initramfs-test/dev"), Err(FsError::NotFound));
+
+    let mut tar_archive = Vec::new();
+    ustar::push(&mut tar_archive, b'5', "./bin/", "", &[], false);
+    ustar::push(&mut tar_archive, b'0', "bin/init", "", b"init", false);
+    ustar::push(&mut tar_archive, b'1', "bin/sh", "./bin/init", &[], false);
+    tar_archive.extend_from_slice(&[0; 1024]);
+    assert_eq!(unpack(&tar_archive, "/initramfs-test/"), Ok(3));
+    assert_eq!(fs::read("/initramfs-test/bin/sh").unwrap(), b"init");
+
+    assert_eq!(
+        unpack(b"not an archive", "/initramfs-test"),
+        Err(UnpackError::Archive(ArchiveError::UnknownFormat))
+    );
+    // Files are replaced, but links aren't.
+    assert!(matches!(
+        unpack(&cpio_archive, "/initramfs-test"),
+        Err(UnpackError::Fs {
+            err: FsError::AlreadyExists,
+            ..
+        })
+    ));
+
+    fs::unmount("/initramfs-test").unwrap();
+    fs::remove_dir("/initramfs-test").unwrap();
+
+    // The test image is booted with the repository's initramfs.
+    if MODULES
+        .get()
+        .get()
+        .iter()
+        .any(|module| module.cmdline() == CMDLINE)
+    {
+        assert!(!fs::read("/etc/hostname").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+//! Persistent information about the modules loaded by the bootloader.
+use arrayvec::{ArrayString, ArrayVec};
+use cake::{LimineData, limine::response::ModuleResponse, log::warn};
+
+/// The most modules kept.
+const MAX_MODULES: usize = 16;
+
+/// A file the bootloader loaded alongside the kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct BootModule {
+    path: ArrayString<128>,
+    cmdline: ArrayString<64>,
+    data: &'static [u8],
+}
+
+impl BootModule {
+    /// Returns the path the module was loaded from, truncated to 128 bytes.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the command line given to the module in `limine.conf`, truncated to 64 bytes.
+    pub fn cmdline(&self) -> &str {
+        &self.cmdline
+    }
+
+    /// Returns the contents of the module.
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+/// Copies as much of `bytes` as fits into a string, replacing invalid UTF-8.
+fn truncated<const N: usize>(bytes: &[u8]) -> ArrayString<N> {
+    let mut string = ArrayString::new();
+    for c in bytes.utf8_chunks().flat_map(|chunk| {
+        let replacement = (!chunk.invalid().is_empty()).then_some(char::REPLACEMENT_CHARACTER);
+        chunk.valid().chars().chain(replacement)
+    }) {
+        if string.try_push(c).is_err() {
+            break;
+        }
+    }
+    string
+}
+
+/// The modules the bootloader loaded.
+#[derive(Debug, Clone)]
+pub struct BootModules {
+    modules: ArrayVec<BootModule, MAX_MODULES>,
+}
+
+impl BootModules {
+    /// Creates a new `BootModules` from the given Limine module response.
+    pub fn new(response: LimineData<ModuleResponse>) -> Self {
+        let files = response.modules();
+        if files.len() > MAX_MODULES {
+            warn!("Ignoring {} boot modules", files.len() - MAX_MODULES);
+        }
+        let modules = files
+            .iter()
+            .take(MAX_MODULES)
+            .map(|file| BootModule {
+                path: truncated(file.path().to_bytes()),
+                cmdline: truncated(file.string().to_bytes()),
+                // SAFETY: Modules are loaded into memory the memory manager never reclaims, which stays mapped.
+                data: unsafe { core::slice::from_raw_parts(file.addr(), file.size() as usize) },
+            })
+            .collect();
+        BootModules { modules }
+    }
+
+    /// Returns the modules.
+    pub fn get(&self) -> &[BootModule] {
+        &self.modules
+    }
+}
@@ -0,0 +1,356 @@
+//! Reading of tar archives in the POSIX ustar format, along with the GNU and pax extensions for long names.
+//!
+//! Every entry is a 512 byte header followed by its data, padded to 512 bytes. The archive ends with a zeroed block.
+use alloc::{borrow::Cow, string::String};
+
+use super::{ArchiveError, Entry, EntryKind};
+
+/// The size of headers and the unit data is padded to.
+const BLOCK_SIZE: usize = 512;
+/// The magic of POSIX archives, followed by the version.
+const MAGIC: &[u8] = b"ustar\0";
+
+/// The ranges of the fields of a header.
+mod field {
+    use core::ops::Range;
+
+    pub const NAME: Range<usize> = 0..100;
+    pub const SIZE: Range<usize> = 124..136;
+    pub const CHECKSUM: Range<usize> = 148..156;
+    pub const TYPE: usize = 156;
+    pub const LINK_NAME: Range<usize> = 157..257;
+    pub const MAGIC: Range<usize> = 257..263;
+    /// The directories of the name, only in POSIX archives. GNU archives keep other data there.
+    pub const PREFIX: Range<usize> = 345..500;
+}
+
+/// The types of entries.
+mod kind {
+    pub const FILE: u8 = b'0';
+    /// Used by old archives for regular files.
+    pub const OLD_FILE: u8 = 0;
+    pub const HARD_LINK: u8 = b'1';
+    pub const SYMLINK: u8 = b'2';
+    pub const DIRECTORY: u8 = b'5';
+    pub const CONTIGUOUS: u8 = b'7';
+    /// Extended attributes of the next entry, such as long names.
+    pub const PAX: u8 = b'x';
+    /// Extended attributes of the whole archive.
+    pub const PAX_GLOBAL: u8 = b'g';
+    /// The name of the next entry, when too long for its header.
+    pub const GNU_LONG_NAME: u8 = b'L';
+    /// The target of the next entry, when too long for its header.
+    pub const GNU_LONG_LINK: u8 = b'K';
+}
+
+/// Returns `true` if `data` starts like a ustar archive, be it POSIX or GNU.
+pub fn is_ustar(data: &[u8]) -> bool {
+    data.get(field::MAGIC)
+        .is_some_and(|magic| magic.starts_with(b"ustar"))
+}
+
+/// Returns the entries of the archive `data`.
+pub fn entries(data: &[u8]) -> Entries<'_> {
+    Entries {
+        data,
+        offset: 0,
+        done: false,
+    }
+}
+
+/// Returns the string in `bytes`, which ends at the first NUL if there is one.
+fn string(bytes: &[u8]) -> Result<&str, ArchiveError> {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).map_err(|_| ArchiveError::InvalidHeader("name"))
+}
+
+/// Parses a numeric field, in octal, or in big endian binary if its first bit is set as GNU tar does for large values.
+fn number(bytes: &[u8]) -> Result<u64, ArchiveError> {
+    if let Some((&first, rest)) = bytes.split_first()
+        && first & 0x80 != 0
+    {
+        return rest
+            .iter()
+            .try_fold(u64::from(first & 0x7f), |value, &byte| {
+                value.checked_mul(256).map(|value| value | u64::from(byte))
+            })
+            .ok_or(ArchiveError::InvalidHeader("number"));
+    }
+    let digits = core::str::from_utf8(bytes)
+        .map_err(|_| ArchiveError::InvalidHeader("number"))?
+        .trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| ArchiveError::InvalidHeader("number"))
+}
+
+/// Returns `true` if the checksum of `header` is right. It sums the bytes of the header, with the checksum counted as
+/// spaces.
+fn checksum_matches(header: &[u8]) -> Result<bool, ArchiveError> {
+    let expected = number(&header[field::CHECKSUM])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(index, &byte)| {
+            if field::CHECKSUM.contains(&index) {
+                u64::from(b' ')
+            } else {
+                u64::from(byte)
+            }
+        })
+        .sum();
+    Ok(sum == expected)
+}
+
+/// The entries of a ustar archive.
+#[derive(Debug, Clone)]
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+/// The names given to the next entry by the entries extending it.
+#[derive(Debug, Default)]
+struct LongNames<'a> {
+    path: Option<&'a str>,
+    link: Option<&'a str>,
+}
+
+impl<'a> LongNames<'a> {
+    /// Reads the `path` and `linkpath` of the records of a pax header, each of which is `<length> <key>=<value>\n`.
+    fn read_pax(&mut self, mut records: &'a [u8]) -> Result<(), ArchiveError> {
+        let invalid = ArchiveError::InvalidHeader("pax record");
+        while !records.is_empty() {
+            let space = records
+                .iter()
+                .position(|&byte| byte == b' ')
+                .ok_or(invalid)?;
+            let length: usize = core::str::from_utf8(&records[..space])
+                .ok()
+                .and_then(|length| length.parse().ok())
+                .filter(|&length| length > space + 1 && length <= records.len())
+                .ok_or(invalid)?;
+            let record = records[space + 1..length]
+                .strip_suffix(b"\n")
+                .ok_or(invalid)?;
+            let record = core::str::from_utf8(record).map_err(|_| invalid)?;
+            match record.split_once('=') {
+                Some(("path", path)) => self.path = Some(path),
+                Some(("linkpath", link)) => self.link = Some(link),
+                Some(_) => {}
+                None => return Err(invalid),
+            }
+            records = &records[length..];
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Entries<'a> {
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, ArchiveError> {
+        let mut long_names = LongNames::default();
+        loop {
+            let header = self
+                .data
+                .get(self.offset..self.offset + BLOCK_SIZE)
+                .ok_or(ArchiveError::Truncated)?;
+            if header.iter().all(|&byte| byte == 0) {
+                return Ok(None);
+            }
+            if !is_ustar(header) {
+                return Err(ArchiveError::InvalidHeader("magic"));
+            }
+            if !checksum_matches(header)? {
+                return Err(ArchiveError::InvalidHeader("checksum"));
+            }
+            let size = usize::try_from(number(&header[field::SIZE])?)
+                .map_err(|_| ArchiveError::Truncated)?;
+            let start = self.offset + BLOCK_SIZE;
+            let data = start
+                .checked_add(size)
+                .and_then(|end| self.data.get(start..end))
+                .ok_or(ArchiveError::Truncated)?;
+            self.offset = start + size.next_multiple_of(BLOCK_SIZE);
+
+            match header[field::TYPE] {
+                kind::GNU_LONG_NAME => long_names.path = Some(string(data)?),
+                kind::GNU_LONG_LINK => long_names.link = Some(string(data)?),
+                kind::PAX => long_names.read_pax(data)?,
+                kind::PAX_GLOBAL => {}
+                type_flag => {
+                    let path = match long_names.path {
+                        Some(path) => Cow::Borrowed(path),
+                        None => Self::path(header)?,
+                    };
+                    let link = match long_names.link {
+                        Some(link) => link,
+                        None => string(&header[field::LINK_NAME])?,
+                    };
+                    let kind = match type_flag {
+                        kind::FILE | kind::OLD_FILE | kind::CONTIGUOUS => EntryKind::File(data),
+                        kind::DIRECTORY => EntryKind::Directory,
+                        kind::SYMLINK => EntryKind::Symlink(link),
+                        kind::HARD_LINK => EntryKind::HardLink(link),
+                        _ => EntryKind::Other,
+                    };
+                    return Ok(Some(Entry { path, kind }));
+                }
+            }
+        }
+    }
+
+    /// Returns the path stored in `header`, joining its prefix and name.
+    fn path(header: &'a [u8]) -> Result<Cow<'a, str>, ArchiveError> {
+        let name = string(&header[field::NAME])?;
+        if &header[field::MAGIC] != MAGIC {
+            return Ok(Cow::Borrowed(name));
+        }
+        let prefix = string(&header[field::PREFIX])?;
+        if prefix.is_empty() {
+            return Ok(Cow::Borrowed(name));
+        }
+        let mut path = String::from(prefix);
+        path.push('/');
+        path.push_str(name);
+        Ok(Cow::Owned(path))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.parse().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+/// Appends an entry to the archive `archive`, in the POSIX format unless `gnu`.
+pub(super) fn push(
+    archive: &mut alloc::vec::Vec<u8>,
+    kind: u8,
+    name: &str,
+    link: &str,
+    data: &[u8],
+    gnu: bool,
+) {
+    let mut header = [0; BLOCK_SIZE];
+    let name = match name.rsplit_once('/') {
+        Some((prefix, name)) if name.len() < 100 && !gnu => {
+            header[field::PREFIX][..prefix.len()].copy_from_slice(prefix.as_bytes());
+            name
+        }
+        _ => name,
+    };
+    header[field::NAME][..name.len()].copy_from_slice(name.as_bytes());
+    header[field::LINK_NAME][..link.len()].copy_from_slice(link.as_bytes());
+    let size = alloc::format!("{:011o}\0", data.len());
+    header[field::SIZE].copy_from_slice(size.as_bytes());
+    header[field::TYPE] = kind;
+    header[field::MAGIC].copy_from_slice(if gnu { b"ustar " } else { MAGIC });
+    header[263..265].copy_from_slice(if gnu { b" \0" } else { b"00" });
+    header[field::CHECKSUM].fill(b' ');
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[field::CHECKSUM].copy_from_slice(alloc::format!("{sum:06o}\0 ").as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+}
+
+#[kproc::test("ustar archive parsing")]
+fn ustar_parsing() {
+    use alloc::{format, vec::Vec};
+
+    let long = format!("{}/file", "long".repeat(40));
+    let mut gnu_name = Vec::from(long.as_bytes());
+    gnu_name.push(0);
+    let pax = "31 path=pax/a/much/longer/name\n";
+
+    let mut archive = Vec::new();
+    push(&mut archive, kind::DIRECTORY, "./etc/", "", &[], false);
+    push(&mut archive, kind::FILE, "etc/motd", "", b"hello", false);
+    push(&mut archive, kind::SYMLINK, "motd", "etc/motd", &[], false);
+    push(
+        &mut archive,
+        kind::HARD_LINK,
+        "hard",
+        "etc/motd",
+        &[],
+        false,
+    );
+    push(
+        &mut archive,
+        kind::FILE,
+        "deep/nested/file",
+        "",
+        &[7; 600],
+        false,
+    );
+    push(
+        &mut archive,
+        kind::GNU_LONG_NAME,
+        "././@LongLink",
+        "",
+        &gnu_name,
+        true,
+    );
+    push(&mut archive, kind::FILE, "truncated", "", b"gnu", true);
+    push(
+        &mut archive,
+        kind::PAX,
+        "PaxHeader",
+        "",
+        pax.as_bytes(),
+        false,
+    );
+    push(&mut archive, kind::FILE, "short", "", b"pax", false);
+    push(&mut archive, b'3', "dev/tty", "", &[], false);
+    archive.extend_from_slice(&[0; BLOCK_SIZE * 2]);
+    assert!(is_ustar(&archive));
+
+    let parsed: Vec<_> = entries(&archive).map(Result::unwrap).collect();
+    let names: Vec<_> = parsed.iter().map(|entry| entry.path.as_ref()).collect();
+    assert_eq!(
+        names,
+        [
+            "./etc/",
+            "etc/motd",
+            "motd",
+            "hard",
+            "deep/nested/file",
+            long.as_str(),
+            "pax/a/much/longer/name",
+            "dev/tty"
+        ]
+    );
+    assert_eq!(parsed[0].kind, EntryKind::Directory);
+    assert_eq!(parsed[1].kind, EntryKind::File(b"hello"));
+    assert_eq!(parsed[2].kind, EntryKind::Symlink("etc/motd"));
+    assert_eq!(parsed[3].kind, EntryKind::HardLink("etc/motd"));
+    assert_eq!(parsed[4].kind, EntryKind::File(&[7; 600]));
+    assert_eq!(parsed[5].kind, EntryKind::File(b"gnu"));
+    assert_eq!(parsed[6].kind, EntryKind::File(b"pax"));
+    assert_eq!(parsed[7].kind, EntryKind::Other);
+
+    let mut corrupted = archive.clone();
+    corrupted[BLOCK_SIZE] ^= 1;
+    assert_eq!(
+        entries(&corrupted).nth(1),
+        Some(Err(ArchiveError::InvalidHeader("checksum")))
+    );
+    assert_eq!(
+        entries(&archive[..BLOCK_SIZE * 2]).nth(1),
+        Some(Err(ArchiveError::Truncated))
+    );
+}
@@ -1,19 +1,55 @@
-use cake::log::info;
-use x86_64::registers::control::Cr2;
+use cake::log::{info, warn};
+use nmm::{
+    FaultReason, MapFlags, MemError,
+    paging::{Address, VirtAddr},
+};
+use x86_64::{
+    PrivilegeLevel,
+    registers::control::Cr2,
+    structures::idt::{InterruptStackFrameValue, PageFaultErrorCode},
+};
 
 use crate::{
-    context::{InterruptCodeContext, InterruptContext, PageFaultInterruptContext},
+    context::{ContextValue, InterruptCodeContext, InterruptContext, PageFaultInterruptContext},
     interrupt_wrapper, interrupts,
     mp::{self, LAPIC},
     panic::panic_stacktrace,
-    println,
+    println, proc, time,
 };
 
 #[inline(never)]
 #[unsafe(no_mangle)]
 extern "C" fn exception_brk() {}
 
-pub fn general_handler(ctx: InterruptContext, _: u8, name: &'static str) {
+/// Returns `true` if the exception was raised by code running in ring 3.
+fn from_user(frame: &InterruptStackFrameValue) -> bool {
+    frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Kills the user thread that raised an exception and switches to the next thread instead of halting the kernel.
+fn kill_user(
+    name: &'static str,
+    code: Option<u64>,
+    regs: &mut ContextValue,
+    frame: &mut InterruptStackFrameValue,
+) {
+    warn!(
+        "{} in user thread {:?} at {:?} (error code {:?}), killing it",
+        name,
+        proc::current(),
+        frame.instruction_pointer,
+        code
+    );
+    proc::kill_faulting(regs, frame);
+}
+
+pub fn general_handler(mut ctx: InterruptContext, _: u8, name: &'static str) {
+    if from_user(&ctx.int_frame) {
+        // SAFETY: `ctx` is the interrupted context on this core's stack and isn't referenced anywhere else.
+        let ctx = unsafe { ctx.modify() };
+        kill_user(name, None, &mut ctx.context, &mut ctx.int_frame);
+        return;
+    }
     println!("===== {} =====", name);
     println!("(no error code)");
     println!("== CPU STATE ==");
@@ -24,7 +60,13 @@ pub fn general_handler(ctx: InterruptContext, _: u8, name: &'static str) {
     loop {}
 }
 
-pub fn general_code_handler(ctx: InterruptCodeContext, _: u8, name: &'static str) {
+pub fn general_code_handler(mut ctx: InterruptCodeContext, _: u8, name: &'static str) {
+    if from_user(&ctx.int_frame) {
+        // SAFETY: `ctx` is the interrupted context on this core's stack and isn't referenced anywhere else.
+        let ctx = unsafe { ctx.modify() };
+        kill_user(name, Some(ctx.code), &mut ctx.context, &mut ctx.int_frame);
+        return;
+    }
     println!("===== {} =====", name);
     println!("ERROR CODE: {:?}", ctx.code);
     println!("== CPU STATE ==");
@@ -35,9 +77,76 @@ pub fn general_code_handler(ctx: InterruptCodeContext, _: u8, name: &'static str
     loop {}
 }
 
-pub fn page_fault_handler(ctx: PageFaultInterruptContext) {
+/// Tries to resolve a page fault at `addr` by backing a page of a lazy mapping or copying a copy-on-write page, in the
+/// current thread's address space for the lower half and in the kernel address space for the higher half.
+fn resolve_page_fault(addr: VirtAddr, code: PageFaultErrorCode) -> Result<(), MemError> {
+    let space = (addr.as_u64() < proc::loader::USER_END)
+        .then(proc::current_address_space)
+        .flatten();
+
+    if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // The page is present, so only the first write to a copy-on-write page can be fixed.
+        let allowed = match &space {
+            Some(space) => space.translate(addr),
+            None => nmm::translate(addr),
+        }
+        .map_or(MapFlags::empty(), |(_, flags)| flags);
+        if !code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            || !allowed.contains(MapFlags::COPY_ON_WRITE)
+        {
+            return Err(MemError::UnresolvedFault {
+                addr,
+                reason: FaultReason::AccessDenied { allowed },
+            });
+        }
+    }
+
+    let mut access = MapFlags::empty();
+    if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        access |= MapFlags::WRITABLE;
+    }
+    if code.contains(PageFaultErrorCode::USER_MODE) {
+        access |= MapFlags::USER_ACCESSIBLE;
+    }
+    if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        access |= MapFlags::EXECUTABLE;
+    }
+
+    match space {
+        Some(space) => space.resolve_fault(addr, access),
+        None if addr.as_u64() < proc::loader::USER_END => Err(MemError::UnresolvedFault {
+            addr,
+            reason: FaultReason::NoRegion,
+        }),
+        None => nmm::resolve_fault(addr, access),
+    }
+}
+
+pub fn page_fault_handler(mut ctx: PageFaultInterruptContext) {
+    let addr = VirtAddr::new_truncate(Cr2::read_raw());
+    let error = match resolve_page_fault(addr, ctx.error_code) {
+        Ok(()) => return,
+        Err(e) => e,
+    };
+
+    if from_user(&ctx.int_frame) {
+        warn!("{:?} at {:#x}: {}", ctx.error_code, addr.as_u64(), error);
+        // SAFETY: `ctx` is the interrupted context on this core's stack and isn't referenced anywhere else.
+        let ctx = unsafe { ctx.modify() };
+        kill_user(
+            "PAGE FAULT",
+            Some(ctx.error_code.bits()),
+            &mut ctx.context,
+            &mut ctx.int_frame,
+        );
+        return;
+    }
     println!("===== PAGE FAULT =====");
-    println!("{:?}: {:?}", ctx.error_code, Cr2::read());
+    println!("{:?} at {:#x}", ctx.error_code, addr.as_u64());
+    println!("{}", error);
+    if let Some(region) = nmm::lazy_region(addr) {
+        println!("In lazy region {:?}", region);
+    }
     println!("== CPU STATE ==");
     println!("{}", ctx.context);
     println!("== STACK TRACE ==");
@@ -83,6 +192,71 @@ pub extern "C" fn apic_error(_: InterruptContext, _: u8) {
 
 interrupt_wrapper!(apic_error, apic_error_raw);
 
-pub extern "C" fn timer_handler(_: InterruptContext, _: u8) {}
+pub extern "C" fn timer_handler(ctx: InterruptContext, _: u8) {
+    // Expired timers may wake threads, so run them before picking the next one.
+    time::wheel::tick();
+    proc::sched_next(ctx);
+
+    unsafe {
+        LAPIC.eoi();
+    }
+}
 
 interrupt_wrapper!(timer_handler, timer_handler_raw);
+
+pub extern "C" fn yield_handler(ctx: InterruptContext, _: u8) {
+    // Raised by software, so there is nothing to acknowledge.
+    proc::sched_next(ctx);
+}
+
+interrupt_wrapper!(yield_handler, yield_handler_raw);
+
+#[kproc::test("Lazy mappings are backed on the first access")]
+fn lazy_mappings() {
+    use core::alloc::Layout;
+
+    use nmm::{MapSource, arch::L1_PAGE_SIZE, paging::AddressExt};
+
+    let page = L1_PAGE_SIZE as usize;
+    let layout = Layout::from_size_align(2 * page, page).unwrap();
+    let base = nmm::reserve_virtual(layout).unwrap();
+    let second = VirtAddr::new(base.as_u64() + L1_PAGE_SIZE);
+    nmm::map(base, MapSource::Lazy, 2 * page, MapFlags::empty()).unwrap();
+    assert!(nmm::translate(base).is_none());
+
+    // The first read faults, and the handler backs the page with a zeroed frame.
+    // SAFETY: The range is mapped, lazily.
+    let contents = unsafe { core::slice::from_raw_parts(base.as_ptr::<u8>(), page) };
+    assert!(contents.iter().all(|&byte| byte == 0));
+    let (_, flags) = nmm::translate(base).unwrap();
+    assert!(!flags.contains(MapFlags::WRITABLE));
+    assert!(nmm::translate(second).is_none());
+
+    // Accesses the mapping doesn't allow aren't resolved, whether the page is backed yet or not.
+    let denied = |result| {
+        matches!(
+            result,
+            Err(MemError::UnresolvedFault {
+                reason: FaultReason::AccessDenied { .. },
+                ..
+            })
+        )
+    };
+    let write = PageFaultErrorCode::CAUSED_BY_WRITE;
+    assert!(denied(resolve_page_fault(
+        base,
+        write | PageFaultErrorCode::PROTECTION_VIOLATION
+    )));
+    assert!(denied(resolve_page_fault(second, write)));
+    assert!(denied(resolve_page_fault(
+        second,
+        PageFaultErrorCode::USER_MODE
+    )));
+    assert!(nmm::translate(second).is_none());
+
+    // SAFETY: Nothing uses the range anymore.
+    unsafe {
+        nmm::unmap(base, 2 * page).unwrap();
+        nmm::free_virtual(base, layout).unwrap();
+    }
+}
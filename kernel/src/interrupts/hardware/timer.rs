@@ -1,16 +1,14 @@
-//! Timer interrupt handler and utilities.
+//! The PIC timer interrupt handler. Real time units come from the [time](crate::time) module.
 
-use crate::{context::InterruptContext, interrupt_wrapper};
+use crate::{context::InterruptContext, interrupt_wrapper, proc::sched_next};
 
 use super::InterruptIndex;
 
-/// The frequency of the timer interrupt.
-pub const TIMER_FREQUENCY: f32 = 18.2065; // stolen from https://wiki.osdev.org/Programmable_Interval_Timer
-
 pub(super) extern "C" fn timer_handler(frame: InterruptContext) {
-    unsafe {
-        // sched_next(frame);
+    sched_next(frame);
 
+    // SAFETY: This is the handler for the PIC timer IRQ, so the EOI is for the interrupt being serviced.
+    unsafe {
         super::PICS
             .lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.into());
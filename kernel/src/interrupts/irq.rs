@@ -0,0 +1,190 @@
+//! Dynamically registered handlers for device interrupts.
+//!
+//! Every vector has an entry stub that pushes its vector number in place of an error code and jumps to a common
+//! dispatcher, which calls the handlers registered for that vector and signals the end of the interrupt. The stub of a
+//! vector is installed in the IDT of every core when the first handler is registered for it.
+//!
+//! Several handlers may share a vector, which is how level-triggered lines shared by multiple devices are handled:
+//! every handler is called on each interrupt, and reports whether its device was the one asserting the line.
+use core::arch::global_asm;
+
+use alloc::vec::Vec;
+use cake::{RwLock, log::warn};
+use x86_64::VirtAddr;
+
+use crate::{context::InterruptCodeContext, interrupt_wrapper, mp::LAPIC};
+
+use super::{IDT, vector};
+
+/// A handler for a device interrupt. It is called with the vector and the cookie it was registered with, in interrupt
+/// context with interrupts disabled.
+pub type IrqHandler = fn(vector: u8, cookie: usize) -> IrqReturn;
+
+/// What an [IrqHandler] did about an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt came from the handler's device and was handled.
+    Handled,
+    /// The interrupt didn't come from the handler's device.
+    NotMine,
+}
+
+/// An error that occurred while registering an interrupt handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum IrqError {
+    /// The vector is reserved for exceptions, the legacy PICs or the kernel.
+    #[error("Vector {0} is reserved")]
+    Reserved(u8),
+    /// The same handler is already registered with the same cookie.
+    #[error("Handler already registered for vector {0}")]
+    AlreadyRegistered(u8),
+}
+
+/// The size of every entry stub in bytes.
+const STUB_SIZE: u64 = 16;
+
+/// The handlers registered for every vector.
+static HANDLERS: [RwLock<Vec<(IrqHandler, usize)>>; 256] = [const { RwLock::new(Vec::new()) }; 256];
+
+global_asm!(
+    ".pushsection .text.irq_stubs, \"ax\"",
+    ".balign {size}",
+    ".global irq_stubs",
+    "irq_stubs:",
+    ".set irq_vector, 0",
+    ".rept 256",
+    ".balign {size}",
+    "pushq $irq_vector",
+    "jmp {dispatch}",
+    ".set irq_vector, irq_vector + 1",
+    ".endr",
+    ".popsection",
+    size = const STUB_SIZE,
+    dispatch = sym dispatch_raw,
+    options(att_syntax),
+);
+
+unsafe extern "C" {
+    /// The entry stubs, [STUB_SIZE] bytes apart and indexed by vector.
+    static irq_stubs: [u8; 0];
+}
+
+/// Returns the address of the entry stub of `vector`.
+fn stub(vector: u8) -> VirtAddr {
+    // SAFETY: Only the address of the stubs is taken.
+    let base = unsafe { irq_stubs.as_ptr() };
+    VirtAddr::from_ptr(base) + vector as u64 * STUB_SIZE
+}
+
+extern "C" fn dispatch(ctx: InterruptCodeContext) {
+    let vector = ctx.code as u8;
+    let mut handled = false;
+    for (handler, cookie) in HANDLERS[vector as usize].read().iter() {
+        // Every handler has to run, more than one device on a shared line may be asserting it.
+        handled |= handler(vector, *cookie) == IrqReturn::Handled;
+    }
+    if !handled {
+        warn!("Unhandled interrupt on vector {}", vector);
+    }
+    // SAFETY: This is the end of the handler of an interrupt delivered by the LAPIC.
+    unsafe { LAPIC.eoi() };
+}
+
+// The stubs push the vector where the CPU would push an error code.
+interrupt_wrapper!(dispatch, dispatch_raw, error_code);
+
+/// Registers `handler` to be called with `cookie` whenever `vector` is raised, on any core. Several handlers may be
+/// registered for the same vector.
+///
+/// `vector` must not be reserved, see [vector::is_dynamic]. It is usually allocated with [vector::allocate].
+pub fn register_irq(vector: u8, handler: IrqHandler, cookie: usize) -> Result<(), IrqError> {
+    if !vector::is_dynamic(vector) {
+        return Err(IrqError::Reserved(vector));
+    }
+    let first = super::without_interrupts(|| {
+        let mut handlers = HANDLERS[vector as usize].write();
+        if handlers.contains(&(handler, cookie)) {
+            return Err(IrqError::AlreadyRegistered(vector));
+        }
+        handlers.push((handler, cookie));
+        Ok(handlers.len() == 1)
+    })?;
+    if first {
+        IDT.update_all(|idt| {
+            // SAFETY: The stub is a valid interrupt entry point that dispatches to the registered handlers.
+            unsafe { idt[vector].set_handler_addr(stub(vector)) };
+        });
+    }
+    Ok(())
+}
+
+/// Unregisters `handler` registered with `cookie` for `vector`. Returns `false` if it wasn't registered.
+///
+/// The stub stays installed, so interrupts still in flight are reported as unhandled instead of faulting. This must not
+/// be called from a handler of the same vector.
+pub fn unregister_irq(vector: u8, handler: IrqHandler, cookie: usize) -> bool {
+    super::without_interrupts(|| {
+        let mut handlers = HANDLERS[vector as usize].write();
+        let Some(index) = handlers
+            .iter()
+            .position(|entry| *entry == (handler, cookie))
+        else {
+            return false;
+        };
+        handlers.remove(index);
+        true
+    })
+}
+
+/// Returns the number of handlers registered for `vector`.
+pub fn handler_count(vector: u8) -> usize {
+    super::without_interrupts(|| HANDLERS[vector as usize].read().len())
+}
+
+#[kproc::test("Shared IRQ handlers")]
+fn shared_irq_handlers() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::mp::lapic::icr::IPIDestination;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn count(_: u8, cookie: usize) -> IrqReturn {
+        CALLS.fetch_add(cookie, Ordering::SeqCst);
+        IrqReturn::Handled
+    }
+    /// Raises `vector` on the current core and waits until the handlers have added up to `calls`.
+    fn raise(vector: u8, calls: usize) {
+        LAPIC
+            .icr()
+            .send_vector(IPIDestination::SelfOnly, vector)
+            .wait();
+        super::enable();
+        while CALLS.load(Ordering::SeqCst) < calls {
+            core::hint::spin_loop();
+        }
+        super::disable();
+        assert_eq!(CALLS.load(Ordering::SeqCst), calls);
+    }
+
+    let timer = super::KernelInterrupt::Timer as u8;
+    assert_eq!(
+        register_irq(timer, count, 1),
+        Err(IrqError::Reserved(timer))
+    );
+    let vector = vector::allocate().expect("No free vector");
+    register_irq(vector, count, 1).unwrap();
+    register_irq(vector, count, 2).unwrap();
+    assert_eq!(
+        register_irq(vector, count, 2),
+        Err(IrqError::AlreadyRegistered(vector))
+    );
+    assert_eq!(handler_count(vector), 2);
+    raise(vector, 3);
+
+    assert!(unregister_irq(vector, count, 1));
+    assert!(!unregister_irq(vector, count, 1));
+    raise(vector, 5);
+
+    assert!(unregister_irq(vector, count, 2));
+    vector::free(vector);
+}
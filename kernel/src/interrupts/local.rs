@@ -76,6 +76,23 @@ impl LocalIdt {
         });
     }
 
+    /// Applies `update` to the IDT of every core and makes it visible on each of them, like calling [get_mut](Self::get_mut)
+    /// and [swap_and_sync](Self::swap_and_sync) on every core.
+    ///
+    /// Only the entries changed by `update` may be used by interrupts arriving during the swap, since the other cores
+    /// keep taking interrupts while their tables are rewritten. Unchanged entries are overwritten with identical values.
+    pub fn update_all(&self, update: impl Fn(&mut InterruptDescriptorTable)) {
+        for (_, tables) in self.tables.iter() {
+            let mut tables = tables.write();
+            without_interrupts(|| {
+                let (front, back) = &mut *tables;
+                update(back);
+                mem::swap(front, back);
+                *back = front.clone();
+            });
+        }
+    }
+
     /// Load the local IDT into the CPU's IDT register. This only needs to be done once per core.
     ///
     /// # Safety
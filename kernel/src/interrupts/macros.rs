@@ -1,8 +1,17 @@
 /// Wrapper for an interrupt handler. Based on [this implementation](https://github.com/bendudson/EuraliOS/blob/main/kernel/src/interrupts.rs#L117)
 /// which is further based on another rust os which I'm not bothering to go down the rabbit hole to find.
+///
+/// Exceptions that push an error code must use the `error_code` form. It keeps the stack aligned for the call and
+/// discards the error code before returning, so the handler can resume the interrupted (or a switched-to) context.
 #[macro_export]
 macro_rules! interrupt_wrapper {
     ($handler: path, $raw: ident) => {
+        $crate::interrupt_wrapper!(@wrap $handler, $raw, "", "", "");
+    };
+    ($handler: path, $raw: ident, error_code) => {
+        $crate::interrupt_wrapper!(@wrap $handler, $raw, "sub rsp, 8", "add rsp, 8", "add rsp, 8");
+    };
+    (@wrap $handler: path, $raw: ident, $align: literal, $unalign: literal, $discard_code: literal) => {
         #[unsafe(naked)]
         #[allow(missing_docs)]
         pub extern "x86-interrupt" fn $raw(_: x86_64::structures::idt::InterruptStackFrame) {
@@ -30,7 +39,10 @@ macro_rules! interrupt_wrapper {
 
                 // C abi requires that the first parameter is in rdi, so we need to move the stack pointer to rdi.
                 "mov rdi, rsp",
+                // Realign the stack for the call if the CPU pushed an error code.
+                $align,
                 "call {handler}",
+                $unalign,
 
                 // Pop all registers from the stack. Pop the registers in the SAME order that they are defined in InterruptRegisters.
                 "pop r15",
@@ -49,6 +61,9 @@ macro_rules! interrupt_wrapper {
                 "pop rbx",
                 "pop rax",
 
+                // Discard the error code, if there is one.
+                $discard_code,
+
                 // Re-enable interrupts.
                 "sti",
                 // Return from interrupt.
@@ -72,6 +87,15 @@ macro_rules! define_interrupt {
             $tbl.$name.set_handler_fn(mem::transmute([<raw_$name>] as *const ()));
         }
     };
+    (code $tbl: expr, $inner: path, $name: ident, $code: literal) => {
+        ::paste::paste! {
+            extern "C" fn [<_$name>](ctx: *mut ()) {
+                $inner(unsafe {mem::transmute(ctx)}, $code, BASIC_HANDLERS[$code as usize]);
+            }
+            interrupt_wrapper!([<_$name>], [<raw_$name>], error_code);
+            $tbl.$name.set_handler_fn(mem::transmute([<raw_$name>] as *const ()));
+        }
+    };
 }
 
 /// Initializes the interrupt table with the given handlers.
@@ -93,24 +117,24 @@ macro_rules! init_idt {
             crate::define_interrupt!($table, $normal_handler, bound_range_exceeded, 5);
             crate::define_interrupt!($table, $normal_handler, invalid_opcode, 6);
             crate::define_interrupt!($table, $normal_handler, device_not_available, 7);
-            crate::define_interrupt!($table, $code_handler, double_fault, 8);
-            crate::define_interrupt!($table, $code_handler, invalid_tss, 10);
-            crate::define_interrupt!($table, $code_handler, segment_not_present, 11);
-            crate::define_interrupt!($table, $code_handler, stack_segment_fault, 12);
-            crate::define_interrupt!($table, $code_handler, general_protection_fault, 13);
-            crate::interrupt_wrapper!($page_fault_handler, raw_page_fault);
+            crate::define_interrupt!(code $table, $code_handler, double_fault, 8);
+            crate::define_interrupt!(code $table, $code_handler, invalid_tss, 10);
+            crate::define_interrupt!(code $table, $code_handler, segment_not_present, 11);
+            crate::define_interrupt!(code $table, $code_handler, stack_segment_fault, 12);
+            crate::define_interrupt!(code $table, $code_handler, general_protection_fault, 13);
+            crate::interrupt_wrapper!($page_fault_handler, raw_page_fault, error_code);
             $table
                 .page_fault
                 .set_handler_fn(mem::transmute(raw_page_fault as *const ()));
             crate::define_interrupt!($table, $normal_handler, x87_floating_point, 16);
-            crate::define_interrupt!($table, $code_handler, alignment_check, 17);
+            crate::define_interrupt!(code $table, $code_handler, alignment_check, 17);
             crate::define_interrupt!($table, $normal_handler, machine_check, 18);
             crate::define_interrupt!($table, $normal_handler, simd_floating_point, 19);
             crate::define_interrupt!($table, $normal_handler, virtualization, 20);
-            crate::define_interrupt!($table, $code_handler, cp_protection_exception, 21);
+            crate::define_interrupt!(code $table, $code_handler, cp_protection_exception, 21);
             crate::define_interrupt!($table, $normal_handler, hv_injection_exception, 28);
-            crate::define_interrupt!($table, $code_handler, vmm_communication_exception, 29);
-            crate::define_interrupt!($table, $code_handler, security_exception, 30);
+            crate::define_interrupt!(code $table, $code_handler, vmm_communication_exception, 29);
+            crate::define_interrupt!(code $table, $code_handler, security_exception, 30);
         }
     };
 }
@@ -5,10 +5,12 @@ use x86_64::VirtAddr;
 
 mod exception;
 pub mod hardware;
+pub mod irq;
 
 pub mod local;
 mod lock;
 mod macros;
+pub mod vector;
 
 use crate::{
     context::{InterruptCodeContext, InterruptContext, PageFaultInterruptContext},
@@ -16,6 +18,7 @@ use crate::{
     interrupts::local::LocalIdt,
 };
 
+pub use irq::{IrqHandler, IrqReturn, register_irq, unregister_irq};
 pub use lock::{InterruptMutex, InterruptMutexGuard};
 
 /// The local IDT for each core.
@@ -31,6 +34,9 @@ pub type PageFaultHandler = fn(ctx: PageFaultInterruptContext);
 /// The interrupts that are guaranteed to be available on each x86_64 CPU.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KernelInterrupt {
+    /// The yield interrupt.
+    /// Raised in software by a thread to give up the rest of its time slice.
+    Yield = 251,
     /// The LAPIC timer interrupt.
     Timer = 252,
     /// The panic interrupt.
@@ -70,6 +76,8 @@ fn init() -> Result<(), Infallible> {
                 .set_handler_addr(VirtAddr::from_ptr(exception::panic_handler_raw as *mut ()));
             idt[KernelInterrupt::Timer as u8]
                 .set_handler_addr(VirtAddr::from_ptr(exception::timer_handler_raw as *mut ()));
+            idt[KernelInterrupt::Yield as u8]
+                .set_handler_addr(VirtAddr::from_ptr(exception::yield_handler_raw as *mut ()));
         };
     }
     hardware::define_hardware();
@@ -0,0 +1,72 @@
+//! Allocation of IDT vectors for device interrupts.
+//!
+//! Vectors below [FIRST_DYNAMIC_VECTOR] belong to CPU exceptions and the legacy PICs, and the vectors of
+//! [KernelInterrupt] are reserved for the kernel, so neither is ever handed out.
+use cake::Mutex;
+
+use super::{KernelInterrupt, hardware::PIC_2_OFFSET};
+
+/// The first vector that can be allocated, right after the vectors of the secondary PIC.
+pub const FIRST_DYNAMIC_VECTOR: u8 = PIC_2_OFFSET + 8;
+/// The last vector that can be allocated, right before the first [KernelInterrupt].
+pub const LAST_DYNAMIC_VECTOR: u8 = KernelInterrupt::Yield as u8 - 1;
+
+/// One bit per vector, set while it is allocated.
+static ALLOCATED: Mutex<[u64; 4]> = Mutex::new([0; 4]);
+
+/// Returns `true` if `vector` is in the range handed out by the allocator.
+pub fn is_dynamic(vector: u8) -> bool {
+    (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR).contains(&vector)
+}
+
+/// Allocates a free vector, or returns `None` if all of them are in use.
+pub fn allocate() -> Option<u8> {
+    let mut allocated = ALLOCATED.lock();
+    let vector = (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR)
+        .find(|vector| allocated[*vector as usize / 64] & bit(*vector) == 0)?;
+    allocated[vector as usize / 64] |= bit(vector);
+    Some(vector)
+}
+
+/// Allocates `count` consecutive free vectors, the first of which is aligned to `count` rounded up to a power of two,
+/// as multi-message MSI requires. Returns the first vector, or `None` if there is no such range.
+pub fn allocate_block(count: u8) -> Option<u8> {
+    let align = count.max(1).checked_next_power_of_two()?;
+    let mut allocated = ALLOCATED.lock();
+    let first = (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR)
+        .filter(|first| first % align == 0)
+        .find(|first| {
+            (*first..first.saturating_add(count)).all(|vector| {
+                is_dynamic(vector) && allocated[vector as usize / 64] & bit(vector) == 0
+            })
+        })?;
+    for vector in first..first + count {
+        allocated[vector as usize / 64] |= bit(vector);
+    }
+    Some(first)
+}
+
+/// Marks the specific `vector` as allocated. Returns `false` if it is reserved or already in use.
+pub fn reserve(vector: u8) -> bool {
+    if !is_dynamic(vector) {
+        return false;
+    }
+    let mut allocated = ALLOCATED.lock();
+    let word = &mut allocated[vector as usize / 64];
+    let free = *word & bit(vector) == 0;
+    *word |= bit(vector);
+    free
+}
+
+/// Returns `vector` to the allocator.
+///
+/// # Panics
+/// Panics if `vector` isn't a dynamic vector.
+pub fn free(vector: u8) {
+    assert!(is_dynamic(vector), "Vector {vector} isn't allocatable");
+    ALLOCATED.lock()[vector as usize / 64] &= !bit(vector);
+}
+
+fn bit(vector: u8) -> u64 {
+    1 << (vector % 64)
+}
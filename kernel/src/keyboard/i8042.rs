@@ -0,0 +1,162 @@
+//! The Intel 8042 PS/2 controller, to which the keyboard is attached on its first port.
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+use crate::time::Instant;
+
+/// The data port, through which bytes are exchanged with the controller and the devices.
+const DATA: u16 = 0x60;
+/// The status register when read, the command register when written.
+const COMMAND: u16 = 0x64;
+
+/// The output buffer holds a byte to be read from [DATA].
+const OUTPUT_FULL: u8 = 1 << 0;
+/// The input buffer holds a byte the controller hasn't consumed yet.
+const INPUT_FULL: u8 = 1 << 1;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const TEST_FIRST_PORT: u8 = 0xab;
+const SELF_TEST: u8 = 0xaa;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+
+/// The first port raises IRQ 1 when its output is full.
+const FIRST_IRQ: u8 = 1 << 0;
+/// The second port raises IRQ 12 when its output is full.
+const SECOND_IRQ: u8 = 1 << 1;
+/// The clock of the first port is disabled.
+const FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+/// Scancodes from the first port are translated to set 1.
+const TRANSLATION: u8 = 1 << 6;
+
+/// The keyboard command to start sending scancodes.
+const ENABLE_SCANNING: u8 = 0xf4;
+/// The reply of the keyboard to a successful command.
+const ACK: u8 = 0xfa;
+
+/// How long to wait for the controller or the keyboard to respond.
+const TIMEOUT: Duration = Duration::from_millis(10);
+
+/// An error that occurred while initializing the PS/2 controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ControllerError {
+    /// The controller or the keyboard didn't respond in time, usually because there is no controller.
+    #[error("PS/2 controller timed out")]
+    Timeout,
+    /// The controller failed its self test.
+    #[error("PS/2 controller self test failed with {0:#x}")]
+    SelfTestFailed(u8),
+    /// The first port failed its interface test.
+    #[error("PS/2 port test failed with {0:#x}")]
+    PortTestFailed(u8),
+    /// The keyboard didn't acknowledge a command.
+    #[error("Keyboard replied {0:#x} instead of an acknowledgement")]
+    NoAck(u8),
+}
+
+/// Reads the status register.
+pub fn read_status() -> u8 {
+    // SAFETY: Reading the status register has no side effects.
+    unsafe { Port::<u8>::new(COMMAND).read() }
+}
+
+/// Reads the data port. Only meaningful when [output_full] is `true`.
+pub fn read_data() -> u8 {
+    // SAFETY: Reading the data port only consumes the byte in the output buffer.
+    unsafe { Port::<u8>::new(DATA).read() }
+}
+
+/// Returns `true` if `status` says there is a byte to read from the data port.
+pub fn output_full(status: u8) -> bool {
+    status & OUTPUT_FULL != 0
+}
+
+/// Spins until `ready` returns `true` for the status register, or [TIMEOUT] passes.
+fn wait(ready: impl Fn(u8) -> bool) -> Result<(), ControllerError> {
+    let start = Instant::now();
+    while !ready(read_status()) {
+        if start.elapsed() > TIMEOUT {
+            return Err(ControllerError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Waits for a byte from the controller or the keyboard and reads it.
+fn read() -> Result<u8, ControllerError> {
+    wait(output_full)?;
+    Ok(read_data())
+}
+
+/// Writes `value` to `port` once the controller is ready to accept it.
+fn write(port: u16, value: u8) -> Result<(), ControllerError> {
+    wait(|status| status & INPUT_FULL == 0)?;
+    // SAFETY: Only the PS/2 controller ports are written, and only while the controller is waiting for input.
+    unsafe { Port::<u8>::new(port).write(value) };
+    Ok(())
+}
+
+/// Sends the controller command `command`.
+fn command(command: u8) -> Result<(), ControllerError> {
+    write(COMMAND, command)
+}
+
+/// Reads the controller configuration byte.
+fn read_config() -> Result<u8, ControllerError> {
+    command(READ_CONFIG)?;
+    read()
+}
+
+/// Writes the controller configuration byte.
+fn write_config(config: u8) -> Result<(), ControllerError> {
+    command(WRITE_CONFIG)?;
+    write(DATA, config)
+}
+
+/// Resets the controller, checks that the first port works, and enables scanning on the keyboard. Interrupts of both
+/// ports stay disabled until [enable_interrupts] is called.
+///
+/// Returns `true` if the controller translates scancodes to set 1.
+pub fn init() -> Result<bool, ControllerError> {
+    command(DISABLE_FIRST_PORT)?;
+    command(DISABLE_SECOND_PORT)?;
+    // Drop whatever the firmware left in the output buffer.
+    while output_full(read_status()) {
+        read_data();
+    }
+
+    let config = read_config()? & !(FIRST_IRQ | SECOND_IRQ);
+    write_config(config)?;
+
+    command(SELF_TEST)?;
+    match read()? {
+        0x55 => {}
+        result => return Err(ControllerError::SelfTestFailed(result)),
+    }
+    // Some controllers reset their configuration during the self test.
+    write_config(config)?;
+
+    command(TEST_FIRST_PORT)?;
+    match read()? {
+        0x00 => {}
+        result => return Err(ControllerError::PortTestFailed(result)),
+    }
+    command(ENABLE_FIRST_PORT)?;
+
+    write(DATA, ENABLE_SCANNING)?;
+    match read()? {
+        ACK => {}
+        reply => return Err(ControllerError::NoAck(reply)),
+    }
+    Ok(config & TRANSLATION != 0)
+}
+
+/// Makes the first port raise IRQ 1 whenever a byte arrives from the keyboard.
+pub fn enable_interrupts() -> Result<(), ControllerError> {
+    let config = read_config()?;
+    write_config((config | FIRST_IRQ) & !FIRST_CLOCK_DISABLED)
+}
@@ -0,0 +1,124 @@
+//! Keyboard layouts, mapping keys to the characters they type.
+use super::{KeyCode, Modifiers};
+
+/// Returns the character typed by `key` on a US layout with `modifiers` active, or `None` if it doesn't type one.
+///
+/// Ctrl combined with a letter or one of `@[\]^_` types the corresponding C0 control character, like a terminal does.
+pub fn us(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    use KeyCode::*;
+    let shift = modifiers.shift();
+
+    if let Some(c) = keypad(key, modifiers) {
+        return Some(c);
+    }
+
+    let (lower, upper) = match key {
+        Backtick => ('`', '~'),
+        Key1 => ('1', '!'),
+        Key2 => ('2', '@'),
+        Key3 => ('3', '#'),
+        Key4 => ('4', '$'),
+        Key5 => ('5', '%'),
+        Key6 => ('6', '^'),
+        Key7 => ('7', '&'),
+        Key8 => ('8', '*'),
+        Key9 => ('9', '('),
+        Key0 => ('0', ')'),
+        Minus => ('-', '_'),
+        Equals => ('=', '+'),
+        LeftBracket => ('[', '{'),
+        RightBracket => (']', '}'),
+        Backslash => ('\\', '|'),
+        Semicolon => (';', ':'),
+        Quote => ('\'', '"'),
+        Comma => (',', '<'),
+        Period => ('.', '>'),
+        Slash => ('/', '?'),
+        Space => (' ', ' '),
+        Tab => ('\t', '\t'),
+        Enter | KeypadEnter => ('\n', '\n'),
+        Backspace => ('\x08', '\x08'),
+        Escape => ('\x1b', '\x1b'),
+        _ => {
+            let letter = letter(key)?;
+            // Caps Lock inverts Shift for letters only.
+            let upper = shift != modifiers.contains(Modifiers::CAPS_LOCK);
+            return Some(if modifiers.ctrl() {
+                control(letter)
+            } else if upper {
+                letter.to_ascii_uppercase()
+            } else {
+                letter
+            });
+        }
+    };
+    let c = if shift { upper } else { lower };
+    if modifiers.ctrl() && matches!(c, '@' | '[' | '\\' | ']' | '^' | '_') {
+        return Some(control(c));
+    }
+    Some(c)
+}
+
+/// Returns the lowercase letter of `key`, if it is a letter key.
+fn letter(key: KeyCode) -> Option<char> {
+    use KeyCode::*;
+    Some(match key {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    })
+}
+
+/// Returns the character of a keypad key. The digits and the period only type while Num Lock is on, and act as
+/// navigation keys otherwise.
+fn keypad(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    use KeyCode::*;
+    let digits = modifiers.contains(Modifiers::NUM_LOCK);
+    Some(match key {
+        KeypadDivide => '/',
+        KeypadMultiply => '*',
+        KeypadMinus => '-',
+        KeypadPlus => '+',
+        KeypadPeriod if digits => '.',
+        Keypad0 if digits => '0',
+        Keypad1 if digits => '1',
+        Keypad2 if digits => '2',
+        Keypad3 if digits => '3',
+        Keypad4 if digits => '4',
+        Keypad5 if digits => '5',
+        Keypad6 if digits => '6',
+        Keypad7 if digits => '7',
+        Keypad8 if digits => '8',
+        Keypad9 if digits => '9',
+        _ => return None,
+    })
+}
+
+/// Returns the C0 control character typed by Ctrl and `c`.
+fn control(c: char) -> char {
+    char::from(c.to_ascii_uppercase() as u8 & 0x1f)
+}
@@ -0,0 +1,337 @@
+//! The PS/2 keyboard driver.
+//!
+//! The keyboard is attached to the first port of the i8042 controller and raises ISA IRQ 1, which is routed through
+//! the IOAPIC, or the legacy PIC if it can't be routed. Every byte it sends is decoded from scancode set 1 or 2 into a
+//! [KeyEvent] and appended to a lock-free queue, from which kernel code can [poll] events or [read] them, blocking
+//! until one arrives.
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use cake::{
+    Mutex,
+    log::{info, warn},
+};
+use x86_64::VirtAddr;
+
+use crate::{
+    context::InterruptContext,
+    declare_module, interrupt_wrapper,
+    interrupts::{
+        self, IDT, IrqReturn,
+        hardware::{InterruptIndex, PICS},
+        irq::IrqError,
+    },
+    mp::{
+        self,
+        ioapic::{self, RoutingError},
+    },
+    proc::{self, ThreadID},
+};
+
+mod i8042;
+mod layout;
+mod queue;
+pub mod scancode;
+
+pub use i8042::ControllerError;
+pub use queue::EventQueue;
+pub use scancode::{Decoder, KeyCode, ScancodeSet};
+
+/// The ISA IRQ of the first PS/2 port.
+const KEYBOARD_IRQ: u8 = 1;
+
+/// The events decoded by the interrupt handler and not read yet.
+static QUEUE: EventQueue<256> = EventQueue::new();
+/// Decodes the bytes received from the keyboard. Set up for the scancode set in use during initialization.
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new(ScancodeSet::Set1));
+/// The threads blocked in [read], woken up by the next event.
+static WAITERS: Mutex<Vec<ThreadID>> = Mutex::new(Vec::new());
+
+/// Whether a key was pressed or released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    /// The key went down, or is repeating because it is held down.
+    Pressed,
+    /// The key went up.
+    Released,
+}
+
+bitflags! {
+    /// The modifier keys held and the lock keys toggled on when a key event happened.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Modifiers: u16 {
+        /// The left Shift key is held.
+        const LEFT_SHIFT = 1 << 0;
+        /// The right Shift key is held.
+        const RIGHT_SHIFT = 1 << 1;
+        /// The left Ctrl key is held.
+        const LEFT_CTRL = 1 << 2;
+        /// The right Ctrl key is held.
+        const RIGHT_CTRL = 1 << 3;
+        /// The left Alt key is held.
+        const LEFT_ALT = 1 << 4;
+        /// The right Alt (AltGr) key is held.
+        const RIGHT_ALT = 1 << 5;
+        /// The left Super key is held.
+        const LEFT_SUPER = 1 << 6;
+        /// The right Super key is held.
+        const RIGHT_SUPER = 1 << 7;
+        /// Caps Lock is on.
+        const CAPS_LOCK = 1 << 8;
+        /// Num Lock is on.
+        const NUM_LOCK = 1 << 9;
+        /// Scroll Lock is on.
+        const SCROLL_LOCK = 1 << 10;
+    }
+}
+
+impl Modifiers {
+    /// Returns `true` if either Shift key is held.
+    pub fn shift(self) -> bool {
+        self.intersects(Self::LEFT_SHIFT | Self::RIGHT_SHIFT)
+    }
+
+    /// Returns `true` if either Ctrl key is held.
+    pub fn ctrl(self) -> bool {
+        self.intersects(Self::LEFT_CTRL | Self::RIGHT_CTRL)
+    }
+
+    /// Returns `true` if either Alt key is held.
+    pub fn alt(self) -> bool {
+        self.intersects(Self::LEFT_ALT | Self::RIGHT_ALT)
+    }
+}
+
+/// A key going down or up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The physical key.
+    pub key: KeyCode,
+    /// Whether the key was pressed or released.
+    pub state: KeyState,
+    /// The modifiers in effect, including the change made by this event if the key is a modifier itself.
+    pub modifiers: Modifiers,
+    /// The character typed by the key with the US layout, only set when it is pressed.
+    pub char: Option<char>,
+}
+
+impl KeyEvent {
+    /// Packs the event into 64 bits, so it can be stored in an atomic.
+    pub fn to_bits(self) -> u64 {
+        let pressed = (self.state == KeyState::Pressed) as u64;
+        let char = self.char.map_or(u32::MAX, u32::from) as u64;
+        self.key as u64 | pressed << 8 | (self.modifiers.bits() as u64) << 16 | char << 32
+    }
+
+    /// Unpacks an event packed by [KeyEvent::to_bits].
+    ///
+    /// # Panics
+    /// Panics if `bits` wasn't produced by [KeyEvent::to_bits].
+    pub fn from_bits(bits: u64) -> Self {
+        KeyEvent {
+            key: KeyCode::from_u8(bits as u8).expect("Invalid key code"),
+            state: if bits & 1 << 8 != 0 {
+                KeyState::Pressed
+            } else {
+                KeyState::Released
+            },
+            modifiers: Modifiers::from_bits_retain((bits >> 16) as u16),
+            char: char::from_u32((bits >> 32) as u32),
+        }
+    }
+}
+
+/// An error that occurred while setting up the keyboard interrupt.
+#[derive(Debug, thiserror::Error)]
+pub enum KeyboardError {
+    /// IRQ 1 couldn't be routed through the IOAPIC.
+    #[error("Failed to route the keyboard IRQ: {0}")]
+    Routing(#[from] RoutingError),
+    /// The interrupt handler couldn't be registered.
+    #[error("Failed to register the keyboard handler: {0}")]
+    Irq(#[from] IrqError),
+    /// The controller refused to enable the keyboard interrupt.
+    #[error("Failed to enable the keyboard interrupt: {0}")]
+    Controller(#[from] ControllerError),
+}
+
+declare_module!("keyboard", init, KeyboardError);
+
+fn init() -> Result<(), KeyboardError> {
+    let translated = match i8042::init() {
+        Ok(translated) => translated,
+        Err(err) => {
+            warn!("No PS/2 keyboard: {}", err);
+            return Ok(());
+        }
+    };
+    let set = if translated {
+        ScancodeSet::Set1
+    } else {
+        ScancodeSet::Set2
+    };
+    *DECODER.lock() = Decoder::new(set);
+
+    match ioapic::routing::route_isa(KEYBOARD_IRQ, mp::current_core_id() as u8) {
+        Ok(route) => {
+            interrupts::register_irq(route.vector, handle_irq, 0)?;
+            ioapic::routing::unmask(route.gsi)?;
+            info!(
+                "PS/2 keyboard using {:?} on GSI {}, vector {}",
+                set, route.gsi, route.vector
+            );
+        }
+        Err(err) => {
+            warn!("{}, falling back to the PIC", KeyboardError::Routing(err));
+            IDT.update_all(|idt| {
+                // SAFETY: The handler is a valid interrupt entry point that acknowledges the PIC.
+                unsafe {
+                    idt[InterruptIndex::Keyboard as u8]
+                        .set_handler_addr(VirtAddr::from_ptr(pic_handler_raw as *mut ()));
+                }
+            });
+            // SAFETY: The handler of the keyboard IRQ is installed above.
+            unsafe {
+                let mut pics = PICS.lock();
+                let [primary, secondary] = pics.read_masks();
+                pics.write_masks(primary & !(1 << KEYBOARD_IRQ), secondary);
+            }
+            info!("PS/2 keyboard using {:?} through the PIC", set);
+        }
+    }
+    i8042::enable_interrupts()?;
+    Ok(())
+}
+
+/// Decodes the byte waiting in the controller, if any, and wakes up the readers if it completed an event.
+fn receive() -> bool {
+    if !i8042::output_full(i8042::read_status()) {
+        return false;
+    }
+    let byte = i8042::read_data();
+    if let Some(event) = DECODER.lock().feed(byte) {
+        if !QUEUE.push(event) {
+            warn!("Keyboard queue full, dropping {:?}", event.key);
+        }
+        for pid in WAITERS.lock().drain(..) {
+            proc::wake(pid);
+        }
+    }
+    true
+}
+
+fn handle_irq(_: u8, _: usize) -> IrqReturn {
+    if receive() {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
+}
+
+extern "C" fn pic_handler(_: InterruptContext) {
+    receive();
+    // SAFETY: This is the handler of the keyboard IRQ delivered by the PIC.
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8)
+    };
+}
+
+interrupt_wrapper!(pic_handler, pic_handler_raw);
+
+/// Removes the oldest key event from the queue, or returns `None` if there is none.
+pub fn poll() -> Option<KeyEvent> {
+    QUEUE.pop()
+}
+
+/// Returns the oldest key event from the queue, blocking the current thread until one arrives. Spins instead if the
+/// scheduler isn't running on this core.
+pub fn read() -> KeyEvent {
+    loop {
+        if let Some(event) = QUEUE.pop() {
+            return event;
+        }
+        let blocked = proc::block(|pid| {
+            WAITERS.lock().push(pid);
+            // An event may have arrived between the check above and registering as a waiter.
+            if !QUEUE.is_empty() {
+                proc::wake(pid);
+            }
+        });
+        if !blocked {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Returns the next character typed, blocking until a key that types one is pressed.
+pub fn read_char() -> char {
+    loop {
+        if let Some(char) = read().char {
+            return char;
+        }
+    }
+}
+
+#[kproc::test("Scancode decoding")]
+fn scancode_decoding() {
+    fn feed(decoder: &mut Decoder, bytes: &[u8]) -> Vec<KeyEvent> {
+        bytes
+            .iter()
+            .filter_map(|byte| decoder.feed(*byte))
+            .collect()
+    }
+
+    // Set 1: Shift + A, then a, release codes have bit 7 set.
+    let mut set1 = Decoder::new(ScancodeSet::Set1);
+    let events = feed(&mut set1, &[0x2a, 0x1e, 0x9e, 0xaa, 0x1e]);
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[1].key, KeyCode::A);
+    assert_eq!(events[1].char, Some('A'));
+    assert_eq!(events[2].state, KeyState::Released);
+    assert_eq!(events[2].char, None);
+    assert_eq!(events[3].key, KeyCode::LeftShift);
+    assert!(!events[3].modifiers.shift());
+    assert_eq!(events[4].char, Some('a'));
+    // Extended keys and Pause.
+    let events = feed(
+        &mut set1,
+        &[0xe0, 0x48, 0xe0, 0xc8, 0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5],
+    );
+    assert_eq!(events.len(), 3);
+    assert_eq!(
+        (events[0].key, events[0].state),
+        (KeyCode::Up, KeyState::Pressed)
+    );
+    assert_eq!(
+        (events[1].key, events[1].state),
+        (KeyCode::Up, KeyState::Released)
+    );
+    assert_eq!(events[2].key, KeyCode::Pause);
+
+    // Set 2: Caps Lock inverts Shift for letters only, release codes are prefixed with F0.
+    let mut set2 = Decoder::new(ScancodeSet::Set2);
+    let events = feed(
+        &mut set2,
+        &[0x58, 0xf0, 0x58, 0x1c, 0x12, 0x1c, 0x16, 0xf0, 0x12],
+    );
+    assert!(set2.modifiers().contains(Modifiers::CAPS_LOCK));
+    let chars: Vec<_> = events.iter().filter_map(|event| event.char).collect();
+    assert_eq!(chars, ['A', 'a', '!']);
+    assert_eq!(events.last().unwrap().state, KeyState::Released);
+    let events = feed(&mut set2, &[0xe0, 0x75, 0xe0, 0xf0, 0x75, 0x14, 0x21]);
+    assert_eq!(
+        (events[0].key, events[1].state),
+        (KeyCode::Up, KeyState::Released)
+    );
+    assert_eq!(events[2].key, KeyCode::LeftCtrl);
+    assert_eq!(events[3].char, Some('\x03'));
+
+    // Events survive the round trip through the queue.
+    let queue = EventQueue::<2>::new();
+    assert!(queue.push(events[3]));
+    assert!(queue.push(events[0]));
+    assert!(!queue.push(events[1]));
+    assert_eq!(queue.pop(), Some(events[3]));
+    assert_eq!(queue.pop(), Some(events[0]));
+    assert_eq!(queue.pop(), None);
+}
@@ -0,0 +1,80 @@
+//! A lock-free ring buffer of key events.
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::KeyEvent;
+
+/// A bounded ring buffer of key events with a single producer and any number of consumers, none of which ever block.
+///
+/// The producer is the keyboard interrupt handler, which only ever runs on one core at a time. Events are stored
+/// packed into atomics, so a consumer racing with another one never observes a torn event.
+#[derive(Debug)]
+pub struct EventQueue<const N: usize> {
+    slots: [AtomicU64; N],
+    /// The index of the next event to read. Only ever increases, wrapping around.
+    head: AtomicUsize,
+    /// The index of the next event to write. Only ever increases, wrapping around.
+    tail: AtomicUsize,
+}
+
+impl<const N: usize> EventQueue<N> {
+    /// Creates an empty queue.
+    pub const fn new() -> Self {
+        EventQueue {
+            slots: [const { AtomicU64::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends `event`. Returns `false` and drops it if the queue is full.
+    ///
+    /// Must only be called by one producer at a time.
+    pub fn push(&self, event: KeyEvent) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= N {
+            return false;
+        }
+        self.slots[tail % N].store(event.to_bits(), Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Removes the oldest event, or returns `None` if the queue is empty.
+    pub fn pop(&self) -> Option<KeyEvent> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head == self.tail.load(Ordering::Acquire) {
+                return None;
+            }
+            // The slot can't be overwritten before `head` moves past it, so the value is valid if the exchange succeeds.
+            let bits = self.slots[head % N].load(Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                head.wrapping_add(1),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(KeyEvent::from_bits(bits)),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Returns `true` if there are no events to read.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of events waiting to be read.
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+}
+
+impl<const N: usize> Default for EventQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
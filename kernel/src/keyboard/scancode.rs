@@ -0,0 +1,494 @@
+//! Decoding of PS/2 scancode sets 1 and 2 into key events.
+use super::{KeyEvent, KeyState, Modifiers, layout};
+
+/// A physical key, independent of the keyboard layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+#[allow(missing_docs)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftSuper,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightSuper,
+    Menu,
+    RightCtrl,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Up,
+    Left,
+    Down,
+    Right,
+    NumLock,
+    KeypadDivide,
+    KeypadMultiply,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+impl KeyCode {
+    /// The last key code, used to validate raw values.
+    const LAST: KeyCode = KeyCode::Keypad9;
+
+    /// Converts a raw value produced by `key as u8` back into a key code.
+    pub fn from_u8(value: u8) -> Option<KeyCode> {
+        // SAFETY: The variants are numbered contiguously from zero up to `LAST`.
+        (value <= Self::LAST as u8).then(|| unsafe { core::mem::transmute::<u8, KeyCode>(value) })
+    }
+}
+
+/// The scancode set a keyboard sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// The IBM PC XT set, which the i8042 translates set 2 into by default.
+    Set1,
+    /// The IBM PC AT set, the native set of every PS/2 keyboard.
+    Set2,
+}
+
+/// Turns a stream of scancode bytes into key events, keeping track of the modifiers.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    set: ScancodeSet,
+    modifiers: Modifiers,
+    /// An `E0` prefix was received.
+    extended: bool,
+    /// A set 2 `F0` (break) prefix was received.
+    release: bool,
+    /// The number of bytes of a Pause sequence that are still to be skipped.
+    skip: u8,
+}
+
+impl Decoder {
+    /// Creates a decoder for `set` with no modifiers active.
+    pub const fn new(set: ScancodeSet) -> Self {
+        Decoder {
+            set,
+            modifiers: Modifiers::empty(),
+            extended: false,
+            release: false,
+            skip: 0,
+        }
+    }
+
+    /// Returns the scancode set being decoded.
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Returns the currently active modifiers.
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Feeds the next byte received from the keyboard. Returns an event once a full scancode was received.
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            // Acknowledgements, echoes and errors aren't scancodes.
+            0x00 | 0xee | 0xfa | 0xfc | 0xfd | 0xfe | 0xff if !self.extended && !self.release => {
+                return None;
+            }
+            // The self test result, which is the break code of the left shift in set 1.
+            0xaa if self.set == ScancodeSet::Set2 && !self.release => return None,
+            0xe0 => {
+                self.extended = true;
+                return None;
+            }
+            0xe1 => {
+                // Pause has no break code: E1 1D 45 E1 9D C5 in set 1, E1 14 77 E1 F0 14 F0 77 in set 2.
+                self.skip = match self.set {
+                    ScancodeSet::Set1 => 5,
+                    ScancodeSet::Set2 => 7,
+                };
+                return Some(self.event(KeyCode::Pause, KeyState::Pressed));
+            }
+            0xf0 if self.set == ScancodeSet::Set2 => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::take(&mut self.extended);
+        let (code, state) = match self.set {
+            ScancodeSet::Set1 if byte & 0x80 != 0 => (byte & 0x7f, KeyState::Released),
+            ScancodeSet::Set1 => (byte, KeyState::Pressed),
+            ScancodeSet::Set2 if core::mem::take(&mut self.release) => (byte, KeyState::Released),
+            ScancodeSet::Set2 => (byte, KeyState::Pressed),
+        };
+        let key = match (self.set, extended) {
+            (ScancodeSet::Set1, false) => set1(code),
+            (ScancodeSet::Set1, true) => set1_extended(code),
+            (ScancodeSet::Set2, false) => set2(code),
+            (ScancodeSet::Set2, true) => set2_extended(code),
+        }?;
+        Some(self.event(key, state))
+    }
+
+    /// Applies the effect of `key` on the modifiers and builds its event.
+    fn event(&mut self, key: KeyCode, state: KeyState) -> KeyEvent {
+        let pressed = state == KeyState::Pressed;
+        let held = match key {
+            KeyCode::LeftShift => Some(Modifiers::LEFT_SHIFT),
+            KeyCode::RightShift => Some(Modifiers::RIGHT_SHIFT),
+            KeyCode::LeftCtrl => Some(Modifiers::LEFT_CTRL),
+            KeyCode::RightCtrl => Some(Modifiers::RIGHT_CTRL),
+            KeyCode::LeftAlt => Some(Modifiers::LEFT_ALT),
+            KeyCode::RightAlt => Some(Modifiers::RIGHT_ALT),
+            KeyCode::LeftSuper => Some(Modifiers::LEFT_SUPER),
+            KeyCode::RightSuper => Some(Modifiers::RIGHT_SUPER),
+            _ => None,
+        };
+        let lock = match key {
+            KeyCode::CapsLock => Some(Modifiers::CAPS_LOCK),
+            KeyCode::NumLock => Some(Modifiers::NUM_LOCK),
+            KeyCode::ScrollLock => Some(Modifiers::SCROLL_LOCK),
+            _ => None,
+        };
+        if let Some(held) = held {
+            self.modifiers.set(held, pressed);
+        }
+        if let Some(lock) = lock
+            && pressed
+        {
+            self.modifiers.toggle(lock);
+        }
+
+        KeyEvent {
+            key,
+            state,
+            modifiers: self.modifiers,
+            char: pressed.then(|| layout::us(key, self.modifiers)).flatten(),
+        }
+    }
+}
+
+/// Translates a set 1 make code without prefix.
+fn set1(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Key1,
+        0x03 => Key2,
+        0x04 => Key3,
+        0x05 => Key4,
+        0x06 => Key5,
+        0x07 => Key6,
+        0x08 => Key7,
+        0x09 => Key8,
+        0x0a => Key9,
+        0x0b => Key0,
+        0x0c => Minus,
+        0x0d => Equals,
+        0x0e => Backspace,
+        0x0f => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1a => LeftBracket,
+        0x1b => RightBracket,
+        0x1c => Enter,
+        0x1d => LeftCtrl,
+        0x1e => A,
+        0x1f => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2a => LeftShift,
+        0x2b => Backslash,
+        0x2c => Z,
+        0x2d => X,
+        0x2e => C,
+        0x2f => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadMultiply,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3a => CapsLock,
+        0x3b => F1,
+        0x3c => F2,
+        0x3d => F3,
+        0x3e => F4,
+        0x3f => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4a => KeypadMinus,
+        0x4b => Keypad4,
+        0x4c => Keypad5,
+        0x4d => Keypad6,
+        0x4e => KeypadPlus,
+        0x4f => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+/// Translates a set 1 make code following an `E0` prefix.
+fn set1_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1c => KeypadEnter,
+        0x1d => RightCtrl,
+        0x35 => KeypadDivide,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => Up,
+        0x49 => PageUp,
+        0x4b => Left,
+        0x4d => Right,
+        0x4f => End,
+        0x50 => Down,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5b => LeftSuper,
+        0x5c => RightSuper,
+        0x5d => Menu,
+        // The fake shifts around Print Screen and the navigation keys (E0 2A, E0 36) are dropped here.
+        _ => return None,
+    })
+}
+
+/// Translates a set 2 make code without prefix.
+fn set2(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0a => F8,
+        0x0b => F6,
+        0x0c => F4,
+        0x0d => Tab,
+        0x0e => Backtick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Key1,
+        0x1a => Z,
+        0x1b => S,
+        0x1c => A,
+        0x1d => W,
+        0x1e => Key2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Key4,
+        0x26 => Key3,
+        0x29 => Space,
+        0x2a => V,
+        0x2b => F,
+        0x2c => T,
+        0x2d => R,
+        0x2e => Key5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Key6,
+        0x3a => M,
+        0x3b => J,
+        0x3c => U,
+        0x3d => Key7,
+        0x3e => Key8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Key0,
+        0x46 => Key9,
+        0x49 => Period,
+        0x4a => Slash,
+        0x4b => L,
+        0x4c => Semicolon,
+        0x4d => P,
+        0x4e => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5a => Enter,
+        0x5b => RightBracket,
+        0x5d => Backslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6b => Keypad4,
+        0x6c => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7a => Keypad3,
+        0x7b => KeypadMinus,
+        0x7c => KeypadMultiply,
+        0x7d => Keypad9,
+        0x7e => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+/// Translates a set 2 make code following an `E0` prefix.
+fn set2_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1f => LeftSuper,
+        0x27 => RightSuper,
+        0x2f => Menu,
+        0x4a => KeypadDivide,
+        0x5a => KeypadEnter,
+        0x69 => End,
+        0x6b => Left,
+        0x6c => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7a => PageDown,
+        0x7c => PrintScreen,
+        0x7d => PageUp,
+        // The fake shifts around Print Screen and the navigation keys (E0 12, E0 59) are dropped here.
+        _ => return None,
+    })
+}
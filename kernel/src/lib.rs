@@ -24,19 +24,29 @@ use interrupts::hardware;
 use kserial::client::get_serial_client;
 
 pub mod acpi;
+pub mod ahci;
+pub mod block;
 pub mod context;
 pub mod display;
+pub mod fs;
 pub mod gdt;
+pub mod initramfs;
 pub mod interrupts;
+pub mod keyboard;
 pub mod memory;
 pub mod mp;
+pub mod net;
+pub mod nvme;
 pub mod output;
 pub mod panic;
 pub mod pci;
-//pub mod proc;
+pub mod proc;
 pub mod requests;
 pub mod serial;
+pub mod syscall;
 pub mod testing;
+pub mod time;
+pub mod virtio;
 
 /// The size of the kernel stack in bytes.
 pub const STACK_SIZE: u64 = 1 << 15; // 32 KiB
@@ -105,8 +115,18 @@ pub(crate) unsafe fn init_kernel_services() {
     display::MODULE.init();
     acpi::MODULE.init();
     mp::MODULE.init();
+    time::MODULE.init();
     pci::MODULE.init();
-    //proc::MODULE.init();
+    keyboard::MODULE.init();
+    virtio::MODULE.init();
+    ahci::MODULE.init();
+    nvme::MODULE.init();
+    // Every PCI driver is registered by now, so the listing shows what each function ended up bound to.
+    pci::driver::log_devices();
+    fs::MODULE.init();
+    initramfs::MODULE.init();
+    syscall::MODULE.init();
+    proc::MODULE.init();
     info!("Kernel services initialized");
 }
 
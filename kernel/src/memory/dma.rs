@@ -0,0 +1,129 @@
+//! Memory shared with devices through DMA.
+use core::ptr;
+
+use nmm::{
+    MapFlags, MemError, MemoryMapping,
+    paging::{Address, AddressExt, VirtAddr},
+};
+
+/// A zeroed buffer of physically contiguous memory, mapped into the kernel's address space, that devices can access
+/// through its physical address. The memory is freed when the buffer is dropped, so it must outlive every request
+/// handed to a device that uses it.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    mapping: MemoryMapping,
+}
+
+// SAFETY: The buffer owns its memory, which is only accessed through raw pointers and volatile copies.
+unsafe impl Send for DmaBuffer {}
+// SAFETY: See above.
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    /// Allocates a buffer of at least `size` bytes, rounded up to whole pages.
+    pub fn new(size: usize) -> Result<Self, MemError> {
+        let size = size.next_multiple_of(nmm::arch::L1_PAGE_SIZE as usize);
+        let phys = nmm::reserve_contiguous(size)?;
+        let mapping = match nmm::create_phys_mapping(phys, size, MapFlags::WRITABLE) {
+            Ok(mapping) => mapping,
+            Err(err) => {
+                // SAFETY: The frames were just reserved and never handed out.
+                unsafe { nmm::free_contiguous(phys, size) };
+                return Err(err);
+            }
+        };
+        // SAFETY: The mapping was just created writable and is `size` bytes long.
+        unsafe { ptr::write_bytes(mapping.as_mut_ptr::<u8>(), 0, size) };
+        Ok(DmaBuffer { mapping })
+    }
+
+    /// Returns the physical address of the start of the buffer, to hand to devices.
+    pub fn phys_addr(&self) -> u64 {
+        self.mapping.phys_base().as_u64()
+    }
+
+    /// Returns the size of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.mapping.byte_size()
+    }
+
+    /// Returns `true` if the buffer is empty, which never happens.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a pointer to the start of the buffer. Devices may write to the buffer at any time while they own part of
+    /// it, so it should only be accessed through volatile operations.
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.mapping.as_mut_ptr()
+    }
+
+    /// Copies `data` into the buffer at `offset`.
+    ///
+    /// # Panics
+    /// Panics if `data` doesn't fit in the buffer.
+    pub fn write_bytes(&self, offset: usize, data: &[u8]) {
+        assert!(
+            offset + data.len() <= self.len(),
+            "Write outside of the DMA buffer"
+        );
+        // SAFETY: The range is within the buffer, which doesn't overlap `data`.
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.as_mut_ptr::<u8>().add(offset),
+                data.len(),
+            )
+        };
+    }
+
+    /// Copies the bytes of the buffer at `offset` into `data`.
+    ///
+    /// # Panics
+    /// Panics if `data` is larger than what is left of the buffer.
+    pub fn read_bytes(&self, offset: usize, data: &mut [u8]) {
+        assert!(
+            offset + data.len() <= self.len(),
+            "Read outside of the DMA buffer"
+        );
+        // SAFETY: The range is within the buffer, which doesn't overlap `data`.
+        unsafe {
+            ptr::copy_nonoverlapping(
+                self.as_mut_ptr::<u8>().add(offset),
+                data.as_mut_ptr(),
+                data.len(),
+            )
+        };
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let (phys, size) = (self.mapping.phys_base(), self.mapping.byte_size());
+        // SAFETY: The buffer is owned, and devices are done with it once it is dropped.
+        unsafe {
+            nmm::free_phys_mapping(self.mapping).expect("Failed to unmap DMA buffer");
+            nmm::free_contiguous(phys, size);
+        }
+    }
+}
+
+#[kproc::test("DMA buffers")]
+fn dma_buffers() {
+    let buffer = DmaBuffer::new(3 * 4096 + 1).unwrap();
+    assert_eq!(buffer.len(), 4 * 4096);
+    assert!(buffer.phys_addr().is_multiple_of(4096));
+    // The frames are contiguous, every page is backed by the frame following the previous one.
+    for page in 0..4 {
+        let virt =
+            VirtAddr::from_mut_ptr(buffer.as_mut_ptr::<u8>().wrapping_add(page * 4096)).unwrap();
+        let (phys, _) = nmm::translate(virt).unwrap();
+        assert_eq!(phys.as_u64(), buffer.phys_addr() + page as u64 * 4096);
+    }
+    let mut data = [0xff; 16];
+    buffer.read_bytes(4096, &mut data);
+    assert_eq!(data, [0; 16]);
+    buffer.write_bytes(4096, b"Hello, device!!!");
+    buffer.read_bytes(4096, &mut data);
+    assert_eq!(&data, b"Hello, device!!!");
+}
@@ -2,9 +2,9 @@ use core::{convert::Infallible, mem};
 
 use cake::{ResourceGuard, log::info};
 use nmm::{
-    InitConfig,
+    InitConfig, MapFlags, MapSource,
     arch::HIGHER_HALF_START,
-    paging::{Address, PageTable, VirtAddr},
+    paging::{Address, AddressExt, PageTable, VirtAddr},
 };
 use x86_64::{
     VirtAddr as XVirtAddr,
@@ -19,6 +19,7 @@ use crate::{
 };
 
 pub mod allocator;
+pub mod dma;
 pub mod elf_req_data;
 pub mod paging;
 pub mod req_data;
@@ -47,7 +48,14 @@ fn init() -> Result<(), Infallible> {
     Ok(())
 }
 
-fn init_heap() {}
+fn init_heap() {
+    configure_heap_allocator(
+        "Kernel",
+        allocator::init,
+        map::kernel_heap::START,
+        map::kernel_heap::SIZE,
+    );
+}
 
 /// Configure a heap allocator with the given name, allocator function, and heap size.
 /// alloc_fn should be a function that takes two usize arguments: the start and end of the heap (in that order).
@@ -57,35 +65,26 @@ fn configure_heap_allocator(
     heap_start: VirtAddr,
     heap_size: u64,
 ) {
-    // let heap_end = heap_start + heap_size;
-    // let heap_start_page = Page::containing_address(heap_start);
-    // let heap_end_page = Page::containing_address(heap_end);
-    // let heap_range: PageRangeInclusive<KernelPageSize> =
-    //     Page::range_inclusive(heap_start_page, heap_end_page - 1);
+    let heap_end = heap_start + heap_size;
 
-    // info!(
-    //     "{} Heap range: 0x{:x} - 0x{:x} ({:?} - {:?}: {} pages)",
-    //     alloc_name,
-    //     heap_start,
-    //     heap_end,
-    //     heap_start_page,
-    //     heap_end_page,
-    //     heap_range.len()
-    // );
+    info!(
+        "{} Heap range: {:#x} - {:#x} ({} bytes)",
+        alloc_name,
+        heap_start.as_u64(),
+        heap_end.as_u64(),
+        heap_size
+    );
 
-    // unsafe {
-    //     paging::phys::FRAME_ALLOCATOR.get().map_range(
-    //         heap_range,
-    //         PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-    //     )
-    // }
-    // .expect("Unable to map heap");
+    nmm::map(
+        heap_start,
+        MapSource::Anon { zero: false },
+        heap_size as usize,
+        MapFlags::WRITABLE,
+    )
+    .expect("Unable to map heap");
 
-    // info!(
-    //     "{} Heap initialized at 0x{:x} - 0x{:x}",
-    //     alloc_name, heap_start, heap_end
-    // );
-    // info!("Initializing {} allocator", alloc_name);
-    // unsafe { alloc_fn(heap_start.as_mut_ptr(), heap_end.as_mut_ptr()) };
-    // info!("{} allocator initialized", alloc_name);
+    info!("Initializing {} allocator", alloc_name);
+    // SAFETY: The heap range was just mapped and is reserved for this allocator in the kernel map.
+    unsafe { alloc_fn(heap_start.as_mut_ptr(), heap_end.as_mut_ptr()) };
+    info!("{} allocator initialized", alloc_name);
 }
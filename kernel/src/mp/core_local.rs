@@ -79,6 +79,37 @@ impl<T, C: ConstructMethod<T>> CoreLocal<T, C> {
         }
         panic!("No data for core ID {}", core_id);
     }
+
+    /// Try to get a mutable reference to the data for the current core without spinning.
+    /// Returns `None` if the data is currently locked.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.get(crate::mp::current_core_id())
+            .expect("No data for the current core")
+            .try_write()
+    }
+
+    /// Get the lock for the data of the core with the given APIC ID.
+    /// Returns `None` if there is no such core.
+    pub fn get(&self, core_id: u64) -> Option<&RwLock<T>> {
+        if core_id == 0 {
+            return Some(&self.bootstrap);
+        }
+
+        let apps = self.get_or_init_applications();
+        apps.binary_search_by_key(&core_id, |(id, _)| *id)
+            .ok()
+            .map(|ind| &apps[ind].1)
+    }
+
+    /// Iterate over the data of every core as `(APIC ID, data)` pairs, starting with the bootstrap core.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &RwLock<T>)> {
+        let apps: &[(u64, RwLock<T>)] = if CORES.is_completed() {
+            self.get_or_init_applications()
+        } else {
+            &[]
+        };
+        core::iter::once((0, &self.bootstrap)).chain(apps.iter().map(|(id, l)| (*id, l)))
+    }
 }
 
 unsafe impl<T, C> Send for CoreLocal<T, C> where C: ConstructMethod<T> {}
@@ -1,61 +1,153 @@
 //! Various Rust abstractions for IOAPIC support.
+//!
+//! Every IOAPIC described by the MADT is mapped during initialization and handles the contiguous range of global system
+//! interrupts (GSIs) starting at its GSI base. The [routing] module maps ISA IRQs and GSIs to IDT vectors on top of them.
 use core::fmt::Debug;
 
 use ::acpi::sdt::madt::{Madt, MadtEntry};
-use cake::log::info;
-use cake::{Once, OnceMutex};
+use alloc::vec::Vec;
+use cake::log::{info, warn};
+use cake::{Mutex, Once};
 use modular_bitfield::prelude::*;
 use nmm::MapFlags;
-use nmm::paging::AddressExt;
 
-use crate::{
-    acpi,
-    mp::{apic_page_flags, id},
-};
+use crate::{acpi, mp::id};
 
 mod redirection;
+pub mod routing;
 mod version;
 
-pub use redirection::RedirectionEntry;
+pub use redirection::{DeliveryMode, Polarity, RedirectionEntry, TriggerMode};
+pub use routing::{IrqConfig, IrqRoute, RoutingError};
 pub use version::IoApicVersion;
 
-/// Represents an I/O Advanced Programmable Interrupt Controller (IOAPIC).
-#[derive(Debug)]
-pub struct IoApic {
-    base: Once<u64>,
-    mapped: OnceMutex<*mut u8>,
-}
+/// The IOAPICs of the system, ordered by their GSI base.
+static IOAPICS: Once<Vec<IoApic>> = Once::new();
 
-impl IoApic {
-    /// Creates an empty IOAPIC instance.
-    pub const fn new() -> Self {
-        Self {
-            base: Once::new(),
-            mapped: OnceMutex::uninitialized(),
+/// Maps every IOAPIC described by the MADT, masks all of their inputs and sets up the interrupt routing.
+///
+/// # Panics
+/// Panics if there is no MADT or it doesn't describe any IOAPIC.
+pub fn init() {
+    let madt = acpi::get_table::<Madt>().expect("Failed to get MADT");
+    let mut ioapics = Vec::new();
+    let mut overrides = Vec::new();
+    let mut nmi_sources = Vec::new();
+    for entry in madt.table_pin().entries() {
+        match entry {
+            MadtEntry::IoApic(entry) => {
+                let (address, gsi_base) =
+                    (entry.io_apic_address, entry.global_system_interrupt_base);
+                // SAFETY: The MADT describes the register block of an IOAPIC at `address`.
+                let ioapic = unsafe { IoApic::map(entry.io_apic_id, address as u64, gsi_base) };
+                info!(
+                    "IO APIC {} at {:#x}: GSIs {}..{}, {:?}",
+                    ioapic.apic_id,
+                    address,
+                    gsi_base,
+                    gsi_base + ioapic.gsi_count(),
+                    ioapic.version()
+                );
+                ioapics.push(ioapic);
+            }
+            MadtEntry::InterruptSourceOverride(entry) if entry.bus == 0 => {
+                overrides.push((entry.irq, entry.global_system_interrupt, entry.flags));
+            }
+            MadtEntry::InterruptSourceOverride(entry) => {
+                let bus = entry.bus;
+                warn!("Ignoring interrupt source override for unknown bus {}", bus);
+            }
+            MadtEntry::NmiSource(entry) => {
+                nmi_sources.push((entry.global_system_interrupt, entry.flags));
+            }
+            _ => {}
         }
     }
+    assert!(!ioapics.is_empty(), "No IOAPIC found in MADT");
+    ioapics.sort_by_key(|ioapic| ioapic.gsi_base);
 
-    /// Initializes the IOAPIC by reading the MADT and mapping the IOAPIC's physical address into the kernel's address space.
-    pub fn init(&self) {
-        let madt = acpi::get_table::<Madt>().expect("Failed to get MADT");
-        for entry in madt.table_pin().entries() {
-            if let MadtEntry::IoApic(i) = entry {
-                self.base.call_once(|| i.io_apic_address as u64);
-                break;
+    for ioapic in &ioapics {
+        for index in 0..ioapic.gsi_count() as u8 {
+            // SAFETY: `index` is below the number of redirection entries, and masking an input has no side effects.
+            unsafe {
+                let mut entry = ioapic.read_redirection_entry(index);
+                entry.set_mask(true);
+                ioapic.write_redirection_entry(index, entry);
             }
         }
+    }
+    IOAPICS.call_once(|| ioapics);
+    routing::init(&overrides, &nmi_sources);
+}
+
+/// Returns all IOAPICs of the system, ordered by their GSI base. Empty before [init].
+pub fn ioapics() -> &'static [IoApic] {
+    IOAPICS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the IOAPIC that handles `gsi`.
+pub fn for_gsi(gsi: u32) -> Option<&'static IoApic> {
+    ioapics().iter().find(|ioapic| ioapic.handles(gsi))
+}
 
-        let base = *self.base.get().expect("No IOAPIC found in MADT");
-        info!("IO APIC base address: {:#x}", base);
-        let phys_addr = x86_64::PhysAddr::new(base);
+/// Represents an I/O Advanced Programmable Interrupt Controller (IOAPIC).
+#[derive(Debug)]
+pub struct IoApic {
+    apic_id: u8,
+    gsi_base: u32,
+    gsi_count: u32,
+    mapped: *mut u8,
+    /// Held across the writes to the register select and the accesses to the data window.
+    window: Mutex<()>,
+}
+
+// SAFETY: The register window is only accessed while holding `window`, the mapping itself never changes.
+unsafe impl Send for IoApic {}
+// SAFETY: See above.
+unsafe impl Sync for IoApic {}
+
+impl IoApic {
+    /// Maps the register block of the IOAPIC with the ID `apic_id` at the physical address `base`, whose first input is
+    /// the global system interrupt `gsi_base`.
+    ///
+    /// # Safety
+    /// `base` must be the address of the register block of an IOAPIC.
+    pub unsafe fn map(apic_id: u8, base: u64, gsi_base: u32) -> Self {
         let map = nmm::create_phys_mapping(
-            phys_addr.into(),
+            x86_64::PhysAddr::new(base).into(),
             1024,
             MapFlags::CACHE_DISABLE | MapFlags::WRITABLE,
         )
-        .expect("Failed to map LAPIC");
+        .expect("Failed to map IOAPIC");
+        let mut ioapic = Self {
+            apic_id,
+            gsi_base,
+            gsi_count: 0,
+            mapped: map.as_mut_ptr(),
+            window: Mutex::new(()),
+        };
+        ioapic.gsi_count = ioapic.version().max_redirection_entries() as u32 + 1;
+        ioapic
+    }
+
+    /// Returns the ID the MADT assigns to this IOAPIC.
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
 
-        self.mapped.call_init(|| map.as_mut_ptr());
+    /// Returns the global system interrupt of the first input of this IOAPIC.
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    /// Returns the number of inputs (and so redirection entries) of this IOAPIC.
+    pub fn gsi_count(&self) -> u32 {
+        self.gsi_count
+    }
+
+    /// Returns `true` if `gsi` is one of the inputs of this IOAPIC.
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.gsi_count).contains(&gsi)
     }
 
     /// Reads a value from the IOAPIC register at the given offset.
@@ -66,8 +158,7 @@ impl IoApic {
     where
         T: Copy,
     {
-        let ptr = self.mapped.get();
-        let ptr = unsafe { ptr.add(byte_off) } as *const T;
+        let ptr = unsafe { self.mapped.add(byte_off) } as *const T;
         unsafe { ptr.read_volatile() }
     }
 
@@ -79,13 +170,13 @@ impl IoApic {
     where
         T: Copy,
     {
-        let ptr = self.mapped.get();
-        let ptr = unsafe { ptr.add(byte_off) } as *mut T;
+        let ptr = unsafe { self.mapped.add(byte_off) } as *mut T;
         unsafe { ptr.write_volatile(value) }
     }
 
     /// Reads from the IOAPIC register specified by `reg`.
     pub unsafe fn read_register(&self, reg: u8) -> u32 {
+        let _window = self.window.lock();
         unsafe {
             self.write_offset(0x00, reg);
             self.read_offset(0x10)
@@ -107,6 +198,7 @@ impl IoApic {
     /// # Safety
     /// The caller must ensure that the given register is valid.
     pub unsafe fn write_register(&self, reg: u8, value: u32) {
+        let _window = self.window.lock();
         unsafe { self.write_offset(0x00, reg) };
         unsafe { self.write_offset(0x10, value) };
     }
@@ -153,6 +245,20 @@ impl IoApic {
         let val = unsafe { self.read_register_64(0x10 + (index * 2)) };
         RedirectionEntry::from_bytes(val.to_ne_bytes())
     }
+
+    /// Writes the redirection entry at the given index. The destination is written before the low half, so an entry
+    /// that gets unmasked never fires towards a stale destination.
+    /// # Safety
+    /// The caller must ensure that the given index is valid, and that the entry routes the input to a vector with a
+    /// handler installed if it is unmasked.
+    pub unsafe fn write_redirection_entry(&self, index: u8, entry: RedirectionEntry) {
+        let val = u64::from_ne_bytes(entry.into_bytes());
+        // SAFETY: Both halves of the entry are valid registers as guaranteed by the caller.
+        unsafe {
+            self.write_register(0x11 + (index * 2), (val >> 32) as u32);
+            self.write_register(0x10 + (index * 2), val as u32);
+        }
+    }
 }
 
 /// Represents the ID register of the IOAPIC.
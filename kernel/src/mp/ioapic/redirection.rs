@@ -6,42 +6,44 @@ use modular_bitfield::prelude::*;
 #[bitfield(bytes = 8)]
 pub struct RedirectionEntry {
     /// The vector number of the interrupt being sent
-    interrupt_vector: B8,
+    pub interrupt_vector: B8,
     /// The delivery mode of the interrupt
     #[bits = 3]
-    delivery_mode: DeliveryMode,
+    pub delivery_mode: DeliveryMode,
     /// This field determines the interpretation of the Destination field.
     /// When DESTMOD=0 (physical mode), a destination APIC is identified by its ID.
     /// Bits 56 through 59 of the Destination field specify the 4 bit APIC ID. When DESTMOD=1 (logical
     /// mode), destinations are identified by matching on the logical destination under the control of the
     /// Destination Format Register and Logical Destination Register in each Local APIC.
-    destination_mode: bool,
+    pub destination_mode: bool,
     /// The Delivery Status bit contains the current status of the
     /// delivery of this interrupt. Delivery Status is read-only and writes to this bit (as part of a 32 bit
     /// word) do not effect this bit.
-    delivery_status: bool,
+    pub delivery_status: bool,
     /// This bit specifies the polarity of the interrupt signal. 0=High active, 1=Low active.
-    polarity: bool,
+    #[bits = 1]
+    pub polarity: Polarity,
     /// This bit is used for level triggered interrupts. Its meaning is undefined for
     /// edge triggered interrupts. For level triggered interrupts, this bit is set to 1 when local APIC(s)
     /// accept the level interrupt sent by the IOAPIC. The Remote IRR bit is set to 0 when an EOI
     /// message with a matching interrupt vector is received from a local APIC.
-    remote_irr: bool,
+    pub remote_irr: bool,
     /// The trigger mode field indicates the type of signal on the interrupt pin that
     ///triggers an interrupt. 1=Level sensitive, 0=Edge sensitive.
-    trigger_mode: bool,
+    #[bits = 1]
+    pub trigger_mode: TriggerMode,
     /// When this bit is 1, the interrupt signal is masked. Edge-sensitive
     /// interrupts signaled on a masked interrupt pin are ignored (i.e., not delivered or held pending).
     /// Level-asserts or negates occurring on a masked level-sensitive pin are also ignored and have no
     /// side effects.
-    mask: bool,
+    pub mask: bool,
     #[skip]
     __: B39,
     /// The Destination Mode of this entry is Physical Mode (bit 11=0), bits
     /// [59:56] contain an APIC ID. If Logical Mode is selected (bit 11=1), the Destination Field
     /// potentially defines a set of processors. Bits [63:56] of the Destination Field specify the logical
     /// destination address.
-    destination: B8,
+    pub destination: B8,
 }
 
 /// Delivery types of IPIs
@@ -84,6 +86,24 @@ pub enum DeliveryMode {
     _Invalid2 = 0b110,
 }
 
+/// The polarity of an interrupt signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Specifier)]
+pub enum Polarity {
+    /// The interrupt is asserted while the signal is high.
+    ActiveHigh = 0,
+    /// The interrupt is asserted while the signal is low.
+    ActiveLow = 1,
+}
+
+/// The kind of signal that triggers an interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Specifier)]
+pub enum TriggerMode {
+    /// The interrupt is raised on the rising (or falling, if active low) edge of the signal.
+    Edge = 0,
+    /// The interrupt is raised as long as the signal is asserted, until the interrupt is acknowledged.
+    Level = 1,
+}
+
 impl Debug for RedirectionEntry {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("RedirectionEntry")
@@ -0,0 +1,328 @@
+//! Routing of legacy ISA IRQs and global system interrupts (GSIs) to IDT vectors through the IOAPICs.
+//!
+//! ISA IRQs are identity mapped to GSIs unless the MADT has an interrupt source override for them, which may also change
+//! their polarity and trigger mode. Routes are programmed masked, so the caller can install a handler for the vector
+//! before calling [unmask].
+use alloc::collections::btree_map::BTreeMap;
+use cake::{
+    Mutex, Once,
+    log::{info, warn},
+};
+
+use crate::{
+    interrupts::{self, vector},
+    mp::{self, LAPIC},
+};
+
+use super::{DeliveryMode, IoApic, Polarity, RedirectionEntry, TriggerMode, for_gsi};
+
+/// The number of legacy ISA IRQs.
+pub const ISA_IRQS: u8 = 16;
+
+/// An error that occurred while routing an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RoutingError {
+    /// The ISA IRQ doesn't exist.
+    #[error("There is no ISA IRQ {0}")]
+    NoIsaIrq(u8),
+    /// None of the IOAPICs handles the GSI.
+    #[error("No IOAPIC handles GSI {0}")]
+    NoIoApic(u32),
+    /// The GSI is already routed, or reserved as an NMI source.
+    #[error("GSI {0} is already routed")]
+    AlreadyRouted(u32),
+    /// The GSI hasn't been routed.
+    #[error("GSI {0} isn't routed")]
+    NotRouted(u32),
+    /// Every vector is in use.
+    #[error("No free interrupt vector")]
+    NoFreeVector,
+}
+
+/// How an interrupt line is signalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqConfig {
+    /// The level the line is asserted at.
+    pub polarity: Polarity,
+    /// Whether the interrupt is raised by an edge or by a level of the line.
+    pub trigger: TriggerMode,
+}
+
+impl IrqConfig {
+    /// The signalling of ISA interrupts: active high and edge triggered.
+    pub const ISA: Self = IrqConfig {
+        polarity: Polarity::ActiveHigh,
+        trigger: TriggerMode::Edge,
+    };
+    /// The signalling of PCI interrupts: active low and level triggered.
+    pub const PCI: Self = IrqConfig {
+        polarity: Polarity::ActiveLow,
+        trigger: TriggerMode::Level,
+    };
+
+    /// Decodes the MPS INTI flags of a MADT entry, using `bus_default` where they say the bus default applies.
+    pub fn from_inti_flags(flags: u16, bus_default: Self) -> Self {
+        IrqConfig {
+            polarity: match flags & 0b11 {
+                0b01 => Polarity::ActiveHigh,
+                0b11 => Polarity::ActiveLow,
+                _ => bus_default.polarity,
+            },
+            trigger: match (flags >> 2) & 0b11 {
+                0b01 => TriggerMode::Edge,
+                0b11 => TriggerMode::Level,
+                _ => bus_default.trigger,
+            },
+        }
+    }
+}
+
+/// An IOAPIC input routed to a vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRoute {
+    /// The global system interrupt.
+    pub gsi: u32,
+    /// The vector the interrupt is delivered with.
+    pub vector: u8,
+    /// How the line is signalled.
+    pub config: IrqConfig,
+    /// The APIC ID of the core the interrupt is delivered to.
+    pub destination: u8,
+}
+
+#[derive(Debug)]
+struct Route {
+    route: IrqRoute,
+    /// Whether the vector was allocated by [route_gsi] and is freed with the route.
+    owns_vector: bool,
+}
+
+/// The GSI and signalling of every ISA IRQ, after applying the interrupt source overrides.
+static ISA: Once<[(u32, IrqConfig); ISA_IRQS as usize]> = Once::new();
+/// The routed GSIs.
+static ROUTES: Mutex<BTreeMap<u32, Route>> = Mutex::new(BTreeMap::new());
+
+/// Applies the interrupt source overrides `(irq, gsi, flags)` of the ISA bus, and programs the NMI sources
+/// `(gsi, flags)` to deliver NMIs to the current core.
+pub(super) fn init(overrides: &[(u8, u32, u16)], nmi_sources: &[(u32, u16)]) {
+    let mut isa = core::array::from_fn(|irq| (irq as u32, IrqConfig::ISA));
+    for &(irq, gsi, flags) in overrides {
+        let Some(slot) = isa.get_mut(irq as usize) else {
+            warn!("Ignoring interrupt source override for ISA IRQ {}", irq);
+            continue;
+        };
+        *slot = (gsi, IrqConfig::from_inti_flags(flags, IrqConfig::ISA));
+        info!("ISA IRQ {} is GSI {} ({:?})", irq, gsi, slot.1);
+    }
+    ISA.call_once(|| isa);
+
+    for &(gsi, flags) in nmi_sources {
+        let Some(ioapic) = for_gsi(gsi) else {
+            warn!("No IOAPIC handles NMI source GSI {}", gsi);
+            continue;
+        };
+        let config = IrqConfig::from_inti_flags(flags, IrqConfig::ISA);
+        let mut entry = RedirectionEntry::new();
+        entry.set_delivery_mode(DeliveryMode::Nmi);
+        entry.set_polarity(config.polarity);
+        // NMIs are always edge triggered, whatever the flags say.
+        entry.set_trigger_mode(TriggerMode::Edge);
+        entry.set_destination(mp::current_core_id() as u8);
+        // SAFETY: The input belongs to this IOAPIC, and NMIs are handled by the NMI exception handler.
+        unsafe { ioapic.write_redirection_entry(index_of(ioapic, gsi), entry) };
+        info!("GSI {} delivers NMIs", gsi);
+    }
+}
+
+/// Returns the GSI and signalling of the ISA IRQ `irq`, after applying the interrupt source overrides.
+pub fn isa_irq(irq: u8) -> Result<(u32, IrqConfig), RoutingError> {
+    ISA.get()
+        .and_then(|isa| isa.get(irq as usize))
+        .copied()
+        .ok_or(RoutingError::NoIsaIrq(irq))
+}
+
+/// Routes the ISA IRQ `irq` to a newly allocated vector on the core with the APIC ID `destination`. The input stays
+/// masked until [unmask] is called with the returned GSI.
+pub fn route_isa(irq: u8, destination: u8) -> Result<IrqRoute, RoutingError> {
+    let (gsi, config) = isa_irq(irq)?;
+    route_gsi(gsi, config, destination)
+}
+
+/// Routes `gsi` to a newly allocated vector on the core with the APIC ID `destination`. The input stays masked until
+/// [unmask] is called. The vector is freed again by [unroute].
+pub fn route_gsi(gsi: u32, config: IrqConfig, destination: u8) -> Result<IrqRoute, RoutingError> {
+    let vector = vector::allocate().ok_or(RoutingError::NoFreeVector)?;
+    program(gsi, vector, config, destination, true).inspect_err(|_| vector::free(vector))
+}
+
+/// Routes `gsi` to `vector`, which is owned by the caller, on the core with the APIC ID `destination`. Several
+/// level-triggered GSIs may share a vector. The input stays masked until [unmask] is called.
+pub fn route_gsi_to(
+    gsi: u32,
+    vector: u8,
+    config: IrqConfig,
+    destination: u8,
+) -> Result<IrqRoute, RoutingError> {
+    program(gsi, vector, config, destination, false)
+}
+
+fn program(
+    gsi: u32,
+    vector: u8,
+    config: IrqConfig,
+    destination: u8,
+    owns_vector: bool,
+) -> Result<IrqRoute, RoutingError> {
+    let ioapic = for_gsi(gsi).ok_or(RoutingError::NoIoApic(gsi))?;
+    let index = index_of(ioapic, gsi);
+    interrupts::without_interrupts(|| {
+        let mut routes = ROUTES.lock();
+        // SAFETY: The input belongs to this IOAPIC.
+        let current = unsafe { ioapic.read_redirection_entry(index) };
+        if routes.contains_key(&gsi) || current.delivery_mode() == DeliveryMode::Nmi {
+            return Err(RoutingError::AlreadyRouted(gsi));
+        }
+
+        let mut entry = RedirectionEntry::new();
+        entry.set_interrupt_vector(vector);
+        entry.set_delivery_mode(DeliveryMode::Fixed);
+        entry.set_polarity(config.polarity);
+        entry.set_trigger_mode(config.trigger);
+        entry.set_destination(destination);
+        entry.set_mask(true);
+        // SAFETY: The input belongs to this IOAPIC and stays masked.
+        unsafe { ioapic.write_redirection_entry(index, entry) };
+
+        let route = IrqRoute {
+            gsi,
+            vector,
+            config,
+            destination,
+        };
+        routes.insert(gsi, Route { route, owns_vector });
+        Ok(route)
+    })
+}
+
+/// Masks `gsi` and removes its route, freeing its vector if it was allocated by [route_gsi].
+pub fn unroute(gsi: u32) -> Result<IrqRoute, RoutingError> {
+    interrupts::without_interrupts(|| {
+        let mut routes = ROUTES.lock();
+        let route = routes.remove(&gsi).ok_or(RoutingError::NotRouted(gsi))?;
+        set_mask(gsi, true);
+        if route.owns_vector {
+            vector::free(route.route.vector);
+        }
+        Ok(route.route)
+    })
+}
+
+/// Returns the route of `gsi`, if it is routed.
+pub fn route(gsi: u32) -> Option<IrqRoute> {
+    interrupts::without_interrupts(|| ROUTES.lock().get(&gsi).map(|route| route.route))
+}
+
+/// Stops `gsi` from raising interrupts.
+pub fn mask(gsi: u32) -> Result<(), RoutingError> {
+    update_mask(gsi, true)
+}
+
+/// Lets the routed `gsi` raise interrupts.
+pub fn unmask(gsi: u32) -> Result<(), RoutingError> {
+    update_mask(gsi, false)
+}
+
+fn update_mask(gsi: u32, masked: bool) -> Result<(), RoutingError> {
+    interrupts::without_interrupts(|| {
+        let routes = ROUTES.lock();
+        if !routes.contains_key(&gsi) {
+            return Err(RoutingError::NotRouted(gsi));
+        }
+        set_mask(gsi, masked);
+        Ok(())
+    })
+}
+
+/// Updates the mask bit of the routed `gsi`. The caller must hold the [ROUTES] lock.
+fn set_mask(gsi: u32, masked: bool) {
+    let ioapic = for_gsi(gsi).expect("Routed GSI without IOAPIC");
+    let index = index_of(ioapic, gsi);
+    // SAFETY: The input belongs to this IOAPIC, and its route has a vector the owner installed a handler for.
+    unsafe {
+        let mut entry = ioapic.read_redirection_entry(index);
+        entry.set_mask(masked);
+        ioapic.write_redirection_entry(index, entry);
+    }
+}
+
+/// Signals the end of a routed interrupt. For level-triggered inputs, the LAPIC broadcasts the EOI to the IOAPICs, which
+/// lets the input raise the interrupt again.
+///
+/// # Safety
+/// Must be called exactly once at the end of the handler of a routed interrupt.
+pub unsafe fn eoi() {
+    // SAFETY: Guaranteed by the caller.
+    unsafe { LAPIC.eoi() };
+}
+
+fn index_of(ioapic: &IoApic, gsi: u32) -> u8 {
+    (gsi - ioapic.gsi_base()) as u8
+}
+
+#[kproc::test("ISA IRQ routing")]
+fn isa_routing() {
+    use ::acpi::sdt::madt::{Madt, MadtEntry};
+
+    use crate::acpi;
+
+    // The PIT, which QEMU overrides to GSI 2.
+    const IRQ: u8 = 0;
+
+    let madt = acpi::get_table::<Madt>().expect("Failed to get MADT");
+    let mut expected: [_; ISA_IRQS as usize] =
+        core::array::from_fn(|irq| (irq as u32, IrqConfig::ISA));
+    for entry in madt.table_pin().entries() {
+        if let MadtEntry::InterruptSourceOverride(entry) = entry
+            && entry.bus == 0
+        {
+            let (gsi, flags) = (entry.global_system_interrupt, entry.flags);
+            expected[entry.irq as usize] = (gsi, IrqConfig::from_inti_flags(flags, IrqConfig::ISA));
+        }
+    }
+    for irq in 0..ISA_IRQS {
+        assert_eq!(isa_irq(irq), Ok(expected[irq as usize]), "ISA IRQ {irq}");
+    }
+    assert_eq!(isa_irq(ISA_IRQS), Err(RoutingError::NoIsaIrq(ISA_IRQS)));
+    assert_eq!(
+        route_isa(ISA_IRQS, 0),
+        Err(RoutingError::NoIsaIrq(ISA_IRQS))
+    );
+
+    let destination = mp::current_core_id() as u8;
+    let (gsi, config) = expected[IRQ as usize];
+    let route = route_isa(IRQ, destination).unwrap();
+    assert_eq!(
+        (route.gsi, route.config, route.destination),
+        (gsi, config, destination)
+    );
+    assert_eq!(self::route(gsi), Some(route));
+    let ioapic = for_gsi(gsi).unwrap();
+    // SAFETY: The input belongs to this IOAPIC.
+    let entry = unsafe { ioapic.read_redirection_entry(index_of(ioapic, gsi)) };
+    assert_eq!(entry.interrupt_vector(), route.vector);
+    assert_eq!(entry.delivery_mode(), DeliveryMode::Fixed);
+    assert_eq!(entry.polarity(), config.polarity);
+    assert_eq!(entry.trigger_mode(), config.trigger);
+    assert_eq!(entry.destination(), destination);
+    assert!(entry.mask());
+    assert_eq!(
+        route_isa(IRQ, destination),
+        Err(RoutingError::AlreadyRouted(gsi))
+    );
+
+    assert_eq!(unroute(gsi), Ok(route));
+    assert_eq!(self::route(gsi), None);
+    assert_eq!(unroute(gsi), Err(RoutingError::NotRouted(gsi)));
+    assert_eq!(unmask(gsi), Err(RoutingError::NotRouted(gsi)));
+}
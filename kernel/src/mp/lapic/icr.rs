@@ -163,9 +163,16 @@ impl<'a> InterruptCommandRegister<'a> {
     /// Sends an IPI to the specified destination with the given interrupt vector.
     #[must_use = "The returned PossiblyPending should be used to check/wait for IPI delivery"]
     pub fn send(&self, dest: IPIDestination, vector: KernelInterrupt) -> PossiblyPending<'_> {
+        self.send_vector(dest, vector as u8)
+    }
+
+    /// Sends an IPI to the specified destination with an arbitrary interrupt vector, such as one with a dynamically
+    /// registered handler.
+    #[must_use = "The returned PossiblyPending should be used to check/wait for IPI delivery"]
+    pub fn send_vector(&self, dest: IPIDestination, vector: u8) -> PossiblyPending<'_> {
         let mut icr = InterruptCommandRegisterValue(0);
 
-        icr.set_vector(vector as u8);
+        icr.set_vector(vector);
         icr.set_delivery_mode(DeliverMode::Fixed);
         icr.set_destination_mode(false); // Physical mode
         icr.set_level(true); // Assert
@@ -9,11 +9,7 @@ use crate::{
     declare_module,
     gdt::LGDT,
     interrupts::{self, KernelInterrupt, hardware},
-    mp::{
-        ioapic::IoApic,
-        lapic::{LAPIC_BASE_MSR, Lapic},
-        mp_setup::{dispatch_all, dispatch_others, trampoline::core_wait},
-    },
+    mp::lapic::{LAPIC_BASE_MSR, Lapic},
 };
 
 pub mod ioapic;
@@ -24,7 +20,8 @@ mod core_local;
 mod mp_setup;
 
 pub use mp_setup::{
-    CoreContext, MODULE as PREINIT_MODULE, cores, dispatch_to, is_initialized as has_init_mp,
+    CoreContext, MODULE as PREINIT_MODULE, cores, current_core_index, dispatch_all,
+    dispatch_others, dispatch_to, is_initialized as has_init_mp, trampoline::core_wait,
 };
 
 pub use core_local::{CloneBootstrap, ConstructMethod, Constructor, CoreLocal};
@@ -34,20 +31,11 @@ pub use req_data::{ApplicationCore, ApplicationCores};
 /// The local APIC for the current core.
 pub static LAPIC: Lapic = Lapic::new();
 
-/// The IO APIC for the current system.
-pub static IOAPIC: IoApic = IoApic::new();
-
 fn init() -> Result<(), Infallible> {
     LAPIC.init();
-    IOAPIC.init();
-    info!("IO APIC Version: {:?}", IOAPIC.version());
-    let version = IOAPIC.version();
-    info!(
-        "Max Redirection Entries: {}",
-        version.max_redirection_entries()
-    );
-
-    // Disable the PICs
+    ioapic::init();
+
+    // Disable the PICs, device interrupts are routed through the IOAPICs instead.
     unsafe {
         hardware::disable();
     }
@@ -62,6 +50,7 @@ fn load_idt() {
         LGDT.load();
         crate::interrupts::IDT.load();
     }
+    crate::context::fpu::init_core();
 }
 
 fn apic_init() {
@@ -46,6 +46,15 @@ pub fn cores() -> &'static BTreeMap<u32, &'static RwLock<CoreContext>> {
     CORES.wait()
 }
 
+/// Returns the index of the current core: 0 for the bootstrap core, then the application cores by APIC ID, as
+/// [CoreLocal](crate::mp::CoreLocal) orders them. Unlike APIC IDs, indices have no gaps.
+pub fn current_core_index() -> usize {
+    match crate::mp::current_core_id() {
+        0 => 0,
+        id => 1 + cores().range(..id as u32).count(),
+    }
+}
+
 /// Dispatches a function to a specific CPU by its APIC ID.
 pub fn dispatch_to(cpu_id: u32, f: fn() -> ()) -> Result<(), &'static str> {
     let cores = cores();
@@ -0,0 +1,151 @@
+//! The Address Resolution Protocol, mapping IPv4 addresses to MAC addresses on the local network.
+//!
+//! Packets for a station whose MAC address isn't known yet are held back while a request is broadcast, and sent once
+//! the reply arrives.
+use core::net::Ipv4Addr;
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+
+use super::{
+    Interface, NetError,
+    ethernet::{MacAddress, ether_type},
+};
+
+/// The size of a packet for IPv4 over Ethernet.
+const PACKET_SIZE: usize = 28;
+/// The hardware type of Ethernet.
+const HTYPE_ETHERNET: u16 = 1;
+/// The operation of requests.
+const OP_REQUEST: u16 = 1;
+/// The operation of replies.
+const OP_REPLY: u16 = 2;
+/// The most packets held back while waiting for replies. The oldest are dropped first.
+const MAX_PENDING: usize = 16;
+
+/// The MAC addresses learned by an interface and the packets waiting for one.
+#[derive(Debug, Default)]
+pub struct ArpCache {
+    entries: BTreeMap<Ipv4Addr, MacAddress>,
+    pending: Vec<(Ipv4Addr, Vec<u8>)>,
+}
+
+impl ArpCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        ArpCache::default()
+    }
+
+    /// Returns the MAC address of `address`, if it is known.
+    pub fn lookup(&self, address: Ipv4Addr) -> Option<MacAddress> {
+        self.entries.get(&address).copied()
+    }
+}
+
+/// An ARP packet for IPv4 over Ethernet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ArpPacket {
+    operation: u16,
+    sender_mac: MacAddress,
+    sender_ip: Ipv4Addr,
+    target_mac: MacAddress,
+    target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < PACKET_SIZE
+            || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+            || u16::from_be_bytes([packet[2], packet[3]]) != ether_type::IPV4
+            || packet[4] != 6
+            || packet[5] != 4
+        {
+            return None;
+        }
+        Some(ArpPacket {
+            operation: u16::from_be_bytes([packet[6], packet[7]]),
+            sender_mac: MacAddress(packet[8..14].try_into().unwrap()),
+            sender_ip: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[14..18]).unwrap()),
+            target_mac: MacAddress(packet[18..24].try_into().unwrap()),
+            target_ip: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[24..28]).unwrap()),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; PACKET_SIZE] {
+        let mut packet = [0; PACKET_SIZE];
+        packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&ether_type::IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac.0);
+        packet[14..18].copy_from_slice(&self.sender_ip.octets());
+        packet[18..24].copy_from_slice(&self.target_mac.0);
+        packet[24..28].copy_from_slice(&self.target_ip.octets());
+        packet
+    }
+}
+
+/// Sends the IPv4 `packet` to the station `next_hop` through `interface`, resolving its MAC address first if needed.
+pub(super) fn send(
+    interface: &Interface,
+    next_hop: Ipv4Addr,
+    packet: Vec<u8>,
+) -> Result<(), NetError> {
+    let config = interface.config;
+    if next_hop.is_broadcast() || next_hop == config.broadcast() {
+        return interface.transmit(MacAddress::BROADCAST, ether_type::IPV4, &packet);
+    }
+    let mut cache = interface.arp.lock();
+    if let Some(mac) = cache.lookup(next_hop) {
+        drop(cache);
+        return interface.transmit(mac, ether_type::IPV4, &packet);
+    }
+    if cache.pending.len() == MAX_PENDING {
+        cache.pending.remove(0);
+    }
+    cache.pending.push((next_hop, packet));
+    drop(cache);
+    let request = ArpPacket {
+        operation: OP_REQUEST,
+        sender_mac: interface.device.mac_address(),
+        sender_ip: config.address,
+        target_mac: MacAddress::default(),
+        target_ip: next_hop,
+    };
+    interface.transmit(MacAddress::BROADCAST, ether_type::ARP, &request.to_bytes())
+}
+
+/// Handles an ARP packet received by `interface`: learns the address of the sender and answers requests for the
+/// address of the interface.
+pub(super) fn receive(interface: &Interface, packet: &[u8]) {
+    let Some(packet) = ArpPacket::parse(packet) else {
+        return;
+    };
+    let config = interface.config;
+    let for_us = packet.target_ip == config.address;
+    let mut cache = interface.arp.lock();
+    // Only stations talking to this one are learned, others are only updated.
+    if !for_us && !cache.entries.contains_key(&packet.sender_ip) {
+        return;
+    }
+    cache.entries.insert(packet.sender_ip, packet.sender_mac);
+    let (ready, pending) = core::mem::take(&mut cache.pending)
+        .into_iter()
+        .partition::<Vec<_>, _>(|(next_hop, _)| *next_hop == packet.sender_ip);
+    cache.pending = pending;
+    drop(cache);
+
+    for (_, queued) in ready {
+        let _ = interface.transmit(packet.sender_mac, ether_type::IPV4, &queued);
+    }
+    if for_us && packet.operation == OP_REQUEST {
+        let reply = ArpPacket {
+            operation: OP_REPLY,
+            sender_mac: interface.device.mac_address(),
+            sender_ip: config.address,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        let _ = interface.transmit(packet.sender_mac, ether_type::ARP, &reply.to_bytes());
+    }
+}
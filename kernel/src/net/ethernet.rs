@@ -0,0 +1,83 @@
+//! Ethernet II framing.
+use core::fmt;
+
+use alloc::vec::Vec;
+
+/// The size of the header of a frame.
+pub const HEADER_SIZE: usize = 14;
+/// The smallest frame, without its checksum. Shorter frames are padded.
+const MIN_FRAME_SIZE: usize = 60;
+
+/// The protocols carried by frames.
+pub mod ether_type {
+    /// IPv4.
+    pub const IPV4: u16 = 0x0800;
+    /// The Address Resolution Protocol.
+    pub const ARP: u16 = 0x0806;
+}
+
+/// A MAC address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// The address every station receives frames for.
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    /// Returns `true` if frames sent to the address are received by a group of stations, including the broadcast
+    /// address.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// A received frame.
+#[derive(Debug, Clone, Copy)]
+pub struct EthernetFrame<'a> {
+    /// The station the frame is for.
+    pub destination: MacAddress,
+    /// The station that sent the frame.
+    pub source: MacAddress,
+    /// The protocol of the payload, see [ether_type].
+    pub ether_type: u16,
+    /// The payload, which may include padding.
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    /// Parses `frame`, returning `None` if it is too short.
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < HEADER_SIZE {
+            return None;
+        }
+        Some(EthernetFrame {
+            destination: MacAddress(frame[0..6].try_into().unwrap()),
+            source: MacAddress(frame[6..12].try_into().unwrap()),
+            ether_type: u16::from_be_bytes([frame[12], frame[13]]),
+            payload: &frame[HEADER_SIZE..],
+        })
+    }
+}
+
+/// Builds a frame carrying `payload`, padded to the smallest frame size.
+pub fn build(
+    destination: MacAddress,
+    source: MacAddress,
+    ether_type: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((HEADER_SIZE + payload.len()).max(MIN_FRAME_SIZE));
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&source.0);
+    frame.extend_from_slice(&ether_type.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(frame.len().max(MIN_FRAME_SIZE), 0);
+    frame
+}
@@ -0,0 +1,93 @@
+//! ICMP echo: answering pings and sending them.
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+
+use crate::{
+    interrupts::InterruptMutex,
+    time::{Duration, Instant},
+};
+
+use super::{
+    NetError, WaitQueue,
+    ipv4::{self, Ipv4Packet, checksum, protocol},
+    route,
+};
+
+/// The type of echo replies.
+const ECHO_REPLY: u8 = 0;
+/// The type of echo requests.
+const ECHO_REQUEST: u8 = 8;
+/// The identifier of the echo requests sent by [ping].
+const ECHO_ID: u16 = 0x6b72;
+/// The payload of the echo requests sent by [ping].
+const ECHO_PAYLOAD: &[u8] = b"kernel ping";
+
+/// The sequence number of the next echo request.
+static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+/// The sequence numbers of the echo requests sent, and whether they were answered.
+static PENDING: InterruptMutex<BTreeMap<u16, bool>> = InterruptMutex::new(BTreeMap::new());
+/// The threads waiting for echo replies.
+static WAITERS: WaitQueue = WaitQueue::new();
+
+/// Builds an echo message of type `kind`.
+fn echo(kind: u8, id: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + payload.len());
+    message.extend_from_slice(&[kind, 0, 0, 0]);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(payload);
+    let sum = checksum(&[&message]);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
+
+/// Sends an echo request to `destination` and waits up to `timeout` for the reply. Returns the round-trip time.
+pub fn ping(destination: Ipv4Addr, timeout: Duration) -> Result<Duration, NetError> {
+    let route = route(destination)?;
+    let sequence = NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    PENDING.lock().insert(sequence, false);
+    let start = Instant::now();
+    let request = echo(ECHO_REQUEST, ECHO_ID, sequence, ECHO_PAYLOAD);
+    if let Err(err) = ipv4::send(&route, destination, protocol::ICMP, &request) {
+        PENDING.lock().remove(&sequence);
+        return Err(err);
+    }
+    let replied = WAITERS.wait(Some(start + timeout), || {
+        PENDING
+            .lock()
+            .get(&sequence)
+            .copied()
+            .filter(|&replied| replied)
+    });
+    PENDING.lock().remove(&sequence);
+    replied.map(|_| start.elapsed()).ok_or(NetError::TimedOut)
+}
+
+/// Handles an ICMP message.
+pub(super) fn receive(packet: &Ipv4Packet) {
+    let message = packet.payload;
+    if message.len() < 8 || checksum(&[message]) != 0 {
+        return;
+    }
+    let id = u16::from_be_bytes([message[4], message[5]]);
+    let sequence = u16::from_be_bytes([message[6], message[7]]);
+    match message[0] {
+        ECHO_REQUEST => {
+            let reply = echo(ECHO_REPLY, id, sequence, &message[8..]);
+            if let Ok(route) = route(packet.source) {
+                let _ = ipv4::send(&route, packet.source, protocol::ICMP, &reply);
+            }
+        }
+        ECHO_REPLY if id == ECHO_ID => {
+            if let Some(replied) = PENDING.lock().get_mut(&sequence) {
+                *replied = true;
+            }
+            WAITERS.wake_all();
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,176 @@
+//! IPv4 packets.
+//!
+//! Fragmented packets and IP options aren't supported: received fragments are dropped and sent packets must fit in a
+//! single frame.
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use super::{Interface, NetError, Route, arp, icmp, udp};
+
+/// The size of a header without options.
+pub const HEADER_SIZE: usize = 20;
+/// The hop limit of sent packets.
+const DEFAULT_TTL: u8 = 64;
+/// The "more fragments" flag and the fragment offset, in the flags and fragment offset field.
+const FRAGMENT_MASK: u16 = 0x3fff;
+
+/// The protocols carried by packets.
+pub mod protocol {
+    /// The Internet Control Message Protocol.
+    pub const ICMP: u8 = 1;
+    /// The User Datagram Protocol.
+    pub const UDP: u8 = 17;
+}
+
+/// The identification of the next packet sent.
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Computes the Internet checksum of the concatenation of `parts`, all of which but the last must have an even length.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        let mut words = part.chunks_exact(2);
+        for word in &mut words {
+            sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+        }
+        if let [last] = words.remainder() {
+            sum += (*last as u32) << 8;
+        }
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A received packet.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Packet<'a> {
+    /// The address of the sender.
+    pub source: Ipv4Addr,
+    /// The address the packet is for.
+    pub destination: Ipv4Addr,
+    /// The protocol of the payload, see [protocol].
+    pub protocol: u8,
+    /// The hops left.
+    pub ttl: u8,
+    /// The payload, without the padding of the frame.
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Parses `packet`, returning `None` if it is malformed, its header checksum is wrong or it is a fragment.
+    pub fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+            return None;
+        }
+        if checksum(&[&packet[..header_len]]) != 0 {
+            return None;
+        }
+        if u16::from_be_bytes([packet[6], packet[7]]) & FRAGMENT_MASK != 0 {
+            return None;
+        }
+        Some(Ipv4Packet {
+            source: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()),
+            destination: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap()),
+            protocol: packet[9],
+            ttl: packet[8],
+            payload: &packet[header_len..total_len],
+        })
+    }
+}
+
+/// Builds a packet carrying `payload` from `source` to `destination`.
+pub fn build(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_len = (HEADER_SIZE + payload.len()) as u16;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Don't fragment.
+    packet.extend_from_slice(&0x4000u16.to_be_bytes());
+    packet.extend_from_slice(&[DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    let header_checksum = checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Sends `payload` to `destination` along `route`. Packets for the address of the interface are delivered locally.
+pub fn send(
+    route: &Route,
+    destination: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let interface = &route.interface;
+    if HEADER_SIZE + payload.len() > interface.device.mtu() {
+        return Err(NetError::MessageTooLong(payload.len()));
+    }
+    let packet = build(route.source, destination, protocol, payload);
+    if destination == interface.config.address {
+        receive(interface, &packet);
+        return Ok(());
+    }
+    if !interface.device.link_up() {
+        return Err(NetError::LinkDown);
+    }
+    arp::send(interface, route.next_hop, packet)
+}
+
+/// Handles a packet received by `interface`.
+pub(super) fn receive(interface: &Interface, packet: &[u8]) {
+    let Some(packet) = Ipv4Packet::parse(packet) else {
+        return;
+    };
+    let config = interface.config;
+    if packet.destination != config.address
+        && !packet.destination.is_broadcast()
+        && packet.destination != config.broadcast()
+    {
+        return;
+    }
+    match packet.protocol {
+        protocol::ICMP => icmp::receive(&packet),
+        protocol::UDP => udp::receive(&packet),
+        _ => {}
+    }
+}
+
+#[kproc::test("IPv4 checksum and parsing")]
+fn ipv4_checksum_parsing() {
+    // A header with a known checksum of 0xb861.
+    let header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(checksum(&[&header]), 0xb861);
+    assert_eq!(checksum(&[&header[..4], &header[4..]]), 0xb861);
+    // Odd lengths are padded with a zero byte.
+    assert_eq!(checksum(&[&[0x12, 0x34, 0x56]]), !0x6834);
+
+    let source = Ipv4Addr::new(10, 0, 2, 15);
+    let destination = Ipv4Addr::new(10, 0, 2, 2);
+    let mut packet = build(source, destination, protocol::UDP, b"payload");
+    // Frames may pad the packet.
+    packet.extend_from_slice(&[0; 4]);
+    let parsed = Ipv4Packet::parse(&packet).unwrap();
+    assert_eq!((parsed.source, parsed.destination), (source, destination));
+    assert_eq!((parsed.protocol, parsed.ttl), (protocol::UDP, DEFAULT_TTL));
+    assert_eq!(parsed.payload, b"payload");
+    packet[12] ^= 1;
+    assert!(Ipv4Packet::parse(&packet).is_none());
+}
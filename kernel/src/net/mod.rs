@@ -0,0 +1,328 @@
+//! A minimal IPv4 network stack.
+//!
+//! Network drivers expose their devices through [NetDevice] and register them with [register], which makes them an
+//! [Interface] with an IPv4 configuration. Received frames are handed back to the stack with [receive], which answers
+//! ARP requests and ICMP echo requests and delivers UDP datagrams to the [udp::UdpSocket]s bound to their port.
+//!
+//! There is no DHCP client: interfaces are configured statically, by default with the addresses QEMU's user networking
+//! hands out.
+use core::{
+    fmt::Debug,
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use cake::log::info;
+
+use crate::{
+    interrupts::InterruptMutex,
+    proc::{self, ThreadID},
+    time::{self, Duration, Instant},
+};
+
+pub mod arp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod udp;
+
+use ethernet::{EthernetFrame, MacAddress, ether_type};
+
+/// How often threads waiting for the network poll the devices, in case they don't raise interrupts.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An error reported by the network stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum NetError {
+    /// No interface reaches the address.
+    #[error("No route to {0}")]
+    NoRoute(Ipv4Addr),
+    /// A socket is already bound to the port.
+    #[error("Port {0} is already in use")]
+    AddressInUse(u16),
+    /// Every ephemeral port is in use.
+    #[error("No free port")]
+    NoFreePort,
+    /// The message doesn't fit in a single packet.
+    #[error("Message of {0} bytes is too long")]
+    MessageTooLong(usize),
+    /// Nothing was received before the timeout.
+    #[error("Timed out")]
+    TimedOut,
+    /// The link of the device is down.
+    #[error("Link is down")]
+    LinkDown,
+    /// The device failed to send the frame.
+    #[error("Device error")]
+    Device,
+}
+
+/// A device sending and receiving Ethernet frames.
+pub trait NetDevice: Debug + Send + Sync {
+    /// Returns the name the device is registered under.
+    fn name(&self) -> &str;
+
+    /// Returns the MAC address of the device.
+    fn mac_address(&self) -> MacAddress;
+
+    /// Returns the largest payload of a frame in bytes.
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    /// Returns `true` if the device is connected to the network.
+    fn link_up(&self) -> bool;
+
+    /// Sends `frame`, which starts with the Ethernet header and has no checksum.
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Hands the frames received so far to the stack, for devices whose interrupts can't be relied on.
+    fn poll(&self);
+}
+
+/// The IPv4 configuration of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    /// The address of the interface.
+    pub address: Ipv4Addr,
+    /// The mask of the subnet reachable directly.
+    pub netmask: Ipv4Addr,
+    /// The router packets for other subnets are sent through.
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl InterfaceConfig {
+    /// The configuration QEMU's user networking hands out over DHCP to the first guest.
+    pub const QEMU_USER: InterfaceConfig = InterfaceConfig {
+        address: Ipv4Addr::new(10, 0, 2, 15),
+        netmask: Ipv4Addr::new(255, 255, 255, 0),
+        gateway: Some(Ipv4Addr::new(10, 0, 2, 2)),
+    };
+
+    /// Returns `true` if `address` is on the subnet of the interface.
+    pub fn is_local(&self, address: Ipv4Addr) -> bool {
+        address.to_bits() & self.netmask.to_bits()
+            == self.address.to_bits() & self.netmask.to_bits()
+    }
+
+    /// Returns the broadcast address of the subnet of the interface.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.address.to_bits() | !self.netmask.to_bits())
+    }
+}
+
+/// A network device registered with the stack and its configuration.
+#[derive(Debug)]
+pub struct Interface {
+    device: Arc<dyn NetDevice>,
+    config: InterfaceConfig,
+    arp: InterruptMutex<arp::ArpCache>,
+}
+
+impl Interface {
+    /// Returns the device of the interface.
+    pub fn device(&self) -> &Arc<dyn NetDevice> {
+        &self.device
+    }
+
+    /// Returns the IPv4 configuration of the interface.
+    pub fn config(&self) -> InterfaceConfig {
+        self.config
+    }
+
+    /// Handles a frame received by the device.
+    fn receive(&self, frame: &[u8]) {
+        let Some(frame) = EthernetFrame::parse(frame) else {
+            return;
+        };
+        if frame.destination != self.device.mac_address() && !frame.destination.is_multicast() {
+            return;
+        }
+        match frame.ether_type {
+            ether_type::ARP => arp::receive(self, frame.payload),
+            ether_type::IPV4 => ipv4::receive(self, frame.payload),
+            _ => {}
+        }
+    }
+
+    /// Sends `payload` in a frame to `destination`.
+    fn transmit(
+        &self,
+        destination: MacAddress,
+        ether_type: u16,
+        payload: &[u8],
+    ) -> Result<(), NetError> {
+        let frame = ethernet::build(destination, self.device.mac_address(), ether_type, payload);
+        self.device.transmit(&frame)
+    }
+}
+
+/// The registered interfaces.
+static INTERFACES: InterruptMutex<Vec<Arc<Interface>>> = InterruptMutex::new(Vec::new());
+
+/// Registers `device` as an interface configured with `config`.
+pub fn register(device: Arc<dyn NetDevice>, config: InterfaceConfig) -> Arc<Interface> {
+    info!(
+        "Network interface {}: MAC {}, address {}/{}{}",
+        device.name(),
+        device.mac_address(),
+        config.address,
+        config.netmask.to_bits().count_ones(),
+        if device.link_up() { "" } else { ", link down" }
+    );
+    let interface = Arc::new(Interface {
+        device,
+        config,
+        arp: InterruptMutex::new(arp::ArpCache::new()),
+    });
+    INTERFACES.lock().push(interface.clone());
+    interface
+}
+
+/// Removes the interface of the device registered as `name`. Returns `false` if there was none.
+pub fn unregister(name: &str) -> bool {
+    let mut interfaces = INTERFACES.lock();
+    let count = interfaces.len();
+    interfaces.retain(|interface| interface.device.name() != name);
+    interfaces.len() != count
+}
+
+/// Returns the interface of the device registered as `name`.
+pub fn find(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|interface| interface.device.name() == name)
+        .cloned()
+}
+
+/// Returns every registered interface.
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+/// Hands `frame`, received by the device registered as `name`, to the stack. This may be called from interrupt
+/// handlers.
+pub fn receive(name: &str, frame: &[u8]) {
+    if let Some(interface) = find(name) {
+        interface.receive(frame);
+    }
+}
+
+/// Polls every device for received frames.
+pub fn poll() {
+    for interface in interfaces() {
+        interface.device.poll();
+    }
+}
+
+/// How a packet reaches its destination.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// The interface the packet is sent through.
+    pub interface: Arc<Interface>,
+    /// The address the packet is sent from.
+    pub source: Ipv4Addr,
+    /// The station the frame carrying the packet is sent to: the destination itself or a router.
+    pub next_hop: Ipv4Addr,
+}
+
+/// Finds the route to `destination`: through the interface on its subnet if there is one, or through the first
+/// interface with a gateway.
+pub fn route(destination: Ipv4Addr) -> Result<Route, NetError> {
+    let interfaces = INTERFACES.lock();
+    let direct = |interface: &Arc<Interface>| {
+        destination.is_broadcast()
+            || interface.config.is_local(destination)
+            || interface.config.address == destination
+    };
+    if let Some(interface) = interfaces.iter().find(|interface| direct(interface)) {
+        return Ok(Route {
+            interface: interface.clone(),
+            source: interface.config.address,
+            next_hop: destination,
+        });
+    }
+    interfaces
+        .iter()
+        .find_map(|interface| {
+            Some(Route {
+                interface: interface.clone(),
+                source: interface.config.address,
+                next_hop: interface.config.gateway?,
+            })
+        })
+        .ok_or(NetError::NoRoute(destination))
+}
+
+/// Threads waiting for something the stack receives.
+#[derive(Debug)]
+struct WaitQueue {
+    waiters: InterruptMutex<Vec<ThreadID>>,
+    /// Incremented on every wakeup, so a waiter notices the ones between its check and its registration.
+    generation: AtomicU64,
+}
+
+impl WaitQueue {
+    const fn new() -> Self {
+        WaitQueue {
+            waiters: InterruptMutex::new(Vec::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Wakes every waiting thread.
+    fn wake_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        for pid in self.waiters.lock().drain(..) {
+            proc::wake(pid);
+        }
+    }
+
+    /// Calls `check` until it returns a value, or `None` once `deadline` has passed. Between calls, the thread blocks
+    /// until woken by [WaitQueue::wake_all], or polls the devices if the scheduler isn't running.
+    fn wait<T>(
+        &self,
+        deadline: Option<Instant>,
+        mut check: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        loop {
+            let generation = self.generation.load(Ordering::SeqCst);
+            poll();
+            if let Some(value) = check() {
+                return Some(value);
+            }
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return None;
+            }
+            // Wake up periodically to poll devices without interrupts.
+            let wakeup = deadline.map_or(now + POLL_INTERVAL, |deadline| {
+                deadline.min(now + POLL_INTERVAL)
+            });
+            let mut timer = None;
+            let mut current = None;
+            let blocked = proc::block(|pid| {
+                current = Some(pid);
+                self.waiters.lock().push(pid);
+                timer = Some(time::wheel::at(wakeup, move || {
+                    proc::wake(pid);
+                }));
+                if self.generation.load(Ordering::SeqCst) != generation {
+                    proc::wake(pid);
+                }
+            });
+            if !blocked {
+                core::hint::spin_loop();
+                continue;
+            }
+            if let Some(timer) = timer {
+                timer.cancel();
+            }
+            if let Some(pid) = current {
+                self.waiters.lock().retain(|&waiter| waiter != pid);
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! UDP sockets.
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{
+    interrupts::InterruptMutex,
+    time::{Duration, Instant},
+};
+
+use super::{
+    NetError, WaitQueue,
+    ipv4::{self, Ipv4Packet, checksum, protocol},
+    route,
+};
+
+/// The size of the header of a datagram.
+const HEADER_SIZE: usize = 8;
+/// The first port handed out to sockets bound to port 0.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+/// The most datagrams queued on a socket. Datagrams arriving to a full queue are dropped.
+const QUEUE_LIMIT: usize = 64;
+
+/// A datagram waiting to be received.
+#[derive(Debug)]
+struct Datagram {
+    source: SocketAddrV4,
+    data: Vec<u8>,
+}
+
+/// The state of a bound socket.
+#[derive(Debug)]
+struct SocketState {
+    queue: InterruptMutex<VecDeque<Datagram>>,
+    waiters: WaitQueue,
+}
+
+/// The bound sockets, by port.
+static SOCKETS: InterruptMutex<BTreeMap<u16, Arc<SocketState>>> =
+    InterruptMutex::new(BTreeMap::new());
+
+/// Computes the checksum of a datagram, including the IPv4 pseudo-header.
+fn datagram_checksum(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let mut pseudo_header = [0; 12];
+    pseudo_header[0..4].copy_from_slice(&source.octets());
+    pseudo_header[4..8].copy_from_slice(&destination.octets());
+    pseudo_header[9] = protocol::UDP;
+    pseudo_header[10..12].copy_from_slice(&(datagram.len() as u16).to_be_bytes());
+    checksum(&[&pseudo_header, datagram])
+}
+
+/// A UDP socket bound to a local port.
+///
+/// The port is released when the socket is dropped.
+#[derive(Debug)]
+pub struct UdpSocket {
+    port: u16,
+    state: Arc<SocketState>,
+}
+
+impl UdpSocket {
+    /// Binds a socket to `port` on every interface, or to a free ephemeral port if `port` is 0.
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = if port == 0 {
+            EPHEMERAL_PORTS
+                .clone()
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(NetError::NoFreePort)?
+        } else if sockets.contains_key(&port) {
+            return Err(NetError::AddressInUse(port));
+        } else {
+            port
+        };
+        let state = Arc::new(SocketState {
+            queue: InterruptMutex::new(VecDeque::new()),
+            waiters: WaitQueue::new(),
+        });
+        sockets.insert(port, state.clone());
+        Ok(UdpSocket { port, state })
+    }
+
+    /// Returns the port the socket is bound to.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` in a datagram to `destination`.
+    pub fn send_to(&self, data: &[u8], destination: SocketAddrV4) -> Result<usize, NetError> {
+        let route = route(*destination.ip())?;
+        let len = HEADER_SIZE + data.len();
+        if len > u16::MAX as usize - ipv4::HEADER_SIZE {
+            return Err(NetError::MessageTooLong(data.len()));
+        }
+        let mut datagram = Vec::with_capacity(len);
+        datagram.extend_from_slice(&self.port.to_be_bytes());
+        datagram.extend_from_slice(&destination.port().to_be_bytes());
+        datagram.extend_from_slice(&(len as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        // A checksum of 0 means there is none, so it is sent as its complement.
+        let sum = match datagram_checksum(route.source, *destination.ip(), &datagram) {
+            0 => 0xffff,
+            sum => sum,
+        };
+        datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+        ipv4::send(&route, *destination.ip(), protocol::UDP, &datagram)?;
+        Ok(data.len())
+    }
+
+    /// Receives a datagram into `buf` without waiting, returning its length and its sender, or `None` if there is
+    /// none. Datagrams longer than `buf` are truncated.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, SocketAddrV4)> {
+        let datagram = self.state.queue.lock().pop_front()?;
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Some((len, datagram.source))
+    }
+
+    /// Waits for a datagram and receives it into `buf`. See [UdpSocket::try_recv_from].
+    pub fn recv_from(&self, buf: &mut [u8]) -> (usize, SocketAddrV4) {
+        self.state
+            .waiters
+            .wait(None, || self.try_recv_from(buf))
+            .expect("Waiting without a deadline returned nothing")
+    }
+
+    /// Waits up to `timeout` for a datagram and receives it into `buf`. See [UdpSocket::try_recv_from].
+    pub fn recv_from_timeout(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, SocketAddrV4), NetError> {
+        self.state
+            .waiters
+            .wait(Some(Instant::now() + timeout), || self.try_recv_from(buf))
+            .ok_or(NetError::TimedOut)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Delivers a datagram to the socket bound to its port.
+pub(super) fn receive(packet: &Ipv4Packet) {
+    let datagram = packet.payload;
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    let sum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if sum != 0 && datagram_checksum(packet.source, packet.destination, datagram) != 0 {
+        return;
+    }
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let destination_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let Some(state) = SOCKETS.lock().get(&destination_port).cloned() else {
+        return;
+    };
+    let mut queue = state.queue.lock();
+    if queue.len() == QUEUE_LIMIT {
+        return;
+    }
+    queue.push_back(Datagram {
+        source: SocketAddrV4::new(packet.source, source_port),
+        data: datagram[HEADER_SIZE..].to_vec(),
+    });
+    drop(queue);
+    state.waiters.wake_all();
+}
+
+#[kproc::test("UDP loopback")]
+fn udp_loopback() {
+    // Datagrams to the address of an interface are delivered locally, so this only needs one to be registered.
+    let Some(interface) = super::interfaces().into_iter().next() else {
+        return;
+    };
+    let address = interface.config().address;
+    let receiver = UdpSocket::bind(0).unwrap();
+    assert!(EPHEMERAL_PORTS.contains(&receiver.local_port()));
+    assert_eq!(
+        UdpSocket::bind(receiver.local_port()).unwrap_err(),
+        NetError::AddressInUse(receiver.local_port())
+    );
+    let sender = UdpSocket::bind(0).unwrap();
+    assert_ne!(sender.local_port(), receiver.local_port());
+
+    let destination = SocketAddrV4::new(address, receiver.local_port());
+    assert_eq!(sender.send_to(b"hello", destination), Ok(5));
+    assert_eq!(sender.send_to(b"truncated", destination), Ok(9));
+    let mut buf = [0; 16];
+    let (len, source) = receiver
+        .recv_from_timeout(&mut buf, Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(&buf[..len], b"hello");
+    assert_eq!(source, SocketAddrV4::new(address, sender.local_port()));
+    let mut short = [0; 4];
+    assert_eq!(
+        receiver.try_recv_from(&mut short).map(|(len, _)| len),
+        Some(4)
+    );
+    assert_eq!(&short, b"trun");
+    assert_eq!(
+        receiver.recv_from_timeout(&mut buf, Duration::from_millis(20)),
+        Err(NetError::TimedOut)
+    );
+
+    // The port is released with the socket.
+    let port = receiver.local_port();
+    drop(receiver);
+    drop(UdpSocket::bind(port).unwrap());
+}
@@ -0,0 +1,701 @@
+//! NVMe controllers.
+//!
+//! The controller is reset and enabled with an admin queue when it is probed, then identified along with its active
+//! namespaces. An I/O queue pair is created for every core, up to what the controller grants, and commands are
+//! submitted to the queue of the core issuing them. Each namespace is registered as a block device named
+//! `nvme<controller>n<namespace>`.
+//!
+//! Completion queues raise their own MSI-X interrupt, which wakes the threads waiting for their commands. Controllers
+//! without MSI-X are polled instead, as are all of them until the scheduler is running.
+use core::{
+    convert::Infallible,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use cake::log::{info, warn};
+
+use crate::{
+    block::{self, BlockDevice, BlockError},
+    declare_module,
+    interrupts::IrqReturn,
+    memory::dma::DmaBuffer,
+    mp,
+    pci::{
+        self, Capability, DriverEntry, MappedBar, PciDevice, PciDeviceId, PciDriver, PciHandle,
+        ProbeError,
+    },
+    time::{Duration, Instant},
+};
+
+mod queue;
+
+use queue::{Command, QueuePair, Status, prp_pages};
+
+/// The registers of the controller.
+mod reg {
+    /// The capabilities of the controller.
+    pub const CAP: usize = 0x00;
+    /// The version of the specification the controller implements.
+    pub const VS: usize = 0x08;
+    /// The controller configuration.
+    pub const CC: usize = 0x14;
+    /// The controller status.
+    pub const CSTS: usize = 0x1c;
+    /// The sizes of the admin queues.
+    pub const AQA: usize = 0x24;
+    /// The address of the admin submission queue.
+    pub const ASQ: usize = 0x28;
+    /// The address of the admin completion queue.
+    pub const ACQ: usize = 0x30;
+}
+
+/// Enables the controller.
+const CC_EN: u32 = 1 << 0;
+/// The size of submission queue entries, as a power of two.
+const CC_IOSQES: u32 = 6 << 16;
+/// The size of completion queue entries, as a power of two.
+const CC_IOCQES: u32 = 4 << 20;
+/// The controller is ready to process commands.
+const CSTS_RDY: u32 = 1 << 0;
+/// The controller hit a fatal error.
+const CSTS_CFS: u32 = 1 << 1;
+
+/// The admin commands issued.
+mod admin {
+    pub const CREATE_IO_SQ: u8 = 0x01;
+    pub const DELETE_IO_CQ: u8 = 0x04;
+    pub const CREATE_IO_CQ: u8 = 0x05;
+    pub const IDENTIFY: u8 = 0x06;
+    pub const SET_FEATURES: u8 = 0x09;
+}
+
+/// The I/O commands issued.
+mod io {
+    pub const FLUSH: u8 = 0x00;
+    pub const WRITE: u8 = 0x01;
+    pub const READ: u8 = 0x02;
+}
+
+/// The data structures returned by Identify.
+mod identify {
+    pub const NAMESPACE: u32 = 0x00;
+    pub const CONTROLLER: u32 = 0x01;
+    pub const ACTIVE_NAMESPACES: u32 = 0x02;
+}
+
+/// The feature setting the number of I/O queues.
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// The size of the memory pages the controller is configured for, which PRP entries describe.
+const PAGE_SIZE: usize = 4096;
+/// The size of the admin queues.
+const ADMIN_QUEUE_SIZE: u16 = 32;
+/// The size of the I/O queues, if the controller supports it.
+const IO_QUEUE_SIZE: u16 = 64;
+/// The most bytes transferred by a single command, if the controller supports it.
+const MAX_TRANSFER: usize = 128 * 1024;
+
+/// The number of controllers found, used to name their namespaces.
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+declare_module!("nvme", init);
+
+fn init() -> Result<(), Infallible> {
+    pci::register_driver(&PCI_DRIVER);
+    Ok(())
+}
+
+/// Spins until `condition` holds, for up to `timeout`. Returns `false` if it never did.
+fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if condition() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// The driver for NVMe controllers, matched by their class.
+#[derive(Debug)]
+pub struct NvmeDriver;
+
+/// A bound controller and the namespaces registered through it.
+#[derive(Debug)]
+pub struct NvmeState {
+    controller: Arc<NvmeController>,
+    namespaces: Vec<Arc<NvmeNamespace>>,
+}
+
+impl NvmeState {
+    /// Returns the controller.
+    pub fn controller(&self) -> &Arc<NvmeController> {
+        &self.controller
+    }
+}
+
+impl PciDriver for NvmeDriver {
+    const NAME: &'static str = "nvme";
+    const IDS: &'static [PciDeviceId] = &[PciDeviceId::class(0x01, 0x08).with_prog_if(0x02)];
+    type State = NvmeState;
+
+    fn probe(handle: PciHandle, _: &PciDeviceId) -> Result<NvmeState, ProbeError> {
+        let controller = Arc::new(NvmeController::new(handle)?);
+        let mut namespaces = Vec::new();
+        for id in controller.active_namespaces()? {
+            match NvmeNamespace::new(&controller, id) {
+                Ok(Some(namespace)) => {
+                    let namespace = Arc::new(namespace);
+                    block::register(namespace.clone());
+                    namespaces.push(namespace);
+                }
+                Ok(None) => {}
+                Err(err) => warn!("NVMe namespace {id}: {err}"),
+            }
+        }
+        Ok(NvmeState {
+            controller,
+            namespaces,
+        })
+    }
+
+    fn remove(state: NvmeState) {
+        for namespace in state.namespaces {
+            block::unregister(namespace.name());
+        }
+    }
+}
+
+/// The PCI driver entry of [NvmeDriver].
+pub static PCI_DRIVER: DriverEntry = DriverEntry::new::<NvmeDriver>();
+
+fn handle_irq(_: u8, cookie: usize) -> IrqReturn {
+    // SAFETY: The cookie points to a queue pair of the controller, which outlives the registration of the handler.
+    let queue = unsafe { &*(cookie as *const QueuePair) };
+    queue.reap();
+    IrqReturn::Handled
+}
+
+/// What a controller reports about itself in response to Identify.
+#[derive(Debug, Clone, Default)]
+struct ControllerIdentity {
+    serial: String,
+    model: String,
+    /// The most bytes transferred by a single command, or 0 if there is no limit.
+    max_transfer: usize,
+    namespaces: u32,
+}
+
+impl ControllerIdentity {
+    fn parse(data: &[u8; PAGE_SIZE]) -> Self {
+        let string =
+            |range: Range<usize>| String::from(String::from_utf8_lossy(&data[range]).trim());
+        // In units of the minimum page size, which is the one the controller is configured for.
+        let max_transfer = match data[77] {
+            0 => 0,
+            mdts => PAGE_SIZE << mdts.min(16),
+        };
+        ControllerIdentity {
+            serial: string(4..24),
+            model: string(24..64),
+            max_transfer,
+            namespaces: u32::from_le_bytes(data[516..520].try_into().unwrap()),
+        }
+    }
+}
+
+/// What a namespace reports about itself in response to Identify.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NamespaceIdentity {
+    sectors: u64,
+    sector_size: usize,
+    /// The bytes of metadata stored with each sector.
+    metadata_size: u16,
+}
+
+impl NamespaceIdentity {
+    fn parse(data: &[u8; PAGE_SIZE]) -> Self {
+        let format = (data[26] & 0xf) as usize;
+        let offset = 128 + 4 * format;
+        let format = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        NamespaceIdentity {
+            sectors: u64::from_le_bytes(data[0..8].try_into().unwrap()),
+            sector_size: 1 << ((format >> 16) & 0xff).min(31),
+            metadata_size: format as u16,
+        }
+    }
+}
+
+/// An NVMe controller.
+#[derive(Debug)]
+pub struct NvmeController {
+    // Dropped first, so the interrupt handlers are unregistered before the queues they use are freed.
+    handle: PciHandle,
+    regs: MappedBar,
+    number: usize,
+    admin: Arc<QueuePair>,
+    /// The I/O queues, the one of a core picked by its identifier.
+    io: Vec<Arc<QueuePair>>,
+    identity: ControllerIdentity,
+    /// How long the controller may take to become ready or to stop.
+    timeout: Duration,
+}
+
+impl NvmeController {
+    fn new(mut handle: PciHandle) -> Result<Self, ProbeError> {
+        let regs = handle.map_bar(0)?;
+        // SAFETY: The registers are only read here while `handle` is alive.
+        let capabilities = unsafe { regs.read::<u64>(reg::CAP) };
+        // SAFETY: See above.
+        let read = |offset| unsafe { regs.read::<u32>(offset) };
+        if (capabilities >> 48) & 0xf != 0 {
+            return Err(ProbeError::Unsupported(
+                "NVMe controller without 4 KiB pages",
+            ));
+        }
+        let max_queue_size = ((capabilities & 0xffff) + 1).min(u16::MAX as u64) as u16;
+        let doorbell_stride = 4 << ((capabilities >> 32) & 0xf);
+        let timeout = Duration::from_millis(500 * ((capabilities >> 24) & 0xff).max(1));
+        let version = read(reg::VS);
+
+        // SAFETY: Disabling the controller stops it from accessing memory.
+        unsafe { regs.write(reg::CC, 0u32) };
+        if !wait_for(timeout, || read(reg::CSTS) & CSTS_RDY == 0) {
+            return Err(ProbeError::Device("NVMe controller reset timed out"));
+        }
+
+        // The admin queue posts to the first vector, and each I/O queue to its own if there are enough of them.
+        let cores = mp::cores().len() + 1;
+        let vectors = if handle.device().capability(Capability::MSI_X).is_some() {
+            handle.enable_interrupts(cores + 1).unwrap_or(0)
+        } else {
+            0
+        };
+        let io_vector = |id: u16| match vectors {
+            0 => None,
+            1 => Some(0),
+            vectors => Some(1 + (id as usize - 1) % (vectors - 1)),
+        };
+
+        let admin_size = ADMIN_QUEUE_SIZE.min(max_queue_size);
+        let admin = QueuePair::new(0, admin_size, regs, doorbell_stride, handle.vector(0))
+            .map_err(|_| ProbeError::Device("Out of memory"))?;
+        let admin = Arc::new(admin);
+        // Bound again so it is dropped before the queues if probing fails, unregistering the handlers first.
+        let mut handle = handle;
+        if vectors > 0 {
+            handle.request_irq(0, handle_irq, Arc::as_ptr(&admin) as usize)?;
+        }
+        let (submission, completion) = admin.addresses();
+        let size = admin_size as u32 - 1;
+        // SAFETY: The admin queues live as long as the controller, which is disabled when it is dropped.
+        unsafe {
+            regs.write(reg::AQA, size << 16 | size);
+            write_u64(regs, reg::ASQ, submission);
+            write_u64(regs, reg::ACQ, completion);
+        }
+        handle.enable_bus_master();
+        // SAFETY: See above.
+        unsafe { regs.write(reg::CC, CC_EN | CC_IOSQES | CC_IOCQES) };
+        let ready = wait_for(timeout, || read(reg::CSTS) & (CSTS_RDY | CSTS_CFS) != 0);
+        if !ready || read(reg::CSTS) & CSTS_CFS != 0 {
+            // SAFETY: See above.
+            unsafe { regs.write(reg::CC, 0u32) };
+            return Err(ProbeError::Device("NVMe controller failed to start"));
+        }
+
+        let mut controller = NvmeController {
+            handle,
+            regs,
+            number: CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed),
+            admin,
+            io: Vec::new(),
+            identity: ControllerIdentity::default(),
+            timeout,
+        };
+        let buffer = DmaBuffer::new(PAGE_SIZE).map_err(|_| ProbeError::Device("Out of memory"))?;
+        controller
+            .identify(identify::CONTROLLER, 0, &buffer)
+            .map_err(|_| ProbeError::Device("NVMe Identify Controller failed"))?;
+        let mut data = [0; PAGE_SIZE];
+        buffer.read_bytes(0, &mut data);
+        controller.identity = ControllerIdentity::parse(&data);
+
+        // The number of queues granted may be lower than the number requested, both counts are 0-based.
+        let requested = cores.min(u16::MAX as usize) as u32 - 1;
+        let (status, granted) = controller.admin_command(Command {
+            opcode: admin::SET_FEATURES,
+            cdw: [
+                FEATURE_NUMBER_OF_QUEUES,
+                requested << 16 | requested,
+                0,
+                0,
+                0,
+                0,
+            ],
+            ..Default::default()
+        });
+        let count = if status.is_success() {
+            (granted & 0xffff).min(granted >> 16) as usize + 1
+        } else {
+            1
+        };
+        let io_size = IO_QUEUE_SIZE.min(max_queue_size);
+        for id in 1..=count.min(cores) as u16 {
+            let vector = io_vector(id);
+            let queue = QueuePair::new(
+                id,
+                io_size,
+                regs,
+                doorbell_stride,
+                vector.and_then(|index| controller.handle.vector(index)),
+            )
+            .map_err(|_| ProbeError::Device("Out of memory"))?;
+            let queue = Arc::new(queue);
+            controller.create_io_queue(&queue, vector)?;
+            if let Some(index) = vector {
+                let cookie = Arc::as_ptr(&queue) as usize;
+                controller.handle.request_irq(index, handle_irq, cookie)?;
+            }
+            controller.io.push(queue);
+        }
+
+        info!(
+            "NVMe {}.{} controller nvme{}: {} ({}), {} I/O queues{}",
+            version >> 16,
+            (version >> 8) & 0xff,
+            controller.number,
+            controller.identity.model,
+            controller.identity.serial,
+            controller.io.len(),
+            if vectors > 0 { "" } else { ", polled" }
+        );
+        Ok(controller)
+    }
+
+    /// Returns the PCI function of the controller.
+    pub fn pci_device(&self) -> &'static PciDevice {
+        self.handle.device()
+    }
+
+    /// Returns the model of the controller.
+    pub fn model(&self) -> &str {
+        &self.identity.model
+    }
+
+    /// Returns the serial number of the controller.
+    pub fn serial(&self) -> &str {
+        &self.identity.serial
+    }
+
+    /// Returns the number of I/O queues.
+    pub fn io_queue_count(&self) -> usize {
+        self.io.len()
+    }
+
+    /// Returns the I/O queue of the current core.
+    fn io_queue(&self) -> &QueuePair {
+        &self.io[mp::current_core_index() % self.io.len()]
+    }
+
+    /// Executes an admin command that doesn't transfer data.
+    fn admin_command(&self, command: Command) -> (Status, u32) {
+        // SAFETY: The command doesn't point the controller to memory.
+        unsafe { self.admin.execute(command) }
+    }
+
+    /// Reads the data structure `cns` about the namespace `namespace` into `buffer`.
+    fn identify(&self, cns: u32, namespace: u32, buffer: &DmaBuffer) -> Result<(), Status> {
+        let command = Command {
+            opcode: admin::IDENTIFY,
+            namespace,
+            prp1: buffer.phys_addr(),
+            cdw: [cns, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        // SAFETY: The buffer is a page long and outlives the command.
+        let (status, _) = unsafe { self.admin.execute(command) };
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(status)
+        }
+    }
+
+    /// Creates the completion queue then the submission queue of `queue`, raising the interrupt `vector`.
+    fn create_io_queue(&self, queue: &QueuePair, vector: Option<usize>) -> Result<(), ProbeError> {
+        let (submission, completion) = queue.addresses();
+        let id = queue.id() as u32;
+        let size = (queue.size() as u32 - 1) << 16;
+        let interrupts = match vector {
+            Some(index) => (index as u32) << 16 | 1 << 1,
+            None => 0,
+        };
+        let command = Command {
+            opcode: admin::CREATE_IO_CQ,
+            prp1: completion,
+            cdw: [size | id, interrupts | 1, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        // SAFETY: The queue lives as long as the controller, which is disabled when it is dropped.
+        if !unsafe { self.admin.execute(command) }.0.is_success() {
+            return Err(ProbeError::Device(
+                "NVMe Create I/O Completion Queue failed",
+            ));
+        }
+        let command = Command {
+            opcode: admin::CREATE_IO_SQ,
+            prp1: submission,
+            cdw: [size | id, id << 16 | 1, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        // SAFETY: See above.
+        if !unsafe { self.admin.execute(command) }.0.is_success() {
+            self.admin_command(Command {
+                opcode: admin::DELETE_IO_CQ,
+                cdw: [id, 0, 0, 0, 0, 0],
+                ..Default::default()
+            });
+            return Err(ProbeError::Device(
+                "NVMe Create I/O Submission Queue failed",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the identifiers of the active namespaces.
+    fn active_namespaces(&self) -> Result<Vec<u32>, ProbeError> {
+        let buffer = DmaBuffer::new(PAGE_SIZE).map_err(|_| ProbeError::Device("Out of memory"))?;
+        // Controllers before 1.1 can't list them, every namespace up to their number is tried instead.
+        // SAFETY: The controller owns the handle that mapped its registers.
+        let version = unsafe { self.regs.read::<u32>(reg::VS) };
+        if version < 0x1_01_00
+            || self
+                .identify(identify::ACTIVE_NAMESPACES, 0, &buffer)
+                .is_err()
+        {
+            return Ok((1..=self.identity.namespaces).collect());
+        }
+        let mut data = [0; PAGE_SIZE];
+        buffer.read_bytes(0, &mut data);
+        Ok(data
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .take_while(|&id| id != 0)
+            .collect())
+    }
+}
+
+impl Drop for NvmeController {
+    fn drop(&mut self) {
+        // SAFETY: Disabling the controller deletes the I/O queues and stops it from accessing memory before the queues
+        // are freed.
+        unsafe { self.regs.write(reg::CC, 0u32) };
+        // SAFETY: The handle is dropped after this, with the other fields.
+        if !wait_for(self.timeout, || unsafe {
+            self.regs.read::<u32>(reg::CSTS) & CSTS_RDY == 0
+        }) {
+            warn!("NVMe controller nvme{} failed to stop", self.number);
+        }
+    }
+}
+
+/// Writes the 64-bit register at `offset` as two halves, since controllers don't have to support 64-bit accesses.
+///
+/// # Safety
+/// See [MappedBar::write].
+unsafe fn write_u64(regs: MappedBar, offset: usize, value: u64) {
+    // SAFETY: Guaranteed by the caller.
+    unsafe {
+        regs.write(offset, value as u32);
+        regs.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// A namespace of an NVMe controller.
+#[derive(Debug)]
+pub struct NvmeNamespace {
+    controller: Arc<NvmeController>,
+    id: u32,
+    name: String,
+    identity: NamespaceIdentity,
+}
+
+impl NvmeNamespace {
+    /// Identifies the namespace `id`. Returns `None` if it is inactive or can't be used.
+    fn new(controller: &Arc<NvmeController>, id: u32) -> Result<Option<Self>, ProbeError> {
+        let buffer = DmaBuffer::new(PAGE_SIZE).map_err(|_| ProbeError::Device("Out of memory"))?;
+        controller
+            .identify(identify::NAMESPACE, id, &buffer)
+            .map_err(|_| ProbeError::Device("NVMe Identify Namespace failed"))?;
+        let mut data = [0; PAGE_SIZE];
+        buffer.read_bytes(0, &mut data);
+        let identity = NamespaceIdentity::parse(&data);
+        if identity.sectors == 0 {
+            return Ok(None);
+        }
+        if identity.sector_size < 512 || identity.sector_size > PAGE_SIZE {
+            return Err(ProbeError::Unsupported("NVMe namespace sector size"));
+        }
+        if identity.metadata_size != 0 {
+            return Err(ProbeError::Unsupported("NVMe namespace with metadata"));
+        }
+        let name = format!("nvme{}n{id}", controller.number);
+        info!(
+            "NVMe namespace {name}: {} sectors of {} bytes",
+            identity.sectors, identity.sector_size
+        );
+        Ok(Some(NvmeNamespace {
+            controller: controller.clone(),
+            id,
+            name,
+            identity,
+        }))
+    }
+
+    /// Returns the identifier of the namespace.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the controller of the namespace.
+    pub fn controller(&self) -> &Arc<NvmeController> {
+        &self.controller
+    }
+
+    /// Transfers the `len` bytes starting at `sector` in commands of up to [MAX_TRANSFER] bytes, through a DMA buffer
+    /// that `copy` fills with the range of the data before a write, or drains after a read.
+    fn transfer(
+        &self,
+        sector: u64,
+        len: usize,
+        write: bool,
+        mut copy: impl FnMut(Range<usize>, &DmaBuffer),
+    ) -> Result<(), BlockError> {
+        let sector_size = self.identity.sector_size;
+        let max_transfer = match self.controller.identity.max_transfer {
+            0 => MAX_TRANSFER,
+            limit => MAX_TRANSFER.min(limit),
+        }
+        .max(sector_size);
+        let buffer = DmaBuffer::new(len.min(max_transfer)).map_err(|_| BlockError::OutOfMemory)?;
+        // Holds the addresses of every page after the first, if there are more than two.
+        let list = if len.min(max_transfer) > 2 * PAGE_SIZE {
+            Some(DmaBuffer::new(PAGE_SIZE).map_err(|_| BlockError::OutOfMemory)?)
+        } else {
+            None
+        };
+        for offset in (0..len).step_by(max_transfer) {
+            let range = offset..(offset + max_transfer).min(len);
+            let pages = prp_pages(buffer.phys_addr(), range.len(), PAGE_SIZE as u64);
+            let prp2 = match (&pages[1..], &list) {
+                ([], _) => 0,
+                ([second], _) => *second,
+                (rest, Some(list)) => {
+                    for (i, page) in rest.iter().enumerate() {
+                        list.write_bytes(8 * i, &page.to_le_bytes());
+                    }
+                    list.phys_addr()
+                }
+                (_, None) => unreachable!("Transfer of more than two pages without a PRP list"),
+            };
+            let lba = sector + (offset / sector_size) as u64;
+            let count = (range.len() / sector_size) as u32;
+            let command = Command {
+                opcode: if write { io::WRITE } else { io::READ },
+                namespace: self.id,
+                prp1: pages[0],
+                prp2,
+                cdw: [lba as u32, (lba >> 32) as u32, count - 1, 0, 0, 0],
+            };
+            if write {
+                copy(range.clone(), &buffer);
+            }
+            // SAFETY: The buffer and the list outlive the command.
+            let (status, _) = unsafe { self.controller.io_queue().execute(command) };
+            if !status.is_success() {
+                return Err(BlockError::Io);
+            }
+            if !write {
+                copy(range, &buffer);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.identity.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(sector, buf.len())?;
+        self.transfer(sector, buf.len(), false, |range, buffer| {
+            buffer.read_bytes(0, &mut buf[range]);
+        })
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(sector, buf.len())?;
+        self.transfer(sector, buf.len(), true, |range, buffer| {
+            buffer.write_bytes(0, &buf[range]);
+        })
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = Command {
+            opcode: io::FLUSH,
+            namespace: self.id,
+            ..Default::default()
+        };
+        // SAFETY: The command doesn't point the controller to memory.
+        let (status, _) = unsafe { self.controller.io_queue().execute(command) };
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(BlockError::Io)
+        }
+    }
+}
+
+#[kproc::test("NVMe read and write")]
+fn nvme_read_write() {
+    // The test runner attaches a controller with a scratch namespace, see `krun::QemuConfig::disks`.
+    let disk = block::devices()
+        .into_iter()
+        .find(|disk| disk.name().starts_with("nvme") && !disk.is_read_only())
+        .expect("No writable NVMe namespace, the test runner attaches one");
+    let sector_size = disk.sector_size();
+    // Spans several commands, the first of which needs a PRP list.
+    let len = MAX_TRANSFER + 3 * sector_size;
+    let sectors = (len / sector_size) as u64;
+    assert!(disk.sector_count() >= sectors, "The test disk is too small");
+    let first = disk.sector_count() - sectors;
+    let mut original = alloc::vec![0; len];
+    disk.read(first, &mut original).unwrap();
+    let pattern: Vec<u8> = (0..len).map(|i| (i * 7 + i / sector_size) as u8).collect();
+    disk.write(first, &pattern).unwrap();
+    disk.flush().unwrap();
+    let mut read = alloc::vec![0; len];
+    disk.read(first, &mut read).unwrap();
+    assert!(
+        read == pattern,
+        "Data read back differs from the data written"
+    );
+    disk.write(first, &original).unwrap();
+    disk.flush().unwrap();
+}
@@ -0,0 +1,304 @@
+//! Submission and completion queue pairs.
+//!
+//! Commands are written to the submission queue and announced by writing its tail doorbell. The controller posts a
+//! completion for each of them to the completion queue, tagged with the command identifier, and flips the phase bit of
+//! the entries on every pass through the queue so new entries can be told apart from old ones.
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use alloc::{sync::Arc, vec::Vec};
+use nmm::MemError;
+
+use crate::{
+    interrupts::InterruptMutex,
+    memory::dma::DmaBuffer,
+    pci::MappedBar,
+    proc::{self, ThreadID},
+};
+
+/// The size of a submission queue entry.
+pub const SUBMISSION_ENTRY_SIZE: usize = 64;
+/// The size of a completion queue entry.
+pub const COMPLETION_ENTRY_SIZE: usize = 16;
+/// The offset of the first doorbell register.
+const DOORBELLS: usize = 0x1000;
+
+/// A command, as written to a submission queue. The command identifier is filled in when it is submitted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Command {
+    /// The opcode.
+    pub opcode: u8,
+    /// The namespace the command applies to.
+    pub namespace: u32,
+    /// The first PRP entry: the address of the data.
+    pub prp1: u64,
+    /// The second PRP entry: the second page of the data, or the address of the PRP list.
+    pub prp2: u64,
+    /// The command specific dwords 10 to 15.
+    pub cdw: [u32; 6],
+}
+
+impl Command {
+    fn to_bytes(self, id: u16) -> [u8; SUBMISSION_ENTRY_SIZE] {
+        let mut entry = [0; SUBMISSION_ENTRY_SIZE];
+        entry[0] = self.opcode;
+        entry[2..4].copy_from_slice(&id.to_le_bytes());
+        entry[4..8].copy_from_slice(&self.namespace.to_le_bytes());
+        entry[24..32].copy_from_slice(&self.prp1.to_le_bytes());
+        entry[32..40].copy_from_slice(&self.prp2.to_le_bytes());
+        for (i, dword) in self.cdw.iter().enumerate() {
+            entry[40 + 4 * i..44 + 4 * i].copy_from_slice(&dword.to_le_bytes());
+        }
+        entry
+    }
+}
+
+/// The status of a completed command, without the phase bit: 0 on success.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status(pub u16);
+
+impl Status {
+    /// Returns `true` if the command succeeded.
+    pub fn is_success(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// A submitted command that hasn't completed yet.
+#[derive(Debug)]
+struct Request {
+    done: AtomicBool,
+    status: AtomicU32,
+    /// The first dword of the command specific result.
+    result: AtomicU32,
+    waiter: InterruptMutex<Option<ThreadID>>,
+}
+
+impl Request {
+    fn new() -> Self {
+        Request {
+            done: AtomicBool::new(false),
+            status: AtomicU32::new(0),
+            result: AtomicU32::new(0),
+            waiter: InterruptMutex::new(None),
+        }
+    }
+
+    fn complete(&self, status: u16, result: u32) {
+        self.status.store(status as u32, Ordering::SeqCst);
+        self.result.store(result, Ordering::SeqCst);
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(pid) = self.waiter.lock().take() {
+            proc::wake(pid);
+        }
+    }
+}
+
+/// The state of a queue pair, owned by whoever holds its lock.
+#[derive(Debug)]
+struct Rings {
+    submission: DmaBuffer,
+    completion: DmaBuffer,
+    sq_tail: u16,
+    /// The last submission queue head reported by the controller.
+    sq_head: u16,
+    cq_head: u16,
+    phase: bool,
+    /// The requests in flight, by command identifier.
+    requests: Vec<Option<Arc<Request>>>,
+}
+
+/// A submission queue and the completion queue it posts to.
+#[derive(Debug)]
+pub struct QueuePair {
+    id: u16,
+    size: u16,
+    regs: MappedBar,
+    doorbell_stride: usize,
+    /// The CPU vector of the interrupt of the completion queue, if it raises one.
+    vector: Option<u8>,
+    rings: InterruptMutex<Rings>,
+}
+
+impl QueuePair {
+    /// Allocates a pair of `size` entries with the identifier `id`, whose doorbells are in `regs` and spaced by
+    /// `doorbell_stride` bytes. The controller is told about it separately.
+    pub fn new(
+        id: u16,
+        size: u16,
+        regs: MappedBar,
+        doorbell_stride: usize,
+        vector: Option<u8>,
+    ) -> Result<Self, MemError> {
+        let submission = DmaBuffer::new(size as usize * SUBMISSION_ENTRY_SIZE)?;
+        let completion = DmaBuffer::new(size as usize * COMPLETION_ENTRY_SIZE)?;
+        Ok(QueuePair {
+            id,
+            size,
+            regs,
+            doorbell_stride,
+            vector,
+            rings: InterruptMutex::new(Rings {
+                submission,
+                completion,
+                sq_tail: 0,
+                sq_head: 0,
+                cq_head: 0,
+                phase: true,
+                requests: (0..size).map(|_| None).collect(),
+            }),
+        })
+    }
+
+    /// Returns the identifier of the pair.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Returns the number of entries of each queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the physical addresses of the submission queue and the completion queue.
+    pub fn addresses(&self) -> (u64, u64) {
+        let rings = self.rings.lock();
+        (rings.submission.phys_addr(), rings.completion.phys_addr())
+    }
+
+    fn ring_doorbell(&self, completion: bool, value: u16) {
+        let index = 2 * self.id as usize + completion as usize;
+        // SAFETY: Doorbells only tell the controller how far the queues were processed.
+        unsafe {
+            self.regs
+                .write(DOORBELLS + index * self.doorbell_stride, value as u32)
+        };
+    }
+
+    /// Submits `command` and waits for it to complete. Returns its status and the first dword of its result.
+    ///
+    /// The thread blocks if the completion queue raises an interrupt and the scheduler is running, and polls the queue
+    /// otherwise.
+    ///
+    /// # Safety
+    /// The memory the command points the controller to must stay valid until it completes.
+    pub unsafe fn execute(&self, command: Command) -> (Status, u32) {
+        let request = Arc::new(Request::new());
+        loop {
+            let mut rings = self.rings.lock();
+            let full = (rings.sq_tail + 1) % self.size == rings.sq_head;
+            if !full && let Some(id) = rings.requests.iter().position(Option::is_none) {
+                rings.requests[id] = Some(request.clone());
+                let offset = rings.sq_tail as usize * SUBMISSION_ENTRY_SIZE;
+                rings
+                    .submission
+                    .write_bytes(offset, &command.to_bytes(id as u16));
+                rings.sq_tail = (rings.sq_tail + 1) % self.size;
+                // The entry must be visible before the doorbell that exposes it.
+                core::sync::atomic::fence(Ordering::SeqCst);
+                self.ring_doorbell(false, rings.sq_tail);
+                break;
+            }
+            drop(rings);
+            // The queue is full, wait for other commands to complete.
+            self.reap();
+            core::hint::spin_loop();
+        }
+
+        loop {
+            // The interrupt may not be delivered yet, if interrupts are disabled on this core.
+            self.reap();
+            if request.done.load(Ordering::SeqCst) {
+                break;
+            }
+            let blocked = self.vector.is_some()
+                && proc::block(|pid| {
+                    *request.waiter.lock() = Some(pid);
+                    // The command may have completed before the thread was registered as its waiter.
+                    if request.done.load(Ordering::SeqCst) {
+                        proc::wake(pid);
+                    }
+                });
+            if !blocked {
+                core::hint::spin_loop();
+            }
+        }
+        (
+            Status(request.status.load(Ordering::SeqCst) as u16),
+            request.result.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Completes every command the controller posted a completion for. This may be called from interrupt handlers.
+    pub fn reap(&self) {
+        let mut rings = self.rings.lock();
+        let mut reaped = false;
+        loop {
+            let mut entry = [0; COMPLETION_ENTRY_SIZE];
+            let offset = rings.cq_head as usize * COMPLETION_ENTRY_SIZE;
+            rings.completion.read_bytes(offset, &mut entry);
+            let status = u16::from_le_bytes([entry[14], entry[15]]);
+            if (status & 1 != 0) != rings.phase {
+                break;
+            }
+            // The rest of the entry must not be read before its phase bit.
+            core::sync::atomic::fence(Ordering::SeqCst);
+            rings.completion.read_bytes(offset, &mut entry);
+            let result = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            rings.sq_head = u16::from_le_bytes([entry[8], entry[9]]);
+            let id = u16::from_le_bytes([entry[12], entry[13]]) as usize;
+            if let Some(request) = rings.requests.get_mut(id).and_then(Option::take) {
+                request.complete(status >> 1, result);
+            }
+            rings.cq_head += 1;
+            if rings.cq_head == self.size {
+                rings.cq_head = 0;
+                rings.phase = !rings.phase;
+            }
+            reaped = true;
+        }
+        if reaped {
+            self.ring_doorbell(true, rings.cq_head);
+        }
+    }
+}
+
+/// Returns the physical addresses of the pages holding the `len` bytes starting at `addr`, as described by PRP
+/// entries: the first may start anywhere in its page, the others start at a page boundary.
+pub fn prp_pages(addr: u64, len: usize, page_size: u64) -> Vec<u64> {
+    let end = addr + len as u64;
+    let mut pages = Vec::new();
+    let mut page = addr;
+    while page < end {
+        pages.push(page);
+        page = (page / page_size + 1) * page_size;
+    }
+    pages
+}
+
+#[kproc::test("NVMe PRP pages")]
+fn nvme_prp_pages() {
+    assert_eq!(prp_pages(0x1000, 512, 0x1000), [0x1000]);
+    assert_eq!(prp_pages(0x1000, 0x1000, 0x1000), [0x1000]);
+    assert_eq!(prp_pages(0x1000, 0x1001, 0x1000), [0x1000, 0x2000]);
+    // The first entry may have an offset, which the following entries don't.
+    assert_eq!(prp_pages(0x1e00, 0x400, 0x1000), [0x1e00, 0x2000]);
+    assert_eq!(
+        prp_pages(0x10000, 0x3000, 0x1000),
+        [0x10000, 0x11000, 0x12000]
+    );
+    assert!(prp_pages(0x1000, 0, 0x1000).is_empty());
+
+    let command = Command {
+        opcode: 0x02,
+        namespace: 1,
+        prp1: 0x1000,
+        prp2: 0x2000,
+        cdw: [0x10, 0, 7, 0, 0, 0],
+    }
+    .to_bytes(0x1234);
+    assert_eq!(&command[..8], &[0x02, 0, 0x34, 0x12, 1, 0, 0, 0]);
+    assert_eq!(command[24..32], 0x1000u64.to_le_bytes());
+    assert_eq!(command[32..40], 0x2000u64.to_le_bytes());
+    assert_eq!(command[40..44], 0x10u32.to_le_bytes());
+    assert_eq!(command[48..52], 7u32.to_le_bytes());
+}
@@ -0,0 +1,151 @@
+//! Decoding and sizing of Base Address Registers.
+use super::{PciAddress, device::command};
+
+/// The offset of the first BAR in the configuration space.
+const FIRST_BAR: u16 = 0x10;
+
+/// A region of memory or IO space decoded by a function, described by one of its Base Address Registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// A memory region below 4 GiB.
+    Memory32 {
+        /// The physical base address.
+        address: u32,
+        /// The size in bytes, a power of two.
+        size: u32,
+        /// Reads have no side effects, so the region may be mapped write-combining.
+        prefetchable: bool,
+    },
+    /// A memory region anywhere in the physical address space, described by two consecutive BARs.
+    Memory64 {
+        /// The physical base address.
+        address: u64,
+        /// The size in bytes, a power of two.
+        size: u64,
+        /// Reads have no side effects, so the region may be mapped write-combining.
+        prefetchable: bool,
+    },
+    /// A range of IO ports.
+    Io {
+        /// The first port.
+        port: u16,
+        /// The number of ports, a power of two.
+        size: u16,
+    },
+}
+
+impl Bar {
+    /// Returns the base address of the region, a port number for IO BARs.
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { address, .. } => address as u64,
+            Bar::Memory64 { address, .. } => address,
+            Bar::Io { port, .. } => port as u64,
+        }
+    }
+
+    /// Returns the size of the region in bytes, or ports for IO BARs.
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { size, .. } => size as u64,
+            Bar::Memory64 { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+
+    /// Returns `true` if the region is in memory space rather than IO space.
+    pub fn is_memory(&self) -> bool {
+        !matches!(self, Bar::Io { .. })
+    }
+
+    /// Returns `true` if the region is prefetchable. Always `false` for IO BARs.
+    pub fn is_prefetchable(&self) -> bool {
+        match *self {
+            Bar::Memory32 { prefetchable, .. } | Bar::Memory64 { prefetchable, .. } => prefetchable,
+            Bar::Io { .. } => false,
+        }
+    }
+}
+
+/// Decodes and sizes the `count` BARs of the function at `address`. The upper half of a 64-bit BAR, like an
+/// unimplemented BAR, is `None`.
+///
+/// Address decoding is disabled while the BARs are sized, so nothing else may access the function meanwhile.
+pub(super) fn probe(address: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = address.read_u16(command::OFFSET);
+    // SAFETY: Decoding is disabled while the BARs temporarily hold all ones, and restored along with them afterwards.
+    unsafe {
+        address.write_u16(
+            command::OFFSET,
+            command & !(command::IO_SPACE | command::MEMORY_SPACE),
+        )
+    };
+
+    let mut index = 0;
+    while index < count {
+        let (bar, used) = probe_one(address, index, count);
+        bars[index] = bar;
+        index += used;
+    }
+
+    // SAFETY: The command register is restored to its original value.
+    unsafe { address.write_u16(command::OFFSET, command) };
+    bars
+}
+
+/// Decodes and sizes BAR `index`, returning it and the number of BAR slots it uses.
+fn probe_one(address: PciAddress, index: usize, count: usize) -> (Option<Bar>, usize) {
+    let offset = FIRST_BAR + index as u16 * 4;
+    let original = address.read_u32(offset);
+    let mask = size_mask(address, offset, original);
+
+    if original & 1 != 0 {
+        // IO BARs may hardwire the upper 16 bits to zero.
+        let mask = (mask & !0x3) | 0xffff_0000;
+        let size = (!mask).wrapping_add(1);
+        let bar = (mask != 0xffff_0000 && size != 0).then_some(Bar::Io {
+            port: (original & !0x3) as u16,
+            size: size as u16,
+        });
+        return (bar, 1);
+    }
+
+    let prefetchable = original & 0x8 != 0;
+    match (original >> 1) & 0x3 {
+        0x2 if index + 1 < count => {
+            let high_offset = offset + 4;
+            let high = address.read_u32(high_offset);
+            let high_mask = size_mask(address, high_offset, high);
+            let mask = (high_mask as u64) << 32 | (mask & !0xf) as u64;
+            let bar = (mask != 0).then_some(Bar::Memory64 {
+                address: (high as u64) << 32 | (original & !0xf) as u64,
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+            });
+            (bar, 2)
+        }
+        0x0 => {
+            let mask = mask & !0xf;
+            let bar = (mask != 0).then_some(Bar::Memory32 {
+                address: original & !0xf,
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+            });
+            (bar, 1)
+        }
+        // Reserved types and 64-bit BARs in the last slot are malformed.
+        _ => (None, 1),
+    }
+}
+
+/// Writes all ones to the BAR at `offset` and reads back which address bits are implemented, then restores `original`.
+fn size_mask(address: PciAddress, offset: u16, original: u32) -> u32 {
+    // SAFETY: The function doesn't decode addresses while its BARs are being sized, see `probe`.
+    unsafe {
+        address.write_u32(offset, u32::MAX);
+        let mask = address.read_u32(offset);
+        address.write_u32(offset, original);
+        mask
+    }
+}
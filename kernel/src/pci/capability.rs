@@ -0,0 +1,57 @@
+//! The capability list of PCI functions.
+use alloc::vec::Vec;
+
+use super::{PciAddress, device::status};
+
+/// The offset of the capabilities pointer of general devices and PCI-to-PCI bridges.
+const CAPABILITIES_POINTER: u16 = 0x34;
+/// The offset of the capabilities pointer of CardBus bridges.
+const CARDBUS_CAPABILITIES_POINTER: u16 = 0x14;
+/// The most capabilities that fit in the legacy configuration space, used to stop at malformed lists with a cycle.
+const MAX_CAPABILITIES: usize = 48;
+
+/// An entry of the capability list of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// The capability ID, see the associated constants.
+    pub id: u8,
+    /// The offset of the capability in the configuration space.
+    pub offset: u16,
+}
+
+impl Capability {
+    /// PCI Power Management.
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    /// Message Signaled Interrupts.
+    pub const MSI: u8 = 0x05;
+    /// Vendor specific, used by virtio for example.
+    pub const VENDOR: u8 = 0x09;
+    /// PCI Express.
+    pub const PCI_EXPRESS: u8 = 0x10;
+    /// Extended Message Signaled Interrupts.
+    pub const MSI_X: u8 = 0x11;
+}
+
+/// Walks the capability list of the function at `address`, whose header type is `header_type`.
+pub(super) fn walk(address: PciAddress, header_type: u8) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if address.read_u16(status::OFFSET) & status::CAPABILITIES_LIST == 0 {
+        return capabilities;
+    }
+    let pointer = match header_type {
+        2 => CARDBUS_CAPABILITIES_POINTER,
+        _ => CAPABILITIES_POINTER,
+    };
+
+    // The bottom two bits of every pointer are reserved.
+    let mut offset = (address.read_u8(pointer) & !0x3) as u16;
+    while offset >= 0x40 && capabilities.len() < MAX_CAPABILITIES {
+        let header = address.read_u16(offset);
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) & !0x3;
+    }
+    capabilities
+}
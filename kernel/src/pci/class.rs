@@ -1,4 +1,5 @@
 //! An enum tree representing the PCI device class and subclass. This file is kinda gross, and manually done.
+#![allow(dead_code, missing_docs)]
 
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)] //
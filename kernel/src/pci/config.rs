@@ -0,0 +1,320 @@
+//! Access to the configuration space of PCI functions.
+//!
+//! Registers are accessed through the ECAM window covering the function if the MCFG table describes one, and with
+//! configuration mechanism #1 through IO ports otherwise, which only reaches the first 256 bytes of segment 0.
+use core::{fmt, ops::Not};
+
+use cake::Mutex;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+use super::ecam;
+use crate::interrupts;
+
+/// The port selecting the register accessed through [CONFIG_DATA].
+const CONFIG_ADDRESS: u16 = 0xcf8;
+/// The port through which the selected register is accessed.
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// The size of the configuration space reachable through port IO.
+const LEGACY_CONFIG_SIZE: u16 = 256;
+
+/// Held while a register is selected and accessed.
+static PORTS: Mutex<()> = Mutex::new(());
+
+/// The location of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    /// The PCI segment group, always 0 on systems without PCIe.
+    pub segment: u16,
+    /// The bus number.
+    pub bus: u8,
+    /// The device number on the bus, below 32.
+    pub device: u8,
+    /// The function number of the device, below 8.
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Creates the address of a function in segment 0.
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            segment: 0,
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Creates the address of a function in the segment group `segment`.
+    pub const fn in_segment(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Returns the size of the configuration space that can be accessed for this function: 4 KiB when an ECAM window
+    /// covers it, or the 256 bytes reachable through port IO otherwise.
+    pub fn config_size(self) -> u16 {
+        if ecam::windows().iter().any(|window| {
+            window.segment() == self.segment
+                && (window.first_bus()..=window.last_bus()).contains(&self.bus)
+        }) {
+            ecam::CONFIG_SPACE_SIZE as u16
+        } else {
+            LEGACY_CONFIG_SIZE
+        }
+    }
+
+    /// Reads the 32-bit register at `offset`, which is rounded down to a multiple of 4.
+    pub fn read_u32(self, offset: u16) -> u32 {
+        self.read(offset & !3)
+    }
+
+    /// Reads the 16-bit register at `offset`, which is rounded down to a multiple of 2.
+    pub fn read_u16(self, offset: u16) -> u16 {
+        self.read(offset & !1)
+    }
+
+    /// Reads the 8-bit register at `offset`.
+    pub fn read_u8(self, offset: u16) -> u8 {
+        self.read(offset)
+    }
+
+    /// Writes the 32-bit register at `offset`, which is rounded down to a multiple of 4.
+    ///
+    /// # Safety
+    /// Writing configuration registers changes how the function decodes addresses and raises interrupts. The caller
+    /// must own the function, and the new value must not make it conflict with other devices.
+    pub unsafe fn write_u32(self, offset: u16, value: u32) {
+        // SAFETY: Guaranteed by the caller.
+        unsafe { self.write(offset & !3, value) }
+    }
+
+    /// Writes the 16-bit register at `offset`, which is rounded down to a multiple of 2.
+    ///
+    /// # Safety
+    /// See [PciAddress::write_u32].
+    pub unsafe fn write_u16(self, offset: u16, value: u16) {
+        // SAFETY: Guaranteed by the caller.
+        unsafe { self.write(offset & !1, value) }
+    }
+
+    /// Writes the 8-bit register at `offset`.
+    ///
+    /// # Safety
+    /// See [PciAddress::write_u32].
+    pub unsafe fn write_u8(self, offset: u16, value: u8) {
+        // SAFETY: Guaranteed by the caller.
+        unsafe { self.write(offset, value) }
+    }
+
+    /// Reads the register at `offset`, aligned to its size, through ECAM if possible or port IO otherwise. Registers
+    /// beyond [PciAddress::config_size] read as all ones, like those of a missing function.
+    fn read<T: Register>(self, offset: u16) -> T {
+        if let Some(space) = ecam::config_space(self) {
+            // SAFETY: The offset is within the 4 KiB configuration space of the function, and aligned by the caller.
+            return unsafe {
+                space
+                    .add(offset as usize & 0xfff)
+                    .cast::<T>()
+                    .read_volatile()
+            };
+        }
+        if self.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+            return !T::default();
+        }
+        port_io(self, offset, |port| {
+            // SAFETY: The register of the function is selected, reading it has no side effects.
+            unsafe { Port::<T>::new(port).read() }
+        })
+    }
+
+    /// Writes the register at `offset`, aligned to its size, through ECAM if possible or port IO otherwise. Writes
+    /// beyond [PciAddress::config_size] are dropped.
+    ///
+    /// # Safety
+    /// See [PciAddress::write_u32].
+    unsafe fn write<T: Register>(self, offset: u16, value: T) {
+        if let Some(space) = ecam::config_space(self) {
+            // SAFETY: The offset is within the configuration space of the function, the caller guarantees the write is
+            // sound.
+            unsafe {
+                space
+                    .add(offset as usize & 0xfff)
+                    .cast::<T>()
+                    .write_volatile(value)
+            };
+            return;
+        }
+        if self.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        port_io(self, offset, |port| {
+            // SAFETY: The register is selected, the caller guarantees the write is sound.
+            unsafe { Port::<T>::new(port).write(value) }
+        })
+    }
+}
+
+/// A configuration register of 8, 16 or 32 bits.
+trait Register: PortRead + PortWrite + Copy + Default + Not<Output = Self> {}
+
+impl<T: PortRead + PortWrite + Copy + Default + Not<Output = Self>> Register for T {}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Selects the register at `offset` of `address` with configuration mechanism #1 and calls `access` with the data port
+/// to use for it.
+fn port_io<R>(address: PciAddress, offset: u16, access: impl FnOnce(u16) -> R) -> R {
+    let select = 1 << 31
+        | (address.bus as u32) << 16
+        | (address.device as u32 & 0x1f) << 11
+        | (address.function as u32 & 0x7) << 8
+        | (offset as u32 & 0xfc);
+    interrupts::without_interrupts(|| {
+        let _ports = PORTS.lock();
+        // SAFETY: Selecting a register has no side effects, and the data port is only accessed while holding `PORTS`.
+        unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(select) };
+        access(CONFIG_DATA + (offset & 3))
+    })
+}
+
+#[kproc::test("ECAM and port IO agree")]
+fn ecam_and_port_io_agree() {
+    use super::{Capability, HEADER_GENERAL};
+
+    /// The interrupt line register, which only informs software and can be written freely.
+    const INTERRUPT_LINE: u16 = 0x3c;
+    /// The first register of the extended configuration space, only reachable through ECAM.
+    const EXTENDED: u16 = 0x100;
+
+    /// Reads the register at `offset` through port IO.
+    fn port_read<T: Register>(address: PciAddress, offset: u16) -> T {
+        port_io(address, offset, |port| {
+            // SAFETY: Reading configuration registers has no side effects.
+            unsafe { Port::<T>::new(port).read() }
+        })
+    }
+
+    let has_ecam = !ecam::windows().is_empty();
+    if !has_ecam {
+        cake::log::warn!(
+            "No ECAM window, only port IO is tested. Run on the q35 machine to test ECAM"
+        );
+    }
+    let mut extended = 0;
+    for device in super::devices() {
+        let address = device.address;
+        if address.segment != 0 {
+            continue;
+        }
+        let size = address.config_size();
+        if has_ecam {
+            assert_eq!(size, ecam::CONFIG_SPACE_SIZE as u16);
+        }
+        // Every register is the same whatever the access width, and through both mechanisms where port IO reaches.
+        for offset in (0..size).step_by(4) {
+            let value = address.read_u32(offset);
+            for byte in 0..4 {
+                assert_eq!(
+                    address.read_u8(offset + byte),
+                    (value >> (8 * byte)) as u8,
+                    "{} at {:#x}",
+                    address,
+                    offset + byte
+                );
+            }
+            for half in [0, 2] {
+                assert_eq!(
+                    address.read_u16(offset + half),
+                    (value >> (8 * half)) as u16
+                );
+            }
+            if offset < LEGACY_CONFIG_SIZE {
+                assert_eq!(
+                    port_read::<u32>(address, offset),
+                    value,
+                    "{} at {:#x}",
+                    address,
+                    offset
+                );
+                assert_eq!(port_read::<u16>(address, offset + 2), (value >> 16) as u16);
+                assert_eq!(port_read::<u8>(address, offset + 1), (value >> 8) as u8);
+            }
+        }
+
+        // PCIe functions link their extended capabilities from the start of the extended configuration space.
+        if has_ecam && device.capability(Capability::PCI_EXPRESS).is_some() {
+            let mut offset = EXTENDED;
+            let mut count = 0;
+            while offset != 0 {
+                let header = address.read_u32(offset);
+                assert_ne!(header, u32::MAX, "{}: extended space not decoded", address);
+                if header == 0 && offset == EXTENDED {
+                    // The function has no extended capability.
+                    break;
+                }
+                let next = (header >> 20) as u16 & !3;
+                assert!(
+                    next == 0 || (EXTENDED..size).contains(&next),
+                    "{}: extended capability at {:#x} links to {:#x}",
+                    address,
+                    offset,
+                    next
+                );
+                count += 1;
+                assert!(count < 0x100, "{}: extended capability loop", address);
+                offset = next;
+            }
+            extended += 1;
+        }
+    }
+    if has_ecam {
+        assert!(
+            extended > 0,
+            "No PCIe function to read the extended configuration space of, the test runner attaches an NVMe drive"
+        );
+    }
+
+    // Writes of every width land in the register, and read back the same through both mechanisms.
+    let address = super::devices()
+        .iter()
+        .find(|device| device.address.segment == 0 && device.header_type == HEADER_GENERAL)
+        .expect("No function with a general header")
+        .address;
+    let original = address.read_u32(INTERRUPT_LINE);
+    // The interrupt pin and the bytes after it are read-only, their value is written back as is.
+    let with_line = |line: u8| original & !0xff | line as u32;
+    let check = |line: u8| {
+        assert_eq!(address.read_u8(INTERRUPT_LINE), line);
+        assert_eq!(address.read_u16(INTERRUPT_LINE), with_line(line) as u16);
+        assert_eq!(address.read_u32(INTERRUPT_LINE), with_line(line));
+        assert_eq!(port_read::<u32>(address, INTERRUPT_LINE), with_line(line));
+    };
+    // SAFETY: The interrupt line only informs software, and is restored below.
+    unsafe {
+        address.write_u8(INTERRUPT_LINE, 0xa5);
+        check(0xa5);
+        address.write_u16(INTERRUPT_LINE, with_line(0x5a) as u16);
+        check(0x5a);
+        address.write_u32(INTERRUPT_LINE, with_line(0x3c));
+        check(0x3c);
+        port_io(address, INTERRUPT_LINE, |port| {
+            Port::<u8>::new(port).write(0x77)
+        });
+        check(0x77);
+        address.write_u32(INTERRUPT_LINE, original);
+    }
+    assert_eq!(address.read_u32(INTERRUPT_LINE), original);
+}
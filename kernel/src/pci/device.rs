@@ -1,41 +1,172 @@
+//! The functions found during enumeration.
+use alloc::vec::Vec;
+
 use super::{
+    PciAddress,
+    bar::{self, Bar},
+    capability::{self, Capability},
     class::PciDeviceClass,
-    pci_read_u32,
-    vendor_device::{get_vendor, Device, Vendor},
+    vendor_device::{Device, Vendor, get_vendor},
 };
 
-#[derive(Debug)]
-pub struct PCIDevice {
-    pub bus: u8,
-    pub slot: u8,
-    pub vendor: &'static Vendor,
-    pub device: &'static Device,
-    pub device_class: PciDeviceClass,
+/// The command register and its bits.
+pub mod command {
+    /// The offset of the command register.
+    pub const OFFSET: u16 = 0x04;
+    /// The function responds to IO space accesses.
+    pub const IO_SPACE: u16 = 1 << 0;
+    /// The function responds to memory space accesses.
+    pub const MEMORY_SPACE: u16 = 1 << 1;
+    /// The function may act as a bus master, which DMA requires.
+    pub const BUS_MASTER: u16 = 1 << 2;
+    /// The function may not assert its legacy INTx interrupt.
+    pub const INTERRUPT_DISABLE: u16 = 1 << 10;
+}
+
+/// The status register and its bits.
+pub mod status {
+    /// The offset of the status register.
+    pub const OFFSET: u16 = 0x06;
+    /// The function has a capability list.
+    pub const CAPABILITIES_LIST: u16 = 1 << 4;
+}
+
+/// The header type of general devices.
+pub const HEADER_GENERAL: u8 = 0x00;
+/// The header type of PCI-to-PCI bridges.
+pub const HEADER_PCI_BRIDGE: u8 = 0x01;
+/// The header type of CardBus bridges.
+pub const HEADER_CARDBUS_BRIDGE: u8 = 0x02;
+
+/// A function found on a PCI bus, with what was learned about it during enumeration.
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    /// The location of the function.
+    pub address: PciAddress,
+    /// The vendor ID, which may not be in the vendor database.
+    pub vendor_id: u16,
+    /// The device ID assigned by the vendor.
+    pub device_id: u16,
+    /// The base class code.
+    pub class: u8,
+    /// The subclass code.
+    pub subclass: u8,
+    /// The programming interface.
+    pub prog_if: u8,
+    /// The revision ID.
+    pub revision: u8,
+    /// The header type, without the multi-function bit.
+    pub header_type: u8,
+    /// The subsystem vendor ID, 0 for bridges.
+    pub subsystem_vendor_id: u16,
+    /// The subsystem ID, 0 for bridges.
+    pub subsystem_id: u16,
+    /// The legacy interrupt pin, 1 to 4 for INTA# to INTD#, or 0 if the function doesn't use one.
+    pub interrupt_pin: u8,
+    /// The legacy interrupt line assigned by the firmware.
+    pub interrupt_line: u8,
+    /// The decoded BARs. The upper half of a 64-bit BAR is `None`.
+    pub bars: [Option<Bar>; 6],
+    /// The capability list, in the order the function links it.
+    pub capabilities: Vec<Capability>,
+    /// The secondary and subordinate bus numbers of a PCI-to-PCI bridge.
+    pub bridged_buses: Option<(u8, u8)>,
 }
 
-pub(super) fn pci_get_device(bus: u8, slot: u8) -> Option<PCIDevice> {
-    let vid_did = pci_read_u32(bus, slot, 0, 0);
-    if (vid_did & 0xFFFF) == 0xFFFF {
-        return None;
+impl PciDevice {
+    /// Reads the header of the function at `address`, sizes its BARs and walks its capabilities. Returns `None` if
+    /// there is no function there.
+    pub(super) fn probe(address: PciAddress) -> Option<Self> {
+        let id = address.read_u32(0x00);
+        if id as u16 == 0xffff {
+            return None;
+        }
+        let class = address.read_u32(0x08);
+        let header_type = header_type(address) & 0x7f;
+
+        let (bar_count, subsystem, bridged_buses) = match header_type {
+            HEADER_GENERAL => (6, address.read_u32(0x2c), None),
+            HEADER_PCI_BRIDGE => {
+                let buses = address.read_u32(0x18);
+                (2, 0, Some(((buses >> 8) as u8, (buses >> 16) as u8)))
+            }
+            _ => (0, 0, None),
+        };
+        let interrupt = address.read_u16(0x3c);
+
+        Some(PciDevice {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            subsystem_vendor_id: subsystem as u16,
+            subsystem_id: (subsystem >> 16) as u16,
+            interrupt_pin: (interrupt >> 8) as u8,
+            interrupt_line: interrupt as u8,
+            bars: bar::probe(address, bar_count),
+            capabilities: capability::walk(address, header_type),
+            bridged_buses,
+        })
+    }
+
+    /// Returns the decoded class, or `None` if the class code is unknown.
+    pub fn device_class(&self) -> Option<PciDeviceClass> {
+        PciDeviceClass::new(self.class, self.subclass, self.prog_if).ok()
+    }
+
+    /// Returns the vendor from the vendor database, if it is known.
+    pub fn vendor(&self) -> Option<&'static Vendor> {
+        get_vendor(self.vendor_id)
+    }
+
+    /// Returns the device from the vendor database, if it is known.
+    pub fn device(&self) -> Option<&'static Device> {
+        self.vendor()?.get_device(self.device_id)
     }
 
-    let vendor_id = (vid_did & 0xFFFF) as u16;
-    let device_id = (vid_did >> 16) as u16;
-    let class_subclass = pci_read_u32(bus, slot, 0, 8);
-    let class = (class_subclass >> 24) as u8;
-    let subclass = (class_subclass >> 16) as u8;
-    let prog_if = (class_subclass >> 8) as u8;
+    /// Returns BAR `index`, if it is implemented.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
 
-    let vendor = get_vendor(vendor_id)?;
-    let device = vendor.get_device(device_id)?;
+    /// Returns the first capability with the ID `id`.
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().copied().find(|cap| cap.id == id)
+    }
 
-    let device_class = PciDeviceClass::new(class, subclass, prog_if).ok()?;
+    /// Returns every capability with the ID `id`.
+    pub fn capabilities_with_id(&self, id: u8) -> impl Iterator<Item = Capability> + '_ {
+        self.capabilities
+            .iter()
+            .copied()
+            .filter(move |cap| cap.id == id)
+    }
+
+    /// Returns `true` if the function is a PCI-to-PCI bridge.
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HEADER_PCI_BRIDGE
+    }
+
+    /// Sets the bits `set` and clears the bits `clear` in the command register.
+    ///
+    /// # Safety
+    /// Enabling decoding or bus mastering lets the function access memory, so the caller must own it and have
+    /// programmed it correctly.
+    pub unsafe fn update_command(&self, set: u16, clear: u16) {
+        let command = self.address.read_u16(command::OFFSET);
+        // SAFETY: The caller owns the function.
+        unsafe {
+            self.address
+                .write_u16(command::OFFSET, (command | set) & !clear)
+        };
+    }
+}
 
-    Some(PCIDevice {
-        bus,
-        slot,
-        vendor,
-        device,
-        device_class,
-    })
+/// Reads the header type of the function at `address`, including the multi-function bit.
+pub(super) fn header_type(address: PciAddress) -> u8 {
+    address.read_u8(0x0e)
 }
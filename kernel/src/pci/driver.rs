@@ -0,0 +1,375 @@
+//! The PCI driver model.
+//!
+//! A driver implements [PciDriver], with a static table of the functions it supports, and its module registers the
+//! static [DriverEntry] of the driver with [register_driver] when it is initialized. Every function found during
+//! enumeration that matches the table and isn't bound to a driver yet is then probed, receiving a [PciHandle] that owns
+//! its resources. The state the driver returns stays bound to the function until it is [unbind]ed.
+use core::fmt::{self, Debug};
+
+use alloc::{boxed::Box, vec::Vec};
+use cake::{
+    Mutex,
+    log::{info, warn},
+};
+
+use super::{PciAddress, PciDevice, handle::PciHandle, msi::MsiError};
+use crate::interrupts::irq::IrqError;
+
+/// An entry of the ID table of a driver. Every field that is set must match for a function to match the entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDeviceId {
+    /// The vendor ID to match.
+    pub vendor_id: Option<u16>,
+    /// The device ID to match.
+    pub device_id: Option<u16>,
+    /// The base class to match.
+    pub class: Option<u8>,
+    /// The subclass to match.
+    pub subclass: Option<u8>,
+    /// The programming interface to match.
+    pub prog_if: Option<u8>,
+}
+
+impl PciDeviceId {
+    /// Matches the functions with the vendor ID `vendor_id` and the device ID `device_id`.
+    pub const fn new(vendor_id: u16, device_id: u16) -> Self {
+        PciDeviceId {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// Matches the functions of the base class `class` and the subclass `subclass`, from any vendor.
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        PciDeviceId {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if: None,
+        }
+    }
+
+    /// Further restricts the entry to the programming interface `prog_if`.
+    pub const fn with_prog_if(mut self, prog_if: u8) -> Self {
+        self.prog_if = Some(prog_if);
+        self
+    }
+
+    /// Returns `true` if `device` matches the entry.
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn field<T: PartialEq>(expected: Option<T>, actual: T) -> bool {
+            expected.is_none_or(|expected| expected == actual)
+        }
+        field(self.vendor_id, device.vendor_id)
+            && field(self.device_id, device.device_id)
+            && field(self.class, device.class)
+            && field(self.subclass, device.subclass)
+            && field(self.prog_if, device.prog_if)
+    }
+}
+
+/// An error that prevented a driver from binding to a function.
+#[derive(Debug, thiserror::Error)]
+pub enum ProbeError {
+    /// The function isn't supported after all, for example because it lacks a feature.
+    #[error("Unsupported device: {0}")]
+    Unsupported(&'static str),
+    /// The function failed to initialize.
+    #[error("Device failed to initialize: {0}")]
+    Device(&'static str),
+    /// A BAR the driver needs isn't implemented or is of the wrong kind.
+    #[error("Invalid BAR {0}")]
+    InvalidBar(usize),
+    /// The function doesn't support the interrupts requested.
+    #[error("No interrupts available")]
+    NoInterrupts,
+    /// Message signaled interrupts couldn't be enabled.
+    #[error(transparent)]
+    Msi(#[from] MsiError),
+    /// An interrupt handler couldn't be registered.
+    #[error(transparent)]
+    Irq(#[from] IrqError),
+}
+
+/// A driver for PCI functions.
+pub trait PciDriver: 'static {
+    /// The name of the driver.
+    const NAME: &'static str;
+    /// The functions the driver supports.
+    const IDS: &'static [PciDeviceId];
+    /// The state of the driver for every function it is bound to. It owns the [PciHandle] of the function, and is
+    /// shown in the device listing.
+    type State: Debug + Send + 'static;
+
+    /// Initializes the function owned by `handle`, which matches the entry `id` of the ID table.
+    fn probe(handle: PciHandle, id: &PciDeviceId) -> Result<Self::State, ProbeError>;
+
+    /// Shuts the function down before it is unbound. The resources of its handle are released when `state` is dropped.
+    fn remove(state: Self::State) {
+        drop(state);
+    }
+}
+
+/// A type erased [PciDriver], declared as a static with [DriverEntry::new] and registered with [register_driver].
+pub struct DriverEntry {
+    /// The name of the driver.
+    pub name: &'static str,
+    /// The functions the driver supports.
+    pub ids: &'static [PciDeviceId],
+    probe: ProbeFn,
+}
+
+/// Probes a function with a [PciDriver], erasing the type of its state.
+type ProbeFn = fn(PciHandle, &PciDeviceId) -> Result<Box<dyn BoundState>, ProbeError>;
+
+impl DriverEntry {
+    /// Creates the entry of the driver `D`.
+    pub const fn new<D: PciDriver>() -> Self {
+        DriverEntry {
+            name: D::NAME,
+            ids: D::IDS,
+            probe: probe::<D>,
+        }
+    }
+
+    /// Returns the first entry of the ID table matching `device`.
+    fn matching(&self, device: &PciDevice) -> Option<&'static PciDeviceId> {
+        self.ids.iter().find(|id| id.matches(device))
+    }
+}
+
+impl Debug for DriverEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DriverEntry")
+            .field("name", &self.name)
+            .field("ids", &self.ids)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The state of a driver bound to a function, with the type of the driver erased.
+trait BoundState: Debug + Send {
+    fn remove(self: Box<Self>);
+}
+
+/// Wraps the state of the driver `D`, so it is removed by the right driver.
+struct State<D: PciDriver>(D::State);
+
+impl<D: PciDriver> Debug for State<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<D: PciDriver> BoundState for State<D> {
+    fn remove(self: Box<Self>) {
+        D::remove(self.0);
+    }
+}
+
+fn probe<D: PciDriver>(
+    handle: PciHandle,
+    id: &PciDeviceId,
+) -> Result<Box<dyn BoundState>, ProbeError> {
+    Ok(Box::new(State::<D>(D::probe(handle, id)?)))
+}
+
+/// A driver bound to a function.
+#[derive(Debug)]
+struct Binding {
+    address: PciAddress,
+    driver: &'static DriverEntry,
+    state: Box<dyn BoundState>,
+}
+
+/// The registered drivers.
+static DRIVERS: Mutex<Vec<&'static DriverEntry>> = Mutex::new(Vec::new());
+/// The functions bound to a driver.
+static BINDINGS: Mutex<Vec<Binding>> = Mutex::new(Vec::new());
+/// The functions a driver is being probed against, which no other driver may claim meanwhile. Locked after
+/// [BINDINGS].
+static PROBING: Mutex<Vec<PciAddress>> = Mutex::new(Vec::new());
+
+/// Reserves the function at `address` for a probe. Returns `false` if it is bound or already being probed.
+fn reserve(address: PciAddress) -> bool {
+    let bindings = BINDINGS.lock();
+    let mut probing = PROBING.lock();
+    if probing.contains(&address) || bindings.iter().any(|binding| binding.address == address) {
+        return false;
+    }
+    probing.push(address);
+    true
+}
+
+/// Releases the reservation of the function at `address` once its probe is over.
+fn release(address: PciAddress) {
+    PROBING.lock().retain(|probing| *probing != address);
+}
+
+/// Registers `driver` and probes it against every matching function that isn't bound yet. Returns the number of
+/// functions it was bound to.
+pub fn register_driver(driver: &'static DriverEntry) -> usize {
+    DRIVERS.lock().push(driver);
+    let mut bound = 0;
+    for device in super::devices() {
+        let Some(id) = driver.matching(device) else {
+            continue;
+        };
+        // The function is reserved rather than the lock held while probing, drivers may take a while and look at the
+        // bindings.
+        if !reserve(device.address) {
+            continue;
+        }
+        match (driver.probe)(PciHandle::new(device), id) {
+            Ok(state) => {
+                info!("PCI {}: bound to {}", device.address, driver.name);
+                BINDINGS.lock().push(Binding {
+                    address: device.address,
+                    driver,
+                    state,
+                });
+                bound += 1;
+            }
+            Err(err) => warn!(
+                "PCI {}: {} failed to probe: {}",
+                device.address, driver.name, err
+            ),
+        }
+        release(device.address);
+    }
+    bound
+}
+
+/// Returns the registered drivers.
+pub fn drivers() -> Vec<&'static DriverEntry> {
+    DRIVERS.lock().clone()
+}
+
+/// Returns `true` if a driver is bound to the function at `address`.
+pub fn is_bound(address: PciAddress) -> bool {
+    BINDINGS
+        .lock()
+        .iter()
+        .any(|binding| binding.address == address)
+}
+
+/// Removes the driver bound to the function at `address`, releasing the resources of the function. Returns `false` if
+/// no driver was bound to it.
+pub fn unbind(address: PciAddress) -> bool {
+    let binding = {
+        let mut bindings = BINDINGS.lock();
+        let Some(index) = bindings
+            .iter()
+            .position(|binding| binding.address == address)
+        else {
+            return false;
+        };
+        bindings.remove(index)
+    };
+    info!("PCI {}: unbinding {}", address, binding.driver.name);
+    binding.state.remove();
+    true
+}
+
+/// Calls `f` with every function found during enumeration, the name of the driver bound to it and the state of that
+/// driver.
+pub fn for_each_device(mut f: impl FnMut(&'static PciDevice, Option<(&'static str, &dyn Debug)>)) {
+    let bindings = BINDINGS.lock();
+    for device in super::devices() {
+        let binding = bindings
+            .iter()
+            .find(|binding| binding.address == device.address)
+            .map(|binding| (binding.driver.name, &binding.state as &dyn Debug));
+        f(device, binding);
+    }
+}
+
+/// Logs every function found during enumeration along with the driver bound to it and its state.
+pub fn log_devices() {
+    for_each_device(|device, binding| match binding {
+        Some((driver, state)) => info!(
+            "PCI {} [{:04x}:{:04x}] {}: {:?}",
+            device.address, device.vendor_id, device.device_id, driver, state
+        ),
+        None => info!(
+            "PCI {} [{:04x}:{:04x}] unbound",
+            device.address, device.vendor_id, device.device_id
+        ),
+    });
+}
+
+#[kproc::test("PCI driver binding")]
+fn pci_driver_binding() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static REMOVED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct HostBridge {
+        #[allow(dead_code)]
+        handle: PciHandle,
+    }
+
+    struct HostBridgeDriver;
+
+    impl PciDriver for HostBridgeDriver {
+        const NAME: &'static str = "test-host-bridge";
+        const IDS: &'static [PciDeviceId] = &[PciDeviceId::class(0x06, 0x00)];
+        type State = HostBridge;
+
+        fn probe(handle: PciHandle, id: &PciDeviceId) -> Result<HostBridge, ProbeError> {
+            assert!(id.matches(handle.device()));
+            Ok(HostBridge { handle })
+        }
+
+        fn remove(state: HostBridge) {
+            REMOVED.fetch_add(1, Ordering::SeqCst);
+            drop(state);
+        }
+    }
+
+    static PCI_DRIVER: DriverEntry = DriverEntry::new::<HostBridgeDriver>();
+
+    let host = PciAddress::new(0, 0, 0);
+    // Leave the command registers of the host bridges as they were, the handles clear them when dropped.
+    let bridges: Vec<_> = super::find_by_class(0x06, 0x00)
+        .map(|device| {
+            let address = device.address;
+            (address, address.read_u16(super::command::OFFSET))
+        })
+        .collect();
+    let bound = register_driver(&PCI_DRIVER);
+    assert!(bound >= 1);
+    assert!(is_bound(host));
+    assert_eq!(
+        register_driver(&PCI_DRIVER),
+        0,
+        "Functions are only bound once"
+    );
+
+    let mut listed = 0;
+    for_each_device(|device, binding| {
+        if let Some((driver, _)) = binding
+            && driver == HostBridgeDriver::NAME
+        {
+            assert!(PCI_DRIVER.matching(device).is_some());
+            listed += 1;
+        }
+    });
+    assert_eq!(listed, bound);
+
+    for &(address, command) in &bridges {
+        assert!(unbind(address));
+        assert!(!unbind(address));
+        // SAFETY: The host bridge is restored to the state it was in before the test.
+        unsafe { address.write_u16(super::command::OFFSET, command) };
+    }
+    assert_eq!(REMOVED.load(Ordering::SeqCst), bound);
+    DRIVERS
+        .lock()
+        .retain(|driver| !core::ptr::eq(*driver, &PCI_DRIVER));
+}
@@ -0,0 +1,134 @@
+//! The PCI Express Enhanced Configuration Access Mechanism, which exposes the full 4 KiB configuration space of every
+//! function through memory.
+//!
+//! The MCFG table describes a window of physical memory per segment group and range of buses. Every bus takes 1 MiB of
+//! its window and is only mapped the first time one of its functions is accessed, since most windows span 256 buses
+//! that are nearly all empty.
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use ::acpi::sdt::mcfg::Mcfg;
+use alloc::vec::Vec;
+use cake::{Once, log::info};
+use nmm::MapFlags;
+use x86_64::PhysAddr;
+
+use super::PciAddress;
+use crate::acpi;
+
+/// The size of the configuration space of every function.
+pub const CONFIG_SPACE_SIZE: usize = 4096;
+/// The size of the part of a window covering one bus.
+const BUS_SIZE: usize = CONFIG_SPACE_SIZE * 8 * 32;
+
+/// The windows described by the MCFG table. Empty if there is none.
+static WINDOWS: Once<Vec<Window>> = Once::new();
+
+/// The configuration window of a range of buses in a segment group.
+#[derive(Debug)]
+pub struct Window {
+    segment: u16,
+    first_bus: u8,
+    last_bus: u8,
+    /// The address of the configuration space of bus 0, even if the window starts at a later bus.
+    base: PhysAddr,
+    /// The mapping of every bus in the range, null until it is first accessed.
+    buses: Vec<AtomicPtr<u8>>,
+}
+
+impl Window {
+    /// Returns the segment group the window belongs to.
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+
+    /// Returns the first bus covered by the window.
+    pub fn first_bus(&self) -> u8 {
+        self.first_bus
+    }
+
+    /// Returns the last bus covered by the window.
+    pub fn last_bus(&self) -> u8 {
+        self.last_bus
+    }
+
+    /// Returns `true` if the window covers the bus of `address`.
+    fn covers(&self, address: PciAddress) -> bool {
+        address.segment == self.segment && (self.first_bus..=self.last_bus).contains(&address.bus)
+    }
+
+    /// Returns the mapping of the bus of `address`, mapping it first if needed.
+    fn bus(&self, address: PciAddress) -> *mut u8 {
+        let slot = &self.buses[(address.bus - self.first_bus) as usize];
+        let mapped = slot.load(Ordering::Acquire);
+        if !mapped.is_null() {
+            return mapped;
+        }
+        let base = self.base + address.bus as u64 * BUS_SIZE as u64;
+        let mapping = nmm::create_phys_mapping(
+            base.into(),
+            BUS_SIZE,
+            MapFlags::CACHE_DISABLE | MapFlags::WRITABLE,
+        )
+        .expect("Failed to map ECAM window");
+        match slot.compare_exchange(
+            ptr::null_mut(),
+            mapping.as_mut_ptr(),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => mapping.as_mut_ptr(),
+            Err(mapped) => {
+                // Another core mapped the bus first.
+                // SAFETY: The mapping was never handed out.
+                unsafe { nmm::free_phys_mapping(mapping) }.expect("Failed to unmap ECAM window");
+                mapped
+            }
+        }
+    }
+}
+
+/// Reads the windows from the MCFG table, if there is one.
+pub(super) fn init() {
+    WINDOWS.call_once(|| {
+        let Ok(mcfg) = acpi::get_table::<Mcfg>() else {
+            info!("No MCFG table, using port IO for the PCI configuration space");
+            return Vec::new();
+        };
+        mcfg.table()
+            .entries()
+            .iter()
+            .map(|entry| {
+                let (base, segment) = (entry.base_address, entry.pci_segment_group);
+                let (first_bus, last_bus) = (entry.bus_number_start, entry.bus_number_end);
+                info!(
+                    "ECAM window at {:#x}: segment {}, buses {}..={}",
+                    base, segment, first_bus, last_bus
+                );
+                Window {
+                    segment,
+                    first_bus,
+                    last_bus,
+                    base: PhysAddr::new(base),
+                    buses: (first_bus..=last_bus)
+                        .map(|_| AtomicPtr::new(ptr::null_mut()))
+                        .collect(),
+                }
+            })
+            .collect()
+    });
+}
+
+/// Returns the windows described by the MCFG table.
+pub fn windows() -> &'static [Window] {
+    WINDOWS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns a pointer to the configuration space of the function at `address`, or `None` if no window covers it.
+pub(super) fn config_space(address: PciAddress) -> Option<*mut u8> {
+    let window = windows().iter().find(|window| window.covers(address))?;
+    let offset = ((address.device as usize & 0x1f) << 3 | (address.function as usize & 0x7))
+        * CONFIG_SPACE_SIZE;
+    // SAFETY: The offset of the function is within the mapping of its bus.
+    Some(unsafe { window.bus(address).add(offset) })
+}
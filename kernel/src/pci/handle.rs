@@ -0,0 +1,247 @@
+//! The handle through which a driver owns the resources of a function it is bound to.
+use alloc::vec::Vec;
+use nmm::{MapFlags, MemoryMapping};
+use x86_64::PhysAddr;
+
+use super::{
+    PciDevice, command,
+    driver::ProbeError,
+    msi::{Msi, MsiX},
+};
+use crate::{
+    interrupts::{self, IrqHandler},
+    mp,
+};
+
+/// A memory BAR mapped into the kernel's address space.
+///
+/// The BAR is unmapped and the function stops decoding it when the [PciHandle] that mapped it is dropped, which copies
+/// of the `MappedBar` can outlive. Accessing its registers is thus unsafe, the caller guarantees the handle is alive.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedBar {
+    base: *mut u8,
+    size: usize,
+}
+
+// SAFETY: The registers are only accessed through unsafe methods, whose callers guarantee the mapping is still valid,
+// with volatile operations.
+unsafe impl Send for MappedBar {}
+// SAFETY: See above.
+unsafe impl Sync for MappedBar {}
+
+impl MappedBar {
+    /// Returns a pointer to the start of the BAR.
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.base.cast()
+    }
+
+    /// Returns the size of the BAR in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the register of type `T` at `offset`.
+    ///
+    /// # Panics
+    /// Panics if the register isn't within the BAR or isn't aligned.
+    ///
+    /// # Safety
+    /// The [PciHandle] that mapped the BAR must not have been dropped.
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        // SAFETY: The register is within the mapping, which the caller guarantees is still valid, and device registers
+        // are only accessed with volatile operations.
+        unsafe { self.register::<T>(offset).read_volatile() }
+    }
+
+    /// Writes the register of type `T` at `offset`.
+    ///
+    /// # Panics
+    /// Panics if the register isn't within the BAR or isn't aligned.
+    ///
+    /// # Safety
+    /// The [PciHandle] that mapped the BAR must not have been dropped. Writing device registers can make the device
+    /// access arbitrary memory, the caller must uphold the rules of the device.
+    pub unsafe fn write<T: Copy>(&self, offset: usize, value: T) {
+        // SAFETY: The register is within the mapping, the caller guarantees it is still valid and the write is sound.
+        unsafe { self.register::<T>(offset).write_volatile(value) }
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + size_of::<T>() <= self.size && offset.is_multiple_of(align_of::<T>()),
+            "Register at {offset:#x} outside of the BAR"
+        );
+        self.base.wrapping_add(offset).cast()
+    }
+}
+
+/// The interrupts a function raises, and how they are delivered.
+#[derive(Debug)]
+enum Interrupts {
+    None,
+    Msi(Msi),
+    MsiX(MsiX),
+}
+
+/// Exclusive ownership of a function, handed to a driver when it is probed.
+///
+/// The BARs mapped and the interrupts requested through the handle are released when it is dropped, which happens when
+/// the driver is removed from the function. Bus mastering and address decoding are disabled along with them.
+#[derive(Debug)]
+pub struct PciHandle {
+    device: &'static PciDevice,
+    mappings: Vec<(usize, MemoryMapping)>,
+    interrupts: Interrupts,
+    handlers: Vec<(u8, IrqHandler, usize)>,
+}
+
+impl PciHandle {
+    pub(super) fn new(device: &'static PciDevice) -> Self {
+        PciHandle {
+            device,
+            mappings: Vec::new(),
+            interrupts: Interrupts::None,
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Returns the function the handle owns.
+    pub fn device(&self) -> &'static PciDevice {
+        self.device
+    }
+
+    /// Maps the memory BAR `index` uncached and enables memory decoding. Mapping the same BAR again returns the
+    /// existing mapping.
+    pub fn map_bar(&mut self, index: usize) -> Result<MappedBar, ProbeError> {
+        let bar = self
+            .device
+            .bar(index)
+            .filter(|bar| bar.is_memory())
+            .ok_or(ProbeError::InvalidBar(index))?;
+        let size = bar.size() as usize;
+        if let Some((_, mapping)) = self.mappings.iter().find(|(bar, _)| *bar == index) {
+            return Ok(MappedBar {
+                base: mapping.as_mut_ptr(),
+                size,
+            });
+        }
+        let mapping = nmm::create_phys_mapping(
+            PhysAddr::new(bar.address()).into(),
+            size,
+            MapFlags::CACHE_DISABLE | MapFlags::WRITABLE,
+        )
+        .map_err(|_| ProbeError::InvalidBar(index))?;
+        let mapped = MappedBar {
+            base: mapping.as_mut_ptr(),
+            size,
+        };
+        self.mappings.push((index, mapping));
+        // SAFETY: The function is owned by the handle and its BAR is now mapped.
+        unsafe { self.device.update_command(command::MEMORY_SPACE, 0) };
+        Ok(mapped)
+    }
+
+    /// Returns the first port of the IO BAR `index`, and enables IO decoding.
+    pub fn io_bar(&self, index: usize) -> Result<u16, ProbeError> {
+        let bar = self
+            .device
+            .bar(index)
+            .filter(|bar| !bar.is_memory())
+            .ok_or(ProbeError::InvalidBar(index))?;
+        // SAFETY: The function is owned by the handle.
+        unsafe { self.device.update_command(command::IO_SPACE, 0) };
+        Ok(bar.address() as u16)
+    }
+
+    /// Lets the function master the bus, which it needs to perform DMA.
+    pub fn enable_bus_master(&self) {
+        // SAFETY: The function is owned by the handle, whose driver programs the DMA addresses.
+        unsafe { self.device.update_command(command::BUS_MASTER, 0) };
+    }
+
+    /// Enables `count` interrupt vectors on the current core, through MSI-X if the function supports it and MSI
+    /// otherwise. Returns the number of vectors actually enabled, which is lower than `count` if the function doesn't
+    /// support that many. Handlers are then attached with [PciHandle::request_irq].
+    ///
+    /// MSI vectors can't always be masked, so they should be enabled before the function is allowed to master the
+    /// bus.
+    pub fn enable_interrupts(&mut self, count: usize) -> Result<usize, ProbeError> {
+        assert!(
+            matches!(self.interrupts, Interrupts::None),
+            "Interrupts already enabled"
+        );
+        let destination = mp::current_core_id() as u8;
+        if let Some(supported) = MsiX::supported_vectors(self.device) {
+            // SAFETY: The function is owned by the handle.
+            let msix = unsafe { MsiX::enable(self.device, count.min(supported), destination) }?;
+            let count = msix.count();
+            self.interrupts = Interrupts::MsiX(msix);
+            return Ok(count);
+        }
+        if let Some(supported) = Msi::supported_vectors(self.device) {
+            // SAFETY: The function is owned by the handle.
+            let msi = unsafe { Msi::enable(self.device, count.min(supported), destination) }?;
+            let count = msi.count();
+            self.interrupts = Interrupts::Msi(msi);
+            return Ok(count);
+        }
+        Err(ProbeError::NoInterrupts)
+    }
+
+    /// Returns the vector of the interrupt `index` enabled by [PciHandle::enable_interrupts].
+    pub fn vector(&self, index: usize) -> Option<u8> {
+        match &self.interrupts {
+            Interrupts::None => None,
+            Interrupts::Msi(msi) => (index < msi.count()).then(|| msi.vector(index)),
+            Interrupts::MsiX(msix) => (index < msix.count()).then(|| msix.vector(index)),
+        }
+    }
+
+    /// Registers `handler` to be called with `cookie` for the interrupt `index`, and unmasks it.
+    pub fn request_irq(
+        &mut self,
+        index: usize,
+        handler: IrqHandler,
+        cookie: usize,
+    ) -> Result<u8, ProbeError> {
+        let vector = self.vector(index).ok_or(ProbeError::NoInterrupts)?;
+        interrupts::register_irq(vector, handler, cookie)?;
+        self.handlers.push((vector, handler, cookie));
+        match &self.interrupts {
+            Interrupts::MsiX(msix) => msix.unmask(index),
+            Interrupts::Msi(msi) => {
+                // Functions without per-vector masking can't mask, their vectors are already live.
+                let _ = msi.unmask(index);
+            }
+            Interrupts::None => {}
+        }
+        Ok(vector)
+    }
+}
+
+impl Drop for PciHandle {
+    fn drop(&mut self) {
+        for (vector, handler, cookie) in self.handlers.drain(..) {
+            interrupts::unregister_irq(vector, handler, cookie);
+        }
+        // The MSI-X table lives in a BAR, so interrupts are turned off while the function still decodes memory.
+        match core::mem::replace(&mut self.interrupts, Interrupts::None) {
+            Interrupts::None => {}
+            Interrupts::Msi(msi) => msi.disable(),
+            Interrupts::MsiX(msix) => msix.disable(),
+        }
+        // SAFETY: The function is owned by the handle, and stops accessing memory and decoding addresses before the
+        // resources it used are released.
+        unsafe {
+            self.device.update_command(
+                command::INTERRUPT_DISABLE,
+                command::BUS_MASTER | command::MEMORY_SPACE | command::IO_SPACE,
+            )
+        };
+        for (_, mapping) in self.mappings.drain(..) {
+            // SAFETY: The registers of the mapped BARs handed out are only accessed while the handle is alive, and
+            // decoding is disabled.
+            unsafe { nmm::free_phys_mapping(mapping) }.expect("Failed to unmap BAR");
+        }
+    }
+}
@@ -1,52 +1,179 @@
-#![allow(dead_code, missing_docs)]
-//! This whole module is deprecated and is pending a rewrite.
+//! PCI enumeration and the registry of the functions found.
+//!
+//! During initialization, every bus reachable from the host bridges is scanned, recursing into PCI-to-PCI bridges. Every
+//! function found is recorded with its header, sized BARs and capability list, whether or not its vendor is known, and
+//! can then be looked up with [devices], [find] and friends.
+use core::convert::Infallible;
+
 use alloc::vec::Vec;
-use cake::Mutex;
-use device::{PCIDevice, pci_get_device};
-use x86_64::instructions::port::Port;
+use cake::{Once, log::info};
 
 use crate::declare_module;
 
-mod class;
+mod bar;
+mod capability;
+pub mod class;
+mod config;
 mod device;
+pub mod driver;
+pub mod ecam;
+mod handle;
+pub mod msi;
 mod vendor_device;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
-pub enum PCIInitError {}
+pub use bar::Bar;
+pub use capability::Capability;
+pub use config::PciAddress;
+pub use device::{
+    HEADER_CARDBUS_BRIDGE, HEADER_GENERAL, HEADER_PCI_BRIDGE, PciDevice, command, status,
+};
+pub use driver::{DriverEntry, PciDeviceId, PciDriver, ProbeError, register_driver, unbind};
+pub use handle::{MappedBar, PciHandle};
+pub use vendor_device::{Device, SubDevice, Vendor};
+
+/// The functions found during enumeration, ordered by address.
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
 
-static PCI_DEVICES: Mutex<Vec<PCIDevice>> = Mutex::new(Vec::new());
+declare_module!("PCI", init);
 
-fn init() -> Result<(), PCIInitError> {
-    let mut pci_devices = PCI_DEVICES.lock();
-    for i in 0..255 {
-        for j in 0..32 {
-            if let Some(device) = pci_get_device(i, j) {
-                pci_devices.push(device);
+fn init() -> Result<(), Infallible> {
+    ecam::init();
+    let mut devices = Vec::new();
+    let mut segments: Vec<_> = ecam::windows()
+        .iter()
+        .map(|window| (window.segment(), window.first_bus()))
+        .collect();
+    // Only the first bus of every segment group is a root, the others are reached through bridges.
+    segments.sort();
+    segments.dedup_by_key(|(segment, _)| *segment);
+    if segments.is_empty() {
+        segments.push((0, 0));
+    }
+    for (segment, bus) in segments {
+        let host = PciAddress::in_segment(segment, bus, 0, 0);
+        if device::header_type(host) & 0x80 == 0 {
+            scan_bus(segment, bus, &mut devices);
+        } else {
+            // Every function of a multi-function host bridge is the host controller of the bus with its number. A
+            // bus past 255 can't exist, so the host controller claiming it is skipped.
+            for function in 0..8 {
+                if PciAddress::in_segment(segment, bus, 0, function).read_u16(0x00) != 0xffff
+                    && let Some(bus) = bus.checked_add(function)
+                {
+                    scan_bus(segment, bus, &mut devices);
+                }
             }
         }
     }
+    devices.sort_by_key(|device| device.address);
+
+    for device in &devices {
+        info!(
+            "PCI {} [{:04x}:{:04x}] class {:02x}.{:02x}.{:02x}{}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device
+                .device()
+                .map_or_else(Default::default, |dev| alloc::format!(" {}", dev.name)),
+        );
+    }
+    DEVICES.call_once(|| devices);
     Ok(())
 }
 
-declare_module!("PCI", init, PCIInitError);
+/// Records every function on `bus` and the buses behind its bridges.
+fn scan_bus(segment: u16, bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let Some(first) = PciDevice::probe(PciAddress::in_segment(segment, bus, device, 0)) else {
+            continue;
+        };
+        let multi_function = device::header_type(first.address) & 0x80 != 0;
+        scan_function(first, devices);
+        if multi_function {
+            for function in 1..8 {
+                let address = PciAddress::in_segment(segment, bus, device, function);
+                if let Some(found) = PciDevice::probe(address) {
+                    scan_function(found, devices);
+                }
+            }
+        }
+    }
+}
+
+/// Records `function`, and scans the bus behind it if it is a bridge.
+fn scan_function(function: PciDevice, devices: &mut Vec<PciDevice>) {
+    let PciAddress { segment, bus, .. } = function.address;
+    let secondary = function
+        .bridged_buses
+        .map(|(secondary, _)| secondary)
+        // A bridge that the firmware didn't configure, or one looping back, has nothing to scan.
+        .filter(|secondary| *secondary > bus);
+    devices.push(function);
+    if let Some(secondary) = secondary {
+        scan_bus(segment, secondary, devices);
+    }
+}
 
-static PCI_COMMS: Mutex<(Port<u32>, Port<u32>)> = Mutex::new((Port::new(0xCF8), Port::new(0xCFC)));
+/// Returns every function found during enumeration, ordered by address. Empty before initialization.
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
 
-fn pci_read_u32(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
-    let mut comms = PCI_COMMS.lock();
-    let (cfg, data) = &mut *comms;
+/// Returns the function at `address`.
+pub fn find(address: PciAddress) -> Option<&'static PciDevice> {
+    devices().iter().find(|device| device.address == address)
+}
 
-    let bus = (bus as u32) << 16;
-    let slot = (slot as u32) << 11;
-    let func = (func as u32) << 8;
-    let offset = (offset as u32) & 0xFC;
+/// Returns every function with the vendor ID `vendor_id` and the device ID `device_id`.
+pub fn find_by_id(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
 
-    let mut address: u32 = 0x8000_0000;
+/// Returns every function with the base class `class` and the subclass `subclass`.
+pub fn find_by_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
 
-    address |= bus | slot | func | offset;
+#[kproc::test("PCI enumeration")]
+fn pci_enumeration() {
+    let host = find(PciAddress::new(0, 0, 0)).expect("No host bridge");
+    assert_eq!((host.class, host.subclass), (0x06, 0x00));
+    assert!(find_by_class(0x06, 0x00).any(|device| device.address == host.address));
+    assert!(find_by_id(host.vendor_id, host.device_id).count() >= 1);
 
-    unsafe {
-        cfg.write(address);
-        data.read()
+    for (index, device) in devices().iter().enumerate() {
+        assert_ne!(device.vendor_id, 0xffff);
+        // Every function is recorded once.
+        assert!(
+            devices()[index + 1..]
+                .iter()
+                .all(|other| other.address != device.address)
+        );
+        for bar in device.bars.iter().flatten() {
+            assert!(
+                bar.size().is_power_of_two(),
+                "{}: {:?}",
+                device.address,
+                bar
+            );
+            assert_eq!(
+                bar.address() % bar.size(),
+                0,
+                "{}: {:?}",
+                device.address,
+                bar
+            );
+        }
+        for capability in &device.capabilities {
+            assert!(capability.offset >= 0x40 && capability.offset & 0x3 == 0);
+        }
     }
 }
@@ -0,0 +1,473 @@
+//! Message Signaled Interrupts.
+//!
+//! Instead of asserting an interrupt line routed through the IOAPIC, a function using MSI or MSI-X raises an interrupt
+//! by writing a message to the address of a LAPIC, which then delivers the vector encoded in the message. Every vector
+//! is allocated from the dynamic range of the IDT, so handlers are registered with
+//! [register_irq](crate::interrupts::register_irq) like for any other device interrupt.
+//!
+//! MSI supports a block of up to 32 consecutive vectors sharing one message address, and can only mask them
+//! individually if the function implements per-vector masking. MSI-X supports up to 2048 independent vectors, each with
+//! its own message and mask bit in a table that lives in one of the BARs of the function.
+use alloc::vec::Vec;
+use nmm::{MapFlags, MemoryMapping};
+use x86_64::PhysAddr;
+
+use super::{Capability, PciAddress, PciDevice, command};
+use crate::interrupts::vector;
+
+/// The address of the LAPICs in the MSI address space, with the destination APIC ID in bits 12 to 19.
+const LAPIC_MESSAGE_BASE: u64 = 0xfee0_0000;
+
+/// The offset of the message control register in both capabilities.
+const MESSAGE_CONTROL: u16 = 0x02;
+
+/// MSI message control: MSI is enabled.
+const MSI_ENABLE: u16 = 1 << 0;
+/// MSI message control: the function supports 64-bit message addresses.
+const MSI_64BIT: u16 = 1 << 7;
+/// MSI message control: the function supports per-vector masking.
+const MSI_PER_VECTOR_MASK: u16 = 1 << 8;
+
+/// MSI-X message control: MSI-X is enabled.
+const MSIX_ENABLE: u16 = 1 << 15;
+/// MSI-X message control: every vector of the function is masked, whatever its own mask bit says.
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+/// The size of an MSI-X table entry.
+const MSIX_ENTRY_SIZE: usize = 16;
+/// MSI-X vector control: the vector is masked.
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// An error that occurred while enabling message signaled interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum MsiError {
+    /// The function doesn't have the capability.
+    #[error("{0} doesn't support {1}")]
+    Unsupported(PciAddress, &'static str),
+    /// More vectors were requested than the function supports, or none at all.
+    #[error("{0} supports {1} vectors, {2} requested")]
+    VectorCount(PciAddress, usize, usize),
+    /// There aren't enough free vectors in the IDT.
+    #[error("No free vectors left")]
+    NoFreeVector,
+    /// The MSI-X table is in a BAR that isn't implemented or isn't in memory space.
+    #[error("{0} has its MSI-X table in the invalid BAR {1}")]
+    InvalidTableBar(PciAddress, u8),
+    /// The function doesn't support masking vectors individually.
+    #[error("{0} doesn't support masking MSI vectors")]
+    MaskingUnsupported(PciAddress),
+}
+
+/// A message that raises `vector` on the LAPIC with the APIC ID `destination` when written by a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    /// The address the function writes the message to.
+    pub address: u64,
+    /// The data written, which encodes the vector and the delivery mode.
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// Creates an edge-triggered message with fixed delivery of `vector` to the LAPIC with the APIC ID `destination`,
+    /// in physical destination mode.
+    pub fn new(destination: u8, vector: u8) -> Self {
+        MsiMessage {
+            address: LAPIC_MESSAGE_BASE | (destination as u64) << 12,
+            data: vector as u32,
+        }
+    }
+}
+
+/// The MSI capability of a function, with a block of vectors allocated to it.
+#[derive(Debug)]
+pub struct Msi {
+    address: PciAddress,
+    offset: u16,
+    control: u16,
+    first_vector: u8,
+    count: u8,
+}
+
+impl Msi {
+    /// Returns the maximum number of vectors `device` supports through MSI, or `None` if it doesn't support MSI.
+    pub fn supported_vectors(device: &PciDevice) -> Option<usize> {
+        let cap = device.capability(Capability::MSI)?;
+        let control = device.address.read_u16(cap.offset + MESSAGE_CONTROL);
+        Some(1 << ((control >> 1) & 0x7).min(5))
+    }
+
+    /// Allocates a block of `count` vectors, rounded up to a power of two and at least one, and makes `device` raise
+    /// them on the LAPIC with the APIC ID `destination`. Legacy INTx interrupts of the function are disabled.
+    ///
+    /// The vectors are live as soon as this returns, so their handlers should be registered beforehand. The function
+    /// chooses which vector of the block it raises for which event.
+    ///
+    /// # Safety
+    /// The caller must own `device`.
+    pub unsafe fn enable(
+        device: &PciDevice,
+        count: usize,
+        destination: u8,
+    ) -> Result<Self, MsiError> {
+        let address = device.address;
+        let cap = device
+            .capability(Capability::MSI)
+            .ok_or(MsiError::Unsupported(address, "MSI"))?;
+        let supported = Self::supported_vectors(device).unwrap_or(1);
+        let count = count.next_power_of_two();
+        if count > supported {
+            return Err(MsiError::VectorCount(address, supported, count));
+        }
+        let first_vector = vector::allocate_block(count as u8).ok_or(MsiError::NoFreeVector)?;
+
+        let offset = cap.offset;
+        let control = address.read_u16(offset + MESSAGE_CONTROL);
+        let message = MsiMessage::new(destination, first_vector);
+        // The data register follows the upper half of the address if the function supports 64-bit addresses.
+        let data_offset = if control & MSI_64BIT != 0 { 0x0c } else { 0x08 };
+        let multiple_message = (count.trailing_zeros() as u16) << 4;
+        // SAFETY: The caller owns the function, and the message targets vectors that were just allocated to it.
+        unsafe {
+            device.update_command(command::INTERRUPT_DISABLE, 0);
+            address.write_u16(offset + MESSAGE_CONTROL, control & !MSI_ENABLE);
+            address.write_u32(offset + 0x04, message.address as u32);
+            if control & MSI_64BIT != 0 {
+                address.write_u32(offset + 0x08, (message.address >> 32) as u32);
+            }
+            address.write_u16(offset + data_offset, message.data as u16);
+            address.write_u16(
+                offset + MESSAGE_CONTROL,
+                (control & !(0x7 << 4)) | multiple_message | MSI_ENABLE,
+            );
+        }
+        Ok(Msi {
+            address,
+            offset,
+            control,
+            first_vector,
+            count: count as u8,
+        })
+    }
+
+    /// Returns the vector with the index `index` in the block.
+    ///
+    /// # Panics
+    /// Panics if `index` is out of bounds.
+    pub fn vector(&self, index: usize) -> u8 {
+        assert!(
+            index < self.count as usize,
+            "MSI vector {index} out of bounds"
+        );
+        self.first_vector + index as u8
+    }
+
+    /// Returns the number of vectors in the block.
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    /// Masks the vector with the index `index`, so the function holds it pending instead of raising it.
+    pub fn mask(&self, index: usize) -> Result<(), MsiError> {
+        self.set_masked(index, true)
+    }
+
+    /// Unmasks the vector with the index `index`. A pending interrupt is raised right away.
+    pub fn unmask(&self, index: usize) -> Result<(), MsiError> {
+        self.set_masked(index, false)
+    }
+
+    fn set_masked(&self, index: usize, masked: bool) -> Result<(), MsiError> {
+        if self.control & MSI_PER_VECTOR_MASK == 0 {
+            return Err(MsiError::MaskingUnsupported(self.address));
+        }
+        let bit = 1 << (self.vector(index) - self.first_vector);
+        let mask_offset = self.offset
+            + if self.control & MSI_64BIT != 0 {
+                0x10
+            } else {
+                0x0c
+            };
+        let mask = self.address.read_u32(mask_offset);
+        let mask = if masked { mask | bit } else { mask & !bit };
+        // SAFETY: Only the mask bits of vectors owned by this block change.
+        unsafe { self.address.write_u32(mask_offset, mask) };
+        Ok(())
+    }
+
+    /// Disables MSI on the function and frees the vectors. Their handlers must have been unregistered.
+    pub fn disable(self) {
+        let control = self.address.read_u16(self.offset + MESSAGE_CONTROL);
+        // SAFETY: The function stops sending messages, the vectors are only freed afterwards.
+        unsafe {
+            self.address
+                .write_u16(self.offset + MESSAGE_CONTROL, control & !MSI_ENABLE)
+        };
+        for vector in self.first_vector..self.first_vector + self.count {
+            vector::free(vector);
+        }
+    }
+}
+
+/// The MSI-X capability of a function, with its table mapped and a vector allocated for some of its entries.
+#[derive(Debug)]
+pub struct MsiX {
+    address: PciAddress,
+    offset: u16,
+    /// The mapping of the page the table starts at, up to its end.
+    mapping: MemoryMapping,
+    table: *mut u32,
+    table_size: usize,
+    vectors: Vec<u8>,
+}
+
+// SAFETY: The table is only accessed with volatile reads and writes of whole entries owned by the capability.
+unsafe impl Send for MsiX {}
+// SAFETY: See above.
+unsafe impl Sync for MsiX {}
+
+impl MsiX {
+    /// Returns the number of entries in the MSI-X table of `device`, or `None` if it doesn't support MSI-X.
+    pub fn supported_vectors(device: &PciDevice) -> Option<usize> {
+        let cap = device.capability(Capability::MSI_X)?;
+        let control = device.address.read_u16(cap.offset + MESSAGE_CONTROL);
+        Some((control & 0x7ff) as usize + 1)
+    }
+
+    /// Maps the MSI-X table of `device`, allocates a vector for each of its first `count` entries and makes them raise
+    /// it on the LAPIC with the APIC ID `destination`. MSI-X is enabled and legacy INTx interrupts of the function are
+    /// disabled.
+    ///
+    /// Every entry starts masked, and is unmasked with [MsiX::unmask] once its handler is registered.
+    ///
+    /// # Safety
+    /// The caller must own `device`.
+    pub unsafe fn enable(
+        device: &PciDevice,
+        count: usize,
+        destination: u8,
+    ) -> Result<Self, MsiError> {
+        let address = device.address;
+        let cap = device
+            .capability(Capability::MSI_X)
+            .ok_or(MsiError::Unsupported(address, "MSI-X"))?;
+        let table_size = Self::supported_vectors(device).unwrap_or(0);
+        if count == 0 || count > table_size {
+            return Err(MsiError::VectorCount(address, table_size, count));
+        }
+
+        let offset = cap.offset;
+        let table_location = address.read_u32(offset + 0x04);
+        let bir = (table_location & 0x7) as u8;
+        let bar = device
+            .bar(bir as usize)
+            .filter(|bar| bar.is_memory())
+            .ok_or(MsiError::InvalidTableBar(address, bir))?;
+        // The table is only required to be 8 byte aligned, so the mapping starts at its page.
+        let table_address = PhysAddr::new(bar.address() + (table_location & !0x7) as u64);
+        let page = table_address.align_down(4096u64);
+        let skew = (table_address - page) as usize;
+        let mapping = nmm::create_phys_mapping(
+            page.into(),
+            skew + table_size * MSIX_ENTRY_SIZE,
+            MapFlags::CACHE_DISABLE | MapFlags::WRITABLE,
+        )
+        .expect("Failed to map MSI-X table");
+
+        let mut vectors = Vec::with_capacity(count);
+        for _ in 0..count {
+            let Some(vector) = vector::allocate() else {
+                vectors.into_iter().for_each(vector::free);
+                // SAFETY: The table was never handed out.
+                unsafe { nmm::free_phys_mapping(mapping) }.expect("Failed to unmap MSI-X table");
+                return Err(MsiError::NoFreeVector);
+            };
+            vectors.push(vector);
+        }
+        let msix = MsiX {
+            address,
+            offset,
+            mapping,
+            table: mapping.as_mut_ptr::<u8>().wrapping_add(skew).cast(),
+            table_size,
+            vectors,
+        };
+
+        let control = address.read_u16(offset + MESSAGE_CONTROL);
+        // SAFETY: The caller owns the function. Its entries are programmed while the whole function is masked, and
+        // only target the vectors just allocated to it.
+        unsafe {
+            device.update_command(command::MEMORY_SPACE | command::INTERRUPT_DISABLE, 0);
+            address.write_u16(
+                offset + MESSAGE_CONTROL,
+                control | MSIX_ENABLE | MSIX_FUNCTION_MASK,
+            );
+            for (index, vector) in msix.vectors.iter().enumerate() {
+                msix.write_entry(index, MsiMessage::new(destination, *vector), true);
+            }
+            address.write_u16(
+                offset + MESSAGE_CONTROL,
+                (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+            );
+        }
+        Ok(msix)
+    }
+
+    /// Returns the vector allocated to the entry `index`.
+    ///
+    /// # Panics
+    /// Panics if no vector was allocated to the entry.
+    pub fn vector(&self, index: usize) -> u8 {
+        self.vectors[index]
+    }
+
+    /// Returns the number of entries that have a vector.
+    pub fn count(&self) -> usize {
+        self.vectors.len()
+    }
+
+    /// Returns the number of entries of the table.
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+
+    /// Masks the entry `index`, so the function holds its interrupts pending instead of raising them.
+    pub fn mask(&self, index: usize) {
+        self.set_masked(index, true);
+    }
+
+    /// Unmasks the entry `index`. A pending interrupt is raised right away.
+    pub fn unmask(&self, index: usize) {
+        self.set_masked(index, false);
+    }
+
+    /// Makes the entry `index` raise its vector on the LAPIC with the APIC ID `destination` instead. The entry is
+    /// masked while it is reprogrammed, and left masked or not as it was.
+    pub fn set_destination(&self, index: usize, destination: u8) {
+        let control = self.entry(index).wrapping_add(3);
+        // SAFETY: The entry is within the table and owned by this capability.
+        let masked = unsafe { control.read_volatile() } & MSIX_VECTOR_MASKED != 0;
+        self.mask(index);
+        // SAFETY: The entry targets the vector allocated to it.
+        unsafe {
+            self.write_entry(
+                index,
+                MsiMessage::new(destination, self.vector(index)),
+                masked,
+            )
+        };
+    }
+
+    fn set_masked(&self, index: usize, masked: bool) {
+        let control = self.entry(index).wrapping_add(3);
+        // SAFETY: The entry is within the table and owned by this capability, only its mask bit changes.
+        unsafe {
+            let value = control.read_volatile();
+            control.write_volatile(if masked {
+                value | MSIX_VECTOR_MASKED
+            } else {
+                value & !MSIX_VECTOR_MASKED
+            });
+        }
+    }
+
+    /// Returns a pointer to the first dword of the entry `index`.
+    ///
+    /// # Panics
+    /// Panics if no vector was allocated to the entry.
+    fn entry(&self, index: usize) -> *mut u32 {
+        assert!(
+            index < self.vectors.len(),
+            "MSI-X entry {index} out of bounds"
+        );
+        self.table.wrapping_add(index * MSIX_ENTRY_SIZE / 4)
+    }
+
+    /// Writes `message` to the entry `index`, and its vector control with the mask bit `masked`.
+    ///
+    /// # Safety
+    /// The message must target a vector owned by the capability.
+    unsafe fn write_entry(&self, index: usize, message: MsiMessage, masked: bool) {
+        let entry = self.entry(index);
+        // SAFETY: The entry is within the mapped table.
+        unsafe {
+            entry.write_volatile(message.address as u32);
+            entry.add(1).write_volatile((message.address >> 32) as u32);
+            entry.add(2).write_volatile(message.data);
+            let control = entry.add(3).read_volatile();
+            entry.add(3).write_volatile(if masked {
+                control | MSIX_VECTOR_MASKED
+            } else {
+                control & !MSIX_VECTOR_MASKED
+            });
+        }
+    }
+
+    /// Disables MSI-X on the function, frees the vectors and unmaps the table. Their handlers must have been
+    /// unregistered.
+    pub fn disable(self) {
+        for index in 0..self.vectors.len() {
+            self.mask(index);
+        }
+        let control = self.address.read_u16(self.offset + MESSAGE_CONTROL);
+        // SAFETY: The function stops sending messages, the vectors are only freed afterwards.
+        unsafe {
+            self.address
+                .write_u16(self.offset + MESSAGE_CONTROL, control & !MSIX_ENABLE)
+        };
+        // SAFETY: The table isn't accessed anymore now that the capability is consumed.
+        unsafe { nmm::free_phys_mapping(self.mapping) }.expect("Failed to unmap MSI-X table");
+        self.vectors.iter().copied().for_each(vector::free);
+    }
+}
+
+#[kproc::test("MSI messages")]
+fn msi_messages() {
+    let message = MsiMessage::new(3, 0x40);
+    assert_eq!(message.address, 0xfee0_3000);
+    assert_eq!(message.data, 0x40);
+
+    for device in super::devices() {
+        if let Some(vectors) = Msi::supported_vectors(device) {
+            assert!(vectors.is_power_of_two() && vectors <= 32);
+        }
+        if let Some(entries) = MsiX::supported_vectors(device) {
+            assert!((1..=2048).contains(&entries));
+        }
+    }
+
+    // Drivers own the functions they are bound to, so only a function without one can be tried, such as the e1000e
+    // of the q35 machine or a virtio device whose driver isn't built in.
+    let Some(device) = super::devices().iter().find(|device| {
+        !super::driver::is_bound(device.address)
+            && (MsiX::supported_vectors(device).is_some()
+                || Msi::supported_vectors(device).is_some())
+    }) else {
+        cake::log::warn!("Skipping enabling MSI, no function without a driver supports it");
+        return;
+    };
+    let address = device.address;
+    let (cap, enable) = match device.capability(Capability::MSI_X) {
+        Some(cap) => (cap, MSIX_ENABLE),
+        None => (device.capability(Capability::MSI).unwrap(), MSI_ENABLE),
+    };
+    let enabled = || address.read_u16(cap.offset + MESSAGE_CONTROL) & enable != 0;
+    // The handle clears the command register when dropped.
+    let command = address.read_u16(command::OFFSET);
+
+    let mut handle = super::PciHandle::new(device);
+    let count = handle.enable_interrupts(2).unwrap();
+    assert!(count >= 1);
+    assert!(enabled());
+    let vectors: Vec<u8> = (0..count)
+        .map(|index| handle.vector(index).unwrap())
+        .collect();
+    assert!(vectors.iter().all(|&vector| vector::is_dynamic(vector)));
+    drop(handle);
+    assert!(!enabled());
+    // The vectors were freed along with the handle.
+    for vector in vectors {
+        assert!(vector::reserve(vector));
+        vector::free(vector);
+    }
+    // SAFETY: The function is restored to the state it was in before the test.
+    unsafe { address.write_u16(command::OFFSET, command) };
+}
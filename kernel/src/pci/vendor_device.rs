@@ -1,3 +1,4 @@
+#![allow(dead_code, missing_docs)]
 // Ok. It's scheming time.
 // My plan is a (hopefullY) small proc macro that will generate the PCI device structs for us using pci.ids as the source.
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
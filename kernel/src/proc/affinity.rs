@@ -0,0 +1,65 @@
+//! CPU affinity masks for threads.
+use core::fmt::Debug;
+
+/// The number of cores a [CpuMask] can describe. xAPIC IDs are 8 bits wide.
+pub const MAX_CORES: usize = 256;
+
+/// A set of cores, indexed by APIC ID, that a thread is allowed to run on.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CpuMask([u64; MAX_CORES / 64]);
+
+impl CpuMask {
+    /// A mask that allows every core.
+    pub const fn all() -> Self {
+        CpuMask([u64::MAX; MAX_CORES / 64])
+    }
+
+    /// A mask that allows no core.
+    pub const fn empty() -> Self {
+        CpuMask([0; MAX_CORES / 64])
+    }
+
+    /// A mask that only allows the core with the given APIC ID.
+    pub const fn single(core_id: u32) -> Self {
+        let mut mask = Self::empty();
+        mask.0[core_id as usize / 64] = 1 << (core_id % 64);
+        mask
+    }
+
+    /// Allows the core with the given APIC ID.
+    pub fn insert(&mut self, core_id: u32) {
+        self.0[core_id as usize / 64] |= 1 << (core_id % 64);
+    }
+
+    /// Disallows the core with the given APIC ID.
+    pub fn remove(&mut self, core_id: u32) {
+        self.0[core_id as usize / 64] &= !(1 << (core_id % 64));
+    }
+
+    /// Returns `true` if the core with the given APIC ID is allowed.
+    pub fn contains(&self, core_id: u32) -> bool {
+        (core_id as usize) < MAX_CORES && self.0[core_id as usize / 64] & (1 << (core_id % 64)) != 0
+    }
+
+    /// Returns `true` if no core is allowed.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|w| *w == 0)
+    }
+}
+
+impl Default for CpuMask {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Debug for CpuMask {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if *self == Self::all() {
+            return f.write_str("CpuMask(all)");
+        }
+        f.debug_set()
+            .entries((0..MAX_CORES as u32).filter(|id| self.contains(*id)))
+            .finish()
+    }
+}
@@ -0,0 +1,487 @@
+//! Loads ELF executables into user memory.
+//!
+//! Every executable is loaded into a fresh [AddressSpace]. Every `PT_LOAD` segment is backed by fresh zeroed frames
+//! with the permissions from its `p_flags`, and a page shared by two segments gets the permissions of both. The file
+//! contents are copied in through the higher half direct map, so segments can be mapped read-only from the start and
+//! the BSS is zero without touching user addresses. Position independent executables (`ET_DYN`) are loaded at
+//! [PIE_LOAD_BASE] and their `R_X86_64_RELATIVE` relocations are applied.
+use alloc::{sync::Arc, vec::Vec};
+
+use kelp::{
+    Elf, ElfError,
+    goblin::{
+        elf::{
+            header::{EM_X86_64, ET_DYN},
+            reloc::{R_X86_64_NONE, R_X86_64_RELATIVE},
+        },
+        elf64::program_header::{PF_W, PF_X, PT_LOAD, PT_PHDR, ProgramHeader},
+    },
+};
+use nmm::{
+    AddressSpace, MapFlags, MapSource, MemError,
+    arch::{L1_PAGE_SIZE, VIRTUAL_ADDRESS_WIDTH},
+    paging::{Address, VirtAddr},
+};
+
+use crate::requests::PHYSICAL_MEMORY_OFFSET;
+
+/// The first address above the user half of the address space.
+pub const USER_END: u64 = 1 << (VIRTUAL_ADDRESS_WIDTH - 1);
+/// The address position independent executables are loaded at.
+pub const PIE_LOAD_BASE: u64 = 0x5555_5555_0000;
+/// The top of the initial user stack. One page below [USER_END] is left unmapped.
+pub const USER_STACK_TOP: u64 = USER_END - L1_PAGE_SIZE;
+/// The size of the part of the user stack that is mapped up front and holds the arguments, in bytes.
+pub const USER_STACK_SIZE: u64 = 0x10000; // 64 KiB
+/// The size of the whole user stack in bytes. The part below [USER_STACK_SIZE] is mapped lazily as the stack grows.
+pub const USER_STACK_RESERVE: u64 = 0x80_0000; // 8 MiB
+
+// Auxiliary vector entry types, as defined by the System V ABI.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+
+/// An error that can occur while loading an ELF executable.
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    /// The ELF file is malformed or not an x86_64 executable.
+    #[error("Invalid ELF file: {0}")]
+    Elf(#[from] ElfError),
+    /// A segment lies outside the file or the user half of the address space.
+    #[error("Invalid segment at {vaddr:#x} with size {size:#x}")]
+    InvalidSegment {
+        /// The virtual address of the segment.
+        vaddr: u64,
+        /// The size of the segment in memory.
+        size: u64,
+    },
+    /// The entry point isn't in an executable segment of the user half of the address space.
+    #[error("Invalid entry point {0:#x}")]
+    InvalidEntry(u64),
+    /// A relocation of a type other than `R_X86_64_RELATIVE` was found.
+    #[error("Unsupported relocation type {0}")]
+    UnsupportedRelocation(u64),
+    /// A relocation or the initial stack targets memory that wasn't mapped by the loader.
+    #[error("Write to unmapped user address {0:#x}")]
+    Unmapped(u64),
+    /// The arguments and environment don't fit on the initial stack.
+    #[error("Arguments don't fit on the initial stack")]
+    ArgumentsTooLarge,
+    /// Mapping the image failed.
+    #[error("Failed to map the image: {0}")]
+    Mem(#[from] MemError),
+}
+
+/// An executable that has been loaded into user memory. Dropping the last reference to its address space unloads it.
+#[derive(Debug)]
+pub struct LoadedImage {
+    /// The address space the executable was loaded into.
+    pub address_space: Arc<AddressSpace>,
+    /// The address the executable starts at.
+    pub entry: VirtAddr,
+    /// The initial stack pointer, pointing at `argc`.
+    pub stack_pointer: VirtAddr,
+    /// The offset the executable was loaded at. `0` for `ET_EXEC`.
+    pub load_bias: u64,
+    /// The page aligned ranges mapped for the image and its stack.
+    regions: Vec<(VirtAddr, u64)>,
+}
+
+impl LoadedImage {
+    /// Returns `true` if `addr` lies in memory mapped for the image.
+    fn contains(&self, addr: u64) -> bool {
+        self.regions
+            .iter()
+            .any(|(base, size)| (base.as_u64()..base.as_u64() + size).contains(&addr))
+    }
+}
+
+/// Loads the ELF executable `data` into a new address space and builds its initial stack with `argv` and `envp`.
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedImage, LoadError> {
+    let elf = Elf::new(data)?;
+    elf.check_executable(EM_X86_64)?;
+
+    let header = elf.header();
+    let load_bias = if header.e_type == ET_DYN {
+        PIE_LOAD_BASE
+    } else {
+        0
+    };
+
+    let entry = entry_point(&elf, load_bias)?;
+
+    let mut image = LoadedImage {
+        address_space: Arc::new(AddressSpace::new()?),
+        entry,
+        stack_pointer: VirtAddr::new(USER_STACK_TOP),
+        load_bias,
+        regions: Vec::new(),
+    };
+
+    load_segments(&elf, &mut image)?;
+    relocate(&elf, &image)?;
+    build_stack(&elf, &mut image, argv, envp)?;
+    Ok(image)
+}
+
+/// Returns the user address of the entry point, which must lie in an executable `PT_LOAD` segment.
+fn entry_point(elf: &Elf<'_>, load_bias: u64) -> Result<VirtAddr, LoadError> {
+    let entry = elf.header().e_entry;
+    let executable = elf.segments().iter().any(|s| {
+        s.p_type == PT_LOAD
+            && s.p_flags & PF_X != 0
+            && entry
+                .checked_sub(s.p_vaddr)
+                .is_some_and(|offset| offset < s.p_memsz)
+    });
+    load_bias
+        .checked_add(entry)
+        .filter(|addr| executable && *addr < USER_END)
+        .and_then(VirtAddr::try_new)
+        .ok_or(LoadError::InvalidEntry(entry))
+}
+
+fn load_segments(elf: &Elf<'_>, image: &mut LoadedImage) -> Result<(), LoadError> {
+    for segment in elf.segments().iter().filter(|s| s.p_type == PT_LOAD) {
+        let invalid = || LoadError::InvalidSegment {
+            vaddr: segment.p_vaddr,
+            size: segment.p_memsz,
+        };
+
+        let file_end = segment
+            .p_offset
+            .checked_add(segment.p_filesz)
+            .ok_or_else(invalid)?;
+        let start = image
+            .load_bias
+            .checked_add(segment.p_vaddr)
+            .ok_or_else(invalid)?;
+        let end = start.checked_add(segment.p_memsz).ok_or_else(invalid)?;
+        if segment.p_filesz > segment.p_memsz || file_end > elf.data.len() as u64 || end > USER_END
+        {
+            return Err(invalid());
+        }
+
+        let mut flags = MapFlags::USER_ACCESSIBLE;
+        if segment.p_flags & PF_W != 0 {
+            flags |= MapFlags::WRITABLE;
+        }
+        if segment.p_flags & PF_X != 0 {
+            flags |= MapFlags::EXECUTABLE;
+        }
+
+        // Segments may share a page at their boundaries, such as the end of `.text` and the start of `.data`. Such a
+        // page is only mapped once, with the permissions of both segments.
+        let mut page = start & !(L1_PAGE_SIZE - 1);
+        while page < end {
+            let addr = VirtAddr::new(page);
+            if !image.contains(page) {
+                image.address_space.map(
+                    addr,
+                    MapSource::Anon { zero: true },
+                    L1_PAGE_SIZE as usize,
+                    flags,
+                )?;
+                image.regions.push((addr, L1_PAGE_SIZE));
+            } else if let Some((_, mapped)) = image.address_space.translate(addr)
+                && !mapped.contains(flags)
+            {
+                // SAFETY: Nothing runs in the address space yet, and the page only gains permissions.
+                unsafe {
+                    image
+                        .address_space
+                        .protect(addr, L1_PAGE_SIZE as usize, mapped | flags)?
+                };
+            }
+            page += L1_PAGE_SIZE;
+        }
+
+        let file_data = &elf.data[segment.p_offset as usize..file_end as usize];
+        write_user(image, start, file_data)?;
+        // The BSS is the part of the segment that isn't backed by the file.
+        let bss_start = start + segment.p_filesz;
+        zero_user(image, bss_start, end - bss_start)?;
+    }
+    Ok(())
+}
+
+fn relocate(elf: &Elf<'_>, image: &LoadedImage) -> Result<(), LoadError> {
+    let load_bias = image.load_bias;
+    if load_bias == 0 {
+        return Ok(());
+    }
+    for reloc in elf.relocations().unwrap_or_default() {
+        match reloc.info.kind() as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let target = load_bias.wrapping_add(reloc.offset);
+                let value = load_bias.wrapping_add_signed(reloc.addend);
+                write_user(image, target, &value.to_ne_bytes())?;
+            }
+            kind => return Err(LoadError::UnsupportedRelocation(kind as u64)),
+        }
+    }
+    Ok(())
+}
+
+/// Maps the initial stack and lays out `argc`, `argv`, `envp` and the auxiliary vector as the System V ABI describes.
+fn build_stack(
+    elf: &Elf<'_>,
+    image: &mut LoadedImage,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(), LoadError> {
+    let stack_base = USER_STACK_TOP - USER_STACK_SIZE;
+    image.address_space.map(
+        VirtAddr::new(USER_STACK_TOP - USER_STACK_RESERVE),
+        MapSource::Lazy,
+        (USER_STACK_RESERVE - USER_STACK_SIZE) as usize,
+        MapFlags::USER_ACCESSIBLE | MapFlags::WRITABLE,
+    )?;
+    image.address_space.map(
+        VirtAddr::new(stack_base),
+        MapSource::Anon { zero: true },
+        USER_STACK_SIZE as usize,
+        MapFlags::USER_ACCESSIBLE | MapFlags::WRITABLE,
+    )?;
+    image
+        .regions
+        .push((VirtAddr::new(stack_base), USER_STACK_SIZE));
+
+    // Copy the strings to the top of the stack, remembering where each one ended up.
+    let mut sp = USER_STACK_TOP;
+    let mut push_str = |s: &str| -> Result<u64, LoadError> {
+        let size = s.len() as u64 + 1;
+        if sp - stack_base < size {
+            return Err(LoadError::ArgumentsTooLarge);
+        }
+        sp -= size;
+        write_user(image, sp, s.as_bytes())?;
+        write_user(image, sp + s.len() as u64, &[0])?;
+        Ok(sp)
+    };
+    let argv_ptrs = argv
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+    let envp_ptrs = envp
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let header = elf.header();
+    let auxv = [
+        (AT_PHDR, phdr_address(elf, image.load_bias)?),
+        (AT_PHENT, header.e_phentsize as u64),
+        (AT_PHNUM, header.e_phnum as u64),
+        (AT_PAGESZ, L1_PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, image.entry.as_u64()),
+        (AT_NULL, 0),
+    ];
+
+    let mut words = Vec::with_capacity(argv_ptrs.len() + envp_ptrs.len() + auxv.len() * 2 + 3);
+    words.push(argv_ptrs.len() as u64);
+    words.extend(&argv_ptrs);
+    words.push(0);
+    words.extend(&envp_ptrs);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|(key, value)| [*key, *value]));
+
+    // `argc` must be 16 byte aligned on entry.
+    let size = words.len() as u64 * 8;
+    if sp - stack_base < size + 16 {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+    sp = (sp - size) & !0xF;
+    for (i, word) in words.iter().enumerate() {
+        write_user(image, sp + i as u64 * 8, &word.to_ne_bytes())?;
+    }
+
+    image.stack_pointer = VirtAddr::new(sp);
+    Ok(())
+}
+
+/// Returns the user address of the program header table, for `AT_PHDR`. `0` if the table isn't loaded.
+fn phdr_address(elf: &Elf<'_>, load_bias: u64) -> Result<u64, LoadError> {
+    let segments = elf.segments();
+    let user = |segment: &ProgramHeader, offset: u64| {
+        load_bias
+            .checked_add(segment.p_vaddr)
+            .and_then(|addr| addr.checked_add(offset))
+            .filter(|addr| *addr < USER_END)
+            .ok_or(LoadError::InvalidSegment {
+                vaddr: segment.p_vaddr,
+                size: segment.p_memsz,
+            })
+    };
+    if let Some(phdr) = segments.iter().find(|s| s.p_type == PT_PHDR) {
+        return user(phdr, 0);
+    }
+    // Without a PT_PHDR, the table is usually part of the segment that maps the start of the file.
+    let phoff = elf.header().e_phoff;
+    segments
+        .iter()
+        .find_map(|s| {
+            let offset = phoff.checked_sub(s.p_offset)?;
+            (s.p_type == PT_LOAD && offset < s.p_filesz).then_some((s, offset))
+        })
+        .map_or(Ok(0), |(s, offset)| user(s, offset))
+}
+
+/// Copies `bytes` to the user address `addr` through the direct map, ignoring the permissions of the user mapping.
+fn write_user(image: &LoadedImage, addr: u64, bytes: &[u8]) -> Result<(), LoadError> {
+    for_each_user_page(image, addr, bytes.len() as u64, |dst, offset, len| {
+        // SAFETY: `dst` points to `len` bytes of a frame that was just mapped by the loader.
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes[offset..].as_ptr(), dst, len);
+        }
+    })
+}
+
+/// Zeroes `len` bytes at the user address `addr` through the direct map.
+fn zero_user(image: &LoadedImage, addr: u64, len: u64) -> Result<(), LoadError> {
+    for_each_user_page(image, addr, len, |dst, _, len| {
+        // SAFETY: `dst` points to `len` bytes of a frame that was just mapped by the loader.
+        unsafe { core::ptr::write_bytes(dst, 0, len) };
+    })
+}
+
+/// Calls `f` with the direct map pointer, the offset into the range and the length of every page sized chunk of the user
+/// range `addr..addr + len`. Fails if any part of the range wasn't mapped for `image`.
+fn for_each_user_page(
+    image: &LoadedImage,
+    addr: u64,
+    len: u64,
+    mut f: impl FnMut(*mut u8, usize, usize),
+) -> Result<(), LoadError> {
+    let hhdm = *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("Physical memory offset not provided by bootloader");
+    let mut offset = 0;
+    while offset < len {
+        let current = addr.checked_add(offset).ok_or(LoadError::Unmapped(addr))?;
+        if !image.contains(current) {
+            return Err(LoadError::Unmapped(current));
+        }
+        let chunk = (L1_PAGE_SIZE - current % L1_PAGE_SIZE).min(len - offset);
+        let (phys, _) = image
+            .address_space
+            .translate(VirtAddr::new(current))
+            .ok_or(LoadError::Unmapped(current))?;
+        f(
+            (hhdm + phys.as_u64()) as *mut u8,
+            offset as usize,
+            chunk as usize,
+        );
+        offset += chunk;
+    }
+    Ok(())
+}
+
+#[kproc::test("ELF loader")]
+fn load_elf() {
+    const SIZE: usize = 0x200;
+    const TEXT: u64 = 0x40_0000;
+    const ENTRY: u64 = TEXT + 0xb0;
+    /// The data segment shares the last page of the text segment, and its BSS spans two more pages.
+    const DATA: u64 = TEXT + 0x100;
+    const DATA_SIZE: u64 = 0x2000;
+    const PF_R: u32 = 4;
+
+    // The loader reads the headers in place, so the file must be 8 byte aligned.
+    let mut words = alloc::vec![0u64; SIZE / 8];
+    // SAFETY: The slice covers the words, which outlive it.
+    let file = unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr().cast::<u8>(), SIZE) };
+    let mut put =
+        |offset: usize, bytes: &[u8]| file[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(0, b"\x7fELF\x02\x01\x01");
+    put(16, &2u16.to_le_bytes());
+    put(18, &EM_X86_64.to_le_bytes());
+    put(20, &1u32.to_le_bytes());
+    put(24, &ENTRY.to_le_bytes());
+    put(32, &64u64.to_le_bytes());
+    for (field, value) in [(52, 64u16), (54, 56), (56, 2)] {
+        put(field, &value.to_le_bytes());
+    }
+    let segments = [
+        (PF_R | PF_X, 0, TEXT, 0x100, 0x100),
+        (PF_R | PF_W, 0x100, DATA, 16, DATA_SIZE),
+    ];
+    for (index, (flags, offset, vaddr, file_size, size)) in segments.into_iter().enumerate() {
+        let header = 64 + index * 56;
+        put(header, &PT_LOAD.to_le_bytes());
+        put(header + 4, &flags.to_le_bytes());
+        put(header + 8, &u64::to_le_bytes(offset));
+        put(header + 16, &vaddr.to_le_bytes());
+        put(header + 32, &u64::to_le_bytes(file_size));
+        put(header + 40, &size.to_le_bytes());
+    }
+    // jmp $
+    put(0xb0, &[0xeb, 0xfe]);
+    put(0x100, b"initialized data");
+
+    let image = load(file, &["test"], &[]).unwrap();
+    assert_eq!(image.entry.as_u64(), ENTRY);
+    let space = &image.address_space;
+    let flags = |addr: u64| space.translate(VirtAddr::new(addr)).unwrap().1;
+    assert!(
+        flags(TEXT).contains(MapFlags::USER_ACCESSIBLE | MapFlags::EXECUTABLE | MapFlags::WRITABLE)
+    );
+    for page in [TEXT + L1_PAGE_SIZE, TEXT + 2 * L1_PAGE_SIZE] {
+        assert!(flags(page).contains(MapFlags::USER_ACCESSIBLE | MapFlags::WRITABLE));
+        assert!(!flags(page).contains(MapFlags::EXECUTABLE));
+    }
+    assert!(
+        space
+            .translate(VirtAddr::new(TEXT + 3 * L1_PAGE_SIZE))
+            .is_none()
+    );
+
+    let mut memory = alloc::vec![0xff; DATA_SIZE as usize];
+    let mut offset = 0;
+    for_each_user_page(&image, DATA, DATA_SIZE, |src, _, len| {
+        // SAFETY: `src` points to `len` bytes of a frame mapped by the loader.
+        unsafe { core::ptr::copy_nonoverlapping(src, memory[offset..].as_mut_ptr(), len) };
+        offset += len;
+    })
+    .unwrap();
+    assert_eq!(&memory[..16], b"initialized data");
+    assert!(
+        memory[16..].iter().all(|&byte| byte == 0),
+        "The BSS isn't zeroed"
+    );
+
+    let mut argc = [0; 8];
+    for_each_user_page(&image, image.stack_pointer.as_u64(), 8, |src, _, len| {
+        // SAFETY: See above.
+        unsafe { core::ptr::copy_nonoverlapping(src, argc.as_mut_ptr(), len) };
+    })
+    .unwrap();
+    assert_eq!(u64::from_ne_bytes(argc), 1);
+    assert!(image.stack_pointer.as_u64().is_multiple_of(16));
+
+    // A segment with more bytes in the file than in memory is rejected.
+    file[64 + 56 + 40..64 + 56 + 48].copy_from_slice(&8u64.to_le_bytes());
+    assert!(matches!(
+        load(file, &[], &[]),
+        Err(LoadError::InvalidSegment {
+            vaddr: DATA,
+            size: 8
+        })
+    ));
+    file[64 + 56 + 40..64 + 56 + 48].copy_from_slice(&DATA_SIZE.to_le_bytes());
+
+    // The entry point must be in an executable segment, and its address can't overflow.
+    for entry in [TEXT + 0x100, DATA + 0x1000, u64::MAX] {
+        file[24..32].copy_from_slice(&entry.to_le_bytes());
+        assert!(matches!(
+            load(file, &[], &[]),
+            Err(LoadError::InvalidEntry(invalid)) if invalid == entry
+        ));
+    }
+}
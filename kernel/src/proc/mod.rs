@@ -1,17 +1,45 @@
-#![allow(dead_code, missing_docs)]
-//! This whole module is deprecated and is pending a rewrite.
+//! Kernel threads and the preemptive round-robin scheduler.
+//!
+//! Threads are switched on the LAPIC timer interrupt ([KernelInterrupt::Timer]).
+//! The interrupted context is saved into the current thread, and the interrupt frame is overwritten with the context of
+//! the next thread so the interrupt wrapper's `iretq` resumes it.
+//!
+//! Every core has its own run queue in [SCHEDULERS]. New threads are placed on the least loaded core their
+//! [CpuMask] allows, and a core with nothing to run steals waiting threads from the busiest core.
+//!
+//! User threads run in ring 3 in their own [AddressSpace]. Each one still owns a kernel stack, which is installed as
+//! RSP0 in the TSS whenever the thread is switched in, so interrupts and syscalls from user mode land on it. Their
+//! x87/SSE/AVX registers are saved into their [FpuState] on every switch.
 use core::{convert::Infallible, mem, sync::atomic::AtomicU32};
 
-use cake::OnceMutex;
+use alloc::sync::Arc;
+use cake::{Fuse, log::info};
+use nmm::{AddressSpace, MemError, paging::Address};
 use sched::KernelThreadScheduler;
+use x86_64::{
+    PrivilegeLevel, VirtAddr,
+    structures::{gdt::SegmentSelector, idt::InterruptStackFrameValue},
+};
 
 use crate::{
-    context::{InterruptContext, InterruptContextValue},
+    context::{ContextValue, FpuState, InterruptContext, InterruptContextValue},
     declare_module,
+    gdt::LGDT,
+    interrupts::{self, KernelInterrupt},
+    mp::{self, Constructor, CoreLocal, LAPIC, lapic::icr::IPIDestination},
+    time,
 };
 
+pub mod affinity;
+pub mod loader;
 pub mod sched;
+pub mod stack;
+
+pub use affinity::CpuMask;
+pub use sched::ThreadEntry;
+pub use stack::Stack;
 
+/// A unique identifier for a thread.
 pub type ThreadID = u32;
 // TODO: Should this Thread type be turned into a Process type, or should a Process type contain a Thread?
 // Separating the two might make some things easier, but could also introduce a gap between the two which could be problematic.
@@ -20,11 +48,23 @@ pub type ThreadID = u32;
 /// A thread is a unit of execution within a process.
 #[derive(Debug)]
 pub struct Thread {
+    /// The unique ID of the thread.
     pub pid: ThreadID,
+    /// The name of the thread.
     pub name: &'static str,
+    /// The current scheduling state of the thread.
     pub state: ThreadState,
-    pub stack: Stack,
+    /// The cores this thread is allowed to run on.
+    pub affinity: CpuMask,
+    /// The kernel stack owned by the thread. User threads run on it while handling interrupts and syscalls.
+    /// This is `None` for threads adopted from an existing context (e.g. the bootstrap thread), whose stack the scheduler doesn't own.
+    pub stack: Option<Stack>,
+    /// The saved context of the thread. Only valid while the thread isn't running.
     pub context: InterruptContextValue,
+    /// The address space the thread runs in. Kernel threads run in the kernel address space and have `None`.
+    pub address_space: Option<Arc<AddressSpace>>,
+    /// The saved x87/SSE/AVX registers of the thread. Kernel threads never use them and have `None`.
+    pub fpu: Option<FpuState>,
     // TODO: pub ring: PrivilegeLevel
     // TODO: pub parent: ProcessID
 }
@@ -34,21 +74,76 @@ impl Thread {
     pub fn new(
         pid: ThreadID,
         name: &'static str,
-        stack: Stack,
+        stack: Option<Stack>,
         context: InterruptContextValue,
     ) -> Self {
         Thread {
             pid,
             name,
             state: ThreadState::Waiting,
+            affinity: CpuMask::all(),
             stack,
             context,
+            address_space: None,
+            fpu: None,
         }
     }
-    /// Creates a new thread with the given `stack` and `context`.
-    pub fn from_stack_context(stack: Stack, context: InterruptContextValue) -> Self {
+    /// Creates a new thread with the given `name`, `stack` and `context`, allocating a new thread ID.
+    pub fn from_stack_context(
+        name: &'static str,
+        stack: Option<Stack>,
+        context: InterruptContextValue,
+    ) -> Self {
         let pid = NEXT_PID.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
-        Thread::new(pid, "main", stack, context)
+        Thread::new(pid, name, stack, context)
+    }
+    /// Creates a new kernel thread named `name` that starts at `entry`, allocating its stack.
+    pub fn kernel(name: &'static str, entry: ThreadEntry) -> Result<Self, MemError> {
+        let stack = Stack::allocate_kernel_stack(stack::KERNEL_STACK_SIZE)?;
+        let code_selector = LGDT.get().selectors.kernel_code;
+        // SAFETY: `entry` is a valid function and the stack base is the top of a freshly mapped stack.
+        let context = unsafe {
+            InterruptContextValue::new(
+                VirtAddr::new(entry as usize as u64),
+                VirtAddr::new(stack.get_stack_base().as_u64()),
+                code_selector,
+            )
+        };
+        // TODO: Probably should be an Arc<Thread> or something similar. Really want to avoid Arc<Mutex<Thread>> though.
+        Ok(Thread::from_stack_context(name, Some(stack), context))
+    }
+    /// Creates a new user thread named `name` that starts at `entry` in ring 3 of `address_space` with the stack pointer
+    /// `user_stack`, allocating its kernel stack.
+    ///
+    /// # Safety
+    /// `entry` and `user_stack` must point to user accessible memory of `address_space` that stays mapped while the
+    /// thread runs.
+    pub unsafe fn user(
+        name: &'static str,
+        address_space: Arc<AddressSpace>,
+        entry: VirtAddr,
+        user_stack: VirtAddr,
+    ) -> Result<Self, MemError> {
+        let stack = Stack::allocate_kernel_stack(stack::KERNEL_STACK_SIZE)?;
+        let (code_selector, data_selector) = {
+            let gdt = LGDT.get();
+            (
+                SegmentSelector::new(gdt.selectors.user_code.index(), PrivilegeLevel::Ring3),
+                SegmentSelector::new(gdt.selectors.user_data.index(), PrivilegeLevel::Ring3),
+            )
+        };
+        // SAFETY: The caller guarantees that `entry` and `user_stack` are valid user addresses.
+        let context = unsafe {
+            InterruptContextValue::new_user(entry, user_stack, code_selector, data_selector)
+        };
+        let mut thread = Thread::from_stack_context(name, Some(stack), context);
+        thread.address_space = Some(address_space);
+        thread.fpu = Some(FpuState::new());
+        Ok(thread)
+    }
+    /// Returns `true` if the thread runs in ring 3.
+    pub fn is_user(&self) -> bool {
+        self.context.int_frame.code_segment.rpl() == PrivilegeLevel::Ring3
     }
     /// Updates the thread's context and returns the old context.
     pub fn update_context(&mut self, context: InterruptContextValue) -> InterruptContextValue {
@@ -56,31 +151,553 @@ impl Thread {
     }
 }
 
+/// The next thread ID to hand out.
 pub static NEXT_PID: AtomicU32 = AtomicU32::new(0);
 
+/// The scheduling state of a thread.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ThreadState {
+    /// The thread is currently executing.
     Running,
+    /// The thread is ready to run and waiting for its turn.
     Waiting,
+    /// The thread is waiting for an event and isn't scheduled until it is woken with [wake].
+    Blocked,
+    /// The thread has exited and will be reaped by the scheduler.
     Killed,
 }
 
 declare_module!("proc", init_proc);
 
-pub static KERNEL_THREAD_SCHEDULER: OnceMutex<KernelThreadScheduler> = OnceMutex::uninitialized();
+/// The run queue of every core.
+pub static SCHEDULERS: CoreLocal<KernelThreadScheduler> = CoreLocal::new(
+    KernelThreadScheduler::new(),
+    Constructor(KernelThreadScheduler::new),
+);
+
+/// Blown once every core has its run queue and scheduler tick set up.
+static READY: Fuse = Fuse::new();
+
+/// The frequency of the scheduler tick in Hz.
+pub const SCHED_TICK_HZ: u64 = 250;
 
 fn init_proc() -> Result<(), Infallible> {
-    let scheduler = KernelThreadScheduler::new();
-    KERNEL_THREAD_SCHEDULER.call_init(|| scheduler);
+    arm_tick();
+    for &core_id in mp::cores().keys() {
+        mp::dispatch_to(core_id, arm_tick).expect("Core disappeared");
+    }
+    READY.blow();
+    info!("Scheduler tick armed on {} cores", mp::cores().len() + 1);
     Ok(())
 }
 
-pub fn sched_next(ctx: InterruptContext) {
+/// Arms the periodic scheduler tick on the current core.
+fn arm_tick() {
+    // SAFETY: The timer vector is reserved for the scheduler tick and has a handler installed in every IDT.
+    unsafe { time::timer::arm_periodic(KernelInterrupt::Timer as u8, SCHED_TICK_HZ) };
+}
+
+/// Switches to the next thread. Must only be called from an interrupt handler with `ctx` being the interrupted context.
+pub fn sched_next(mut ctx: InterruptContext) {
+    // SAFETY: `ctx` is the interrupted context on this core's stack and isn't referenced anywhere else.
+    let ctx = unsafe { ctx.modify() };
+    reschedule(&mut ctx.context, &mut ctx.int_frame);
+}
+
+/// Kills the current thread after it faulted in user mode and switches to the next one. Must only be called from an
+/// exception handler with `regs` and `frame` being the interrupted context.
+pub fn kill_faulting(regs: &mut ContextValue, frame: &mut InterruptStackFrameValue) {
+    SCHEDULERS.write().kill_current();
+    reschedule(regs, frame);
+}
+
+/// Switches the interrupted context described by `regs` and `frame` to the next thread.
+fn reschedule(regs: &mut ContextValue, frame: &mut InterruptStackFrameValue) {
     // The interrupt wrapper is guaranteed to disable interrupts and reenable them.
-    if !KERNEL_THREAD_SCHEDULER.is_initialized() || KERNEL_THREAD_SCHEDULER.is_locked().is_some() {
+    if !READY.is_blown() {
         // Still in kernel initialization, just return and continue
         return;
     }
-    let mut sch = KERNEL_THREAD_SCHEDULER.get();
-    sch.switch(ctx);
+    let core_id = mp::current_core_id() as u32;
+
+    // Every holder of a run queue lock disables interrupts, so if our own queue is locked, it's held by another core
+    // that is about to release it. Skip this tick rather than spin in the interrupt handler.
+    let idle = match SCHEDULERS.try_write() {
+        Some(queue) => queue.is_idle(),
+        None => return,
+    };
+
+    // Never hold two run queue locks at once, so steal before locking our own queue again.
+    let stolen = if idle { steal(core_id) } else { None };
+
+    let handoff = {
+        let mut queue = SCHEDULERS.write();
+        if let Some(thread) = stolen {
+            queue.add_thread(thread);
+        }
+        queue.switch(regs, frame, core_id)
+    };
+
+    if let Some(thread) = handoff {
+        place(thread);
+    }
+}
+
+/// Takes a waiting thread that may run on `core_id` from the core with the most stealable threads.
+fn steal(core_id: u32) -> Option<Thread> {
+    let (victim, _) = SCHEDULERS
+        .iter()
+        .filter(|(id, _)| *id != core_id as u64)
+        .filter_map(|(id, queue)| Some((id, queue.try_read()?.stealable_count(core_id))))
+        .filter(|(_, count)| *count > 0)
+        .max_by_key(|(_, count)| *count)?;
+
+    SCHEDULERS.get(victim)?.try_write()?.take_stealable(core_id)
+}
+
+/// Queues `thread` on the least loaded core it is allowed to run on, and kicks that core if it isn't this one.
+fn place(thread: Thread) -> ThreadID {
+    let pid = thread.pid;
+    let (target, _) = SCHEDULERS
+        .iter()
+        .filter(|(id, _)| thread.affinity.contains(*id as u32))
+        .map(|(id, queue)| (id, queue.read().load()))
+        .min_by_key(|(_, load)| *load)
+        .expect("Thread affinity does not allow any core");
+
+    SCHEDULERS
+        .get(target)
+        .expect("Core disappeared")
+        .write()
+        .add_thread(thread);
+
+    if target != mp::current_core_id() {
+        kick(target as u32);
+    }
+    pid
+}
+
+/// Makes the core `core_id` reschedule immediately by sending it the scheduler tick.
+fn kick(core_id: u32) {
+    LAPIC
+        .icr()
+        .send(
+            IPIDestination::Physical(core_id as u8),
+            KernelInterrupt::Timer,
+        )
+        .ignore();
+}
+
+/// Spawns a new kernel thread named `name` that starts executing at `entry` and may run on any core.
+pub fn spawn(name: &'static str, entry: ThreadEntry) -> ThreadID {
+    spawn_with_affinity(name, entry, CpuMask::all())
+}
+
+/// Spawns a new kernel thread named `name` that starts executing at `entry` and may only run on the cores in `affinity`.
+pub fn spawn_with_affinity(name: &'static str, entry: ThreadEntry, affinity: CpuMask) -> ThreadID {
+    assert!(
+        !affinity.is_empty(),
+        "Thread affinity does not allow any core"
+    );
+    let mut thread = Thread::kernel(name, entry).expect("Failed to allocate stack");
+    thread.affinity = affinity;
+    interrupts::without_interrupts(|| place(thread))
+}
+
+/// Spawns a new user thread named `name` that starts executing at `entry` in ring 3 of `address_space` with the stack
+/// pointer `user_stack`.
+///
+/// # Safety
+/// `entry` and `user_stack` must point to user accessible memory of `address_space` that stays mapped while the thread
+/// runs.
+pub unsafe fn spawn_user(
+    name: &'static str,
+    address_space: Arc<AddressSpace>,
+    entry: VirtAddr,
+    user_stack: VirtAddr,
+) -> ThreadID {
+    // SAFETY: Guaranteed by the caller.
+    let thread = unsafe { Thread::user(name, address_space, entry, user_stack) }
+        .expect("Failed to allocate stack");
+    interrupts::without_interrupts(|| place(thread))
+}
+
+/// Loads the ELF executable `data` into a new address space and spawns a user thread named `name` running it with
+/// `argv` and `envp`. The address space is freed once the thread has exited.
+pub fn spawn_elf(
+    name: &'static str,
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<ThreadID, loader::LoadError> {
+    let image = loader::load(data, argv, envp)?;
+    // SAFETY: The loader mapped the entry point and the stack as user accessible memory, and they are never unmapped.
+    Ok(unsafe {
+        spawn_user(
+            name,
+            image.address_space,
+            VirtAddr::new(image.entry.as_u64()),
+            VirtAddr::new(image.stack_pointer.as_u64()),
+        )
+    })
+}
+
+/// Changes the cores the thread `pid` may run on. Returns `false` if there is no such thread.
+///
+/// A waiting thread is moved right away. A running thread is moved the next time its core reschedules.
+pub fn set_affinity(pid: ThreadID, affinity: CpuMask) -> bool {
+    assert!(
+        !affinity.is_empty(),
+        "Thread affinity does not allow any core"
+    );
+    interrupts::without_interrupts(|| {
+        for (core_id, queue) in SCHEDULERS.iter() {
+            let mut queue = queue.write();
+            let running = queue.current == Some(pid);
+            let Some(thread) = queue.threads.get_mut(&pid) else {
+                continue;
+            };
+            thread.affinity = affinity;
+            if affinity.contains(core_id as u32) {
+                return true;
+            }
+            if running {
+                drop(queue);
+                if core_id != mp::current_core_id() {
+                    kick(core_id as u32);
+                }
+                return true;
+            }
+            let thread = queue.remove_thread(pid).expect("Thread vanished");
+            drop(queue);
+            place(thread);
+            return true;
+        }
+        false
+    })
+}
+
+/// Returns the ID of the thread running on this core, if the scheduler has started.
+pub fn current() -> Option<ThreadID> {
+    interrupts::without_interrupts(|| SCHEDULERS.read().current)
+}
+
+/// Returns the address space of the thread running on this core, or `None` if it runs in the kernel address space.
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    interrupts::without_interrupts(|| {
+        let queue = SCHEDULERS.read();
+        let thread = queue.threads.get(&queue.current?)?;
+        thread.address_space.clone()
+    })
+}
+
+/// Gives up the rest of the current thread's time slice.
+///
+/// This raises [KernelInterrupt::Yield], so it also works with interrupts disabled (e.g. inside a syscall).
+pub fn yield_now() {
+    // SAFETY: The yield vector has a handler installed in every IDT that only reschedules.
+    unsafe { core::arch::asm!("int {}", const KernelInterrupt::Yield as u8) };
+}
+
+/// Blocks the current thread until [wake] is called with its ID. Returns `false` without blocking if the scheduler isn't
+/// running on this core yet.
+///
+/// `prepare` is called with the ID of the thread after it has been marked as blocked and before it is descheduled, with
+/// interrupts disabled. This is where the thread hands its ID to whatever wakes it, so the wakeup can't be lost.
+///
+/// Wakeups may be spurious: the idle context is scheduled even while it is blocked if nothing else can run, so callers
+/// should re-check what they are waiting for.
+pub fn block(prepare: impl FnOnce(ThreadID)) -> bool {
+    interrupts::without_interrupts(|| {
+        let Some(pid) = SCHEDULERS.write().block_current() else {
+            return false;
+        };
+        prepare(pid);
+        yield_now();
+        true
+    })
+}
+
+/// Makes the blocked thread `pid` runnable again. Returns `false` if there is no such thread or it isn't blocked.
+///
+/// This may be called from interrupt handlers.
+pub fn wake(pid: ThreadID) -> bool {
+    interrupts::without_interrupts(|| {
+        for (core_id, queue) in SCHEDULERS.iter() {
+            let mut queue = queue.write();
+            let Some(thread) = queue.threads.get_mut(&pid) else {
+                continue;
+            };
+            if thread.state != ThreadState::Blocked {
+                return false;
+            }
+            thread.state = ThreadState::Waiting;
+            drop(queue);
+            if core_id != mp::current_core_id() {
+                kick(core_id as u32);
+            }
+            return true;
+        }
+        false
+    })
+}
+
+/// Marks the current thread as killed and waits to be descheduled. The thread's resources are freed by the scheduler.
+pub fn exit() -> ! {
+    interrupts::without_interrupts(|| SCHEDULERS.write().kill_current());
+    loop {
+        yield_now();
+    }
+}
+
+#[kproc::test("Threads are preempted round-robin")]
+fn threads_preempted() {
+    use core::sync::atomic::Ordering;
+    use time::{Duration, Instant};
+
+    /// How many times each thread waits for the other to take a turn.
+    const ROUNDS: u32 = 3;
+    static TURNS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+    // Neither thread yields, so on a single core they only take turns if the scheduler preempts them.
+    fn take_turns(me: usize) -> ! {
+        TURNS[me].fetch_add(1, Ordering::SeqCst);
+        let mut seen = 0;
+        while TURNS[me].load(Ordering::SeqCst) <= ROUNDS {
+            let other = TURNS[1 - me].load(Ordering::SeqCst);
+            if other != seen {
+                seen = other;
+                TURNS[me].fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        exit()
+    }
+    extern "C" fn first() -> ! {
+        take_turns(0)
+    }
+    extern "C" fn second() -> ! {
+        take_turns(1)
+    }
+
+    let core = CpuMask::single(mp::current_core_id() as u32);
+    spawn_with_affinity("test-first", first, core);
+    spawn_with_affinity("test-second", second, core);
+    interrupts::enable();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while TURNS
+        .iter()
+        .any(|turns| turns.load(Ordering::SeqCst) <= ROUNDS)
+    {
+        assert!(Instant::now() < deadline, "Threads didn't take turns");
+        yield_now();
+    }
+    interrupts::disable();
+}
+
+#[kproc::test("Threads follow their affinity")]
+fn threads_follow_affinity() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use time::{Duration, Instant};
+
+    static CORE: AtomicU32 = AtomicU32::new(u32::MAX);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn report_core() -> ! {
+        while !STOP.load(Ordering::SeqCst) {
+            CORE.store(mp::current_core_id() as u32, Ordering::SeqCst);
+            yield_now();
+        }
+        exit()
+    }
+
+    let bootstrap = mp::current_core_id() as u32;
+    let pid = spawn_with_affinity("test-affinity", report_core, CpuMask::single(bootstrap));
+    interrupts::enable();
+    // Move the thread through every core, and back to the first one.
+    let cores = core::iter::once(bootstrap)
+        .chain(mp::cores().keys().copied())
+        .chain(core::iter::once(bootstrap));
+    for core in cores {
+        assert!(set_affinity(pid, CpuMask::single(core)));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while CORE.load(Ordering::SeqCst) != core {
+            assert!(
+                Instant::now() < deadline,
+                "Thread didn't move to core {core}"
+            );
+            yield_now();
+        }
+    }
+    STOP.store(true, Ordering::SeqCst);
+    interrupts::disable();
+}
+
+#[kproc::test("Address spaces map, translate and unmap")]
+fn address_spaces() {
+    use nmm::{
+        MapFlags, MapSource,
+        arch::{HIGHER_HALF_START, L1_PAGE_SIZE},
+        paging::VirtAddr,
+    };
+
+    use crate::requests::PHYSICAL_MEMORY_OFFSET;
+
+    const BASE: u64 = 0x1000_0000;
+    let page = L1_PAGE_SIZE as usize;
+    let user = MapFlags::USER_ACCESSIBLE | MapFlags::WRITABLE;
+    let first = AddressSpace::new().unwrap();
+    let second = AddressSpace::new().unwrap();
+    assert!(!first.is_active());
+    assert_ne!(first.root_frame(), second.root_frame());
+
+    first
+        .map(
+            VirtAddr::new(BASE),
+            MapSource::Anon { zero: true },
+            3 * page,
+            user,
+        )
+        .unwrap();
+    let frames: [_; 3] = core::array::from_fn(|index| {
+        let (phys, flags) = first
+            .translate(VirtAddr::new(BASE + index as u64 * L1_PAGE_SIZE))
+            .unwrap();
+        assert!(flags.contains(user));
+        phys
+    });
+    assert!(frames[0] != frames[1] && frames[1] != frames[2] && frames[0] != frames[2]);
+    let hhdm = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
+    // SAFETY: The frame was just mapped and zeroed, and nothing else uses it.
+    let contents =
+        unsafe { core::slice::from_raw_parts((hhdm + frames[1].as_u64()) as *const u8, page) };
+    assert!(contents.iter().all(|&byte| byte == 0));
+
+    // The address spaces only share the higher half.
+    assert!(second.translate(VirtAddr::new(BASE)).is_none());
+    second
+        .map(
+            VirtAddr::new(BASE),
+            MapSource::Anon { zero: true },
+            page,
+            user,
+        )
+        .unwrap();
+    assert_ne!(second.translate(VirtAddr::new(BASE)).unwrap().0, frames[0]);
+    assert!(
+        first
+            .map(
+                HIGHER_HALF_START,
+                MapSource::Anon { zero: true },
+                page,
+                user
+            )
+            .is_err()
+    );
+
+    // SAFETY: Nothing runs in the address space.
+    unsafe {
+        first.protect(
+            VirtAddr::new(BASE + L1_PAGE_SIZE),
+            page,
+            MapFlags::USER_ACCESSIBLE,
+        )
+    }
+    .unwrap();
+    let (_, flags) = first.translate(VirtAddr::new(BASE + L1_PAGE_SIZE)).unwrap();
+    assert!(!flags.contains(MapFlags::WRITABLE));
+
+    // SAFETY: See above.
+    unsafe { first.unmap(VirtAddr::new(BASE), 2 * page) }.unwrap();
+    assert!(first.translate(VirtAddr::new(BASE)).is_none());
+    assert!(
+        first
+            .translate(VirtAddr::new(BASE + L1_PAGE_SIZE))
+            .is_none()
+    );
+    assert_eq!(
+        first
+            .translate(VirtAddr::new(BASE + 2 * L1_PAGE_SIZE))
+            .unwrap()
+            .0,
+        frames[2]
+    );
+}
+
+#[kproc::test("Cloned address spaces copy pages on the first write")]
+fn address_space_clones() {
+    use nmm::{
+        MapFlags, MapSource,
+        arch::L1_PAGE_SIZE,
+        paging::{Frame, MemoryFragment, PhysAddr, Small, VirtAddr},
+    };
+
+    use crate::requests::PHYSICAL_MEMORY_OFFSET;
+
+    const BASE: u64 = 0x1000_0000;
+    let addr = |index: u64| VirtAddr::new(BASE + index * L1_PAGE_SIZE);
+    let user = MapFlags::USER_ACCESSIBLE | MapFlags::WRITABLE;
+    let hhdm = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
+    let byte = |phys: PhysAddr| (hhdm + phys.as_u64()) as *mut u8;
+    let references =
+        |phys: PhysAddr| nmm::frame_references(Frame::<Small>::containing_address(phys));
+
+    let parent = AddressSpace::new().unwrap();
+    parent
+        .map(
+            addr(0),
+            MapSource::Anon { zero: true },
+            2 * L1_PAGE_SIZE as usize,
+            user,
+        )
+        .unwrap();
+    let frames: [_; 2] =
+        core::array::from_fn(|index| parent.translate(addr(index as u64)).unwrap().0);
+    // SAFETY: The frames were just mapped and nothing runs in the address space.
+    unsafe {
+        *byte(frames[0]) = 1;
+        *byte(frames[1]) = 2;
+    }
+
+    // Both address spaces share every frame until they write to it.
+    let child = parent.try_clone().unwrap();
+    for (index, &frame) in frames.iter().enumerate() {
+        for space in [&parent, &child] {
+            let (phys, flags) = space.translate(addr(index as u64)).unwrap();
+            assert_eq!(phys, frame);
+            assert!(flags.contains(user | MapFlags::COPY_ON_WRITE));
+        }
+        assert_eq!(references(frame), 2);
+    }
+    child
+        .resolve_fault(addr(0), MapFlags::USER_ACCESSIBLE)
+        .unwrap();
+    assert_eq!(child.translate(addr(0)).unwrap().0, frames[0]);
+
+    // The first write maps a private copy, and drops the reference to the shared frame.
+    child.resolve_fault(addr(0), user).unwrap();
+    let (copy, flags) = child.translate(addr(0)).unwrap();
+    assert_ne!(copy, frames[0]);
+    assert!(flags.contains(user) && !flags.contains(MapFlags::COPY_ON_WRITE));
+    assert_eq!(references(frames[0]), 1);
+    assert_eq!(references(copy), 1);
+    // SAFETY: See above.
+    unsafe {
+        assert_eq!(*byte(copy), 1);
+        *byte(copy) = 3;
+        assert_eq!(*byte(frames[0]), 1);
+    }
+
+    // The parent holds the last reference now, so its page becomes writable in place.
+    parent.resolve_fault(addr(0), user).unwrap();
+    let (phys, flags) = parent.translate(addr(0)).unwrap();
+    assert_eq!(phys, frames[0]);
+    assert!(!flags.contains(MapFlags::COPY_ON_WRITE));
+
+    // Dropping an address space drops its references as well.
+    drop(parent);
+    assert_eq!(references(frames[1]), 1);
+    child.resolve_fault(addr(1), user).unwrap();
+    assert_eq!(child.translate(addr(1)).unwrap().0, frames[1]);
+    // SAFETY: See above.
+    assert_eq!(unsafe { *byte(frames[1]) }, 2);
 }
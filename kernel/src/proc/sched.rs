@@ -1,75 +1,228 @@
+//! A per-core round-robin kernel thread scheduler.
+use core::ops::Bound;
+
 use alloc::collections::btree_map::BTreeMap;
 
-use crate::context::InterruptContext;
+use nmm::{AddressSpace, paging::Address};
+use x86_64::{VirtAddr, structures::idt::InterruptStackFrameValue};
+
+use crate::{
+    context::{ContextValue, InterruptContextValue},
+    gdt::LGDT,
+};
+
+use super::{CpuMask, Thread, ThreadID, ThreadState};
 
-use super::{Thread, ThreadID, ThreadState};
+/// A preemptive round-robin run queue for the kernel threads of a single core.
 #[derive(Debug)]
 pub struct KernelThreadScheduler {
     // Would using a VecDeque or LinkedList be better?
     // Threads that terminate are removed from the list, so it might be ideal?
+    /// All threads queued on this core, keyed by their ID.
     pub threads: BTreeMap<ThreadID, Thread>,
+    /// The currently running thread, if the scheduler has switched at least once.
     pub current: Option<ThreadID>,
-    pub index: usize,
+    /// The context this core was running before it was first scheduled. It is pinned to this core and never stolen.
+    pub idle: Option<ThreadID>,
+    /// A thread that left this core on the last switch. It is handed off on the next switch, once this core is no
+    /// longer running on its stack.
+    outgoing: Option<Thread>,
 }
 // TODO: Can `extern "C"` be safely removed?
+/// The entry point of a kernel thread.
 pub type ThreadEntry = extern "C" fn() -> !;
 
+impl Default for KernelThreadScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl KernelThreadScheduler {
-    pub fn new() -> Self {
+    /// Creates an empty run queue.
+    pub const fn new() -> Self {
         KernelThreadScheduler {
             threads: BTreeMap::new(),
             current: None,
-            index: 0,
+            idle: None,
+            outgoing: None,
         }
     }
 
+    /// Adds an existing thread to the run queue.
     pub fn add_thread(&mut self, thread: Thread) {
         self.threads.insert(thread.pid, thread);
     }
 
-    pub fn remove_thread(&mut self, pid: ThreadID) {
-        self.threads.remove(&pid);
+    /// Removes a thread from the run queue and returns it.
+    pub fn remove_thread(&mut self, pid: ThreadID) -> Option<Thread> {
+        self.threads.remove(&pid)
+    }
+
+    /// Returns the number of runnable threads on this core, excluding the idle context.
+    pub fn load(&self) -> usize {
+        self.threads
+            .iter()
+            .filter(|(tid, t)| {
+                Some(**tid) != self.idle
+                    && matches!(t.state, ThreadState::Running | ThreadState::Waiting)
+            })
+            .count()
+    }
+
+    /// Returns `true` if the core has nothing to run besides its idle context.
+    pub fn is_idle(&self) -> bool {
+        self.load() == 0
+    }
+
+    /// Returns the number of threads that are waiting to run and could be moved to the core `core_id`.
+    pub fn stealable_count(&self, core_id: u32) -> usize {
+        self.stealable(core_id).count()
+    }
+
+    /// Removes a waiting thread that is allowed to run on `core_id`, so it can be moved to that core.
+    pub fn take_stealable(&mut self, core_id: u32) -> Option<Thread> {
+        let tid = self.stealable(core_id).next()?;
+        self.threads.remove(&tid)
+    }
+
+    fn stealable(&self, core_id: u32) -> impl Iterator<Item = ThreadID> {
+        self.threads
+            .iter()
+            .filter(move |(tid, t)| {
+                Some(**tid) != self.idle
+                    && Some(**tid) != self.current
+                    && t.state == ThreadState::Waiting
+                    && t.affinity.contains(core_id)
+            })
+            .map(|(tid, _)| *tid)
+    }
+
+    /// Marks the current thread as killed. It will not be scheduled again.
+    pub fn kill_current(&mut self) {
+        if let Some(thread) = self.current.and_then(|tid| self.threads.get_mut(&tid)) {
+            thread.state = ThreadState::Killed;
+        }
     }
 
-    pub fn spawn(&mut self, entry: ThreadEntry) {
-        // let stack = Stack::allocate_kernel_stack(0x4000, StackFlags::KernelMode)
-        //     .expect("Failed to allocate stack");
-        // let context = unsafe {
-        //     InterruptContextValue::new(
-        //         VirtAddr::new(entry as u64),
-        //         stack.get_stack_base(),
-        //         GDT.1.code_selector(),
-        //     )
-        // };
-        // // TODO: Probably should be an Arc<Thread> or something similar. Really want to avoid Arc<Mutex<Thread>> though.
-        // let thread = Thread::from_stack_context(stack, context);
-        // self.add_thread(thread);
+    /// Marks the current thread as blocked and returns its ID, or `None` if this core hasn't scheduled a thread yet.
+    /// The thread keeps running until the next switch, and isn't scheduled again until it is woken.
+    pub fn block_current(&mut self) -> Option<ThreadID> {
+        let tid = self.current?;
+        self.threads.get_mut(&tid)?.state = ThreadState::Blocked;
+        Some(tid)
     }
-    pub fn switch(&mut self, mut ctx: InterruptContext) {
+
+    /// Saves the interrupted registers and frame into the current thread and replaces them with the context of the next
+    /// runnable thread.
+    ///
+    /// `core_id` is the APIC ID of the core this queue belongs to. If the current thread is no longer allowed to run on
+    /// this core, it is removed from the queue and returned by the following switch, so the caller can place it on
+    /// another core.
+    pub fn switch(
+        &mut self,
+        regs: &mut ContextValue,
+        frame: &mut InterruptStackFrameValue,
+        core_id: u32,
+    ) -> Option<Thread> {
+        let saved = InterruptContextValue {
+            context: *regs,
+            int_frame: *frame,
+        };
+        let handoff = self.outgoing.take();
         if self.threads.is_empty() {
             // No threads to schedule, just return and continue execution.
-            return;
+            return handoff;
+        }
+
+        let current = match self.current {
+            Some(tid) => tid,
+            None => {
+                // The first switch adopts the interrupted context as the idle thread so it keeps being scheduled.
+                let mut thread = Thread::from_stack_context("idle", None, saved);
+                thread.affinity = CpuMask::single(core_id);
+                let pid = thread.pid;
+                self.add_thread(thread);
+                self.idle = Some(pid);
+                pid
+            }
+        };
+
+        let thread = self
+            .threads
+            .get_mut(&current)
+            .expect("Current thread does not exist!");
+        thread.context = saved;
+        if let Some(fpu) = &mut thread.fpu {
+            // SAFETY: The registers still hold the state of the interrupted thread, the kernel never touches them.
+            unsafe { fpu.save() };
         }
-        if let Some(tid) = self.current {
-            let thread = self
+        if thread.state == ThreadState::Running {
+            thread.state = ThreadState::Waiting;
+        }
+        let migrating = thread.state == ThreadState::Waiting && !thread.affinity.contains(core_id);
+        if migrating {
+            // Park the thread so it isn't picked again below.
+            thread.state = ThreadState::Killed;
+        }
+
+        let next = self
+            .next_runnable(current)
+            .or(self.idle)
+            .expect("No runnable thread on this core");
+
+        if migrating {
+            let mut thread = self
                 .threads
-                .get_mut(&tid)
+                .remove(&current)
                 .expect("Current thread does not exist!");
-            thread.context = *&*ctx;
-            thread.state = ThreadState::Waiting
+            thread.state = ThreadState::Waiting;
+            self.outgoing = Some(thread);
         }
-        let key = *self
-            .threads
-            .keys()
-            .cycle()
-            .nth(self.index)
-            .expect("Infallible");
-        self.index += 1;
-        let thread = self.threads.get_mut(&key).expect("Thread does not exist!");
+
+        self.reap(current, next);
+
+        let thread = self.threads.get_mut(&next).expect("Thread does not exist!");
         thread.state = ThreadState::Running;
-        self.current = Some(key);
-        unsafe {
-            ctx.modify().switch(&mut thread.context);
+        self.current = Some(next);
+        if let Some(stack) = &thread.stack {
+            // SAFETY: The stack belongs to `next` and lives as long as the thread, which can't be reaped while it runs.
+            unsafe {
+                LGDT.get()
+                    .set_kernel_stack(VirtAddr::new(stack.top().as_u64()))
+            };
+        }
+        match &thread.address_space {
+            // SAFETY: The thread keeps its address space alive, and `next` can't be reaped while it runs. Whatever ran
+            // before is either `next` or has just been saved, so nothing uses the previous lower half anymore.
+            Some(space) => unsafe { space.activate() },
+            // SAFETY: See above.
+            None => unsafe { AddressSpace::activate_kernel() },
+        }
+        if let Some(fpu) = &thread.fpu {
+            // SAFETY: Every core enables the FPU before it schedules threads.
+            unsafe { fpu.restore() };
         }
+        // The interrupted thread has been saved above. Overwriting the frame makes the interrupt wrapper return into
+        // `next` instead.
+        *regs = thread.context.context;
+        *frame = thread.context.int_frame;
+        handoff
+    }
+
+    /// Finds the next waiting thread after `current`, wrapping around to the start.
+    fn next_runnable(&self, current: ThreadID) -> Option<ThreadID> {
+        self.threads
+            .range((Bound::Excluded(current), Bound::Unbounded))
+            .chain(self.threads.range(..=current))
+            .find(|(_, t)| t.state == ThreadState::Waiting)
+            .map(|(tid, _)| *tid)
+    }
+
+    /// Frees killed threads. `current` is still on its stack until the switch completes, and `next` is about to run,
+    /// so neither is reaped here.
+    fn reap(&mut self, current: ThreadID, next: ThreadID) {
+        self.threads
+            .retain(|tid, t| *tid == current || *tid == next || t.state != ThreadState::Killed);
     }
 }
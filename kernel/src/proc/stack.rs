@@ -0,0 +1,78 @@
+//! Kernel thread stacks.
+use core::alloc::Layout;
+
+use nmm::{MapFlags, MapSource, MemError, arch::L1_PAGE_SIZE, paging::VirtAddr};
+
+/// The default size of a kernel thread stack in bytes.
+pub const KERNEL_STACK_SIZE: u64 = 0x4000; // 16 KiB
+
+/// A stack owned by a thread.
+///
+/// The stack is backed by anonymous memory from nmm. The lowest page of the reserved range is left unmapped to act as a
+/// guard page, so an overflow faults instead of silently corrupting neighbouring memory.
+#[derive(Debug)]
+pub struct Stack {
+    /// The base of the reserved virtual range (i.e. the guard page).
+    reserved_base: VirtAddr,
+    /// The size of the usable stack in bytes (excluding the guard page).
+    size: u64,
+}
+
+impl Stack {
+    /// Allocates a new kernel stack with `size` usable bytes. `size` is rounded up to the page size.
+    pub fn allocate_kernel_stack(size: u64) -> Result<Stack, MemError> {
+        let size = size.next_multiple_of(L1_PAGE_SIZE);
+        let reserved_base = nmm::reserve_virtual(Self::layout(size))?;
+
+        if let Err(e) = nmm::map(
+            reserved_base + L1_PAGE_SIZE,
+            MapSource::Anon { zero: true },
+            size as usize,
+            MapFlags::WRITABLE,
+        ) {
+            // SAFETY: Nothing was mapped into the range, so it is unused.
+            unsafe { nmm::free_virtual(reserved_base, Self::layout(size)) }?;
+            return Err(e);
+        }
+
+        Ok(Stack {
+            reserved_base,
+            size,
+        })
+    }
+
+    /// Returns the initial stack pointer for a new thread.
+    ///
+    /// The returned address is 16 byte aligned minus 8, matching the stack state right after a `call` instruction as the
+    /// System V ABI expects on function entry.
+    pub fn get_stack_base(&self) -> VirtAddr {
+        self.top() - 8
+    }
+
+    /// Returns the highest address of the stack (exclusive).
+    pub fn top(&self) -> VirtAddr {
+        self.reserved_base + L1_PAGE_SIZE + self.size
+    }
+
+    /// Returns the usable size of the stack in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn layout(size: u64) -> Layout {
+        Layout::from_size_align((size + L1_PAGE_SIZE) as usize, L1_PAGE_SIZE as usize)
+            .expect("Invalid stack layout")
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // SAFETY: The stack is only dropped once the owning thread will never run again.
+        unsafe {
+            nmm::unmap(self.reserved_base + L1_PAGE_SIZE, self.size as usize)
+                .expect("Failed to unmap stack");
+            nmm::free_virtual(self.reserved_base, Self::layout(self.size))
+                .expect("Failed to free stack virtual range");
+        }
+    }
+}
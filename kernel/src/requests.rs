@@ -2,6 +2,7 @@
 use core::convert::Infallible;
 
 use cake::limine::BaseRevision;
+use cake::limine::firmware_type::FirmwareType;
 use cake::limine::{paging::Mode, request::*, response::ExecutableAddressResponse};
 use cake::{LimineRequest, Once};
 
@@ -9,6 +10,7 @@ use crate::STACK_SIZE;
 use crate::{
     declare_module,
     display::req_data::FramebufferInfo,
+    initramfs::req_data::BootModules,
     memory::{elf_req_data::KernelElf, req_data::MemoryMap},
     mp::ApplicationCores,
 };
@@ -23,6 +25,8 @@ static PAGING_MODE_REQUEST: PagingModeRequest =
 #[used]
 static EXECUTABLE_ADDRESS_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
 #[used]
+static FIRMWARE_TYPE_REQUEST: FirmwareTypeRequest = FirmwareTypeRequest::new();
+#[used]
 static BASE_REVISION: BaseRevision = BaseRevision::with_revision(3);
 
 /// Physical memory offset provided by the bootloader
@@ -31,6 +35,9 @@ pub static PHYSICAL_MEMORY_OFFSET: Once<u64> = Once::new();
 /// Root System Description Pointer provided by the bootloader
 pub static RSDP_ADDRESS: Once<Option<usize>> = Once::new();
 
+/// Whether the bootloader was started by 64-bit UEFI firmware rather than a BIOS
+pub static UEFI_BOOT: Once<bool> = Once::new();
+
 /// Memory map provided by the bootloader
 #[used]
 pub static MEMORY_MAP: LimineRequest<MemoryMapRequest, MemoryMap> =
@@ -49,6 +56,10 @@ pub static FRAMEBUFFER: LimineRequest<FramebufferRequest, FramebufferInfo> =
 pub static MP_INFO: LimineRequest<MpRequest, ApplicationCores> =
     LimineRequest::new(MpRequest::new());
 
+/// Modules loaded by the bootloader alongside the kernel, such as the initramfs
+pub static MODULES: LimineRequest<ModuleRequest, BootModules> =
+    LimineRequest::new(ModuleRequest::new());
+
 /// Executable address provided by the bootloader
 pub static EXECUTABLE_ADDRESS: Once<&'static ExecutableAddressResponse> = Once::new();
 
@@ -72,11 +83,19 @@ fn init() -> Result<(), Infallible> {
 
     MP_INFO.init(ApplicationCores::new);
 
+    MODULES.init(BootModules::new);
+
     let exec_addr = EXECUTABLE_ADDRESS_REQUEST.get_response().unwrap();
     EXECUTABLE_ADDRESS.call_once(|| exec_addr);
 
     RSDP_ADDRESS.call_once(|| RSDP_ADDRESS_REQUEST.get_response().map(|r| r.address()));
 
+    UEFI_BOOT.call_once(|| {
+        FIRMWARE_TYPE_REQUEST
+            .get_response()
+            .is_some_and(|r| r.firmware_type() == FirmwareType::UEFI_64)
+    });
+
     let lock = KERNEL_ELF.lock_limine();
     let file = lock.file();
     cake::set_kernel_elf(unsafe {
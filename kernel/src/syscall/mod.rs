@@ -0,0 +1,226 @@
+//! The `syscall`/`sysret` entry path from user mode.
+//!
+//! On entry the CPU doesn't switch stacks, so the entry stub borrows the current thread's kernel stack from RSP0 in the
+//! TSS. To find the TSS without touching any general purpose register, every core installs a small [SyscallScratch]
+//! block as its kernel GS base, which the stub reaches with `swapgs`.
+//!
+//! The syscall number is passed in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result
+//! is returned in `rax`; negative values are [SyscallError]s.
+use core::{arch::naked_asm, convert::Infallible};
+
+use alloc::boxed::Box;
+use cake::log::info;
+use x86_64::{
+    VirtAddr,
+    registers::{
+        model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::tss::TaskStateSegment,
+};
+
+use crate::{declare_module, gdt::LGDT, mp};
+
+mod table;
+
+pub use table::{Syscall, SyscallError};
+
+/// The per-core block the entry stub reaches through the kernel GS base.
+#[repr(C)]
+#[derive(Debug)]
+struct SyscallScratch {
+    /// The TSS of the core, whose RSP0 is the kernel stack of the current thread.
+    tss: *const TaskStateSegment,
+    /// The user stack pointer, saved by the entry stub until it's pushed onto the kernel stack.
+    user_rsp: u64,
+}
+
+/// The registers saved by the entry stub, in the order they are pushed.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(missing_docs)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// The user RFLAGS, saved by `syscall`.
+    pub r11: u64,
+    /// The user return address, saved by `syscall`.
+    pub rcx: u64,
+    pub user_rsp: u64,
+}
+
+declare_module!("syscall", init);
+
+fn init() -> Result<(), Infallible> {
+    mp::dispatch_all(init_core);
+    mp::core_wait();
+    info!("Syscalls enabled on {} cores", mp::cores().len() + 1);
+    Ok(())
+}
+
+/// Enables `syscall`/`sysret` on the current core.
+fn init_core() {
+    let gdt = LGDT.get();
+    let scratch = Box::leak(Box::new(SyscallScratch {
+        tss: gdt.tss_ptr(),
+        user_rsp: 0,
+    }));
+    KernelGsBase::write(VirtAddr::from_ptr(scratch));
+
+    Star::write(
+        gdt.selectors.user_code,
+        gdt.selectors.user_data,
+        gdt.selectors.kernel_code,
+        gdt.selectors.kernel_data,
+    )
+    .expect("GDT layout is not compatible with sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // Enter the kernel with interrupts disabled, so nothing can run before the stub has switched stacks.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+
+    // SAFETY: The syscall MSRs have been set up above, so enabling the instruction is sound.
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// The target of the `syscall` instruction.
+///
+/// Switches to the kernel stack of the current thread, saves the user registers as a [SyscallFrame] and hands it to
+/// [table::dispatch]. Interrupts stay disabled for the whole syscall.
+#[unsafe(naked)]
+extern "C" fn syscall_entry() {
+    naked_asm! {
+        // Stash the user stack pointer and load RSP0 from the TSS.
+        "swapgs",
+        "mov gs:[8], rsp",
+        "mov rsp, gs:[0]",
+        "mov rsp, [rsp + 4]",
+        "push qword ptr gs:[8]",
+        "swapgs",
+
+        // Push the frame in the OPPOSITE order that it is defined in SyscallFrame. RSP0 is 16 byte aligned, so after
+        // ten pushes the stack is aligned for the call.
+        "push rcx",
+        "push r11",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+
+        "mov rdi, rsp",
+        "call {dispatch}",
+
+        "pop rax",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+        "sysretq",
+        dispatch = sym table::dispatch,
+    }
+}
+
+#[kproc::test("Syscalls from ring 3")]
+fn user_syscalls() {
+    use alloc::{sync::Arc, vec::Vec};
+    use nmm::{AddressSpace, MapFlags, MapSource, arch::L1_PAGE_SIZE, paging::Address};
+
+    use crate::{
+        interrupts, proc,
+        requests::PHYSICAL_MEMORY_OFFSET,
+        time::{Duration, Instant},
+    };
+
+    const CODE: u64 = 0x40_0000;
+    const DATA: u64 = 0x40_1000;
+    /// What the results hold until the thread stores them.
+    const UNSET: u64 = 0x5a5a_5a5a_5a5a_5a5a;
+
+    let mut code = Vec::new();
+    // mov rax, cs; mov rdx, DATA; mov [rdx], rax
+    code.extend([0x48, 0x8c, 0xc8, 0x48, 0xba]);
+    code.extend(DATA.to_le_bytes());
+    code.extend([0x48, 0x89, 0x02]);
+    // mov eax, Yield; syscall; mov [rdx + 8], rax
+    code.extend([0xb8, Syscall::Yield as u8, 0, 0, 0, 0x0f, 0x05]);
+    code.extend([0x48, 0x89, 0x42, 0x08]);
+    // mov eax, 99; syscall; mov [rdx + 16], rax
+    code.extend([0xb8, 99, 0, 0, 0, 0x0f, 0x05]);
+    code.extend([0x48, 0x89, 0x42, 0x10]);
+    // mov eax, Exit; syscall
+    code.extend([0xb8, Syscall::Exit as u8, 0, 0, 0, 0x0f, 0x05]);
+
+    let space = Arc::new(AddressSpace::new().unwrap());
+    let page = L1_PAGE_SIZE as usize;
+    let user = MapFlags::USER_ACCESSIBLE;
+    let anon = MapSource::Anon { zero: true };
+    space
+        .map(
+            nmm::paging::VirtAddr::new(CODE),
+            anon,
+            page,
+            user | MapFlags::EXECUTABLE,
+        )
+        .unwrap();
+    space
+        .map(
+            nmm::paging::VirtAddr::new(DATA),
+            anon,
+            page,
+            user | MapFlags::WRITABLE,
+        )
+        .unwrap();
+    let hhdm = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
+    let frame = |addr: u64| {
+        let (phys, _) = space.translate(nmm::paging::VirtAddr::new(addr)).unwrap();
+        (hhdm + phys.as_u64()) as *mut u8
+    };
+    let results = frame(DATA).cast::<u64>();
+    // SAFETY: Both frames were just mapped, and nothing runs in the address space yet.
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), frame(CODE), code.len());
+        (0..3).for_each(|index| results.add(index).write_volatile(UNSET));
+    }
+    let result = |index: usize| {
+        // SAFETY: The data page stays mapped while `space` is alive.
+        unsafe { results.add(index).read_volatile() }
+    };
+
+    // SAFETY: The code is mapped above, and never touches the stack, which is left at the top of the data page.
+    unsafe {
+        proc::spawn_user(
+            "test-syscalls",
+            space.clone(),
+            VirtAddr::new(CODE),
+            VirtAddr::new(DATA + L1_PAGE_SIZE),
+        )
+    };
+    interrupts::enable();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while result(2) == UNSET {
+        assert!(Instant::now() < deadline, "The user thread didn't finish");
+        proc::yield_now();
+    }
+    interrupts::disable();
+
+    assert_eq!(result(0) & 3, 3, "The thread didn't run in ring 3");
+    assert_eq!(result(1), 0);
+    assert_eq!(result(2), SyscallError::InvalidSyscall as i64 as u64);
+}
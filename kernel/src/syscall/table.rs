@@ -0,0 +1,115 @@
+//! The syscall table.
+use nmm::{
+    MapFlags,
+    arch::{L1_PAGE_SIZE, VIRTUAL_ADDRESS_WIDTH},
+    paging::{Address, VirtAddr},
+};
+
+use crate::{print, proc};
+
+use super::SyscallFrame;
+
+/// The first address above the user half of the address space.
+const USER_END: u64 = 1 << (VIRTUAL_ADDRESS_WIDTH - 1);
+
+/// The syscall numbers, passed in `rax`.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    /// `write(buf, len)`: Writes the UTF-8 string `buf` to the console. Returns the number of bytes written.
+    Write = 0,
+    /// `exit()`: Terminates the calling thread. Doesn't return.
+    Exit = 1,
+    /// `yield()`: Gives up the rest of the calling thread's time slice. Returns 0.
+    Yield = 2,
+}
+
+impl TryFrom<u64> for Syscall {
+    type Error = SyscallError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Syscall::Write),
+            1 => Ok(Syscall::Exit),
+            2 => Ok(Syscall::Yield),
+            _ => Err(SyscallError::InvalidSyscall),
+        }
+    }
+}
+
+/// The errors a syscall can return. They are returned to user mode as negative values in `rax`.
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// The syscall number is unknown.
+    InvalidSyscall = -1,
+    /// A pointer argument doesn't point to user accessible memory.
+    BadAddress = -2,
+    /// An argument is invalid.
+    InvalidArgument = -3,
+}
+
+/// Dispatches the syscall described by `frame` and stores its result in `frame.rax`.
+pub(super) extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    let result = Syscall::try_from(frame.rax).and_then(|syscall| match syscall {
+        Syscall::Write => sys_write(frame.rdi, frame.rsi),
+        Syscall::Exit => proc::exit(),
+        Syscall::Yield => {
+            proc::yield_now();
+            Ok(0)
+        }
+    });
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(e) => e as i64 as u64,
+    };
+
+    // `sysret` faults in ring 0 if the return address isn't canonical, so never return to one.
+    if frame.rcx >= USER_END {
+        proc::exit();
+    }
+}
+
+fn sys_write(buf: u64, len: u64) -> Result<u64, SyscallError> {
+    let bytes = user_slice(buf, len)?;
+    let s = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{}", s);
+    Ok(len)
+}
+
+/// Returns the user memory at `ptr..ptr + len` as a slice, after checking that all of it is mapped and user accessible.
+/// Untouched pages of lazy mappings in the range are backed first.
+fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], SyscallError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    let end = ptr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    if end > USER_END {
+        return Err(SyscallError::BadAddress);
+    }
+
+    let space = proc::current_address_space().ok_or(SyscallError::BadAddress)?;
+    let mut page = ptr & !(L1_PAGE_SIZE - 1);
+    while page < end {
+        let addr = VirtAddr::new(page);
+        let flags = match space.translate(addr) {
+            Some((_, flags)) => flags,
+            // Lazy pages the thread hasn't touched yet are backed now, as a read from user mode would.
+            None => {
+                space
+                    .resolve_fault(addr, MapFlags::USER_ACCESSIBLE)
+                    .map_err(|_| SyscallError::BadAddress)?;
+                space.translate(addr).ok_or(SyscallError::BadAddress)?.1
+            }
+        };
+        if !flags.contains(MapFlags::USER_ACCESSIBLE) {
+            return Err(SyscallError::BadAddress);
+        }
+        page += L1_PAGE_SIZE;
+    }
+
+    // SAFETY: Every page of the range was checked to be mapped and user accessible above, and user memory isn't
+    // unmapped while its thread is in a syscall.
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
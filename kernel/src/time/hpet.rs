@@ -0,0 +1,116 @@
+//! A minimal High Precision Event Timer driver, used as a reference clock.
+use ::acpi::sdt::hpet::HpetTable;
+use cake::log::{info, warn};
+use nmm::MapFlags;
+
+use crate::acpi;
+
+/// The General Capabilities and ID register.
+const CAPABILITIES: usize = 0x000;
+/// The General Configuration register.
+const CONFIGURATION: usize = 0x010;
+/// The Main Counter Value register.
+const MAIN_COUNTER: usize = 0x0f0;
+
+/// `ENABLE_CNF` in the configuration register: the main counter runs while it is set.
+const ENABLE: u64 = 1 << 0;
+/// `COUNT_SIZE_CAP` in the capabilities register: the main counter is 64 bits wide.
+const COUNTER_64BIT: u64 = 1 << 13;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// The register block of the HPET.
+#[derive(Debug)]
+pub struct Hpet {
+    regs: *mut u64,
+    period_fs: u64,
+    wide: bool,
+}
+
+// SAFETY: The registers are only read after initialization, which is safe from any core.
+unsafe impl Send for Hpet {}
+// SAFETY: See above.
+unsafe impl Sync for Hpet {}
+
+impl Hpet {
+    /// Finds the HPET through the ACPI HPET table, maps its registers and starts its main counter. Returns `None` if
+    /// there is no usable HPET.
+    pub fn from_acpi() -> Option<Self> {
+        let table = acpi::get_table::<HpetTable>().ok()?;
+        let base = table.base_address;
+        let address = base.address;
+        if base.address_space != 0 {
+            warn!("HPET registers aren't in system memory, ignoring it");
+            return None;
+        }
+
+        let mapping = nmm::create_phys_mapping(
+            x86_64::PhysAddr::new(address).into(),
+            0x400,
+            MapFlags::CACHE_DISABLE | MapFlags::WRITABLE,
+        )
+        .ok()?;
+        let mut hpet = Self {
+            regs: mapping.as_mut_ptr(),
+            period_fs: 0,
+            wide: false,
+        };
+
+        // SAFETY: The register block was just mapped.
+        let capabilities = unsafe { hpet.read(CAPABILITIES) };
+        hpet.period_fs = capabilities >> 32;
+        hpet.wide = capabilities & COUNTER_64BIT != 0;
+        if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+            warn!("HPET reports an invalid period of {} fs", hpet.period_fs);
+            return None;
+        }
+
+        // SAFETY: Starting the main counter doesn't affect anything else, none of the comparators are enabled.
+        unsafe {
+            let config = hpet.read(CONFIGURATION);
+            hpet.write(CONFIGURATION, config | ENABLE);
+        }
+        info!(
+            "HPET at {:#x}: {} Hz, {} bit counter",
+            address,
+            hpet.frequency(),
+            if hpet.wide { 64 } else { 32 }
+        );
+        Some(hpet)
+    }
+
+    /// Returns the frequency of the main counter in Hz.
+    pub fn frequency(&self) -> u64 {
+        FEMTOS_PER_SEC / self.period_fs
+    }
+
+    /// Reads the main counter. A 32 bit counter wraps around every few minutes.
+    pub fn counter(&self) -> u64 {
+        // SAFETY: The main counter is always readable.
+        let value = unsafe { self.read(MAIN_COUNTER) };
+        if self.wide {
+            value
+        } else {
+            value & u32::MAX as u64
+        }
+    }
+
+    /// Spins until `ticks` ticks of the main counter have passed.
+    pub fn spin(&self, ticks: u64) {
+        let start = self.counter();
+        let mask = if self.wide { u64::MAX } else { u32::MAX as u64 };
+        while self.counter().wrapping_sub(start) & mask < ticks {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn read(&self, offset: usize) -> u64 {
+        // SAFETY: The caller guarantees that `offset` is a register, which is mapped.
+        unsafe { self.regs.byte_add(offset).read_volatile() }
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        // SAFETY: The caller guarantees that `offset` is a writable register, which is mapped.
+        unsafe { self.regs.byte_add(offset).write_volatile(value) }
+    }
+}
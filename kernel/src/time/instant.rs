@@ -0,0 +1,134 @@
+//! Monotonic time measured with the TSC.
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+use super::tsc_frequency;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A point in monotonic time, like `std::time::Instant`.
+///
+/// Instants are TSC readings, so they are only comparable across cores if the TSCs of all cores are synchronized,
+/// which holds for every CPU with an invariant TSC.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current time.
+    pub fn now() -> Self {
+        Self(read_tsc())
+    }
+
+    /// Creates an instant from a raw TSC value.
+    pub const fn from_tsc(tsc: u64) -> Self {
+        Self(tsc)
+    }
+
+    /// Returns the TSC value of this instant.
+    pub const fn tsc(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time that passed since `earlier`, or `None` if `earlier` is later than this instant.
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(ticks_to_duration)
+    }
+
+    /// Returns the time that passed since `earlier`, or zero if `earlier` is later than this instant.
+    pub fn saturating_duration_since(self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// Returns the time that passed since `earlier`.
+    ///
+    /// # Panics
+    /// Panics if `earlier` is later than this instant.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .expect("supplied instant is later than self")
+    }
+
+    /// Returns the time that passed since this instant.
+    pub fn elapsed(self) -> Duration {
+        Instant::now().saturating_duration_since(self)
+    }
+
+    /// Returns this instant moved `duration` into the future, or `None` if that overflows.
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)?).map(Self)
+    }
+
+    /// Returns this instant moved `duration` into the past, or `None` if that underflows.
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_to_ticks(duration)?).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instant({:?})", ticks_to_duration(self.0))
+    }
+}
+
+/// Reads the TSC of the current core.
+pub fn read_tsc() -> u64 {
+    // SAFETY: RDTSC is available on every x86_64 CPU and has no side effects.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Converts a number of TSC ticks to a duration.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / tsc_frequency() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Converts a duration to a number of TSC ticks, or `None` if it doesn't fit in 64 bits.
+pub fn duration_to_ticks(duration: Duration) -> Option<u64> {
+    (duration.as_nanos() * tsc_frequency() as u128 / NANOS_PER_SEC)
+        .try_into()
+        .ok()
+}
@@ -0,0 +1,171 @@
+//! Timekeeping: calibrated clocks, monotonic time and the per-core LAPIC timer.
+//!
+//! During initialization, the TSC and the LAPIC timer are calibrated against a reference clock with a known frequency:
+//! the HPET if ACPI describes one, or the PIT otherwise. From then on, [Instant] measures monotonic time with the TSC,
+//! and the [timer] module arms the LAPIC timer of the current core in real time units. Deadlines, timeout callbacks and
+//! [sleep] are handled by the per-core timer wheels in [wheel].
+use core::convert::Infallible;
+
+use cake::{
+    Once,
+    log::{info, warn},
+};
+use raw_cpuid::CpuId;
+
+use crate::{
+    declare_module, interrupts,
+    mp::{LAPIC, lapic::lvt::timer::TimerMode},
+};
+
+pub mod hpet;
+mod instant;
+pub mod pit;
+pub mod timer;
+pub mod wheel;
+
+pub use core::time::Duration;
+pub use instant::{Instant, duration_to_ticks, read_tsc, ticks_to_duration};
+pub use wheel::{sleep, sleep_until};
+
+use hpet::Hpet;
+use timer::TIMER_DIVIDER;
+
+/// How long each calibration measures the TSC and the LAPIC timer for.
+const CALIBRATION_WINDOW: Duration = Duration::from_millis(10);
+
+/// The clock the TSC and the LAPIC timer were calibrated against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceClock {
+    /// The High Precision Event Timer.
+    Hpet,
+    /// The legacy Programmable Interval Timer.
+    Pit,
+}
+
+/// The measured frequencies of the clocks.
+#[derive(Debug, Clone, Copy)]
+pub struct Calibration {
+    /// The frequency of the TSC in Hz.
+    pub tsc_hz: u64,
+    /// The frequency the LAPIC timer counts down with in Hz, using [TIMER_DIVIDER].
+    pub lapic_timer_hz: u64,
+    /// The clock both were measured against.
+    pub reference: ReferenceClock,
+}
+
+static CALIBRATION: Once<Calibration> = Once::new();
+static HPET: Once<Option<Hpet>> = Once::new();
+static BOOT: Once<Instant> = Once::new();
+
+declare_module!("time", init);
+
+fn init() -> Result<(), Infallible> {
+    let cpuid = CpuId::with_cpuid_reader(raw_cpuid::CpuIdReaderNative);
+    if !cpuid
+        .get_advanced_power_mgmt_info()
+        .is_some_and(|apm| apm.has_invariant_tsc())
+    {
+        warn!("The TSC isn't invariant, time may drift with the CPU frequency");
+    }
+
+    let hpet = HPET.call_once(Hpet::from_acpi).as_ref();
+    let calibration = interrupts::without_interrupts(|| calibrate(hpet));
+    info!(
+        "Calibrated against the {:?}: TSC at {} Hz, LAPIC timer at {} Hz",
+        calibration.reference, calibration.tsc_hz, calibration.lapic_timer_hz
+    );
+    CALIBRATION.call_once(|| calibration);
+    BOOT.call_once(Instant::now);
+    Ok(())
+}
+
+/// Measures how far the TSC and the LAPIC timer of the current core advance during [CALIBRATION_WINDOW] of the
+/// reference clock.
+fn calibrate(hpet: Option<&Hpet>) -> Calibration {
+    let lvt = LAPIC.lvt();
+    let timer = lvt.timer();
+    timer.set_timer_divider(TIMER_DIVIDER);
+    // SAFETY: The timer is masked, so counting down doesn't raise any interrupt.
+    unsafe {
+        timer.update(|lvt| {
+            lvt.set_mask(true);
+            lvt.set_timer_mode(TimerMode::OneShot);
+        })
+    };
+
+    let (reference, wait): (ReferenceClock, &dyn Fn()) = match hpet {
+        Some(hpet) => {
+            let ticks = hpet.frequency() * CALIBRATION_WINDOW.as_micros() as u64 / 1_000_000;
+            (ReferenceClock::Hpet, &move || hpet.spin(ticks))
+        }
+        None => {
+            let ticks = pit::PIT_FREQUENCY * CALIBRATION_WINDOW.as_micros() as u64 / 1_000_000;
+            // SAFETY: Nothing else uses PIT channel 2.
+            (ReferenceClock::Pit, &move || unsafe {
+                pit::spin(ticks as u16)
+            })
+        }
+    };
+
+    timer.write_timer_initial_count(u32::MAX);
+    let tsc_start = read_tsc();
+    wait();
+    let tsc_end = read_tsc();
+    let lapic_ticks = u32::MAX - timer.read_timer_current_count();
+    timer.write_timer_initial_count(0);
+
+    let per_second = |ticks: u64| ticks * 1_000_000 / CALIBRATION_WINDOW.as_micros() as u64;
+    Calibration {
+        tsc_hz: per_second(tsc_end - tsc_start),
+        lapic_timer_hz: per_second(lapic_ticks as u64),
+        reference,
+    }
+}
+
+/// Returns the calibrated clock frequencies.
+///
+/// # Panics
+/// Panics if the time module hasn't been initialized yet.
+pub fn calibration() -> &'static Calibration {
+    CALIBRATION
+        .get()
+        .expect("Time used before the clocks were calibrated")
+}
+
+/// Returns the frequency of the TSC in Hz.
+pub fn tsc_frequency() -> u64 {
+    calibration().tsc_hz
+}
+
+/// Returns the frequency the LAPIC timer counts down with in Hz, using [TIMER_DIVIDER].
+pub fn lapic_timer_frequency() -> u64 {
+    calibration().lapic_timer_hz
+}
+
+/// Returns the HPET, if the system has one.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()?.as_ref()
+}
+
+/// Returns the time that passed since the clocks were calibrated.
+pub fn uptime() -> Duration {
+    BOOT.get().map_or(Duration::ZERO, |boot| boot.elapsed())
+}
+
+/// Busy-waits for `duration`.
+pub fn spin(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+#[kproc::test("Instants are monotonic")]
+fn instants_are_monotonic() {
+    let start = Instant::now();
+    spin(Duration::from_millis(5));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(5));
+    assert!(Instant::now() > start);
+    assert_eq!(start + elapsed - elapsed, start);
+}
@@ -0,0 +1,44 @@
+//! The legacy Programmable Interval Timer, used as a reference clock when there is no HPET.
+use x86_64::instructions::port::Port;
+
+/// The frequency of the PIT input clock in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// The NMI status and control port, which gates channel 2 and reports its output.
+const CONTROL: u16 = 0x61;
+
+/// Gate input of channel 2.
+const GATE2: u8 = 1 << 0;
+/// Connects channel 2 to the PC speaker.
+const SPEAKER: u8 = 1 << 1;
+/// Output of channel 2.
+const OUT2: u8 = 1 << 5;
+
+/// Spins until `ticks` ticks of the PIT input clock have passed, using channel 2 in one-shot mode. At most
+/// `u16::MAX` ticks (about 55 ms) can be waited for at once.
+///
+/// # Safety
+/// Nothing else may use channel 2 at the same time.
+pub unsafe fn spin(ticks: u16) {
+    let mut control = Port::<u8>::new(CONTROL);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL2_DATA);
+
+    // SAFETY: The caller guarantees exclusive use of channel 2, and the speaker stays disconnected.
+    unsafe {
+        let gate = control.read() & !(GATE2 | SPEAKER);
+        control.write(gate);
+        // Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary.
+        command.write(0b1011_0000);
+        data.write(ticks as u8);
+        data.write((ticks >> 8) as u8);
+        // Raising the gate starts the count, OUT2 goes high once it reaches zero.
+        control.write(gate | GATE2);
+        while control.read() & OUT2 == 0 {
+            core::hint::spin_loop();
+        }
+        control.write(gate);
+    }
+}
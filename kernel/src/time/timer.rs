@@ -0,0 +1,112 @@
+//! The LAPIC timer of the current core, configured in real time units.
+//!
+//! Every function here acts on the LAPIC of the core it runs on. The timer always counts with [TIMER_DIVIDER], the
+//! divider it was calibrated with.
+use core::{sync::atomic, time::Duration};
+
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+
+use crate::mp::{
+    LAPIC,
+    lapic::lvt::timer::{TimerDivider, TimerMode},
+};
+
+use super::{Instant, lapic_timer_frequency};
+
+/// The divider the LAPIC timer runs with.
+pub const TIMER_DIVIDER: TimerDivider = TimerDivider::By16;
+
+/// The `IA32_TSC_DEADLINE` MSR.
+const TSC_DEADLINE_MSR: u32 = 0x6e0;
+
+/// Returns `true` if the LAPIC timer supports TSC-deadline mode.
+pub fn supports_tsc_deadline() -> bool {
+    CpuId::with_cpuid_reader(raw_cpuid::CpuIdReaderNative)
+        .get_feature_info()
+        .is_some_and(|info| info.has_tsc_deadline())
+}
+
+/// Raises `vector` on the current core `frequency_hz` times per second, until the timer is disarmed or re-armed.
+///
+/// # Safety
+/// `vector` must have a handler installed that acknowledges the interrupt.
+pub unsafe fn arm_periodic(vector: u8, frequency_hz: u64) {
+    let count = (lapic_timer_frequency() / frequency_hz.max(1)).clamp(1, u32::MAX as u64);
+    // SAFETY: Guaranteed by the caller.
+    unsafe { arm_counting(vector, TimerMode::Periodic, count as u32) };
+}
+
+/// Raises `vector` on the current core once, after `delay`.
+///
+/// Delays longer than the timer can count (minutes, depending on its frequency) fire early, so callers waiting for a
+/// specific time should check it and re-arm.
+///
+/// # Safety
+/// `vector` must have a handler installed that acknowledges the interrupt.
+pub unsafe fn arm_oneshot(vector: u8, delay: Duration) {
+    let count = (delay.as_nanos() * lapic_timer_frequency() as u128 / 1_000_000_000)
+        .clamp(1, u32::MAX as u128);
+    // SAFETY: Guaranteed by the caller.
+    unsafe { arm_counting(vector, TimerMode::OneShot, count as u32) };
+}
+
+/// Raises `vector` on the current core once, as soon as `deadline` has passed.
+///
+/// This uses TSC-deadline mode where it is supported, and falls back to [arm_oneshot] otherwise.
+///
+/// # Safety
+/// `vector` must have a handler installed that acknowledges the interrupt.
+pub unsafe fn arm_deadline(vector: u8, deadline: Instant) {
+    if !supports_tsc_deadline() {
+        // SAFETY: Guaranteed by the caller.
+        unsafe { arm_oneshot(vector, deadline.saturating_duration_since(Instant::now())) };
+        return;
+    }
+
+    let lvt = LAPIC.lvt();
+    let timer = lvt.timer();
+    // SAFETY: The vector is valid as guaranteed by the caller.
+    unsafe {
+        timer.update(|lvt| {
+            lvt.set_vector(vector);
+            lvt.set_timer_mode(TimerMode::TscDeadline);
+            lvt.set_mask(false);
+        });
+    }
+    // The switch to TSC-deadline mode has to be visible before the deadline is written.
+    atomic::fence(atomic::Ordering::SeqCst);
+    // SAFETY: The CPU supports TSC-deadline mode, and a deadline in the past fires right away. Zero would disarm the
+    // timer instead.
+    unsafe { Msr::new(TSC_DEADLINE_MSR).write(deadline.tsc().max(1)) };
+}
+
+/// Stops the timer of the current core.
+pub fn disarm() {
+    let lvt = LAPIC.lvt();
+    let timer = lvt.timer();
+    // SAFETY: Masking the timer only stops it from raising interrupts.
+    unsafe { timer.update(|lvt| lvt.set_mask(true)) };
+    if timer.read().timer_mode() == TimerMode::TscDeadline as u8 {
+        // SAFETY: TSC-deadline mode is only ever set when the CPU supports it.
+        unsafe { Msr::new(TSC_DEADLINE_MSR).write(0) };
+    } else {
+        timer.write_timer_initial_count(0);
+    }
+}
+
+unsafe fn arm_counting(vector: u8, mode: TimerMode, count: u32) {
+    let lvt = LAPIC.lvt();
+    let timer = lvt.timer();
+    timer.set_timer_divider(TIMER_DIVIDER);
+    // SAFETY: The vector is valid as guaranteed by the caller.
+    unsafe {
+        timer.update(|lvt| {
+            lvt.set_vector(vector);
+            lvt.set_timer_mode(mode);
+            lvt.set_mask(false);
+        });
+    }
+    // Writing the initial count starts the timer.
+    timer.write_timer_initial_count(count);
+}
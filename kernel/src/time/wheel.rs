@@ -0,0 +1,303 @@
+//! Per-core hierarchical timer wheels for deadlines, timeout callbacks and sleeping.
+//!
+//! Every core has its own wheel, advanced by the scheduler tick ([KernelInterrupt::Timer]). Time is counted in
+//! [RESOLUTION] sized slots. The first level holds the timers due within the next [SLOTS] slots, and each further level
+//! covers [SLOTS] times the range of the previous one with the same number of coarser slots. Whenever a level wraps
+//! around, the timers in the next slot of the level above are cascaded down, so every timer is inserted and moved a
+//! bounded number of times no matter how far away it is.
+//!
+//! Callbacks run in the timer interrupt handler of the core they were registered on, with interrupts disabled, so they
+//! must be short and must not block.
+//!
+//! [KernelInterrupt::Timer]: crate::interrupts::KernelInterrupt::Timer
+use core::{fmt, time::Duration};
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, vec::Vec};
+
+use crate::{
+    interrupts,
+    mp::{self, Constructor, CoreLocal},
+    proc,
+};
+
+use super::{Instant, tsc_frequency};
+
+/// The length of a slot of the first level, and so the finest granularity of the wheels.
+pub const RESOLUTION: Duration = Duration::from_millis(1);
+
+/// The number of bits of the slot index within a level.
+const SLOT_BITS: u32 = 6;
+/// The number of slots in every level.
+const SLOTS: usize = 1 << SLOT_BITS;
+/// The number of levels. Together they cover `SLOTS^LEVELS` slots, about 4.6 hours at the default resolution. Timers
+/// further out wait in the last level and are cascaded again until they are in range.
+const LEVELS: usize = 4;
+
+/// A callback run once its deadline has passed.
+pub type TimerCallback = Box<dyn FnOnce() + Send>;
+
+/// The timer wheel of every core.
+static WHEELS: CoreLocal<TimerWheel> =
+    CoreLocal::new(TimerWheel::new(), Constructor(TimerWheel::new));
+
+/// Identifies a registered timer so it can be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    core_id: u64,
+    id: u64,
+}
+
+impl TimerHandle {
+    /// Cancels the timer. Returns `true` if it was cancelled before its callback ran.
+    pub fn cancel(self) -> bool {
+        interrupts::without_interrupts(|| {
+            WHEELS
+                .get(self.core_id)
+                .expect("Timer registered on a core that disappeared")
+                .write()
+                .timers
+                .remove(&self.id)
+                .is_some()
+        })
+    }
+}
+
+struct Timer {
+    deadline: Instant,
+    /// The slot the deadline falls into, rounded up so the timer never fires early.
+    expires: u64,
+    callback: TimerCallback,
+}
+
+struct TimerWheel {
+    /// The last slot that has been processed, or `None` if the wheel hasn't been used yet.
+    now: Option<u64>,
+    next_id: u64,
+    /// All pending timers. Cancelled timers are removed here and skipped when their slot comes up.
+    timers: BTreeMap<u64, Timer>,
+    /// The IDs of the timers in each slot of each level.
+    levels: [[Vec<u64>; SLOTS]; LEVELS],
+}
+
+impl fmt::Debug for TimerWheel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimerWheel")
+            .field("now", &self.now)
+            .field("pending", &self.timers.len())
+            .finish()
+    }
+}
+
+impl TimerWheel {
+    const fn new() -> Self {
+        TimerWheel {
+            now: None,
+            next_id: 0,
+            timers: BTreeMap::new(),
+            levels: [const { [const { Vec::new() }; SLOTS] }; LEVELS],
+        }
+    }
+
+    /// Returns the last processed slot, starting the wheel at the current time on first use.
+    fn now(&mut self) -> u64 {
+        *self.now.get_or_insert_with(|| slot_of(Instant::now()))
+    }
+
+    fn add(&mut self, deadline: Instant, callback: TimerCallback) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let expires = slot_of_deadline(deadline);
+        self.timers.insert(
+            id,
+            Timer {
+                deadline,
+                expires,
+                callback,
+            },
+        );
+        self.place(id, expires);
+        id
+    }
+
+    /// Puts the timer `id` into the slot of the lowest level whose range reaches `expires`.
+    fn place(&mut self, id: u64, expires: u64) {
+        let now = self.now();
+        // Timers that are already due go into the next slot of the first level.
+        let expires = expires.max(now + 1);
+        let delta = expires - now;
+        let level = (0..LEVELS)
+            .find(|level| delta < 1 << (SLOT_BITS * (*level as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+        let slot = (expires >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        self.levels[level][slot].push(id);
+    }
+
+    /// Advances the wheel up to `until`, collecting the callbacks of the expired timers ordered by deadline.
+    fn advance(&mut self, until: u64, expired: &mut Vec<(Instant, u64, TimerCallback)>) {
+        while self.now() < until {
+            let now = self.now() + 1;
+            self.now = Some(now);
+
+            // Cascade the levels that wrapped around, from the top so timers can fall through several levels.
+            let wrapped = (1..LEVELS)
+                .take_while(|level| now.is_multiple_of(1 << (SLOT_BITS * *level as u32)))
+                .last()
+                .unwrap_or(0);
+            for level in (1..=wrapped).rev() {
+                let slot = (now >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+                for id in core::mem::take(&mut self.levels[level][slot]) {
+                    self.expire_or_place(id, now, expired);
+                }
+            }
+            for id in core::mem::take(&mut self.levels[0][now as usize % SLOTS]) {
+                self.expire_or_place(id, now, expired);
+            }
+        }
+        expired.sort_unstable_by_key(|(deadline, id, _)| (*deadline, *id));
+    }
+
+    /// Collects the timer `id` if it expires by the slot `now`, or moves it to the slot it is due in otherwise. Timers
+    /// parked in the last level can come up more than once before they are due.
+    fn expire_or_place(
+        &mut self,
+        id: u64,
+        now: u64,
+        expired: &mut Vec<(Instant, u64, TimerCallback)>,
+    ) {
+        // Cancelled timers are only removed from the map.
+        let Some(expires) = self.timers.get(&id).map(|timer| timer.expires) else {
+            return;
+        };
+        if expires <= now {
+            let timer = self.timers.remove(&id).expect("Timer vanished");
+            expired.push((timer.deadline, id, timer.callback));
+        } else {
+            self.place(id, expires);
+        }
+    }
+}
+
+/// Returns the slot `instant` falls into.
+fn slot_of(instant: Instant) -> u64 {
+    instant.tsc() / ticks_per_slot()
+}
+
+/// Returns the first slot that starts at or after `deadline`.
+fn slot_of_deadline(deadline: Instant) -> u64 {
+    deadline.tsc().div_ceil(ticks_per_slot())
+}
+
+fn ticks_per_slot() -> u64 {
+    (tsc_frequency() as u128 * RESOLUTION.as_nanos() / 1_000_000_000).max(1) as u64
+}
+
+/// Runs `callback` on the current core once `deadline` has passed.
+pub fn at(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    interrupts::without_interrupts(|| {
+        let id = WHEELS.write().add(deadline, Box::new(callback));
+        TimerHandle {
+            core_id: mp::current_core_id(),
+            id,
+        }
+    })
+}
+
+/// Runs `callback` on the current core once `delay` has passed.
+pub fn after(delay: Duration, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    at(Instant::now() + delay, callback)
+}
+
+/// Advances the timer wheel of the current core to the current time and runs the expired callbacks. Called from the
+/// scheduler tick.
+pub(crate) fn tick() {
+    if super::CALIBRATION.get().is_none() {
+        return;
+    }
+    let mut expired = Vec::new();
+    {
+        let mut wheel = WHEELS.write();
+        if wheel.timers.is_empty() {
+            // Nothing to fire, skip ahead instead of walking the slots, which only hold cancelled timers.
+            wheel.now = None;
+            wheel.levels.iter_mut().flatten().for_each(Vec::clear);
+            return;
+        }
+        wheel.advance(slot_of(Instant::now()), &mut expired);
+    }
+    // The wheel is unlocked, so callbacks may register new timers.
+    for (_, _, callback) in expired {
+        callback();
+    }
+}
+
+/// Blocks the current thread until `deadline` has passed.
+///
+/// The thread is woken by the timer wheel, so it oversleeps by up to one scheduler tick. Before the scheduler runs on
+/// this core, this busy-waits instead.
+pub fn sleep_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        let mut timer = None;
+        let blocked = proc::block(|pid| {
+            timer = Some(at(deadline, move || {
+                proc::wake(pid);
+            }));
+        });
+        if !blocked {
+            super::spin(deadline.saturating_duration_since(Instant::now()));
+            return;
+        }
+        // Woken early (see `proc::block`), drop the stale timer before waiting again.
+        if let Some(timer) = timer
+            && Instant::now() < deadline
+        {
+            timer.cancel();
+        }
+    }
+}
+
+/// Blocks the current thread for `duration`. See [sleep_until].
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+#[kproc::test("Timers fire in order and on time")]
+fn timers_fire_in_order() {
+    use alloc::sync::Arc;
+    use cake::Mutex;
+
+    /// How late a timer may fire: up to two scheduler ticks, plus some leeway for emulators.
+    const TOLERANCE: Duration = Duration::from_millis(2 * 1000 / proc::SCHED_TICK_HZ + 10);
+
+    interrupts::enable();
+    let fired = Arc::new(Mutex::new(Vec::new()));
+    let start = Instant::now();
+    let delays = [30, 10, 50, 20, 10, 40];
+    for (index, delay) in delays.into_iter().enumerate() {
+        let fired = fired.clone();
+        after(Duration::from_millis(delay), move || {
+            fired.lock().push((index, Instant::now()));
+        });
+    }
+    let cancelled = after(Duration::from_millis(25), || {
+        panic!("Cancelled timer fired")
+    });
+    assert!(cancelled.cancel());
+
+    sleep(Duration::from_millis(60));
+    assert!(start.elapsed() >= Duration::from_millis(60));
+    // Tick handlers on this core run with interrupts disabled, so once they are off nothing can fire anymore.
+    interrupts::disable();
+
+    let fired = fired.lock();
+    let order = fired.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+    assert_eq!(order, [1, 4, 3, 0, 5, 2]);
+    for (index, at) in fired.iter() {
+        let deadline = start + Duration::from_millis(delays[*index]);
+        assert!(*at >= deadline);
+        assert!(
+            *at - deadline < TOLERANCE,
+            "Timer {index} fired {:?} late",
+            *at - deadline
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! The virtio block device.
+//!
+//! Requests go through a single queue. Every request is a chain of a header, naming the operation and the first
+//! sector, the data and a status byte written by the device. The thread issuing a request blocks until the device
+//! uses it, which the interrupt handler notices, or polls the queue if the device has no interrupt or the scheduler
+//! isn't running yet.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    block::{self, BlockDevice, BlockError},
+    interrupts::{InterruptMutex, IrqReturn},
+    memory::dma::DmaBuffer,
+    pci::{Capability, DriverEntry, PciDevice, PciDeviceId, PciDriver, PciHandle, ProbeError},
+    proc::{self, ThreadID},
+};
+
+use super::{
+    VENDOR_ID, feature,
+    queue::{Buffer, SplitQueue},
+    transport::{NO_VECTOR, Notifier, Transport},
+};
+
+/// The feature bits of block devices.
+mod blk_feature {
+    /// The device reports the largest size of a segment.
+    pub const SIZE_MAX: u64 = 1 << 1;
+    /// The device is read-only.
+    pub const RO: u64 = 1 << 5;
+    /// The device has a write cache, flushed with [super::request::FLUSH].
+    pub const FLUSH: u64 = 1 << 9;
+}
+
+/// The registers of the device configuration.
+mod config {
+    /// The number of 512-byte sectors of the device.
+    pub const CAPACITY: usize = 0x00;
+    /// The largest size of a segment, with [super::blk_feature::SIZE_MAX].
+    pub const SIZE_MAX: usize = 0x08;
+}
+
+/// The request types.
+mod request {
+    pub const IN: u32 = 0;
+    pub const OUT: u32 = 1;
+    pub const FLUSH: u32 = 4;
+}
+
+/// The status the device writes once it completed a request.
+const STATUS_OK: u8 = 0;
+/// The status the device writes for requests of an unsupported type.
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// The size of a sector, which every request is expressed in.
+const SECTOR_SIZE: usize = 512;
+/// The size of the request queue, if the device supports it.
+const QUEUE_SIZE: u16 = 128;
+/// The most bytes transferred by a single request.
+const MAX_TRANSFER: usize = 64 * 1024;
+/// The size of the request header.
+const HEADER_SIZE: usize = 16;
+
+/// The number of block devices found, used to name them.
+static DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The driver for virtio block devices, both transitional and modern.
+#[derive(Debug)]
+pub struct VirtioBlkDriver;
+
+impl PciDriver for VirtioBlkDriver {
+    const NAME: &'static str = "virtio-blk";
+    const IDS: &'static [PciDeviceId] = &[
+        PciDeviceId::new(VENDOR_ID, 0x1001),
+        PciDeviceId::new(VENDOR_ID, 0x1042),
+    ];
+    type State = Arc<VirtioBlk>;
+
+    fn probe(handle: PciHandle, _: &PciDeviceId) -> Result<Arc<VirtioBlk>, ProbeError> {
+        let device = Arc::new(VirtioBlk::new(handle)?);
+        block::register(device.clone());
+        Ok(device)
+    }
+
+    fn remove(state: Arc<VirtioBlk>) {
+        block::unregister(state.name());
+    }
+}
+
+/// The PCI driver entry of [VirtioBlkDriver].
+pub static PCI_DRIVER: DriverEntry = DriverEntry::new::<VirtioBlkDriver>();
+
+/// A request the device hasn't completed yet.
+#[derive(Debug)]
+struct Completion {
+    done: AtomicBool,
+    waiter: InterruptMutex<Option<ThreadID>>,
+}
+
+impl Completion {
+    fn new() -> Self {
+        Completion {
+            done: AtomicBool::new(false),
+            waiter: InterruptMutex::new(None),
+        }
+    }
+
+    fn complete(&self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(pid) = self.waiter.lock().take() {
+            proc::wake(pid);
+        }
+    }
+}
+
+/// The queue and the requests in flight in it, indexed by the first descriptor of their chain.
+#[derive(Debug)]
+struct Inflight {
+    queue: SplitQueue,
+    requests: Vec<Option<Arc<Completion>>>,
+}
+
+/// The state shared with the interrupt handler.
+#[derive(Debug)]
+struct Shared {
+    inflight: InterruptMutex<Inflight>,
+}
+
+impl Shared {
+    /// Completes every request the device is done with.
+    fn reap(&self) {
+        let mut inflight = self.inflight.lock();
+        while let Some((head, _)) = inflight.queue.pop_used() {
+            if let Some(completion) = inflight.requests[head as usize].take() {
+                completion.complete();
+            }
+        }
+    }
+}
+
+fn handle_irq(_: u8, cookie: usize) -> IrqReturn {
+    // SAFETY: The cookie points to the shared state of the device, which outlives the registration of the handler.
+    let shared = unsafe { &*(cookie as *const Shared) };
+    shared.reap();
+    IrqReturn::Handled
+}
+
+/// A virtio block device.
+#[derive(Debug)]
+pub struct VirtioBlk {
+    // Dropped first, so the interrupt handler is unregistered before the state it uses is freed.
+    handle: PciHandle,
+    transport: Transport,
+    shared: Arc<Shared>,
+    notifier: Notifier,
+    name: String,
+    capacity: u64,
+    read_only: bool,
+    flush: bool,
+    max_transfer: usize,
+    interrupts: bool,
+}
+
+impl VirtioBlk {
+    fn new(mut handle: PciHandle) -> Result<Self, ProbeError> {
+        let transport = Transport::new(&mut handle)?;
+        let features = transport.negotiate(
+            feature::VERSION_1 | blk_feature::SIZE_MAX | blk_feature::RO | blk_feature::FLUSH,
+        )?;
+        let capacity = transport
+            .read_config_u64(config::CAPACITY)
+            .ok_or(ProbeError::Device(
+                "Virtio block device without configuration",
+            ))?;
+        let mut max_transfer = MAX_TRANSFER;
+        if features & blk_feature::SIZE_MAX != 0
+            && let Some(size_max) = transport.read_config::<u32>(config::SIZE_MAX)
+            && size_max as usize >= SECTOR_SIZE
+        {
+            max_transfer = max_transfer.min(size_max as usize / SECTOR_SIZE * SECTOR_SIZE);
+        }
+
+        let max_size = transport.max_queue_size(0);
+        if max_size == 0 {
+            transport.fail();
+            return Err(ProbeError::Device(
+                "Virtio block device without a request queue",
+            ));
+        }
+        let size = 1 << QUEUE_SIZE.min(max_size).ilog2();
+        let queue = SplitQueue::new(size).map_err(|_| ProbeError::Device("Out of memory"))?;
+        let shared = Arc::new(Shared {
+            inflight: InterruptMutex::new(Inflight {
+                queue,
+                requests: (0..size).map(|_| None).collect(),
+            }),
+        });
+        // Bound again so it is dropped before the shared state if probing fails, unregistering the handler first.
+        let mut handle = handle;
+
+        // Without MSI-X the device can only raise its legacy interrupt line, so it is polled instead.
+        let interrupts = handle.device().capability(Capability::MSI_X).is_some()
+            && handle.enable_interrupts(1).is_ok();
+        let vector = if interrupts {
+            handle.request_irq(0, handle_irq, Arc::as_ptr(&shared) as usize)?;
+            0
+        } else {
+            NO_VECTOR
+        };
+        transport.set_config_vector(NO_VECTOR)?;
+        // SAFETY: The queue lives as long as the device, which is reset when it is dropped.
+        let notifier = unsafe { transport.setup_queue(0, &shared.inflight.lock().queue, vector) }?;
+        handle.enable_bus_master();
+        transport.driver_ok();
+
+        let index = DEVICE_COUNT.fetch_add(1, Ordering::Relaxed);
+        Ok(VirtioBlk {
+            handle,
+            transport,
+            shared,
+            notifier,
+            name: format!("vd{}", (b'a' + (index % 26) as u8) as char),
+            capacity,
+            read_only: features & blk_feature::RO != 0,
+            flush: features & blk_feature::FLUSH != 0,
+            max_transfer,
+            interrupts,
+        })
+    }
+
+    /// Returns the PCI function of the device.
+    pub fn pci_device(&self) -> &'static PciDevice {
+        self.handle.device()
+    }
+
+    /// Submits a request of type `kind` for `len` bytes of data starting at `sector`, which are read from or written to
+    /// the start of `buffer`, and waits for it to complete.
+    fn request(
+        &self,
+        kind: u32,
+        sector: u64,
+        buffer: &DmaBuffer,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        // The header and the status follow the data in the buffer.
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(&kind.to_le_bytes());
+        header[8..].copy_from_slice(&sector.to_le_bytes());
+        buffer.write_bytes(len, &header);
+        buffer.write_bytes(len + HEADER_SIZE, &[0xff]);
+        let mut chain = Vec::with_capacity(3);
+        chain.push(Buffer {
+            addr: buffer.phys_addr() + len as u64,
+            len: HEADER_SIZE as u32,
+            writable: false,
+        });
+        if len != 0 {
+            chain.push(Buffer {
+                addr: buffer.phys_addr(),
+                len: len as u32,
+                writable: kind == request::IN,
+            });
+        }
+        chain.push(Buffer {
+            addr: buffer.phys_addr() + (len + HEADER_SIZE) as u64,
+            len: 1,
+            writable: true,
+        });
+
+        let completion = Arc::new(Completion::new());
+        loop {
+            let mut inflight = self.shared.inflight.lock();
+            // SAFETY: The buffer outlives the request, which is waited for below.
+            if let Some(head) = unsafe { inflight.queue.push(&chain) } {
+                inflight.requests[head as usize] = Some(completion.clone());
+                if inflight.queue.needs_notification() {
+                    self.notifier.notify();
+                }
+                break;
+            }
+            drop(inflight);
+            // The queue is full, wait for other requests to complete.
+            self.shared.reap();
+            core::hint::spin_loop();
+        }
+        self.wait(&completion);
+
+        let mut status = [0];
+        buffer.read_bytes(len + HEADER_SIZE, &mut status);
+        match status[0] {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    /// Waits for the device to complete the request of `completion`.
+    fn wait(&self, completion: &Completion) {
+        loop {
+            // The interrupt may not be delivered yet, if interrupts are disabled on this core.
+            self.shared.reap();
+            if completion.done.load(Ordering::SeqCst) {
+                return;
+            }
+            let blocked = self.interrupts
+                && proc::block(|pid| {
+                    *completion.waiter.lock() = Some(pid);
+                    // The request may have completed before the thread was registered as its waiter.
+                    if completion.done.load(Ordering::SeqCst) {
+                        proc::wake(pid);
+                    }
+                });
+            if !blocked {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Transfers `len` bytes starting at `sector` through a new DMA buffer, calling `fill` on it before a write and
+    /// `drain` after a read.
+    fn transfer(
+        &self,
+        kind: u32,
+        sector: u64,
+        len: usize,
+        fill: impl FnOnce(&DmaBuffer),
+        drain: impl FnOnce(&DmaBuffer),
+    ) -> Result<(), BlockError> {
+        let buffer = DmaBuffer::new(len + HEADER_SIZE + 1).map_err(|_| BlockError::OutOfMemory)?;
+        fill(&buffer);
+        self.request(kind, sector, &buffer, len)?;
+        drain(&buffer);
+        Ok(())
+    }
+}
+
+impl Drop for VirtioBlk {
+    fn drop(&mut self) {
+        // The device must stop using the queue before its memory is freed.
+        self.transport.reset();
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_request(sector, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(self.max_transfer).enumerate() {
+            let sector = sector + (i * self.max_transfer / SECTOR_SIZE) as u64;
+            let len = chunk.len();
+            self.transfer(
+                request::IN,
+                sector,
+                len,
+                |_| {},
+                |buffer| buffer.read_bytes(0, chunk),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_request(sector, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        for (i, chunk) in buf.chunks(self.max_transfer).enumerate() {
+            let sector = sector + (i * self.max_transfer / SECTOR_SIZE) as u64;
+            self.transfer(
+                request::OUT,
+                sector,
+                chunk.len(),
+                |buffer| buffer.write_bytes(0, chunk),
+                |_| {},
+            )?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // Devices without a write cache write through.
+        if !self.flush {
+            return Ok(());
+        }
+        self.transfer(request::FLUSH, 0, 0, |_| {}, |_| {})
+    }
+}
+
+#[kproc::test("virtio-blk read and write")]
+fn virtio_blk_read_write() {
+    // The test runner attaches a scratch disk, see `krun::QemuConfig::disks`.
+    let disk = block::devices()
+        .into_iter()
+        .find(|disk| disk.name().starts_with("vd") && !disk.is_read_only())
+        .expect("No writable virtio block device, the test runner attaches one");
+    let count = disk.sector_count();
+    assert!(count >= 2);
+    assert_eq!(
+        disk.read(count - 1, &mut [0; 2 * SECTOR_SIZE]),
+        Err(BlockError::OutOfRange(count - 1, count + 1))
+    );
+    assert_eq!(
+        disk.read(0, &mut [0; 100]),
+        Err(BlockError::UnalignedBuffer(100))
+    );
+
+    // Spans several requests, to exercise splitting.
+    let len = MAX_TRANSFER + 2 * SECTOR_SIZE;
+    let sectors = (len / SECTOR_SIZE) as u64;
+    assert!(count >= sectors, "The test disk is too small");
+    let first = count - sectors;
+    let mut original = alloc::vec![0; len];
+    disk.read(first, &mut original).unwrap();
+    let pattern: Vec<u8> = (0..len).map(|i| (i * 7 + i / SECTOR_SIZE) as u8).collect();
+    disk.write(first, &pattern).unwrap();
+    disk.flush().unwrap();
+    let mut read = alloc::vec![0; len];
+    disk.read(first, &mut read).unwrap();
+    assert!(
+        read == pattern,
+        "Data read back differs from the data written"
+    );
+    disk.write(first, &original).unwrap();
+    disk.flush().unwrap();
+}
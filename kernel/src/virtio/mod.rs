@@ -0,0 +1,46 @@
+//! Virtio devices over the modern PCI transport.
+//!
+//! Every virtio device shares the same initialization sequence and the same kind of queues, implemented in
+//! [transport] and [queue]. The drivers for the device types built on top of them are registered with the PCI driver
+//! model when the module is initialized.
+use core::convert::Infallible;
+
+use crate::{declare_module, pci};
+
+pub mod blk;
+pub mod net;
+pub mod queue;
+pub mod transport;
+
+/// The vendor ID of every virtio PCI function.
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// The bits of the device status.
+pub mod status {
+    /// The driver found the device.
+    pub const ACKNOWLEDGE: u8 = 1;
+    /// The driver knows how to drive the device.
+    pub const DRIVER: u8 = 2;
+    /// The driver is ready and the device may be used.
+    pub const DRIVER_OK: u8 = 4;
+    /// Feature negotiation is complete.
+    pub const FEATURES_OK: u8 = 8;
+    /// The device hit an error it can't recover from without a reset.
+    pub const DEVICE_NEEDS_RESET: u8 = 64;
+    /// The driver gave up on the device.
+    pub const FAILED: u8 = 128;
+}
+
+/// The feature bits shared by every device type.
+pub mod feature {
+    /// The device implements the virtio 1.0 interface rather than the legacy one.
+    pub const VERSION_1: u64 = 1 << 32;
+}
+
+declare_module!("virtio", init);
+
+fn init() -> Result<(), Infallible> {
+    pci::register_driver(&blk::PCI_DRIVER);
+    pci::register_driver(&net::PCI_DRIVER);
+    Ok(())
+}
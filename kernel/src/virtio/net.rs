@@ -0,0 +1,325 @@
+//! The virtio network device.
+//!
+//! The device has a receive queue, kept full of buffers the device writes incoming frames to, and a transmit queue.
+//! Buffers of both queues are fixed-size slots of a single DMA buffer per queue, each starting with the header the
+//! device expects before every frame. Received frames are handed to the network stack from the interrupt handler, or
+//! when the stack polls the device if it has no interrupt.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    interrupts::{InterruptMutex, IrqReturn},
+    memory::dma::DmaBuffer,
+    net::{self, InterfaceConfig, NetDevice, NetError, ethernet::MacAddress},
+    pci::{Capability, DriverEntry, PciDevice, PciDeviceId, PciDriver, PciHandle, ProbeError},
+};
+
+use super::{
+    VENDOR_ID, feature,
+    queue::{Buffer, SplitQueue},
+    transport::{NO_VECTOR, Notifier, Transport},
+};
+
+/// The feature bits of network devices.
+mod net_feature {
+    /// The device reports its MAC address.
+    pub const MAC: u64 = 1 << 5;
+    /// The device reports the status of its link.
+    pub const STATUS: u64 = 1 << 16;
+}
+
+/// The registers of the device configuration.
+mod config {
+    /// The MAC address, with [super::net_feature::MAC].
+    pub const MAC: usize = 0x00;
+    /// The link status, with [super::net_feature::STATUS].
+    pub const STATUS: usize = 0x06;
+}
+
+/// The link is up, in the link status.
+const STATUS_LINK_UP: u16 = 1;
+
+/// The index of the receive queue.
+const RX_QUEUE: u16 = 0;
+/// The index of the transmit queue.
+const TX_QUEUE: u16 = 1;
+/// The size of each queue, if the device supports it.
+const QUEUE_SIZE: u16 = 64;
+/// The size of the header preceding every frame.
+const HEADER_SIZE: usize = 12;
+/// The largest frame, without its checksum.
+const MAX_FRAME_SIZE: usize = 1514;
+/// The size of the slot of a buffer, which holds a header and a frame.
+const SLOT_SIZE: usize = 2048;
+
+/// The number of network devices found, used to name them.
+static DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The driver for virtio network devices, both transitional and modern.
+#[derive(Debug)]
+pub struct VirtioNetDriver;
+
+impl PciDriver for VirtioNetDriver {
+    const NAME: &'static str = "virtio-net";
+    const IDS: &'static [PciDeviceId] = &[
+        PciDeviceId::new(VENDOR_ID, 0x1000),
+        PciDeviceId::new(VENDOR_ID, 0x1041),
+    ];
+    type State = Arc<VirtioNet>;
+
+    fn probe(handle: PciHandle, _: &PciDeviceId) -> Result<Arc<VirtioNet>, ProbeError> {
+        let device = Arc::new(VirtioNet::new(handle)?);
+        net::register(device.clone(), InterfaceConfig::QEMU_USER);
+        Ok(device)
+    }
+
+    fn remove(state: Arc<VirtioNet>) {
+        net::unregister(state.name());
+    }
+}
+
+/// The PCI driver entry of [VirtioNetDriver].
+pub static PCI_DRIVER: DriverEntry = DriverEntry::new::<VirtioNetDriver>();
+
+/// A queue and the slots of its buffers.
+#[derive(Debug)]
+struct Ring {
+    queue: SplitQueue,
+    buffers: DmaBuffer,
+    notifier: Notifier,
+    /// The slot of the chain starting at each descriptor.
+    slots: Vec<u16>,
+    /// The slots not in the queue, only used for transmission.
+    free: Vec<u16>,
+}
+
+impl Ring {
+    fn new(transport: &Transport, index: u16, vector: u16) -> Result<Self, ProbeError> {
+        let max_size = transport.max_queue_size(index);
+        if max_size == 0 {
+            return Err(ProbeError::Device("Virtio network device without a queue"));
+        }
+        let size = 1 << QUEUE_SIZE.min(max_size).ilog2();
+        let queue = SplitQueue::new(size).map_err(|_| ProbeError::Device("Out of memory"))?;
+        let buffers = DmaBuffer::new(size as usize * SLOT_SIZE)
+            .map_err(|_| ProbeError::Device("Out of memory"))?;
+        // SAFETY: The queue lives as long as the device, which is reset when it is dropped.
+        let notifier = unsafe { transport.setup_queue(index, &queue, vector) }?;
+        Ok(Ring {
+            queue,
+            buffers,
+            notifier,
+            slots: alloc::vec![0; size as usize],
+            free: (0..size).collect(),
+        })
+    }
+
+    /// Hands the slot `slot` to the device, holding `len` bytes or to be written if `writable`.
+    fn push(&mut self, slot: u16, len: usize, writable: bool) {
+        let buffer = Buffer {
+            addr: self.buffers.phys_addr() + (slot as usize * SLOT_SIZE) as u64,
+            len: len as u32,
+            writable,
+        };
+        // SAFETY: The slots live as long as the queue, and a slot is only pushed once until it is used.
+        let head = unsafe { self.queue.push(&[buffer]) }.expect("Virtio network queue overflow");
+        self.slots[head as usize] = slot;
+    }
+
+    fn notify(&self) {
+        if self.queue.needs_notification() {
+            self.notifier.notify();
+        }
+    }
+}
+
+/// The state shared with the interrupt handler.
+#[derive(Debug)]
+struct Shared {
+    name: String,
+    rx: InterruptMutex<Ring>,
+    tx: InterruptMutex<Ring>,
+}
+
+impl Shared {
+    /// Frees the transmitted slots and hands the received frames to the network stack.
+    fn process(&self) {
+        let mut tx = self.tx.lock();
+        while let Some((head, _)) = tx.queue.pop_used() {
+            let slot = tx.slots[head as usize];
+            tx.free.push(slot);
+        }
+        drop(tx);
+
+        let mut frames = Vec::new();
+        let mut rx = self.rx.lock();
+        while let Some((head, len)) = rx.queue.pop_used() {
+            let slot = rx.slots[head as usize];
+            let len = (len as usize).clamp(HEADER_SIZE, SLOT_SIZE);
+            let mut frame = alloc::vec![0; len - HEADER_SIZE];
+            rx.buffers
+                .read_bytes(slot as usize * SLOT_SIZE + HEADER_SIZE, &mut frame);
+            frames.push(frame);
+            rx.push(slot, SLOT_SIZE, true);
+        }
+        if !frames.is_empty() {
+            rx.notify();
+        }
+        drop(rx);
+
+        // The queues are unlocked, so the stack may transmit replies.
+        for frame in frames {
+            net::receive(&self.name, &frame);
+        }
+    }
+}
+
+fn handle_irq(_: u8, cookie: usize) -> IrqReturn {
+    // SAFETY: The cookie points to the shared state of the device, which outlives the registration of the handler.
+    let shared = unsafe { &*(cookie as *const Shared) };
+    shared.process();
+    IrqReturn::Handled
+}
+
+/// A virtio network device.
+#[derive(Debug)]
+pub struct VirtioNet {
+    // Dropped first, so the interrupt handler is unregistered before the state it uses is freed.
+    handle: PciHandle,
+    transport: Transport,
+    shared: Arc<Shared>,
+    mac: MacAddress,
+    link_status: bool,
+}
+
+impl VirtioNet {
+    fn new(mut handle: PciHandle) -> Result<Self, ProbeError> {
+        let transport = Transport::new(&mut handle)?;
+        let features =
+            transport.negotiate(feature::VERSION_1 | net_feature::MAC | net_feature::STATUS)?;
+        let index = DEVICE_COUNT.fetch_add(1, Ordering::Relaxed);
+        // A locally administered address, for devices that don't report theirs.
+        let mut mac = MacAddress([0x02, 0, 0, 0, 0, index as u8]);
+        if features & net_feature::MAC != 0 {
+            for (i, byte) in mac.0.iter_mut().enumerate() {
+                *byte = transport.read_config(config::MAC + i).unwrap_or_default();
+            }
+        }
+
+        // Without MSI-X the device can only raise its legacy interrupt line, so it is polled instead.
+        let interrupts = handle.device().capability(Capability::MSI_X).is_some()
+            && handle.enable_interrupts(1).is_ok();
+        let vector = if interrupts { 0 } else { NO_VECTOR };
+        transport.set_config_vector(NO_VECTOR)?;
+        let rx = Ring::new(&transport, RX_QUEUE, vector).inspect_err(|_| transport.fail())?;
+        // The receive queue is enabled by now, so the device must forget it before it is freed.
+        let abort = |_: &ProbeError| {
+            transport.reset();
+            transport.fail();
+        };
+        let tx = Ring::new(&transport, TX_QUEUE, vector).inspect_err(abort)?;
+        let shared = Arc::new(Shared {
+            name: format!("eth{index}"),
+            rx: InterruptMutex::new(rx),
+            tx: InterruptMutex::new(tx),
+        });
+        // Bound again so it is dropped before the shared state if probing fails, unregistering the handler first.
+        let mut handle = handle;
+        if interrupts {
+            handle
+                .request_irq(0, handle_irq, Arc::as_ptr(&shared) as usize)
+                .inspect_err(abort)?;
+        }
+
+        // The receive queue is filled before the device starts, but may only be notified after.
+        let mut rx = shared.rx.lock();
+        for slot in core::mem::take(&mut rx.free) {
+            rx.push(slot, SLOT_SIZE, true);
+        }
+        drop(rx);
+        handle.enable_bus_master();
+        transport.driver_ok();
+        shared.rx.lock().notify();
+
+        Ok(VirtioNet {
+            handle,
+            transport,
+            shared,
+            mac,
+            link_status: features & net_feature::STATUS != 0,
+        })
+    }
+
+    /// Returns the PCI function of the device.
+    pub fn pci_device(&self) -> &'static PciDevice {
+        self.handle.device()
+    }
+}
+
+impl Drop for VirtioNet {
+    fn drop(&mut self) {
+        // The device must stop using the queues before their memory is freed.
+        self.transport.reset();
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        &self.shared.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        // Devices that don't report their link status are always up.
+        !self.link_status
+            || self
+                .transport
+                .read_config::<u16>(config::STATUS)
+                .is_some_and(|status| status & STATUS_LINK_UP != 0)
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::MessageTooLong(frame.len()));
+        }
+        loop {
+            let mut tx = self.shared.tx.lock();
+            if let Some(slot) = tx.free.pop() {
+                let offset = slot as usize * SLOT_SIZE;
+                tx.buffers.write_bytes(offset, &[0; HEADER_SIZE]);
+                tx.buffers.write_bytes(offset + HEADER_SIZE, frame);
+                tx.push(slot, HEADER_SIZE + frame.len(), false);
+                tx.notify();
+                return Ok(());
+            }
+            drop(tx);
+            // Every slot is in flight, wait for the device to send some.
+            self.shared.process();
+            core::hint::spin_loop();
+        }
+    }
+
+    fn poll(&self) {
+        self.shared.process();
+    }
+}
+
+#[kproc::test("virtio-net ping")]
+fn virtio_net_ping() {
+    use crate::time::Duration;
+
+    // The test runner attaches a NIC with user networking, see `krun::QemuConfig::network`.
+    let interface = net::find("eth0").expect("No eth0, the test runner attaches a virtio NIC");
+    let config = interface.config();
+    assert_eq!(config, InterfaceConfig::QEMU_USER);
+    assert!(interface.device().link_up(), "The link of eth0 is down");
+    // QEMU's user networking answers pings to the gateway, which needs ARP to resolve its address first.
+    let gateway = config.gateway.unwrap();
+    net::icmp::ping(gateway, Duration::from_secs(2)).unwrap();
+    // Pings to the interface itself are delivered locally.
+    net::icmp::ping(config.address, Duration::from_millis(100)).unwrap();
+}
@@ -0,0 +1,280 @@
+//! Split virtqueues.
+//!
+//! A split virtqueue is made of three areas shared with the device: the descriptor table, describing buffers, the
+//! available ring, where the driver hands chains of descriptors to the device, and the used ring, where the device
+//! hands them back once it is done with them.
+use core::sync::atomic::{Ordering, fence};
+
+use nmm::MemError;
+
+use crate::memory::dma::DmaBuffer;
+
+/// The descriptor continues in the one in `next`.
+const DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device rather than read.
+const DESC_F_WRITE: u16 = 2;
+/// Set by the device in the used ring flags when it doesn't need to be notified of new buffers.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+/// An entry of the descriptor table.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer of a request, in memory the device can access.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    /// The physical address of the buffer.
+    pub addr: u64,
+    /// The length of the buffer in bytes.
+    pub len: u32,
+    /// Whether the device writes the buffer rather than reads it.
+    pub writable: bool,
+}
+
+/// A split virtqueue and the state the driver keeps about it.
+#[derive(Debug)]
+pub struct SplitQueue {
+    memory: DmaBuffer,
+    size: u16,
+    /// The first descriptor of the list of free ones, linked through their `next` field.
+    free_head: u16,
+    free_count: u16,
+    /// The index of the next available ring entry to fill.
+    avail_idx: u16,
+    /// The index of the next used ring entry to read.
+    last_used: u16,
+}
+
+impl SplitQueue {
+    /// Allocates a queue of `size` descriptors, which must be a power of two no larger than 32768.
+    pub fn new(size: u16) -> Result<Self, MemError> {
+        assert!(
+            size.is_power_of_two() && size <= 0x8000,
+            "Invalid queue size {size}"
+        );
+        let (_, _, length) = Self::layout(size);
+        let queue = SplitQueue {
+            memory: DmaBuffer::new(length)?,
+            size,
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used: 0,
+        };
+        for index in 0..size {
+            // SAFETY: The descriptor is within the table, which the device doesn't know about yet.
+            unsafe {
+                queue.descriptor(index).write_volatile(Descriptor {
+                    addr: 0,
+                    len: 0,
+                    flags: 0,
+                    next: index.wrapping_add(1),
+                })
+            };
+        }
+        Ok(queue)
+    }
+
+    /// Returns the offsets of the available ring and the used ring, and the total size of a queue of `size`
+    /// descriptors.
+    fn layout(size: u16) -> (usize, usize, usize) {
+        let size = size as usize;
+        let avail = size * size_of::<Descriptor>();
+        // flags, idx, the ring and used_event.
+        let used = (avail + 2 * (3 + size)).next_multiple_of(4);
+        // flags, idx, the ring of (id, len) pairs and avail_event.
+        (avail, used, used + 2 * 3 + 8 * size)
+    }
+
+    /// Returns the number of descriptors of the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the number of descriptors that aren't in use.
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    /// Returns the physical address of the descriptor table.
+    pub fn descriptors_addr(&self) -> u64 {
+        self.memory.phys_addr()
+    }
+
+    /// Returns the physical address of the available ring.
+    pub fn driver_addr(&self) -> u64 {
+        self.memory.phys_addr() + Self::layout(self.size).0 as u64
+    }
+
+    /// Returns the physical address of the used ring.
+    pub fn device_addr(&self) -> u64 {
+        self.memory.phys_addr() + Self::layout(self.size).1 as u64
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        assert!(index < self.size);
+        self.memory
+            .as_mut_ptr::<Descriptor>()
+            .wrapping_add(index as usize)
+    }
+
+    /// Returns the 16-bit field `index` of the available ring: the flags, the index and then the ring.
+    fn avail(&self, index: usize) -> *mut u16 {
+        let offset = Self::layout(self.size).0 + 2 * index;
+        self.memory.as_mut_ptr::<u8>().wrapping_add(offset).cast()
+    }
+
+    /// Returns the 16-bit field `index` of the used ring: the flags and the index.
+    fn used_header(&self, index: usize) -> *mut u16 {
+        let offset = Self::layout(self.size).1 + 2 * index;
+        self.memory.as_mut_ptr::<u8>().wrapping_add(offset).cast()
+    }
+
+    /// Returns the (id, len) pair of the used ring entry `index`.
+    fn used_entry(&self, index: u16) -> *mut [u32; 2] {
+        let offset = Self::layout(self.size).1 + 4 + 8 * (index % self.size) as usize;
+        self.memory.as_mut_ptr::<u8>().wrapping_add(offset).cast()
+    }
+
+    /// Hands the chain of `buffers` to the device and returns the index of its first descriptor, which identifies it
+    /// once it is used. Returns `None` if there aren't enough free descriptors. The device still has to be notified.
+    ///
+    /// # Safety
+    /// The buffers must stay valid until the chain is returned by [SplitQueue::pop_used].
+    pub unsafe fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        assert!(!buffers.is_empty(), "Empty descriptor chain");
+        if buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(index);
+            // SAFETY: The descriptor is free, so the device doesn't look at it.
+            let next = unsafe { descriptor.read_volatile() }.next;
+            let mut flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            // SAFETY: See above.
+            unsafe {
+                descriptor.write_volatile(Descriptor {
+                    addr: buffer.addr,
+                    len: buffer.len,
+                    flags,
+                    next,
+                })
+            };
+            self.free_head = next;
+            index = next;
+        }
+        self.free_count -= buffers.len() as u16;
+        let slot = 2 + (self.avail_idx % self.size) as usize;
+        // SAFETY: The ring entry is past the available index, so the device doesn't read it yet.
+        unsafe { self.avail(slot).write_volatile(head) };
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // The descriptors and the ring entry must be visible before the index that exposes them.
+        fence(Ordering::SeqCst);
+        // SAFETY: The available index is only written by the driver.
+        unsafe { self.avail(1).write_volatile(self.avail_idx) };
+        Some(head)
+    }
+
+    /// Returns `true` unless the device asked not to be notified of new buffers.
+    pub fn needs_notification(&self) -> bool {
+        fence(Ordering::SeqCst);
+        // SAFETY: The used ring flags are only read.
+        unsafe { self.used_header(0).read_volatile() & USED_F_NO_NOTIFY == 0 }
+    }
+
+    /// Takes the next chain the device is done with, and returns the index of its first descriptor along with the
+    /// number of bytes the device wrote to it. Its descriptors are freed.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        // SAFETY: The used index is only read.
+        let used_idx = unsafe { self.used_header(1).read_volatile() };
+        if used_idx == self.last_used {
+            return None;
+        }
+        // The entry must not be read before the index that exposed it.
+        fence(Ordering::SeqCst);
+        // SAFETY: The entry was exposed by the device, which doesn't write it anymore.
+        let [id, len] = unsafe { self.used_entry(self.last_used).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+        let head = id as u16;
+        let mut index = head;
+        let mut count = 1;
+        let mut descriptor;
+        loop {
+            // SAFETY: The chain was returned by the device, so its descriptors are the driver's again.
+            descriptor = unsafe { self.descriptor(index).read_volatile() };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+            count += 1;
+        }
+        // The last descriptor of the chain is linked to the free ones.
+        descriptor.next = self.free_head;
+        // SAFETY: See above.
+        unsafe { self.descriptor(index).write_volatile(descriptor) };
+        self.free_head = head;
+        self.free_count += count;
+        Some((head, len))
+    }
+}
+
+#[kproc::test("Split virtqueue")]
+fn split_virtqueue() {
+    let mut queue = SplitQueue::new(4).unwrap();
+    let (avail, used, _) = SplitQueue::layout(4);
+    assert_eq!(avail, 64);
+    assert_eq!(used, 80);
+    let buffer = |addr, writable| Buffer {
+        addr,
+        len: 512,
+        writable,
+    };
+    // SAFETY: The device never sees the queue, the buffers are never accessed.
+    let first = unsafe { queue.push(&[buffer(0x1000, false), buffer(0x2000, true)]) }.unwrap();
+    // SAFETY: See above.
+    let second = unsafe { queue.push(&[buffer(0x3000, true), buffer(0x4000, true)]) }.unwrap();
+    assert_eq!(queue.free_descriptors(), 0);
+    // SAFETY: See above.
+    assert!(unsafe { queue.push(&[buffer(0x5000, false)]) }.is_none());
+    // SAFETY: The descriptors are only read.
+    let descriptor = unsafe { queue.descriptor(first).read_volatile() };
+    assert_eq!((descriptor.addr, descriptor.flags), (0x1000, DESC_F_NEXT));
+    // SAFETY: See above.
+    let next = unsafe { queue.descriptor(descriptor.next).read_volatile() };
+    assert_eq!((next.addr, next.flags), (0x2000, DESC_F_WRITE));
+    // SAFETY: The available ring is only read.
+    unsafe {
+        assert_eq!(queue.avail(1).read_volatile(), 2);
+        assert_eq!(queue.avail(2).read_volatile(), first);
+        assert_eq!(queue.avail(3).read_volatile(), second);
+    }
+
+    // Act as the device, using the second chain before the first.
+    assert!(queue.pop_used().is_none());
+    // SAFETY: Nothing else accesses the used ring.
+    unsafe {
+        queue.used_entry(0).write_volatile([second as u32, 1024]);
+        queue.used_entry(1).write_volatile([first as u32, 512]);
+        queue.used_header(1).write_volatile(2);
+    }
+    assert_eq!(queue.pop_used(), Some((second, 1024)));
+    assert_eq!(queue.free_descriptors(), 2);
+    assert_eq!(queue.pop_used(), Some((first, 512)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.free_descriptors(), 4);
+    // Freed descriptors are reused.
+    let chain = [buffer(0x6000, false); 4];
+    // SAFETY: See above.
+    assert!(unsafe { queue.push(&chain) }.is_some());
+}
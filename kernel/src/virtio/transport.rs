@@ -0,0 +1,333 @@
+//! The modern virtio-pci transport.
+//!
+//! A virtio function describes where its registers live through vendor capabilities, each pointing into one of its
+//! BARs: the common configuration, used for feature negotiation and setting up queues, the notification area, the
+//! ISR status and the device-specific configuration.
+use core::sync::atomic::{Ordering, fence};
+
+use crate::pci::{Capability, MappedBar, PciHandle, ProbeError};
+
+use super::{feature, queue::SplitQueue, status};
+
+/// The kinds of structures described by the vendor capabilities.
+mod cfg_type {
+    pub const COMMON: u8 = 1;
+    pub const NOTIFY: u8 = 2;
+    pub const ISR: u8 = 3;
+    pub const DEVICE: u8 = 4;
+}
+
+/// The registers of the common configuration structure.
+mod common {
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    pub const DEVICE_FEATURE: usize = 0x04;
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    pub const DRIVER_FEATURE: usize = 0x0c;
+    pub const CONFIG_MSIX_VECTOR: usize = 0x10;
+    pub const NUM_QUEUES: usize = 0x12;
+    pub const DEVICE_STATUS: usize = 0x14;
+    pub const CONFIG_GENERATION: usize = 0x15;
+    pub const QUEUE_SELECT: usize = 0x16;
+    pub const QUEUE_SIZE: usize = 0x18;
+    pub const QUEUE_MSIX_VECTOR: usize = 0x1a;
+    pub const QUEUE_ENABLE: usize = 0x1c;
+    pub const QUEUE_NOTIFY_OFF: usize = 0x1e;
+    pub const QUEUE_DESC: usize = 0x20;
+    pub const QUEUE_DRIVER: usize = 0x28;
+    pub const QUEUE_DEVICE: usize = 0x30;
+    /// The size of the structure.
+    pub const SIZE: usize = 0x38;
+}
+
+/// The MSI-X vector meaning no interrupt is raised.
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// A structure of the function, at `offset` of a mapped BAR.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    bar: MappedBar,
+    offset: usize,
+    length: usize,
+}
+
+impl Region {
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + size_of::<T>() <= self.length);
+        // SAFETY: Transports are owned by the state of their driver along with the handle that mapped their BARs.
+        unsafe { self.bar.read(self.offset + offset) }
+    }
+
+    /// # Safety
+    /// See [MappedBar::write].
+    unsafe fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + size_of::<T>() <= self.length);
+        // SAFETY: Guaranteed by the caller.
+        unsafe { self.bar.write(self.offset + offset, value) }
+    }
+
+    /// Writes a 64-bit register as two 32-bit halves, low half first, which every device supports.
+    ///
+    /// # Safety
+    /// See [MappedBar::write].
+    unsafe fn write_u64(&self, offset: usize, value: u64) {
+        // SAFETY: Guaranteed by the caller.
+        unsafe {
+            self.write(offset, value as u32);
+            self.write(offset + 4, (value >> 32) as u32);
+        }
+    }
+}
+
+/// The registers of a virtio function, found through its vendor capabilities.
+#[derive(Debug)]
+pub struct Transport {
+    common: Region,
+    notify: Region,
+    notify_multiplier: u32,
+    isr: Region,
+    device: Option<Region>,
+}
+
+impl Transport {
+    /// Finds the structures of the function owned by `handle` and maps the BARs holding them. Fails with
+    /// [ProbeError::Unsupported] if the function only implements the legacy interface.
+    pub fn new(handle: &mut PciHandle) -> Result<Self, ProbeError> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        let address = handle.device().address;
+        let capabilities: alloc::vec::Vec<_> = handle
+            .device()
+            .capabilities_with_id(Capability::VENDOR)
+            .collect();
+        for capability in capabilities {
+            let offset = capability.offset;
+            let kind = address.read_u8(offset + 3);
+            let bar = address.read_u8(offset + 4) as usize;
+            let structure = (
+                bar,
+                address.read_u32(offset + 8) as usize,
+                address.read_u32(offset + 12) as usize,
+            );
+            // The first structure of every kind is the preferred one.
+            match kind {
+                cfg_type::COMMON if common.is_none() => common = Some(structure),
+                cfg_type::NOTIFY if notify.is_none() => {
+                    notify = Some(structure);
+                    notify_multiplier = address.read_u32(offset + 16);
+                }
+                cfg_type::ISR if isr.is_none() => isr = Some(structure),
+                cfg_type::DEVICE if device.is_none() => device = Some(structure),
+                _ => {}
+            }
+        }
+        let mut region = |structure: Option<(usize, usize, usize)>| {
+            let (bar, offset, length) =
+                structure.ok_or(ProbeError::Unsupported("Legacy virtio device"))?;
+            let bar = handle.map_bar(bar)?;
+            if offset + length > bar.size() {
+                return Err(ProbeError::Device("Virtio structure outside of its BAR"));
+            }
+            Ok(Region {
+                bar,
+                offset,
+                length,
+            })
+        };
+        let transport = Transport {
+            common: region(common)?,
+            notify: region(notify)?,
+            notify_multiplier,
+            isr: region(isr)?,
+            device: device.map(|device| region(Some(device))).transpose()?,
+        };
+        if transport.common.length < common::SIZE {
+            return Err(ProbeError::Device("Virtio common configuration too small"));
+        }
+        Ok(transport)
+    }
+
+    /// Returns the device status.
+    pub fn status(&self) -> u8 {
+        self.common.read(common::DEVICE_STATUS)
+    }
+
+    /// Sets `bits` in the device status.
+    pub fn set_status(&self, bits: u8) {
+        let status = self.status() | bits;
+        // SAFETY: The status only moves the device through its initialization.
+        unsafe { self.common.write(common::DEVICE_STATUS, status) };
+    }
+
+    /// Resets the device and waits for the reset to complete. The device stops using its queues.
+    pub fn reset(&self) {
+        // SAFETY: Resetting the device makes it forget about its queues.
+        unsafe { self.common.write(common::DEVICE_STATUS, 0u8) };
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Tells the device initialization failed, so it stops expecting the driver.
+    pub fn fail(&self) {
+        self.set_status(status::FAILED);
+    }
+
+    /// Resets the device and negotiates the features it offers among `supported`, which must include
+    /// [feature::VERSION_1]. Returns the features accepted.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, ProbeError> {
+        self.reset();
+        self.set_status(status::ACKNOWLEDGE);
+        self.set_status(status::DRIVER);
+        let mut offered = 0;
+        for select in 0..2u32 {
+            // SAFETY: Selecting a feature word has no side effects.
+            unsafe { self.common.write(common::DEVICE_FEATURE_SELECT, select) };
+            offered |= (self.common.read::<u32>(common::DEVICE_FEATURE) as u64) << (select * 32);
+        }
+        if offered & feature::VERSION_1 == 0 {
+            self.fail();
+            return Err(ProbeError::Unsupported(
+                "Virtio device without VIRTIO_F_VERSION_1",
+            ));
+        }
+        let accepted = offered & supported;
+        for select in 0..2u32 {
+            // SAFETY: Only features the device offered are accepted.
+            unsafe {
+                self.common.write(common::DRIVER_FEATURE_SELECT, select);
+                self.common
+                    .write(common::DRIVER_FEATURE, (accepted >> (select * 32)) as u32);
+            }
+        }
+        self.set_status(status::FEATURES_OK);
+        if self.status() & status::FEATURES_OK == 0 {
+            self.fail();
+            return Err(ProbeError::Device("Virtio device rejected the features"));
+        }
+        Ok(accepted)
+    }
+
+    /// Tells the device the driver is ready, once its queues are set up.
+    pub fn driver_ok(&self) {
+        self.set_status(status::DRIVER_OK);
+    }
+
+    /// Returns the number of queues of the device.
+    pub fn num_queues(&self) -> u16 {
+        self.common.read(common::NUM_QUEUES)
+    }
+
+    /// Returns the largest size of the queue `index`, or 0 if the device doesn't have it.
+    pub fn max_queue_size(&self, index: u16) -> u16 {
+        // SAFETY: Selecting a queue has no side effects.
+        unsafe { self.common.write(common::QUEUE_SELECT, index) };
+        self.common.read(common::QUEUE_SIZE)
+    }
+
+    /// Hands `queue` to the device as its queue `index`, raising the MSI-X table entry `vector` when buffers are used,
+    /// and enables it. Returns what is used to notify the device of new buffers in the queue. The device is reset if
+    /// the queue can't be used once enabled, so it doesn't keep the queue after it is freed.
+    ///
+    /// # Safety
+    /// The queue must outlive its use by the device, which lasts until the device is reset.
+    pub unsafe fn setup_queue(
+        &self,
+        index: u16,
+        queue: &SplitQueue,
+        vector: u16,
+    ) -> Result<Notifier, ProbeError> {
+        // SAFETY: The caller guarantees the queue stays valid, and the queue is only enabled once fully described.
+        unsafe {
+            self.common.write(common::QUEUE_SELECT, index);
+            self.common.write(common::QUEUE_SIZE, queue.size());
+            self.common.write(common::QUEUE_MSIX_VECTOR, vector);
+            if self.common.read::<u16>(common::QUEUE_MSIX_VECTOR) != vector {
+                return Err(ProbeError::Device(
+                    "Virtio device rejected the queue vector",
+                ));
+            }
+            self.common
+                .write_u64(common::QUEUE_DESC, queue.descriptors_addr());
+            self.common
+                .write_u64(common::QUEUE_DRIVER, queue.driver_addr());
+            self.common
+                .write_u64(common::QUEUE_DEVICE, queue.device_addr());
+            self.common.write(common::QUEUE_ENABLE, 1u16);
+        }
+        let offset = self.common.read::<u16>(common::QUEUE_NOTIFY_OFF) as usize
+            * self.notify_multiplier as usize;
+        if offset + size_of::<u16>() > self.notify.length {
+            self.reset();
+            return Err(ProbeError::Device(
+                "Virtio notification outside of its structure",
+            ));
+        }
+        Ok(Notifier {
+            region: self.notify,
+            offset,
+            index,
+        })
+    }
+
+    /// Raises the MSI-X table entry `vector` when the device configuration changes.
+    pub fn set_config_vector(&self, vector: u16) -> Result<(), ProbeError> {
+        // SAFETY: The vector is one of the function's own table entries.
+        unsafe { self.common.write(common::CONFIG_MSIX_VECTOR, vector) };
+        if self.common.read::<u16>(common::CONFIG_MSIX_VECTOR) != vector {
+            return Err(ProbeError::Device(
+                "Virtio device rejected the config vector",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads and acknowledges the ISR status, only used without MSI-X. Bit 0 is set for queue interrupts, and bit 1
+    /// for configuration changes.
+    pub fn isr_status(&self) -> u8 {
+        self.isr.read(0)
+    }
+
+    /// Reads the device-specific configuration register at `offset`, retrying until the device didn't change the
+    /// configuration while it was read. Returns `None` if the device has no configuration.
+    pub fn read_config<T: Copy>(&self, offset: usize) -> Option<T> {
+        let device = self.device?;
+        Some(self.consistent(|| device.read(offset)))
+    }
+
+    /// Reads the 64-bit device-specific configuration register at `offset` as two 32-bit halves.
+    pub fn read_config_u64(&self, offset: usize) -> Option<u64> {
+        let device = self.device?;
+        Some(self.consistent(|| {
+            device.read::<u32>(offset) as u64 | (device.read::<u32>(offset + 4) as u64) << 32
+        }))
+    }
+
+    /// Calls `read` until the configuration generation is the same before and after.
+    fn consistent<T>(&self, read: impl Fn() -> T) -> T {
+        loop {
+            let generation: u8 = self.common.read(common::CONFIG_GENERATION);
+            let value = read();
+            if self.common.read::<u8>(common::CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+}
+
+/// Tells the device new buffers are available in one of its queues.
+#[derive(Debug, Clone, Copy)]
+pub struct Notifier {
+    region: Region,
+    offset: usize,
+    index: u16,
+}
+
+impl Notifier {
+    /// Notifies the device.
+    pub fn notify(&self) {
+        // Buffers must be visible to the device before it is notified.
+        fence(Ordering::SeqCst);
+        // SAFETY: Notifying a queue only makes the device look at the buffers it was given.
+        unsafe { self.region.write(self.offset, self.index) };
+    }
+}
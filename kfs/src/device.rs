@@ -0,0 +1,313 @@
+//! Devices storing data in fixed-size sectors.
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use spin::RwLock;
+
+/// An error reported by a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DeviceError {
+    /// The request reaches beyond the last sector of the device.
+    #[error("Request out of range")]
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    #[error("Buffer isn't a multiple of the sector size")]
+    UnalignedBuffer,
+    /// The device is read-only.
+    #[error("Device is read-only")]
+    ReadOnly,
+    /// The device failed to complete the request.
+    #[error("I/O error")]
+    Io,
+}
+
+/// A device storing data in fixed-size sectors, which filesystems can live on.
+pub trait SectorDevice {
+    /// Returns the size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// Returns the number of sectors of the device.
+    fn sector_count(&self) -> u64;
+
+    /// Returns `true` if the device can't be written to.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads the sectors starting at `sector` into `buf`, whose length must be a multiple of the sector size.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), DeviceError>;
+
+    /// Writes `buf`, whose length must be a multiple of the sector size, to the sectors starting at `sector`.
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), DeviceError>;
+
+    /// Waits until every completed write is stored persistently.
+    fn flush(&self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    /// Returns the size of the device in bytes.
+    fn size(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+
+    /// Reads `buf.len()` bytes starting at the byte `offset`, which needn't be aligned to sectors.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let sector_size = self.sector_size();
+        let mut sector = vec![0; sector_size];
+        let mut offset = offset;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let index = offset / sector_size as u64;
+            let within = (offset % sector_size as u64) as usize;
+            // Whole sectors are read in place.
+            let whole = if within == 0 {
+                buf.len() / sector_size * sector_size
+            } else {
+                0
+            };
+            let len = if whole > 0 {
+                self.read_sectors(index, &mut buf[..whole])?;
+                whole
+            } else {
+                let len = buf.len().min(sector_size - within);
+                self.read_sectors(index, &mut sector)?;
+                buf[..len].copy_from_slice(&sector[within..within + len]);
+                len
+            };
+            offset += len as u64;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` starting at the byte `offset`, which needn't be aligned to sectors. Sectors only partly written
+    /// are read first.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        let sector_size = self.sector_size();
+        let mut sector = vec![0; sector_size];
+        let mut offset = offset;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let index = offset / sector_size as u64;
+            let within = (offset % sector_size as u64) as usize;
+            let whole = if within == 0 {
+                buf.len() / sector_size * sector_size
+            } else {
+                0
+            };
+            let len = if whole > 0 {
+                self.write_sectors(index, &buf[..whole])?;
+                whole
+            } else {
+                let len = buf.len().min(sector_size - within);
+                self.read_sectors(index, &mut sector)?;
+                sector[within..within + len].copy_from_slice(&buf[..len]);
+                self.write_sectors(index, &sector)?;
+                len
+            };
+            offset += len as u64;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a request of `len` bytes starting at `sector` is a whole number of sectors within `device`.
+fn check_request(device: &impl SectorDevice, sector: u64, len: usize) -> Result<(), DeviceError> {
+    if !len.is_multiple_of(device.sector_size()) {
+        return Err(DeviceError::UnalignedBuffer);
+    }
+    match sector.checked_add((len / device.sector_size()) as u64) {
+        Some(end) if end <= device.sector_count() => Ok(()),
+        _ => Err(DeviceError::OutOfRange),
+    }
+}
+
+macro_rules! forward_device {
+    ($($ty: ty),+) => {
+        $(
+            impl<D: SectorDevice + ?Sized> SectorDevice for $ty {
+                fn sector_size(&self) -> usize {
+                    (**self).sector_size()
+                }
+
+                fn sector_count(&self) -> u64 {
+                    (**self).sector_count()
+                }
+
+                fn is_read_only(&self) -> bool {
+                    (**self).is_read_only()
+                }
+
+                fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+                    (**self).read_sectors(sector, buf)
+                }
+
+                fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), DeviceError> {
+                    (**self).write_sectors(sector, buf)
+                }
+
+                fn flush(&self) -> Result<(), DeviceError> {
+                    (**self).flush()
+                }
+            }
+        )+
+    };
+}
+
+forward_device!(&D, Box<D>, Arc<D>);
+
+/// A device keeping its sectors in memory.
+#[derive(Debug)]
+pub struct MemoryDevice {
+    sector_size: usize,
+    data: RwLock<Vec<u8>>,
+    read_only: bool,
+}
+
+impl MemoryDevice {
+    /// Creates a zeroed device of `sectors` sectors of `sector_size` bytes.
+    pub fn new(sector_size: usize, sectors: u64) -> Self {
+        Self::from_bytes(sector_size, vec![0; sector_size * sectors as usize])
+    }
+
+    /// Creates a device holding `data`, which is cut down to a whole number of sectors.
+    pub fn from_bytes(sector_size: usize, mut data: Vec<u8>) -> Self {
+        data.truncate(data.len() / sector_size * sector_size);
+        MemoryDevice {
+            sector_size,
+            data: RwLock::new(data),
+            read_only: false,
+        }
+    }
+
+    /// Makes the device read-only.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Returns a copy of the contents of the device.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.read().clone()
+    }
+}
+
+impl SectorDevice for MemoryDevice {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.data.read().len() / self.sector_size) as u64
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        check_request(self, sector, buf.len())?;
+        let start = sector as usize * self.sector_size;
+        buf.copy_from_slice(&self.data.read()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        if self.read_only {
+            return Err(DeviceError::ReadOnly);
+        }
+        check_request(self, sector, buf.len())?;
+        let start = sector as usize * self.sector_size;
+        self.data.write()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// A range of the sectors of another device, such as a partition.
+#[derive(Debug, Clone)]
+pub struct Slice<D> {
+    device: D,
+    start: u64,
+    sectors: u64,
+}
+
+impl<D: SectorDevice> Slice<D> {
+    /// Creates a device of the `sectors` sectors of `device` starting at `start`.
+    pub fn new(device: D, start: u64, sectors: u64) -> Result<Self, DeviceError> {
+        match start.checked_add(sectors) {
+            Some(end) if end <= device.sector_count() => Ok(Slice {
+                device,
+                start,
+                sectors,
+            }),
+            _ => Err(DeviceError::OutOfRange),
+        }
+    }
+}
+
+impl<D: SectorDevice> SectorDevice for Slice<D> {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        check_request(self, sector, buf.len())?;
+        self.device.read_sectors(self.start + sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        check_request(self, sector, buf.len())?;
+        self.device.write_sectors(self.start + sector, buf)
+    }
+
+    fn flush(&self) -> Result<(), DeviceError> {
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unaligned_access() {
+        let device = MemoryDevice::new(512, 4);
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        device.write_at(300, &data).unwrap();
+        let mut read = vec![0; 1000];
+        device.read_at(300, &mut read).unwrap();
+        assert_eq!(read, data);
+        let bytes = device.to_bytes();
+        assert!(
+            bytes[..300]
+                .iter()
+                .chain(&bytes[1300..])
+                .all(|&byte| byte == 0)
+        );
+        assert_eq!(
+            device.read_at(1500, &mut read),
+            Err(DeviceError::OutOfRange)
+        );
+
+        let slice = Slice::new(&device, 1, 2).unwrap();
+        let mut sector = [0; 512];
+        slice.read_sectors(0, &mut sector).unwrap();
+        assert_eq!(sector[..], bytes[512..1024]);
+        assert_eq!(
+            slice.read_sectors(2, &mut sector),
+            Err(DeviceError::OutOfRange)
+        );
+        assert!(Slice::new(&device, 3, 2).is_err());
+        assert_eq!(
+            device.read_sectors(0, &mut sector[..100]),
+            Err(DeviceError::UnalignedBuffer)
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! The entries of directories, which never cross the blocks of the directory.
+use alloc::{string::String, vec::Vec};
+
+use super::{Ext2Error, FileType, superblock::u32_at};
+
+/// The size of an entry without its name.
+const HEADER_SIZE: usize = 8;
+
+/// The file types stored in entries with the filetype feature.
+mod file_type {
+    pub const REGULAR: u8 = 1;
+    pub const DIRECTORY: u8 = 2;
+    pub const SYMLINK: u8 = 7;
+}
+
+/// An entry as stored in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub inode: u32,
+    pub name: Vec<u8>,
+    /// The type of the file, if the volume stores it in entries.
+    pub file_type: Option<FileType>,
+}
+
+impl Entry {
+    /// Returns the name of the entry, with the bytes that aren't UTF-8 replaced.
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.name).into_owned()
+    }
+
+    /// Returns `true` for the `.` and `..` entries.
+    pub fn is_dot(&self) -> bool {
+        matches!(&self.name[..], b"." | b"..")
+    }
+}
+
+/// Parses the entries of the directory block `block`, skipping the unused ones. `has_types` tells whether the volume
+/// stores file types in entries, in place of the high byte of the length of their name.
+pub fn parse(block: &[u8], has_types: bool) -> Result<Vec<Entry>, Ext2Error> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        let header = block
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(Ext2Error::Corrupted("directory entry"))?;
+        let inode = u32_at(header, 0);
+        let record_len = match u16::from_le_bytes([header[4], header[5]]) {
+            // 64 KiB blocks would otherwise overflow the length of their only entry.
+            0 | 0xffff if block.len() == 0x10000 && offset == 0 => 0x10000,
+            len => usize::from(len),
+        };
+        let name_len = if has_types {
+            usize::from(header[6])
+        } else {
+            usize::from(u16::from_le_bytes([header[6], header[7]]))
+        };
+        if record_len < HEADER_SIZE
+            || !record_len.is_multiple_of(4)
+            || offset + record_len > block.len()
+            || HEADER_SIZE + name_len > record_len
+        {
+            return Err(Ext2Error::Corrupted("directory entry"));
+        }
+        if inode != 0 {
+            let name = &block[offset + HEADER_SIZE..offset + HEADER_SIZE + name_len];
+            entries.push(Entry {
+                inode,
+                name: Vec::from(name),
+                file_type: has_types.then(|| match header[7] {
+                    file_type::REGULAR => FileType::Regular,
+                    file_type::DIRECTORY => FileType::Directory,
+                    file_type::SYMLINK => FileType::Symlink,
+                    _ => FileType::Other,
+                }),
+            });
+        }
+        offset += record_len;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(inode: u32, record_len: u16, name: &[u8], kind: u8) -> Vec<u8> {
+        let mut bytes = Vec::from(inode.to_le_bytes());
+        bytes.extend_from_slice(&record_len.to_le_bytes());
+        bytes.extend_from_slice(&[name.len() as u8, kind]);
+        bytes.extend_from_slice(name);
+        bytes.resize(usize::from(record_len), 0);
+        bytes
+    }
+
+    #[test]
+    fn directory_entries() {
+        let mut block = entry(2, 12, b".", file_type::DIRECTORY);
+        block.extend(entry(2, 12, b"..", file_type::DIRECTORY));
+        // A removed entry, merged with its neighbour or left with no inode.
+        block.extend(entry(0, 16, b"gone", file_type::REGULAR));
+        block.extend(entry(12, 20, b"kernel.elf", file_type::REGULAR));
+        block.extend(entry(13, 4096 - 60, b"link", file_type::SYMLINK));
+        let entries = parse(&block, true).unwrap();
+        let names: Vec<_> = entries.iter().map(Entry::name).collect();
+        assert_eq!(names, [".", "..", "kernel.elf", "link"]);
+        assert!(entries[1].is_dot() && !entries[2].is_dot());
+        assert_eq!(
+            (entries[3].inode, entries[3].file_type),
+            (13, Some(FileType::Symlink))
+        );
+
+        // Without file types, the name length takes two bytes.
+        let entries = parse(&entry(11, 4096, b"lost+found", 0), false).unwrap();
+        assert_eq!(
+            (entries[0].name(), entries[0].file_type),
+            (String::from("lost+found"), None)
+        );
+
+        let mut corrupted = block.clone();
+        corrupted[12 + 4] = 6;
+        assert_eq!(
+            parse(&corrupted, true),
+            Err(Ext2Error::Corrupted("directory entry"))
+        );
+        assert!(parse(&block[..4000], true).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! Inodes, and the blocks they map their data to.
+use alloc::vec::Vec;
+
+use super::{Ext2Error, FileType, superblock::u32_at};
+
+/// The number of blocks an inode refers to directly.
+const DIRECT_BLOCKS: usize = 12;
+/// The index in [Inode::blocks] of the block listing blocks, then of the one listing such blocks and so on.
+const INDIRECT: [usize; 3] = [12, 13, 14];
+
+/// The offsets of the fields of an inode.
+mod field {
+    pub const MODE: usize = 0;
+    pub const SIZE: usize = 4;
+    pub const LINKS_COUNT: usize = 26;
+    pub const BLOCKS: usize = 28;
+    pub const FLAGS: usize = 32;
+    pub const BLOCK: usize = 40;
+    pub const FILE_ACL: usize = 104;
+    pub const SIZE_HIGH: usize = 108;
+}
+
+/// The flags of inodes that change how their data is stored.
+mod flags {
+    pub const EXTENTS: u32 = 0x0008_0000;
+    pub const INLINE_DATA: u32 = 0x1000_0000;
+}
+
+/// The file types of the mode.
+mod mode {
+    pub const TYPE_MASK: u16 = 0xf000;
+    pub const REGULAR: u16 = 0x8000;
+    pub const DIRECTORY: u16 = 0x4000;
+    pub const SYMLINK: u16 = 0xa000;
+}
+
+/// A file of a volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inode {
+    pub(super) number: u32,
+    mode: u16,
+    size: u64,
+    links: u16,
+    /// The number of 512-byte sectors used by the file, including its blocks of extended attributes.
+    sectors: u32,
+    flags: u32,
+    /// The direct blocks followed by the indirect ones, or the target of a short symbolic link.
+    pub(super) blocks: [u32; 15],
+    /// The block of extended attributes.
+    file_acl: u32,
+}
+
+impl Inode {
+    /// Parses the inode `number` from `bytes`.
+    pub(super) fn parse(number: u32, bytes: &[u8]) -> Self {
+        let mode = u16::from_le_bytes([bytes[field::MODE], bytes[field::MODE + 1]]);
+        let size = u64::from(u32_at(bytes, field::SIZE));
+        // The high half of the size of directories holds something else in ext2.
+        let size = if mode & mode::TYPE_MASK == mode::REGULAR {
+            size | u64::from(u32_at(bytes, field::SIZE_HIGH)) << 32
+        } else {
+            size
+        };
+        Inode {
+            number,
+            mode,
+            size,
+            links: u16::from_le_bytes([bytes[field::LINKS_COUNT], bytes[field::LINKS_COUNT + 1]]),
+            sectors: u32_at(bytes, field::BLOCKS),
+            flags: u32_at(bytes, field::FLAGS),
+            blocks: core::array::from_fn(|index| u32_at(bytes, field::BLOCK + index * 4)),
+            file_acl: u32_at(bytes, field::FILE_ACL),
+        }
+    }
+
+    /// Returns the number of the inode.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Returns the type of the file.
+    pub fn file_type(&self) -> FileType {
+        match self.mode & mode::TYPE_MASK {
+            mode::REGULAR => FileType::Regular,
+            mode::DIRECTORY => FileType::Directory,
+            mode::SYMLINK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+
+    /// Returns the permissions of the file, with the set-user-ID, set-group-ID and sticky bits.
+    pub fn permissions(&self) -> u16 {
+        self.mode & !mode::TYPE_MASK
+    }
+
+    /// Returns the size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the number of directory entries referring to the file.
+    pub fn links(&self) -> u16 {
+        self.links
+    }
+
+    /// Returns `true` if the file is a symbolic link whose target is stored in the inode itself.
+    pub(super) fn is_fast_symlink(&self, block_size: u32) -> bool {
+        let attribute_sectors = if self.file_acl != 0 {
+            block_size / 512
+        } else {
+            0
+        };
+        self.file_type() == FileType::Symlink && self.sectors == attribute_sectors
+    }
+
+    /// Checks that the data of the file is stored in blocks listed by the inode.
+    pub(super) fn check_block_map(&self) -> Result<(), Ext2Error> {
+        if self.flags & flags::EXTENTS != 0 {
+            Err(Ext2Error::Unsupported("extents"))
+        } else if self.flags & flags::INLINE_DATA != 0 {
+            Err(Ext2Error::Unsupported("inline data"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The path through the indirect blocks to the block of a file: the index of the block of the inode, followed by the
+/// index in each block listing blocks.
+pub(super) fn block_path(index: u64, per_block: u64) -> Result<(usize, Vec<usize>), Ext2Error> {
+    if index < DIRECT_BLOCKS as u64 {
+        return Ok((index as usize, Vec::new()));
+    }
+    let mut index = index - DIRECT_BLOCKS as u64;
+    let mut span = per_block;
+    for (depth, &slot) in INDIRECT.iter().enumerate() {
+        if index < span {
+            // The index in each level, from the outermost one.
+            let path = (0..=depth)
+                .rev()
+                .map(|level| (index / per_block.pow(level as u32) % per_block) as usize)
+                .collect();
+            return Ok((slot, path));
+        }
+        index -= span;
+        span *= per_block;
+    }
+    Err(Ext2Error::Corrupted("file size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_paths() {
+        assert_eq!(block_path(0, 256).unwrap(), (0, Vec::new()));
+        assert_eq!(block_path(11, 256).unwrap(), (11, Vec::new()));
+        assert_eq!(block_path(12, 256).unwrap(), (12, Vec::from([0])));
+        assert_eq!(block_path(12 + 255, 256).unwrap(), (12, Vec::from([255])));
+        assert_eq!(block_path(12 + 256, 256).unwrap(), (13, Vec::from([0, 0])));
+        assert_eq!(
+            block_path(12 + 256 + 257, 256).unwrap(),
+            (13, Vec::from([1, 1]))
+        );
+        let triple = 12 + 256 + 256 * 256;
+        assert_eq!(block_path(triple, 256).unwrap(), (14, Vec::from([0, 0, 0])));
+        assert_eq!(
+            block_path(triple + 256 * 256 + 2, 256).unwrap(),
+            (14, Vec::from([1, 0, 2]))
+        );
+        assert!(block_path(triple + 256 * 256 * 256, 256).is_err());
+    }
+}
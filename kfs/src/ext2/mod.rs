@@ -0,0 +1,512 @@
+//! Read-only ext2 volumes, which also covers ext3 volumes whose journal is clean.
+//!
+//! Files are reached from the root directory with [Ext2Fs::lookup] or [Ext2Fs::open], which return the [Inode] of the
+//! file. Their data is found through the blocks listed by the inode, directly or through up to three levels of
+//! indirect blocks, and blocks left at 0 are holes reading as zeroes.
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::device::{DeviceError, SectorDevice};
+use inode::block_path;
+use superblock::{Superblock, incompat};
+
+pub use inode::Inode;
+
+mod dir;
+mod inode;
+mod superblock;
+
+/// The inode of the root directory.
+pub const ROOT_INODE: u32 = 2;
+/// The most symbolic links followed while opening a path.
+const MAX_LINKS: usize = 40;
+
+/// An error that occurred while reading an ext2 volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Ext2Error {
+    /// The superblock doesn't describe an ext2 volume.
+    #[error("Invalid superblock: {0}")]
+    InvalidSuperblock(&'static str),
+    /// The structures of the volume are inconsistent.
+    #[error("Corrupted {0}")]
+    Corrupted(&'static str),
+    /// No file has the given name.
+    #[error("File not found")]
+    NotFound,
+    /// The file isn't a directory.
+    #[error("Not a directory")]
+    NotADirectory,
+    /// The file is a directory.
+    #[error("Is a directory")]
+    IsADirectory,
+    /// The file isn't a symbolic link.
+    #[error("Not a symbolic link")]
+    NotASymlink,
+    /// Too many symbolic links were followed, likely because they form a loop.
+    #[error("Too many levels of symbolic links")]
+    TooManyLinks,
+    /// The volume uses a feature that isn't supported.
+    #[error("Unsupported {0}")]
+    Unsupported(&'static str),
+    /// The device failed.
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+}
+
+/// The type of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// A regular file.
+    Regular,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+    /// A device, pipe or socket.
+    Other,
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name of the entry, with the bytes that aren't UTF-8 replaced.
+    pub name: String,
+    /// The number of the inode it refers to.
+    pub inode: u32,
+    /// The type of the file it refers to.
+    pub file_type: FileType,
+}
+
+/// The blocks listing blocks read lately, by block number.
+type TableCache = Vec<(u32, Vec<u32>)>;
+
+/// An ext2 volume on a device.
+#[derive(Debug)]
+pub struct Ext2Fs<D> {
+    device: D,
+    superblock: Superblock,
+    /// The first block of the table of inodes of each group.
+    inode_tables: Vec<u32>,
+}
+
+impl<D: SectorDevice> Ext2Fs<D> {
+    /// Opens the volume on `device`.
+    pub fn new(device: D) -> Result<Self, Ext2Error> {
+        let superblock = Superblock::read(&device)?;
+        if superblock.block_offset(superblock.blocks_count) > device.size() {
+            return Err(Ext2Error::InvalidSuperblock(
+                "volume larger than the device",
+            ));
+        }
+        let inode_tables = superblock.inode_tables(&device)?;
+        Ok(Ext2Fs {
+            device,
+            superblock,
+            inode_tables,
+        })
+    }
+
+    /// Returns the device the volume is on.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the size of the blocks of the volume.
+    pub fn block_size(&self) -> u32 {
+        self.superblock.block_size
+    }
+
+    /// Returns the label of the volume, if it has one.
+    pub fn label(&self) -> Option<String> {
+        let name = &self.superblock.volume_name;
+        let len = name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(name.len());
+        (len > 0).then(|| String::from_utf8_lossy(&name[..len]).into_owned())
+    }
+
+    /// Returns the inode `number`.
+    pub fn inode(&self, number: u32) -> Result<Inode, Ext2Error> {
+        let superblock = &self.superblock;
+        if number == 0 || number > superblock.inodes_count {
+            return Err(Ext2Error::Corrupted("inode number"));
+        }
+        let group = (number - 1) / superblock.inodes_per_group;
+        let index = (number - 1) % superblock.inodes_per_group;
+        let offset = superblock.block_offset(self.inode_tables[group as usize])
+            + u64::from(index) * u64::from(superblock.inode_size);
+        // Only the fields of the original 128-byte inodes are used.
+        let mut bytes = [0; 128];
+        self.device.read_at(offset, &mut bytes)?;
+        Ok(Inode::parse(number, &bytes))
+    }
+
+    /// Returns the root directory.
+    pub fn root(&self) -> Result<Inode, Ext2Error> {
+        self.inode(ROOT_INODE)
+    }
+
+    /// Returns the block holding the data of `inode` at `index`, or `None` for a hole.
+    fn data_block(
+        &self,
+        inode: &Inode,
+        index: u64,
+        cache: &mut TableCache,
+    ) -> Result<Option<u32>, Ext2Error> {
+        let per_block = u64::from(self.superblock.block_size / 4);
+        let (slot, path) = block_path(index, per_block)?;
+        let mut block = inode.blocks[slot];
+        for index in path {
+            if block == 0 {
+                break;
+            }
+            block = self.table(block, cache)?[index];
+        }
+        if block >= self.superblock.blocks_count {
+            return Err(Ext2Error::Corrupted("block number"));
+        }
+        Ok((block != 0).then_some(block))
+    }
+
+    /// Returns the block numbers listed by `block`, keeping the last ones read in `cache`.
+    fn table<'a>(&self, block: u32, cache: &'a mut TableCache) -> Result<&'a [u32], Ext2Error> {
+        if block >= self.superblock.blocks_count {
+            return Err(Ext2Error::Corrupted("block number"));
+        }
+        let position = match cache.iter().position(|(number, _)| *number == block) {
+            Some(position) => position,
+            None => {
+                let mut bytes = vec![0; self.superblock.block_size as usize];
+                self.device
+                    .read_at(self.superblock.block_offset(block), &mut bytes)?;
+                let table = bytes
+                    .chunks_exact(4)
+                    .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+                    .collect();
+                // Each level of indirection needs one table at a time.
+                if cache.len() == 4 {
+                    cache.remove(0);
+                }
+                cache.push((block, table));
+                cache.len() - 1
+            }
+        };
+        Ok(&cache[position].1)
+    }
+
+    /// Reads the data of `inode` from `offset` into `buf`, returning the number of bytes read.
+    fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        inode.check_block_map()?;
+        let size = inode.size();
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let block_size = u64::from(self.superblock.block_size);
+        let mut cache = TableCache::new();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let count = (len - done).min((block_size - within) as usize);
+            let chunk = &mut buf[done..done + count];
+            match self.data_block(inode, position / block_size, &mut cache)? {
+                Some(block) => self
+                    .device
+                    .read_at(self.superblock.block_offset(block) + within, chunk)?,
+                None => chunk.fill(0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Reads the regular file `file` from `offset` into `buf`, returning the number of bytes read, which is only less
+    /// than the length of `buf` at the end of the file.
+    pub fn read(&self, file: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        if file.file_type() == FileType::Directory {
+            return Err(Ext2Error::IsADirectory);
+        }
+        self.read_data(file, offset, buf)
+    }
+
+    /// Reads the whole file `file`.
+    pub fn read_to_end(&self, file: &Inode) -> Result<Vec<u8>, Ext2Error> {
+        let len = usize::try_from(file.size()).map_err(|_| Ext2Error::Corrupted("file size"))?;
+        let mut data = vec![0; len];
+        let len = self.read(file, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// Returns every entry of the directory `dir`, with `.` and `..`.
+    fn entries(&self, dir: &Inode) -> Result<Vec<dir::Entry>, Ext2Error> {
+        if dir.file_type() != FileType::Directory {
+            return Err(Ext2Error::NotADirectory);
+        }
+        let block_size = self.superblock.block_size as usize;
+        if !dir.size().is_multiple_of(block_size as u64) {
+            return Err(Ext2Error::Corrupted("directory size"));
+        }
+        let len =
+            usize::try_from(dir.size()).map_err(|_| Ext2Error::Corrupted("directory size"))?;
+        let mut data = vec![0; len];
+        self.read_data(dir, 0, &mut data)?;
+        let has_types = self.superblock.feature_incompat & incompat::FILETYPE != 0;
+        let mut entries = Vec::new();
+        for block in data.chunks(block_size) {
+            entries.extend(dir::parse(block, has_types)?);
+        }
+        Ok(entries)
+    }
+
+    /// Returns the entries of the directory `dir`, without `.` and `..`.
+    pub fn read_dir(&self, dir: &Inode) -> Result<Vec<DirEntry>, Ext2Error> {
+        self.entries(dir)?
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .map(|entry| {
+                let file_type = match entry.file_type {
+                    Some(file_type) => file_type,
+                    None => self.inode(entry.inode)?.file_type(),
+                };
+                Ok(DirEntry {
+                    name: entry.name(),
+                    inode: entry.inode,
+                    file_type,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the file named `name` in the directory `dir`, which may be `.` or `..`.
+    pub fn lookup(&self, dir: &Inode, name: &str) -> Result<Inode, Ext2Error> {
+        let entry = self
+            .entries(dir)?
+            .into_iter()
+            .find(|entry| entry.name == name.as_bytes())
+            .ok_or(Ext2Error::NotFound)?;
+        self.inode(entry.inode)
+    }
+
+    /// Returns the target of the symbolic link `link`.
+    pub fn read_link(&self, link: &Inode) -> Result<String, Ext2Error> {
+        if link.file_type() != FileType::Symlink {
+            return Err(Ext2Error::NotASymlink);
+        }
+        let target = if link.is_fast_symlink(self.superblock.block_size) {
+            let bytes: Vec<u8> = link
+                .blocks
+                .iter()
+                .flat_map(|block| block.to_le_bytes())
+                .collect();
+            let len = usize::try_from(link.size())
+                .ok()
+                .filter(|&len| len <= bytes.len());
+            bytes[..len.ok_or(Ext2Error::Corrupted("symbolic link"))?].to_vec()
+        } else {
+            if link.size() > u64::from(self.superblock.block_size) {
+                return Err(Ext2Error::Corrupted("symbolic link"));
+            }
+            let mut target = vec![0; link.size() as usize];
+            self.read_data(link, 0, &mut target)?;
+            target
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    /// Returns the file at `path`, whose names are separated by slashes and start from the root directory, following
+    /// symbolic links.
+    pub fn open(&self, path: &str) -> Result<Inode, Ext2Error> {
+        let root = self.root()?;
+        let mut inode = root.clone();
+        // The names left to look up, from the last one.
+        let mut names: Vec<String> = components(path).rev().map(String::from).collect();
+        let mut links = 0;
+        while let Some(name) = names.pop() {
+            let next = self.lookup(&inode, &name)?;
+            if next.file_type() != FileType::Symlink {
+                inode = next;
+                continue;
+            }
+            links += 1;
+            if links > MAX_LINKS {
+                return Err(Ext2Error::TooManyLinks);
+            }
+            let target = self.read_link(&next)?;
+            if target.starts_with('/') {
+                inode = root.clone();
+            }
+            names.extend(components(&target).rev().map(String::from));
+        }
+        Ok(inode)
+    }
+}
+
+/// Returns the names of `path` other than `.`.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|name| !matches!(*name, "" | "."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryDevice;
+    use std::{fs, os::unix::fs::symlink, path::PathBuf, process::Command};
+
+    /// Builds an ext2 image of `blocks` blocks holding the files used by the tests, with `options` given to mke2fs.
+    fn image(name: &str, blocks: u32, options: &[&str]) -> Ext2Fs<MemoryDevice> {
+        let root = std::env::temp_dir().join(format!("kfs-ext2-{}-{name}", std::process::id()));
+        let source = root.join("source");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(source.join("dir/nested")).unwrap();
+        fs::write(source.join("hello.txt"), "hello ext2\n").unwrap();
+        fs::write(source.join("dir/nested/deep.txt"), "deep").unwrap();
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(source.join("big.bin"), &big).unwrap();
+        let sparse = fs::File::create(source.join("sparse.bin")).unwrap();
+        sparse.set_len(200_000).unwrap();
+        fs::create_dir(source.join("many")).unwrap();
+        for index in 0..100 {
+            fs::write(
+                source.join(format!("many/file number {index}")),
+                index.to_string(),
+            )
+            .unwrap();
+        }
+        symlink("hello.txt", source.join("link")).unwrap();
+        symlink("../hello.txt", source.join("dir/up")).unwrap();
+        symlink("/dir/nested", source.join("absolute")).unwrap();
+        symlink(
+            format!("{}hello.txt", "./".repeat(40)),
+            source.join("long link"),
+        )
+        .unwrap();
+        symlink("loop", source.join("loop")).unwrap();
+
+        let path: PathBuf = root.join("image");
+        fs::write(&path, []).unwrap();
+        let status = Command::new("mke2fs")
+            .args(["-q", "-F", "-t", "ext2", "-L", "kfs-test", "-d"])
+            .arg(&source)
+            .args(options)
+            .arg(&path)
+            .arg(blocks.to_string())
+            .status()
+            .expect("mke2fs is needed to build the test images");
+        assert!(status.success());
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        Ext2Fs::new(MemoryDevice::from_bytes(512, bytes).read_only()).unwrap()
+    }
+
+    fn check(fs: Ext2Fs<MemoryDevice>) {
+        assert_eq!(fs.label().as_deref(), Some("kfs-test"));
+        let root = fs.root().unwrap();
+        let mut names: Vec<_> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "absolute",
+                "big.bin",
+                "dir",
+                "hello.txt",
+                "link",
+                "long link",
+                "loop",
+                "lost+found",
+                "many",
+                "sparse.bin"
+            ]
+        );
+
+        let hello = fs.open("/hello.txt").unwrap();
+        assert_eq!(hello.file_type(), FileType::Regular);
+        assert_eq!(hello.links(), 1);
+        assert_eq!(fs.read_to_end(&hello).unwrap(), b"hello ext2\n");
+        assert_eq!(fs.open("dir/nested/../../hello.txt").unwrap(), hello);
+        assert_eq!(
+            fs.read_to_end(&fs.open("dir/nested/deep.txt").unwrap())
+                .unwrap(),
+            b"deep"
+        );
+
+        // Large files go through indirect blocks, and holes read as zeroes.
+        let big = fs.open("big.bin").unwrap();
+        let expected: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(fs.read_to_end(&big).unwrap(), expected);
+        let mut buf = vec![0; 5000];
+        assert_eq!(fs.read(&big, 299_000, &mut buf).unwrap(), 1000);
+        assert_eq!(buf[..1000], expected[299_000..]);
+        assert_eq!(
+            fs.read_to_end(&fs.open("sparse.bin").unwrap()).unwrap(),
+            vec![0; 200_000]
+        );
+
+        let many = fs.open("many").unwrap();
+        assert_eq!(many.file_type(), FileType::Directory);
+        assert_eq!(fs.read_dir(&many).unwrap().len(), 100);
+        let file = fs.lookup(&many, "file number 99").unwrap();
+        assert_eq!(fs.read_to_end(&file).unwrap(), b"99");
+
+        let link = fs.lookup(&root, "link").unwrap();
+        assert_eq!(link.file_type(), FileType::Symlink);
+        assert_eq!(fs.read_link(&link).unwrap(), "hello.txt");
+        let long = fs.lookup(&root, "long link").unwrap();
+        assert_eq!(fs.read_link(&long).unwrap().len(), 89);
+        for path in ["link", "long link", "dir/up", "absolute/../../link"] {
+            assert_eq!(fs.open(path).unwrap(), hello, "{path}");
+        }
+        assert_eq!(
+            fs.open("absolute/deep.txt").unwrap(),
+            fs.open("dir/nested/deep.txt").unwrap()
+        );
+
+        assert_eq!(fs.open("loop"), Err(Ext2Error::TooManyLinks));
+        assert_eq!(fs.open("missing"), Err(Ext2Error::NotFound));
+        assert_eq!(fs.open("hello.txt/x"), Err(Ext2Error::NotADirectory));
+        assert_eq!(fs.read(&root, 0, &mut buf), Err(Ext2Error::IsADirectory));
+        assert_eq!(fs.read_link(&hello), Err(Ext2Error::NotASymlink));
+    }
+
+    #[test]
+    fn small_blocks() {
+        // Small blocks and groups put the files of larger ones behind two levels of indirection, in several groups.
+        let fs = image("small", 4096, &["-b", "1024", "-g", "1024", "-N", "256"]);
+        assert_eq!(fs.superblock.group_count(), 4);
+        check(fs);
+    }
+
+    #[test]
+    fn large_blocks_without_types() {
+        check(image("large", 1024, &["-b", "4096", "-O", "^filetype"]));
+    }
+
+    #[test]
+    fn revision_0() {
+        let fs = image("revision-0", 4096, &["-r", "0", "-b", "1024"]);
+        assert_eq!(fs.superblock.rev_level, 0);
+        check(fs);
+    }
+
+    #[test]
+    fn invalid_volumes() {
+        assert!(matches!(
+            Ext2Fs::new(MemoryDevice::new(512, 16)),
+            Err(Ext2Error::InvalidSuperblock("magic number"))
+        ));
+        // Volumes storing files in extents, such as ext4 ones, are rejected.
+        let mut ext4 = image("ext4", 1024, &[]).device().to_bytes();
+        ext4[superblock::OFFSET as usize + 96] |= incompat::EXTENTS as u8;
+        assert_eq!(
+            Ext2Fs::new(MemoryDevice::from_bytes(512, ext4)).unwrap_err(),
+            Ext2Error::Unsupported("extents")
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! The superblock, which describes the layout of the volume, and the group descriptors.
+use alloc::{vec, vec::Vec};
+
+use super::Ext2Error;
+use crate::device::SectorDevice;
+
+/// Where the superblock is, whatever the block size.
+pub const OFFSET: u64 = 1024;
+/// The size of the superblock.
+pub const SIZE: usize = 1024;
+/// The size of the group descriptors without the 64bit feature.
+const DESCRIPTOR_SIZE: usize = 32;
+/// The magic number of ext2, ext3 and ext4 volumes.
+const MAGIC: u16 = 0xef53;
+
+/// The offsets of the fields of the superblock.
+mod field {
+    pub const INODES_COUNT: usize = 0;
+    pub const BLOCKS_COUNT: usize = 4;
+    pub const FIRST_DATA_BLOCK: usize = 20;
+    pub const LOG_BLOCK_SIZE: usize = 24;
+    pub const BLOCKS_PER_GROUP: usize = 32;
+    pub const INODES_PER_GROUP: usize = 40;
+    pub const MAGIC: usize = 56;
+    pub const REV_LEVEL: usize = 76;
+    /// The fields after this one only exist from revision 1.
+    pub const INODE_SIZE: usize = 88;
+    pub const FEATURE_INCOMPAT: usize = 96;
+    pub const VOLUME_NAME: usize = 120;
+}
+
+/// The offset of the table of inodes in a group descriptor.
+const INODE_TABLE: usize = 8;
+
+/// The incompatible features, which must all be understood to read the volume.
+pub mod incompat {
+    /// Directory entries store the type of their file.
+    pub const FILETYPE: u32 = 0x0002;
+    /// The volume needs its journal replayed.
+    pub const RECOVER: u32 = 0x0004;
+    /// Files may be stored in extents.
+    pub const EXTENTS: u32 = 0x0040;
+    /// The metadata of groups may be stored in other groups.
+    pub const FLEX_BG: u32 = 0x0200;
+    /// The features that don't change how the volume is read.
+    pub const SUPPORTED: u32 = FILETYPE | FLEX_BG;
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(super) fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// The fields of the superblock the driver uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub first_data_block: u32,
+    pub block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub rev_level: u32,
+    pub inode_size: u32,
+    pub feature_incompat: u32,
+    pub volume_name: [u8; 16],
+}
+
+impl Superblock {
+    /// Reads the superblock of `device`.
+    pub fn read(device: &impl SectorDevice) -> Result<Self, Ext2Error> {
+        if device.size() < OFFSET + SIZE as u64 {
+            return Err(Ext2Error::InvalidSuperblock("device too small"));
+        }
+        let mut bytes = vec![0; SIZE];
+        device.read_at(OFFSET, &mut bytes)?;
+        Self::parse(&bytes)
+    }
+
+    /// Parses and checks the superblock `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Ext2Error> {
+        if u16_at(bytes, field::MAGIC) != MAGIC {
+            return Err(Ext2Error::InvalidSuperblock("magic number"));
+        }
+        let log_block_size = u32_at(bytes, field::LOG_BLOCK_SIZE);
+        if log_block_size > 6 {
+            return Err(Ext2Error::InvalidSuperblock("block size"));
+        }
+        let block_size = 1024 << log_block_size;
+        let rev_level = u32_at(bytes, field::REV_LEVEL);
+        let (inode_size, feature_incompat) = if rev_level == 0 {
+            (128, 0)
+        } else {
+            (
+                u32::from(u16_at(bytes, field::INODE_SIZE)),
+                u32_at(bytes, field::FEATURE_INCOMPAT),
+            )
+        };
+        let superblock = Superblock {
+            inodes_count: u32_at(bytes, field::INODES_COUNT),
+            blocks_count: u32_at(bytes, field::BLOCKS_COUNT),
+            first_data_block: u32_at(bytes, field::FIRST_DATA_BLOCK),
+            block_size,
+            blocks_per_group: u32_at(bytes, field::BLOCKS_PER_GROUP),
+            inodes_per_group: u32_at(bytes, field::INODES_PER_GROUP),
+            rev_level,
+            inode_size,
+            feature_incompat,
+            volume_name: bytes[field::VOLUME_NAME..field::VOLUME_NAME + 16]
+                .try_into()
+                .unwrap(),
+        };
+        if !inode_size.is_power_of_two() || inode_size < 128 || inode_size > block_size {
+            return Err(Ext2Error::InvalidSuperblock("inode size"));
+        }
+        if superblock.blocks_per_group == 0 || superblock.inodes_per_group == 0 {
+            return Err(Ext2Error::InvalidSuperblock("group size"));
+        }
+        if superblock.first_data_block >= superblock.blocks_count {
+            return Err(Ext2Error::InvalidSuperblock("block count"));
+        }
+        if u64::from(superblock.inodes_count)
+            > u64::from(superblock.group_count()) * u64::from(superblock.inodes_per_group)
+        {
+            return Err(Ext2Error::InvalidSuperblock("inode count"));
+        }
+        let unsupported = feature_incompat & !incompat::SUPPORTED;
+        if unsupported & incompat::RECOVER != 0 {
+            return Err(Ext2Error::Unsupported("journal recovery"));
+        } else if unsupported & incompat::EXTENTS != 0 {
+            return Err(Ext2Error::Unsupported("extents"));
+        } else if unsupported != 0 {
+            return Err(Ext2Error::Unsupported("incompatible feature"));
+        }
+        Ok(superblock)
+    }
+
+    /// Returns the number of block groups.
+    pub fn group_count(&self) -> u32 {
+        (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+
+    /// Returns the offset of `block` on the device.
+    pub fn block_offset(&self, block: u32) -> u64 {
+        u64::from(block) * u64::from(self.block_size)
+    }
+
+    /// Reads the group descriptors, returning the first block of the table of inodes of each group.
+    pub fn inode_tables(&self, device: &impl SectorDevice) -> Result<Vec<u32>, Ext2Error> {
+        let count = self.group_count() as usize;
+        let mut bytes = vec![0; count * DESCRIPTOR_SIZE];
+        // The descriptors start in the block following the superblock.
+        device.read_at(self.block_offset(self.first_data_block + 1), &mut bytes)?;
+        let table_blocks = (self.inodes_per_group * self.inode_size).div_ceil(self.block_size);
+        bytes
+            .chunks(DESCRIPTOR_SIZE)
+            .map(|descriptor| {
+                let table = u32_at(descriptor, INODE_TABLE);
+                match table.checked_add(table_blocks) {
+                    Some(end) if table != 0 && end <= self.blocks_count => Ok(table),
+                    _ => Err(Ext2Error::Corrupted("group descriptor")),
+                }
+            })
+            .collect()
+    }
+}
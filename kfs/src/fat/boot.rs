@@ -0,0 +1,406 @@
+//! The boot sector, which describes the layout of the volume, and the creation of new volumes.
+use alloc::vec;
+
+use super::{FatError, FatType, dir, table};
+use crate::device::SectorDevice;
+
+/// The offsets of the fields of the boot sector.
+mod field {
+    pub const BYTES_PER_SECTOR: usize = 0x0b;
+    pub const SECTORS_PER_CLUSTER: usize = 0x0d;
+    pub const RESERVED_SECTORS: usize = 0x0e;
+    pub const FAT_COUNT: usize = 0x10;
+    pub const ROOT_ENTRIES: usize = 0x11;
+    pub const TOTAL_SECTORS_16: usize = 0x13;
+    pub const MEDIA: usize = 0x15;
+    pub const FAT_SIZE_16: usize = 0x16;
+    pub const SECTORS_PER_TRACK: usize = 0x18;
+    pub const HEADS: usize = 0x1a;
+    pub const TOTAL_SECTORS_32: usize = 0x20;
+    /// The fields after this one differ between FAT32 and the others.
+    pub const FAT_SIZE_32: usize = 0x24;
+    pub const EXT_FLAGS: usize = 0x28;
+    pub const ROOT_CLUSTER: usize = 0x2c;
+    pub const FS_INFO: usize = 0x30;
+    pub const BACKUP_BOOT: usize = 0x32;
+    /// The extended boot record, at these offsets on FAT12 and FAT16 and 0x1c bytes further on FAT32.
+    pub const DRIVE: usize = 0x24;
+    pub const EXT_SIGNATURE: usize = 0x26;
+    pub const SERIAL: usize = 0x27;
+    pub const LABEL: usize = 0x2b;
+    pub const FS_TYPE: usize = 0x36;
+    pub const FAT32_SHIFT: usize = 0x1c;
+    pub const SIGNATURE: usize = 0x1fe;
+}
+
+/// The offsets of the fields of the FSInfo sector of FAT32 volumes.
+mod fs_info {
+    pub const LEAD_SIGNATURE: (usize, u32) = (0, 0x4161_5252);
+    pub const STRUCT_SIGNATURE: (usize, u32) = (484, 0x6141_7272);
+    pub const FREE_COUNT: usize = 488;
+    pub const NEXT_FREE: usize = 492;
+    pub const TRAIL_SIGNATURE: (usize, u32) = (508, 0xaa55_0000);
+}
+
+/// The extended boot signature, telling the serial number and label are there.
+const EXT_SIGNATURE: u8 = 0x29;
+/// The media descriptor of fixed disks.
+const MEDIA_FIXED: u8 = 0xf8;
+
+fn u16_at(sector: &[u8], offset: usize) -> u32 {
+    u32::from(u16::from_le_bytes([sector[offset], sector[offset + 1]]))
+}
+
+fn u32_at(sector: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u16(sector: &mut [u8], offset: usize, value: u32) {
+    sector[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+}
+
+fn put_u32(sector: &mut [u8], offset: usize, value: u32) {
+    sector[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Where the regions of a volume are, from its boot sector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub fat_type: FatType,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_count: u32,
+    pub fat_sectors: u32,
+    /// The number of entries of the root directory of FAT12 and FAT16 volumes, which has a region of its own.
+    pub root_entries: u32,
+    /// The first cluster of the root directory of FAT32 volumes.
+    pub root_cluster: u32,
+    /// The number of clusters of the data region, numbered from 2.
+    pub cluster_count: u32,
+    /// The sector of the FSInfo structure of FAT32 volumes.
+    pub fs_info_sector: Option<u32>,
+    /// The only FAT in use when FAT32 volumes don't mirror their FATs.
+    pub active_fat: Option<u32>,
+    /// The label from the extended boot record, padded with spaces.
+    pub label: Option<[u8; 11]>,
+}
+
+impl Layout {
+    /// Parses the boot sector `sector`, which must be at least 512 bytes.
+    pub fn parse(sector: &[u8]) -> Result<Self, FatError> {
+        if sector[field::SIGNATURE..field::SIGNATURE + 2] != [0x55, 0xaa] {
+            return Err(FatError::InvalidBootSector("signature"));
+        }
+        let bytes_per_sector = u16_at(sector, field::BYTES_PER_SECTOR);
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+            return Err(FatError::InvalidBootSector("sector size"));
+        }
+        let sectors_per_cluster = u32::from(sector[field::SECTORS_PER_CLUSTER]);
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err(FatError::InvalidBootSector("cluster size"));
+        }
+        let reserved_sectors = u16_at(sector, field::RESERVED_SECTORS);
+        let fat_count = u32::from(sector[field::FAT_COUNT]);
+        let root_entries = u16_at(sector, field::ROOT_ENTRIES);
+        let fat_sectors = match u16_at(sector, field::FAT_SIZE_16) {
+            0 => u32_at(sector, field::FAT_SIZE_32),
+            size => size,
+        };
+        let total_sectors = match u16_at(sector, field::TOTAL_SECTORS_16) {
+            0 => u32_at(sector, field::TOTAL_SECTORS_32),
+            total => total,
+        };
+        if reserved_sectors == 0 || fat_count == 0 || fat_sectors == 0 {
+            return Err(FatError::InvalidBootSector("layout"));
+        }
+        let root_sectors = (root_entries * dir::ENTRY_SIZE as u32).div_ceil(bytes_per_sector);
+        let data_sectors = fat_count
+            .checked_mul(fat_sectors)
+            .and_then(|fats| fats.checked_add(reserved_sectors + root_sectors))
+            .and_then(|metadata| total_sectors.checked_sub(metadata))
+            .ok_or(FatError::InvalidBootSector("layout"))?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+        // The type only depends on the number of clusters.
+        let fat_type = FatType::for_clusters(cluster_count);
+        let ext = match fat_type {
+            FatType::Fat32 => field::FAT32_SHIFT,
+            _ => 0,
+        };
+        let label = (sector[field::EXT_SIGNATURE + ext] == EXT_SIGNATURE).then(|| {
+            let start = field::LABEL + ext;
+            sector[start..start + 11].try_into().unwrap()
+        });
+        let mut layout = Layout {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_sectors,
+            root_entries,
+            root_cluster: 0,
+            cluster_count,
+            fs_info_sector: None,
+            active_fat: None,
+            label,
+        };
+        if fat_type == FatType::Fat32 {
+            if root_entries != 0 {
+                return Err(FatError::InvalidBootSector("root directory"));
+            }
+            layout.root_cluster = u32_at(sector, field::ROOT_CLUSTER);
+            if !layout.is_cluster(layout.root_cluster) {
+                return Err(FatError::InvalidBootSector("root directory"));
+            }
+            layout.fs_info_sector = match u16_at(sector, field::FS_INFO) {
+                0 | 0xffff => None,
+                sector => Some(sector),
+            };
+            // Bit 7 disables mirroring, leaving only the FAT numbered by the low bits in use.
+            let flags = u16_at(sector, field::EXT_FLAGS);
+            if flags & 0x80 != 0 {
+                layout.active_fat = Some((flags & 0xf).min(fat_count - 1));
+            }
+        } else if root_entries == 0 {
+            return Err(FatError::InvalidBootSector("root directory"));
+        }
+        Ok(layout)
+    }
+
+    /// Returns the size of a cluster in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * self.bytes_per_sector
+    }
+
+    /// Returns `true` if `cluster` is a cluster of the data region.
+    pub fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    /// Returns the offset of the FAT numbered `index`.
+    pub fn fat_offset(&self, index: u32) -> u64 {
+        u64::from(self.reserved_sectors + index * self.fat_sectors)
+            * u64::from(self.bytes_per_sector)
+    }
+
+    /// Returns the offset of the root directory region of FAT12 and FAT16 volumes.
+    pub fn root_offset(&self) -> u64 {
+        self.fat_offset(self.fat_count)
+    }
+
+    /// Returns the size of the root directory region of FAT12 and FAT16 volumes.
+    pub fn root_size(&self) -> u64 {
+        u64::from(self.root_entries) * dir::ENTRY_SIZE as u64
+    }
+
+    /// Returns the offset of the cluster `cluster` of the data region.
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        let data = self.root_offset()
+            + self
+                .root_size()
+                .next_multiple_of(u64::from(self.bytes_per_sector));
+        data + u64::from(cluster - 2) * u64::from(self.cluster_size())
+    }
+
+    /// Returns the offset of the FSInfo sector.
+    pub fn fs_info_offset(&self) -> Option<u64> {
+        self.fs_info_sector
+            .map(|sector| u64::from(sector) * u64::from(self.bytes_per_sector))
+    }
+}
+
+/// The allocation hints of the FSInfo sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsInfo {
+    pub free_count: u32,
+    pub next_free: u32,
+}
+
+impl FsInfo {
+    /// Reads the FSInfo sector of `layout`, if it has a valid one.
+    pub fn read(device: &impl SectorDevice, layout: &Layout) -> Result<Option<Self>, FatError> {
+        let Some(offset) = layout.fs_info_offset() else {
+            return Ok(None);
+        };
+        let mut sector = [0; 512];
+        device.read_at(offset, &mut sector)?;
+        let signed = [
+            fs_info::LEAD_SIGNATURE,
+            fs_info::STRUCT_SIGNATURE,
+            fs_info::TRAIL_SIGNATURE,
+        ]
+        .iter()
+        .all(|&(offset, signature)| u32_at(&sector, offset) == signature);
+        Ok(signed.then(|| FsInfo {
+            free_count: u32_at(&sector, fs_info::FREE_COUNT),
+            next_free: u32_at(&sector, fs_info::NEXT_FREE),
+        }))
+    }
+
+    /// Writes the hints to the FSInfo sector at `offset`.
+    pub fn write(&self, device: &impl SectorDevice, offset: u64) -> Result<(), FatError> {
+        let mut sector = [0; 512];
+        for (offset, signature) in [
+            fs_info::LEAD_SIGNATURE,
+            fs_info::STRUCT_SIGNATURE,
+            fs_info::TRAIL_SIGNATURE,
+        ] {
+            put_u32(&mut sector, offset, signature);
+        }
+        put_u32(&mut sector, fs_info::FREE_COUNT, self.free_count);
+        put_u32(&mut sector, fs_info::NEXT_FREE, self.next_free);
+        device.write_at(offset, &sector)?;
+        Ok(())
+    }
+}
+
+/// Returns the number of sectors a FAT of `entries` entries takes.
+fn fat_sectors(fat_type: FatType, entries: u32, bytes_per_sector: u32) -> u32 {
+    let bytes = match fat_type {
+        FatType::Fat12 => (entries * 3).div_ceil(2),
+        FatType::Fat16 => entries * 2,
+        FatType::Fat32 => entries * 4,
+    };
+    bytes.div_ceil(bytes_per_sector)
+}
+
+/// Returns the label `label` as stored in the boot sector, which must be made of the characters of short names.
+fn encode_label(label: &str) -> Result<[u8; 11], FatError> {
+    let mut encoded = *b"NO NAME    ";
+    if label.is_empty() {
+        return Ok(encoded);
+    }
+    if label.len() > 11
+        || !label
+            .bytes()
+            .all(|byte| byte == b' ' || dir::is_short_char(byte))
+    {
+        return Err(FatError::InvalidName);
+    }
+    encoded.fill(b' ');
+    encoded[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(encoded)
+}
+
+/// Creates an empty volume of the type `fat_type` taking the whole of `device`, with the label `label` made of
+/// uppercase letters, digits, spaces and the symbols allowed in short names. The clusters are as small as the type
+/// allows.
+pub fn format(device: &impl SectorDevice, fat_type: FatType, label: &str) -> Result<(), FatError> {
+    let label = encode_label(label)?;
+    let bytes_per_sector = device.sector_size() as u32;
+    if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+        return Err(FatError::Unsupported("sector size"));
+    }
+    let total_sectors =
+        u32::try_from(device.sector_count()).map_err(|_| FatError::Unsupported("volume size"))?;
+    let (reserved_sectors, root_entries) = match fat_type {
+        FatType::Fat32 => (32, 0),
+        _ => (1, 512),
+    };
+    let fat_count = 2;
+    let root_sectors = (root_entries * dir::ENTRY_SIZE as u32).div_ceil(bytes_per_sector);
+
+    // Fewer clusters need smaller FATs, which leave more sectors to clusters, so their sizes are found together.
+    let mut chosen = None;
+    for sectors_per_cluster in (0..8).map(|shift| 1 << shift) {
+        let mut fat_size = 1;
+        let cluster_count = loop {
+            let data_sectors = total_sectors
+                .checked_sub(reserved_sectors + root_sectors + fat_count * fat_size)
+                .ok_or(FatError::Unsupported("volume size"))?;
+            let cluster_count = data_sectors / sectors_per_cluster;
+            let needed = fat_sectors(fat_type, cluster_count + 2, bytes_per_sector);
+            if needed <= fat_size {
+                break cluster_count;
+            }
+            fat_size = needed;
+        };
+        if FatType::for_clusters(cluster_count) == fat_type {
+            chosen = Some((sectors_per_cluster, fat_size, cluster_count));
+            break;
+        }
+        // Larger clusters only make fewer of them.
+        if cluster_count < fat_type.min_clusters() {
+            break;
+        }
+    }
+    let (sectors_per_cluster, fat_size, cluster_count) =
+        chosen.ok_or(FatError::Unsupported("volume size"))?;
+
+    let metadata_sectors = reserved_sectors + fat_count * fat_size + root_sectors;
+    let zeroes = vec![0; bytes_per_sector as usize * 64];
+    for start in (0..metadata_sectors).step_by(64) {
+        let count = (metadata_sectors - start).min(64);
+        device.write_sectors(
+            u64::from(start),
+            &zeroes[..(count * bytes_per_sector) as usize],
+        )?;
+    }
+
+    let mut boot = vec![0; bytes_per_sector as usize];
+    boot[..3].copy_from_slice(match fat_type {
+        FatType::Fat32 => &[0xeb, 0x58, 0x90],
+        _ => &[0xeb, 0x3c, 0x90],
+    });
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    put_u16(&mut boot, field::BYTES_PER_SECTOR, bytes_per_sector);
+    boot[field::SECTORS_PER_CLUSTER] = sectors_per_cluster as u8;
+    put_u16(&mut boot, field::RESERVED_SECTORS, reserved_sectors);
+    boot[field::FAT_COUNT] = fat_count as u8;
+    put_u16(&mut boot, field::ROOT_ENTRIES, root_entries);
+    if total_sectors < 0x10000 && fat_type != FatType::Fat32 {
+        put_u16(&mut boot, field::TOTAL_SECTORS_16, total_sectors);
+    } else {
+        put_u32(&mut boot, field::TOTAL_SECTORS_32, total_sectors);
+    }
+    boot[field::MEDIA] = MEDIA_FIXED;
+    put_u16(&mut boot, field::SECTORS_PER_TRACK, 32);
+    put_u16(&mut boot, field::HEADS, 64);
+    let ext = if fat_type == FatType::Fat32 {
+        put_u32(&mut boot, field::FAT_SIZE_32, fat_size);
+        put_u32(&mut boot, field::ROOT_CLUSTER, 2);
+        put_u16(&mut boot, field::FS_INFO, 1);
+        put_u16(&mut boot, field::BACKUP_BOOT, 6);
+        field::FAT32_SHIFT
+    } else {
+        put_u16(&mut boot, field::FAT_SIZE_16, fat_size);
+        0
+    };
+    boot[field::DRIVE + ext] = 0x80;
+    boot[field::EXT_SIGNATURE + ext] = EXT_SIGNATURE;
+    // The serial number only has to tell volumes apart, so any value derived from the volume does.
+    put_u32(
+        &mut boot,
+        field::SERIAL + ext,
+        total_sectors.rotate_left(16) ^ 0x4e4f_5641,
+    );
+    boot[field::LABEL + ext..field::LABEL + ext + 11].copy_from_slice(&label);
+    boot[field::FS_TYPE + ext..field::FS_TYPE + ext + 8].copy_from_slice(match fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    boot[field::SIGNATURE..field::SIGNATURE + 2].copy_from_slice(&[0x55, 0xaa]);
+    device.write_sectors(0, &boot)?;
+
+    let layout = Layout::parse(&boot)?;
+    debug_assert_eq!(layout.cluster_count, cluster_count);
+    // The first two entries hold the media descriptor and an end of chain marker.
+    table::init(device, &layout, MEDIA_FIXED)?;
+    if fat_type == FatType::Fat32 {
+        device.write_sectors(6, &boot)?;
+        device.write_at(
+            layout.cluster_offset(layout.root_cluster),
+            &vec![0; layout.cluster_size() as usize],
+        )?;
+        let info = FsInfo {
+            free_count: cluster_count - 1,
+            next_free: 3,
+        };
+        info.write(device, u64::from(bytes_per_sector))?;
+        info.write(device, 7 * u64::from(bytes_per_sector))?;
+    }
+    device.flush()?;
+    Ok(())
+}
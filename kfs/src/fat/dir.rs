@@ -0,0 +1,482 @@
+//! Directory entries, with the long names stored in entries of their own before the short ones.
+use alloc::{string::String, vec::Vec};
+
+use super::FatError;
+
+/// The size of a directory entry.
+pub const ENTRY_SIZE: usize = 32;
+/// The number of characters of a long name held by each entry.
+const LONG_CHARS: usize = 13;
+/// The longest long name, in UTF-16 code units.
+const MAX_LONG_NAME: usize = 255;
+
+/// The first byte of the name of entries that were deleted.
+pub const DELETED: u8 = 0xe5;
+/// The first byte of the name of the entry after the last one.
+pub const END: u8 = 0;
+/// The first byte stored for names really starting with [DELETED].
+const ESCAPED_DELETED: u8 = 0x05;
+
+/// The attributes of entries.
+pub mod attr {
+    pub const READ_ONLY: u8 = 0x01;
+    pub const VOLUME_ID: u8 = 0x08;
+    pub const DIRECTORY: u8 = 0x10;
+    pub const ARCHIVE: u8 = 0x20;
+    /// The combination marking the entries holding parts of long names.
+    pub const LONG_NAME: u8 = 0x0f;
+}
+
+/// The bits of the reserved byte telling the parts of a short name that are shown in lowercase.
+mod case {
+    pub const LOWER_BASE: u8 = 0x08;
+    pub const LOWER_EXTENSION: u8 = 0x10;
+}
+
+/// The offsets of the fields of short entries.
+mod field {
+    pub const ATTRIBUTES: usize = 11;
+    pub const CASE: usize = 12;
+    pub const CLUSTER_HIGH: usize = 20;
+    pub const WRITE_TIME: usize = 22;
+    pub const WRITE_DATE: usize = 24;
+    pub const CLUSTER_LOW: usize = 26;
+    pub const SIZE: usize = 28;
+}
+
+/// The offsets of the fields of long name entries.
+mod long {
+    pub const ORDER: usize = 0;
+    /// Set in the order of the last part of the name, stored first.
+    pub const LAST: u8 = 0x40;
+    pub const CHECKSUM: usize = 13;
+    /// Where the characters are, as UTF-16 code units.
+    pub const CHARS: [usize; super::LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+}
+
+/// The date written to new entries, January 1st 1980, as nothing tells the time here.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// Returns `true` if `byte` may be part of a short name.
+pub fn is_short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&byte)
+}
+
+/// The entry holding the short name and the properties of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortEntry {
+    /// The name padded with spaces, 8 characters then the 3 of the extension.
+    pub name: [u8; 11],
+    pub attributes: u8,
+    pub case: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+impl ShortEntry {
+    /// Creates the entry of a new file or directory.
+    pub fn new(name: [u8; 11], case: u8, attributes: u8, first_cluster: u32) -> Self {
+        ShortEntry {
+            name,
+            attributes,
+            case,
+            first_cluster,
+            size: 0,
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let mut name: [u8; 11] = bytes[..11].try_into().unwrap();
+        if name[0] == ESCAPED_DELETED {
+            name[0] = DELETED;
+        }
+        ShortEntry {
+            name,
+            attributes: bytes[field::ATTRIBUTES],
+            case: bytes[field::CASE],
+            first_cluster: (u32::from(u16_at(field::CLUSTER_HIGH)) << 16)
+                | u32::from(u16_at(field::CLUSTER_LOW)),
+            size: u32::from_le_bytes(bytes[field::SIZE..field::SIZE + 4].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[..11].copy_from_slice(&self.name);
+        if bytes[0] == DELETED {
+            bytes[0] = ESCAPED_DELETED;
+        }
+        bytes[field::ATTRIBUTES] = self.attributes;
+        bytes[field::CASE] = self.case;
+        bytes[field::CLUSTER_HIGH..field::CLUSTER_HIGH + 2]
+            .copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        bytes[field::WRITE_TIME..field::WRITE_TIME + 2].copy_from_slice(&0u16.to_le_bytes());
+        bytes[field::WRITE_DATE..field::WRITE_DATE + 2]
+            .copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        bytes[field::CLUSTER_LOW..field::CLUSTER_LOW + 2]
+            .copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        bytes[field::SIZE..field::SIZE + 4].copy_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+
+    /// Updates the first cluster and size of the entry stored in `bytes`, leaving its other fields.
+    pub fn update(bytes: &mut [u8], first_cluster: u32, size: u32) {
+        bytes[field::CLUSTER_HIGH..field::CLUSTER_HIGH + 2]
+            .copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        bytes[field::CLUSTER_LOW..field::CLUSTER_LOW + 2]
+            .copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        bytes[field::SIZE..field::SIZE + 4].copy_from_slice(&size.to_le_bytes());
+    }
+
+    /// Returns the short name as shown, such as `README.TXT`, in lowercase where the entry asks for it.
+    pub fn display_name(&self) -> String {
+        let part = |bytes: &[u8], lower: bool| -> String {
+            let end = bytes
+                .iter()
+                .rposition(|&byte| byte != b' ')
+                .map_or(0, |index| index + 1);
+            bytes[..end]
+                .iter()
+                .map(|&byte| {
+                    // Bytes outside of ASCII are in a code page, which is taken to be Latin-1.
+                    let c = char::from(byte);
+                    if lower { c.to_ascii_lowercase() } else { c }
+                })
+                .collect()
+        };
+        let mut name = part(&self.name[..8], self.case & case::LOWER_BASE != 0);
+        let extension = part(&self.name[8..], self.case & case::LOWER_EXTENSION != 0);
+        if !extension.is_empty() {
+            name.push('.');
+            name.push_str(&extension);
+        }
+        name
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & attr::DIRECTORY != 0
+    }
+}
+
+/// Returns the checksum of a short name, which the entries of the long name for it hold.
+pub fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// A file as found in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    /// The long name, or the short one if there is none.
+    pub name: String,
+    pub entry: ShortEntry,
+    /// The index of the first entry of the file, holding the start of its long name if it has one.
+    pub first_slot: usize,
+    /// The index of the short entry.
+    pub slot: usize,
+}
+
+impl Found {
+    /// Returns `true` if `name` is the long or short name of the file, ignoring case.
+    pub fn is_named(&self, name: &str) -> bool {
+        let same = |other: &str| {
+            other
+                .chars()
+                .flat_map(char::to_lowercase)
+                .eq(name.chars().flat_map(char::to_lowercase))
+        };
+        same(&self.name) || same(&self.entry.display_name())
+    }
+}
+
+/// The parts of a long name read so far, before its short entry.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// The order of the part expected next, counting down to 1.
+    next: u8,
+    first_slot: usize,
+}
+
+/// Parses the entries of a directory, skipping `.`, `..`, volume labels and deleted entries. Also returns the index
+/// of the entry ending the directory, which is its number of entries if it is full.
+pub fn parse(data: &[u8]) -> (Vec<Found>, usize) {
+    let mut found = Vec::new();
+    let mut long_name: Option<LongName> = None;
+    for (slot, bytes) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+        match bytes[0] {
+            END => return (found, slot),
+            DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+        if bytes[field::ATTRIBUTES] & 0x3f == attr::LONG_NAME {
+            let order = bytes[long::ORDER];
+            let checksum = bytes[long::CHECKSUM];
+            if order & long::LAST != 0 {
+                let count = order & !long::LAST;
+                long_name = (1..=20).contains(&count).then(|| LongName {
+                    units: alloc::vec![0xffff; usize::from(count) * LONG_CHARS],
+                    checksum,
+                    next: count,
+                    first_slot: slot,
+                });
+            }
+            // Parts out of order or of another name void the name.
+            long_name = long_name
+                .filter(|name| name.next == order & !long::LAST && name.checksum == checksum);
+            if let Some(name) = &mut long_name {
+                let start = usize::from((order & !long::LAST) - 1) * LONG_CHARS;
+                for (index, &offset) in long::CHARS.iter().enumerate() {
+                    name.units[start + index] =
+                        u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+                }
+                name.next -= 1;
+            }
+            continue;
+        }
+        let entry = ShortEntry::parse(bytes);
+        let long_name = long_name
+            .take()
+            .filter(|name| name.next == 0 && name.checksum == checksum(&entry.name));
+        if entry.attributes & attr::VOLUME_ID != 0
+            || matches!(&entry.name, b".          " | b"..         ")
+        {
+            continue;
+        }
+        let (name, first_slot) = match long_name {
+            Some(long_name) => {
+                let end = long_name
+                    .units
+                    .iter()
+                    .position(|&unit| unit == 0)
+                    .unwrap_or(long_name.units.len());
+                let name = char::decode_utf16(long_name.units[..end].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, long_name.first_slot)
+            }
+            None => (entry.display_name(), slot),
+        };
+        found.push(Found {
+            name,
+            entry,
+            first_slot,
+            slot,
+        });
+    }
+    (found, data.len() / ENTRY_SIZE)
+}
+
+/// Checks that `name` can name a file.
+pub fn validate(name: &str) -> Result<(), FatError> {
+    let valid = !matches!(name, "" | "." | "..")
+        && name.encode_utf16().count() <= MAX_LONG_NAME
+        // Trailing dots and spaces are dropped by other systems.
+        && !name.ends_with(['.', ' '])
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|\u{7f}".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(FatError::InvalidName)
+    }
+}
+
+/// How a name is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortName {
+    /// The name is a valid short name, once the case given by the flags is applied.
+    Exact([u8; 11], u8),
+    /// The name needs a long name, with this short name derived from it, which is lossy if it can't be told apart
+    /// from other names without a numeric tail.
+    Derived([u8; 11], bool),
+}
+
+/// Returns the short name for `name`, which must be valid.
+pub fn short_name(name: &str) -> ShortName {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    // A name which is already a short one in one case per part needs no long name.
+    let part_case = |part: &str, max: usize, lower: u8| {
+        let fits = !part.is_empty() || max == 3;
+        let fits = fits
+            && part.len() <= max
+            && part
+                .bytes()
+                .all(|byte| is_short_char(byte.to_ascii_uppercase()));
+        if !fits {
+            None
+        } else if part.bytes().any(|byte| byte.is_ascii_lowercase()) {
+            (!part.bytes().any(|byte| byte.is_ascii_uppercase())).then_some(lower)
+        } else {
+            Some(0)
+        }
+    };
+    if let (Some(base_case), Some(extension_case)) = (
+        part_case(base, 8, case::LOWER_BASE),
+        part_case(extension, 3, case::LOWER_EXTENSION),
+    ) {
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+        short[8..8 + extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+        return ShortName::Exact(short, base_case | extension_case);
+    }
+
+    let mut lossy = false;
+    let mut convert = |part: &str, max: usize, out: &mut [u8]| {
+        let mut len = 0;
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                lossy = true;
+                continue;
+            }
+            let byte = match u8::try_from(c.to_ascii_uppercase()) {
+                Ok(byte) if is_short_char(byte) => byte,
+                _ => {
+                    lossy = true;
+                    b'_'
+                }
+            };
+            if len == max {
+                lossy = true;
+                break;
+            }
+            out[len] = byte;
+            len += 1;
+        }
+    };
+    let mut short = [b' '; 11];
+    convert(base.trim_start_matches('.'), 8, &mut short[..8]);
+    convert(extension, 3, &mut short[8..]);
+    if short[0] == b' ' {
+        short[0] = b'_';
+        lossy = true;
+    }
+    ShortName::Derived(short, lossy || base.starts_with('.'))
+}
+
+/// Returns `short` with the numeric tail `~number`.
+pub fn with_tail(short: [u8; 11], number: u32) -> [u8; 11] {
+    let mut tail = [0; 8];
+    let mut digits = number;
+    let mut len = 0;
+    while digits > 0 || len == 0 {
+        tail[7 - len] = b'0' + (digits % 10) as u8;
+        digits /= 10;
+        len += 1;
+    }
+    len += 1;
+    tail[8 - len] = b'~';
+    let base_len = short[..8]
+        .iter()
+        .position(|&byte| byte == b' ')
+        .unwrap_or(8)
+        .min(8 - len);
+    let mut result = short;
+    result[base_len..base_len + len].copy_from_slice(&tail[8 - len..]);
+    result[base_len + len..8].fill(b' ');
+    result
+}
+
+/// Returns the entries holding `name` for the short name whose checksum is `checksum`, in the order they are stored.
+pub fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_CHARS);
+    // The name ends with a NUL unless it fills its last entry, which is padded with 0xffff.
+    if !units.len().is_multiple_of(LONG_CHARS) {
+        units.push(0);
+    }
+    units.resize(count * LONG_CHARS, 0xffff);
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut bytes = [0; ENTRY_SIZE];
+            bytes[long::ORDER] = index as u8 + 1;
+            if index == count - 1 {
+                bytes[long::ORDER] |= long::LAST;
+            }
+            bytes[field::ATTRIBUTES] = attr::LONG_NAME;
+            bytes[long::CHECKSUM] = checksum;
+            for (unit, &offset) in units[index * LONG_CHARS..].iter().zip(&long::CHARS) {
+                bytes[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            bytes
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_names() {
+        assert_eq!(
+            short_name("README.TXT"),
+            ShortName::Exact(*b"README  TXT", 0)
+        );
+        assert_eq!(
+            short_name("kernel.bin"),
+            ShortName::Exact(*b"KERNEL  BIN", case::LOWER_BASE | case::LOWER_EXTENSION)
+        );
+        assert_eq!(short_name("EFI"), ShortName::Exact(*b"EFI        ", 0));
+        assert_eq!(
+            short_name("Readme.txt"),
+            ShortName::Derived(*b"README  TXT", false)
+        );
+        assert_eq!(
+            short_name("a long name.text"),
+            ShortName::Derived(*b"ALONGNAMTEX", true)
+        );
+        assert_eq!(
+            short_name(".bashrc"),
+            ShortName::Derived(*b"BASHRC     ", true)
+        );
+        assert_eq!(
+            short_name("é.tar.gz"),
+            ShortName::Derived(*b"_TAR    GZ ", true)
+        );
+        assert_eq!(with_tail(*b"ALONGNAMTEX", 1), *b"ALONGN~1TEX");
+        assert_eq!(with_tail(*b"AB      C  ", 12), *b"AB~12   C  ");
+        assert_eq!(with_tail(*b"ABCDEFGH   ", 123456), *b"A~123456   ");
+
+        assert_eq!(validate("name with spaces.txt"), Ok(()));
+        for invalid in ["", "..", "a/b", "a:b", "trailing.", "trailing "] {
+            assert_eq!(validate(invalid), Err(FatError::InvalidName));
+        }
+    }
+
+    #[test]
+    fn long_names() {
+        let name = "A name longer than 13 characters, in two entries or more";
+        let short = *b"ANAMEL~1   ";
+        let mut data: Vec<u8> = long_entries(name, checksum(&short)).concat();
+        assert_eq!(data.len(), 5 * ENTRY_SIZE);
+        data.extend_from_slice(&ShortEntry::new(short, 0, attr::ARCHIVE, 5).to_bytes());
+        // A deleted entry followed by a long name whose short entry has another checksum.
+        let mut deleted = ShortEntry::new(*b"GONE       ", 0, attr::ARCHIVE, 0).to_bytes();
+        deleted[0] = DELETED;
+        data.extend_from_slice(&deleted);
+        data.extend(long_entries("orphan", 0).concat());
+        data.extend_from_slice(
+            &ShortEntry::new(*b"KERNEL  BIN", 0x18, attr::ARCHIVE, 9).to_bytes(),
+        );
+        data.extend_from_slice(&[0; ENTRY_SIZE * 2]);
+
+        let (found, end) = parse(&data);
+        assert_eq!(end, 9);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].name, name);
+        assert_eq!((found[0].first_slot, found[0].slot), (0, 5));
+        assert_eq!(found[0].entry.first_cluster, 5);
+        assert!(found[0].is_named("ANAMEL~1"));
+        assert!(found[0].is_named(&name.to_uppercase()));
+        assert_eq!(found[1].name, "kernel.bin");
+        assert_eq!((found[1].first_slot, found[1].slot), (8, 8));
+    }
+}
@@ -0,0 +1,990 @@
+//! FAT12, FAT16 and FAT32 volumes, with long file names.
+//!
+//! Files are reached from the root directory with [FatFs::lookup], which returns a [Node] describing the file. Nodes
+//! are updated by the operations growing or shrinking their file, so each file should have a single node in use.
+use alloc::{string::String, vec, vec::Vec};
+use spin::RwLock;
+
+use crate::device::{DeviceError, SectorDevice};
+use boot::{FsInfo, Layout};
+use dir::{ENTRY_SIZE, Found, ShortEntry, ShortName, attr};
+use table::Table;
+
+pub use boot::format;
+
+mod boot;
+mod dir;
+mod table;
+
+/// The most entries a directory may have.
+const MAX_DIR_ENTRIES: usize = 65536;
+
+/// An error that occurred while using a FAT volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum FatError {
+    /// The boot sector doesn't describe a FAT volume.
+    #[error("Invalid boot sector: {0}")]
+    InvalidBootSector(&'static str),
+    /// The structures of the volume are inconsistent.
+    #[error("Corrupted {0}")]
+    Corrupted(&'static str),
+    /// No file has the given name.
+    #[error("File not found")]
+    NotFound,
+    /// A file already has the given name.
+    #[error("File already exists")]
+    AlreadyExists,
+    /// The file isn't a directory.
+    #[error("Not a directory")]
+    NotADirectory,
+    /// The file is a directory.
+    #[error("Is a directory")]
+    IsADirectory,
+    /// The directory still has entries.
+    #[error("Directory not empty")]
+    DirectoryNotEmpty,
+    /// The name can't be stored.
+    #[error("Invalid name")]
+    InvalidName,
+    /// Every cluster is in use, or the directory is full.
+    #[error("No space left")]
+    NoSpace,
+    /// Files can't reach 4 GiB.
+    #[error("File too large")]
+    FileTooLarge,
+    /// The device is read-only.
+    #[error("Volume is read-only")]
+    ReadOnly,
+    /// The volume uses a feature that isn't supported.
+    #[error("Unsupported {0}")]
+    Unsupported(&'static str),
+    /// The device failed.
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+}
+
+/// The variants of FAT, named after the size of their table entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    /// Up to 4084 clusters.
+    Fat12,
+    /// From 4085 to 65524 clusters.
+    Fat16,
+    /// From 65525 clusters.
+    Fat32,
+}
+
+impl FatType {
+    /// Returns the type of volumes of `clusters` clusters.
+    pub fn for_clusters(clusters: u32) -> Self {
+        match clusters {
+            ..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
+
+    /// Returns the fewest clusters volumes of this type have.
+    fn min_clusters(self) -> u32 {
+        match self {
+            FatType::Fat12 => 1,
+            FatType::Fat16 => 4085,
+            FatType::Fat32 => 65525,
+        }
+    }
+}
+
+/// A file or directory of a volume, as it was when last looked up or modified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The offset of the short entry of the file on the device, `None` for the root directory.
+    entry: Option<u64>,
+    first_cluster: u32,
+    size: u32,
+    attributes: u8,
+}
+
+impl Node {
+    /// Returns a number identifying the file among the ones on the volume, which is 0 for the root directory. A
+    /// removed file may leave its number to a new one.
+    pub fn id(&self) -> u64 {
+        self.entry.map_or(0, |offset| offset / ENTRY_SIZE as u64)
+    }
+
+    /// Returns `true` if the node is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attributes & attr::DIRECTORY != 0
+    }
+
+    /// Returns `true` if the file is marked read-only, which isn't enforced.
+    pub fn is_read_only(&self) -> bool {
+        self.attributes & attr::READ_ONLY != 0
+    }
+
+    /// Returns the size of the file, 0 for directories.
+    pub fn size(&self) -> u64 {
+        u64::from(self.size)
+    }
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The long name of the file, or its short name if it has none.
+    pub name: String,
+    /// The file.
+    pub node: Node,
+}
+
+/// The contents of a directory, with where they are on the device.
+#[derive(Debug)]
+struct DirData {
+    data: Vec<u8>,
+    /// The offset of each of the clusters of the directory, or of the root directory region.
+    extents: Vec<u64>,
+    /// The size of each extent.
+    extent_size: usize,
+}
+
+impl DirData {
+    /// Returns the offset of the entry `slot` on the device.
+    fn offset(&self, slot: usize) -> u64 {
+        let byte = slot * ENTRY_SIZE;
+        self.extents[byte / self.extent_size] + (byte % self.extent_size) as u64
+    }
+
+    fn slots(&self) -> usize {
+        self.data.len() / ENTRY_SIZE
+    }
+}
+
+/// The allocation state of a volume.
+#[derive(Debug)]
+struct State {
+    /// Where to start searching for a free cluster.
+    next_free: u32,
+}
+
+/// A FAT volume on a device.
+#[derive(Debug)]
+pub struct FatFs<D> {
+    device: D,
+    layout: Layout,
+    /// Taken exclusively while modifying the volume, and shared while reading it.
+    state: RwLock<State>,
+}
+
+impl<D: SectorDevice> FatFs<D> {
+    /// Opens the volume on `device`, which starts with its boot sector.
+    pub fn new(device: D) -> Result<Self, FatError> {
+        let mut boot = vec![0; device.sector_size().max(512)];
+        device.read_at(0, &mut boot)?;
+        let layout = Layout::parse(&boot)?;
+        let end = layout.cluster_offset(layout.cluster_count + 2);
+        if end > device.size() {
+            return Err(FatError::InvalidBootSector("volume larger than the device"));
+        }
+        let next_free = match FsInfo::read(&device, &layout)? {
+            Some(info) if layout.is_cluster(info.next_free) => info.next_free,
+            _ => 2,
+        };
+        Ok(FatFs {
+            device,
+            layout,
+            state: RwLock::new(State { next_free }),
+        })
+    }
+
+    /// Returns the device the volume is on.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the type of the volume.
+    pub fn fat_type(&self) -> FatType {
+        self.layout.fat_type
+    }
+
+    /// Returns the size of the clusters files are allocated in.
+    pub fn cluster_size(&self) -> u32 {
+        self.layout.cluster_size()
+    }
+
+    /// Returns the label of the volume from its boot sector, if it has one.
+    pub fn label(&self) -> Option<String> {
+        let label = self.layout.label?;
+        let label: String = label.iter().map(|&byte| char::from(byte)).collect();
+        let label = label.trim_end();
+        (!label.is_empty() && label != "NO NAME").then(|| label.into())
+    }
+
+    /// Returns `true` if the volume can't be modified.
+    pub fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn table(&self) -> Table<'_, D> {
+        Table::new(&self.device, &self.layout)
+    }
+
+    /// Returns the root directory.
+    pub fn root(&self) -> Node {
+        Node {
+            entry: None,
+            first_cluster: match self.layout.fat_type {
+                FatType::Fat32 => self.layout.root_cluster,
+                _ => 0,
+            },
+            size: 0,
+            attributes: attr::DIRECTORY,
+        }
+    }
+
+    /// Reads the contents of the directory `dir`.
+    fn read_dir_data(&self, dir: &Node) -> Result<DirData, FatError> {
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        if dir.entry.is_none() && self.layout.fat_type != FatType::Fat32 {
+            let mut data = vec![0; self.layout.root_size() as usize];
+            self.device.read_at(self.layout.root_offset(), &mut data)?;
+            return Ok(DirData {
+                extent_size: data.len(),
+                data,
+                extents: vec![self.layout.root_offset()],
+            });
+        }
+        let extent_size = self.layout.cluster_size() as usize;
+        let extents: Vec<_> = self
+            .table()
+            .chain(dir.first_cluster)?
+            .into_iter()
+            .map(|cluster| self.layout.cluster_offset(cluster))
+            .collect();
+        if extents.len() * extent_size / ENTRY_SIZE > MAX_DIR_ENTRIES {
+            return Err(FatError::Corrupted("directory"));
+        }
+        let mut data = vec![0; extents.len() * extent_size];
+        for (chunk, &offset) in data.chunks_mut(extent_size).zip(&extents) {
+            self.device.read_at(offset, chunk)?;
+        }
+        Ok(DirData {
+            data,
+            extents,
+            extent_size,
+        })
+    }
+
+    /// Returns the node of the entry found at `slot` of `dir`.
+    fn node(dir: &DirData, found: &Found) -> Node {
+        Node {
+            entry: Some(dir.offset(found.slot)),
+            first_cluster: found.entry.first_cluster,
+            size: if found.entry.is_directory() {
+                0
+            } else {
+                found.entry.size
+            },
+            attributes: found.entry.attributes,
+        }
+    }
+
+    /// Returns the entries of the directory `dir`, without `.` and `..`.
+    pub fn read_dir(&self, dir: &Node) -> Result<Vec<DirEntry>, FatError> {
+        let _state = self.state.read();
+        let data = self.read_dir_data(dir)?;
+        let (found, _) = dir::parse(&data.data);
+        Ok(found
+            .iter()
+            .map(|found| DirEntry {
+                name: found.name.clone(),
+                node: Self::node(&data, found),
+            })
+            .collect())
+    }
+
+    /// Returns the file named `name` in the directory `dir`, comparing names without regard to case.
+    pub fn lookup(&self, dir: &Node, name: &str) -> Result<Node, FatError> {
+        let _state = self.state.read();
+        let data = self.read_dir_data(dir)?;
+        let (found, _) = dir::parse(&data.data);
+        let found = found
+            .iter()
+            .find(|found| found.is_named(name))
+            .ok_or(FatError::NotFound)?;
+        Ok(Self::node(&data, found))
+    }
+
+    /// Returns the file at `path`, whose names are separated by slashes and start from the root directory.
+    pub fn open(&self, path: &str) -> Result<Node, FatError> {
+        let mut node = self.root();
+        for name in path.split('/').filter(|name| !matches!(*name, "" | ".")) {
+            if name == ".." {
+                return Err(FatError::InvalidName);
+            }
+            node = self.lookup(&node, name)?;
+        }
+        Ok(node)
+    }
+
+    /// Reads the file `file` from `offset` into `buf`, returning the number of bytes read, which is only less than the
+    /// length of `buf` at the end of the file.
+    pub fn read(&self, file: &Node, offset: u64, buf: &mut [u8]) -> Result<usize, FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let _state = self.state.read();
+        let size = file.size();
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let chain = self.table().chain(file.first_cluster)?;
+        let cluster_size = u64::from(self.layout.cluster_size());
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = *chain
+                .get((position / cluster_size) as usize)
+                .ok_or(FatError::Corrupted("file size"))?;
+            let within = position % cluster_size;
+            let count = (len - done).min((cluster_size - within) as usize);
+            self.device.read_at(
+                self.layout.cluster_offset(cluster) + within,
+                &mut buf[done..done + count],
+            )?;
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Reads the whole file `file`.
+    pub fn read_to_end(&self, file: &Node) -> Result<Vec<u8>, FatError> {
+        let mut data = vec![0; file.size() as usize];
+        let len = self.read(file, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    fn check_writable(&self) -> Result<(), FatError> {
+        if self.is_read_only() {
+            Err(FatError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes `buf` to the file `file` at `offset`, growing it as needed. The gap left by writing past its end is
+    /// filled with zeroes.
+    pub fn write(&self, file: &mut Node, offset: u64, buf: &[u8]) -> Result<usize, FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        self.check_writable()?;
+        let mut state = self.state.write();
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= u64::from(u32::MAX))
+            .ok_or(FatError::FileTooLarge)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if offset > file.size() {
+            self.fill_zeroes(&mut state, file, file.size(), offset)?;
+        }
+        self.write_data(&mut state, file, offset, buf)?;
+        file.size = file.size.max(end as u32);
+        self.update_entry(file)?;
+        Ok(buf.len())
+    }
+
+    /// Sets the size of the file `file` to `size`, freeing the clusters past it or filling the new bytes with zeroes.
+    pub fn truncate(&self, file: &mut Node, size: u64) -> Result<(), FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        self.check_writable()?;
+        let mut state = self.state.write();
+        let size = u32::try_from(size).map_err(|_| FatError::FileTooLarge)?;
+        if size > file.size {
+            self.fill_zeroes(&mut state, file, file.size(), u64::from(size))?;
+        } else if size < file.size {
+            let keep = size.div_ceil(self.layout.cluster_size());
+            if keep == 0 {
+                if file.first_cluster != 0 {
+                    self.table().free(file.first_cluster)?;
+                }
+                file.first_cluster = 0;
+            } else {
+                let chain = self.table().chain(file.first_cluster)?;
+                let last = *chain
+                    .get(keep as usize - 1)
+                    .ok_or(FatError::Corrupted("file size"))?;
+                self.table().truncate(last)?;
+            }
+        }
+        file.size = size;
+        self.update_entry(file)
+    }
+
+    /// Writes zeroes to the file `file` from `start` to `end`.
+    fn fill_zeroes(
+        &self,
+        state: &mut State,
+        file: &mut Node,
+        start: u64,
+        end: u64,
+    ) -> Result<(), FatError> {
+        let zeroes = vec![0; self.layout.cluster_size() as usize];
+        let mut position = start;
+        while position < end {
+            let len = (end - position).min(zeroes.len() as u64) as usize;
+            self.write_data(state, file, position, &zeroes[..len])?;
+            position += len as u64;
+        }
+        file.size = file.size.max(end as u32);
+        Ok(())
+    }
+
+    /// Writes `buf` to the clusters of `file` at `offset`, allocating the missing ones, without updating its size.
+    fn write_data(
+        &self,
+        state: &mut State,
+        file: &mut Node,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(), FatError> {
+        let cluster_size = u64::from(self.layout.cluster_size());
+        let needed = (offset + buf.len() as u64).div_ceil(cluster_size) as usize;
+        let table = self.table();
+        let mut chain = if file.first_cluster == 0 {
+            Vec::new()
+        } else {
+            table.chain(file.first_cluster)?
+        };
+        while chain.len() < needed {
+            let cluster = match table.allocate(&mut state.next_free, chain.last().copied()) {
+                Ok(cluster) => cluster,
+                Err(err) => {
+                    // Keep what was allocated reachable.
+                    self.update_entry(file)?;
+                    return Err(err);
+                }
+            };
+            if chain.is_empty() {
+                file.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let cluster = chain[(position / cluster_size) as usize];
+            let within = position % cluster_size;
+            let count = (buf.len() - done).min((cluster_size - within) as usize);
+            self.device.write_at(
+                self.layout.cluster_offset(cluster) + within,
+                &buf[done..done + count],
+            )?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Writes the first cluster and size of `file` to its entry.
+    fn update_entry(&self, file: &Node) -> Result<(), FatError> {
+        let Some(offset) = file.entry else {
+            return Ok(());
+        };
+        let mut bytes = [0; ENTRY_SIZE];
+        self.device.read_at(offset, &mut bytes)?;
+        ShortEntry::update(&mut bytes, file.first_cluster, file.size);
+        self.device.write_at(offset, &bytes)?;
+        Ok(())
+    }
+
+    /// Allocates a zeroed cluster, appended to the chain ending with `last` if there is one.
+    fn allocate_zeroed(&self, state: &mut State, last: Option<u32>) -> Result<u32, FatError> {
+        let cluster = self.table().allocate(&mut state.next_free, last)?;
+        self.device.write_at(
+            self.layout.cluster_offset(cluster),
+            &vec![0; self.layout.cluster_size() as usize],
+        )?;
+        Ok(cluster)
+    }
+
+    /// Creates the file or directory `name` in the directory `dir`.
+    pub fn create(&self, dir: &Node, name: &str, directory: bool) -> Result<Node, FatError> {
+        dir::validate(name)?;
+        self.check_writable()?;
+        let mut state = self.state.write();
+        let mut data = self.read_dir_data(dir)?;
+        let (found, end) = dir::parse(&data.data);
+        if found.iter().any(|found| found.is_named(name)) {
+            return Err(FatError::AlreadyExists);
+        }
+
+        let taken = |short: &[u8; 11]| found.iter().any(|found| &found.entry.name == short);
+        let (short, case, long) = match dir::short_name(name) {
+            ShortName::Exact(short, case) => (short, case, Vec::new()),
+            ShortName::Derived(basis, lossy) => {
+                let short = if !lossy && !taken(&basis) {
+                    basis
+                } else {
+                    (1..1_000_000)
+                        .map(|number| dir::with_tail(basis, number))
+                        .find(|short| !taken(short))
+                        .ok_or(FatError::NoSpace)?
+                };
+                (short, 0, dir::long_entries(name, dir::checksum(&short)))
+            }
+        };
+        let needed = long.len() + 1;
+        let slot = self.free_slots(&mut state, dir, &mut data, end, needed)?;
+
+        let first_cluster = if directory {
+            let cluster = self.allocate_zeroed(&mut state, None)?;
+            // `..` refers to the root directory as cluster 0, even on FAT32.
+            let parent = if dir.entry.is_none() {
+                0
+            } else {
+                dir.first_cluster
+            };
+            let mut dots = [0; ENTRY_SIZE * 2];
+            dots[..ENTRY_SIZE].copy_from_slice(
+                &ShortEntry::new(*b".          ", 0, attr::DIRECTORY, cluster).to_bytes(),
+            );
+            dots[ENTRY_SIZE..].copy_from_slice(
+                &ShortEntry::new(*b"..         ", 0, attr::DIRECTORY, parent).to_bytes(),
+            );
+            self.device
+                .write_at(self.layout.cluster_offset(cluster), &dots)?;
+            cluster
+        } else {
+            0
+        };
+        let attributes = if directory {
+            attr::DIRECTORY
+        } else {
+            attr::ARCHIVE
+        };
+        let entry = ShortEntry::new(short, case, attributes, first_cluster);
+        for (index, bytes) in long.iter().chain([&entry.to_bytes()]).enumerate() {
+            self.device.write_at(data.offset(slot + index), bytes)?;
+        }
+        // Entries after the end marker may hold anything, so a new one is needed after the new entries.
+        let after = slot + needed;
+        if after > end && after < data.slots() {
+            self.device.write_at(data.offset(after), &[dir::END])?;
+        }
+        Ok(Node {
+            entry: Some(data.offset(slot + long.len())),
+            first_cluster,
+            size: 0,
+            attributes,
+        })
+    }
+
+    /// Returns the first of `count` consecutive free entries of the directory `dir`, whose contents are `data` and
+    /// whose end is at `end`, growing it if it has none.
+    fn free_slots(
+        &self,
+        state: &mut State,
+        dir: &Node,
+        data: &mut DirData,
+        end: usize,
+        count: usize,
+    ) -> Result<usize, FatError> {
+        let mut run = 0;
+        for slot in 0..data.slots() {
+            let free = slot >= end || data.data[slot * ENTRY_SIZE] == dir::DELETED;
+            run = if free { run + 1 } else { 0 };
+            if run == count {
+                return Ok(slot + 1 - count);
+            }
+        }
+        // The root directory of FAT12 and FAT16 volumes has a fixed size.
+        if dir.entry.is_none() && self.layout.fat_type != FatType::Fat32 {
+            return Err(FatError::NoSpace);
+        }
+        let start = data.slots() - run;
+        let mut last = self.table().chain(dir.first_cluster)?.last().copied();
+        while data.slots() < start + count {
+            if data.slots() + data.extent_size / ENTRY_SIZE > MAX_DIR_ENTRIES {
+                return Err(FatError::NoSpace);
+            }
+            let cluster = self.allocate_zeroed(state, last)?;
+            data.extents.push(self.layout.cluster_offset(cluster));
+            data.data.resize(data.data.len() + data.extent_size, 0);
+            last = Some(cluster);
+        }
+        Ok(start)
+    }
+
+    /// Removes the file or empty directory `name` from the directory `dir`, freeing its clusters.
+    pub fn remove(&self, dir: &Node, name: &str) -> Result<(), FatError> {
+        self.check_writable()?;
+        let _state = self.state.write();
+        let data = self.read_dir_data(dir)?;
+        let (found, _) = dir::parse(&data.data);
+        let found = found
+            .iter()
+            .find(|found| found.is_named(name))
+            .ok_or(FatError::NotFound)?;
+        let node = Self::node(&data, found);
+        if node.is_dir() {
+            let contents = self.read_dir_data(&node)?;
+            if !dir::parse(&contents.data).0.is_empty() {
+                return Err(FatError::DirectoryNotEmpty);
+            }
+        }
+        for slot in found.first_slot..=found.slot {
+            self.device.write_at(data.offset(slot), &[dir::DELETED])?;
+        }
+        if node.first_cluster != 0 {
+            self.table().free(node.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of free clusters.
+    pub fn free_clusters(&self) -> Result<u32, FatError> {
+        let _state = self.state.read();
+        self.table().free_count()
+    }
+
+    /// Writes the allocation hints of FAT32 volumes and flushes the device.
+    pub fn flush(&self) -> Result<(), FatError> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let state = self.state.write();
+        if let Some(offset) = self.layout.fs_info_offset()
+            && FsInfo::read(&self.device, &self.layout)?.is_some()
+        {
+            let info = FsInfo {
+                free_count: self.table().free_count()?,
+                next_free: state.next_free,
+            };
+            info.write(&self.device, offset)?;
+        }
+        self.device.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryDevice;
+    use std::{fs, process::Command};
+
+    /// Formats a device of `sectors` sectors as `fat_type` and opens it.
+    fn volume(fat_type: FatType, sectors: u64) -> FatFs<MemoryDevice> {
+        let device = MemoryDevice::new(512, sectors);
+        format(&device, fat_type, "TEST").unwrap();
+        let fs = FatFs::new(device).unwrap();
+        assert_eq!(fs.fat_type(), fat_type);
+        assert_eq!(fs.label().as_deref(), Some("TEST"));
+        fs
+    }
+
+    fn names(fs: &FatFs<MemoryDevice>, dir: &Node) -> Vec<String> {
+        fs.read_dir(dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    /// Creates, reads, grows, shrinks and removes files and directories.
+    fn exercise(fs: FatFs<MemoryDevice>) {
+        let free = fs.free_clusters().unwrap();
+        let cluster_size = fs.cluster_size() as usize;
+        let root = fs.root();
+
+        let mut file = fs.create(&root, "kernel.bin", false).unwrap();
+        let data: Vec<u8> = (0..cluster_size * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        assert_eq!(fs.write(&mut file, 0, &data).unwrap(), data.len());
+        assert_eq!(fs.read_to_end(&file).unwrap(), data);
+        let mut buf = vec![0; 200];
+        assert_eq!(
+            fs.read(&file, cluster_size as u64 - 100, &mut buf).unwrap(),
+            200
+        );
+        assert_eq!(buf[..], data[cluster_size - 100..cluster_size + 100]);
+
+        let dir = fs
+            .create(&root, "A directory with a long name", true)
+            .unwrap();
+        let sub = fs.create(&dir, "sub", true).unwrap();
+        let mut nested = fs.create(&sub, "Nested File.Text", false).unwrap();
+        // Writing past the end leaves zeroes behind.
+        fs.write(&mut nested, 10, b"nested").unwrap();
+        assert_eq!(
+            fs.read_to_end(
+                &fs.open("/a directory with a long name/SUB/nested file.text")
+                    .unwrap()
+            )
+            .unwrap(),
+            b"\0\0\0\0\0\0\0\0\0\0nested"
+        );
+        assert_eq!(fs.lookup(&root, "kernel.bin").unwrap(), file);
+        assert_eq!(fs.lookup(&root, "KERNEL.BIN").unwrap(), file);
+        assert_eq!(fs.lookup(&dir, "..").unwrap_err(), FatError::NotFound);
+        assert_eq!(
+            fs.create(&root, "Kernel.Bin", false).unwrap_err(),
+            FatError::AlreadyExists
+        );
+        assert_eq!(
+            fs.create(&file, "x", false).unwrap_err(),
+            FatError::NotADirectory
+        );
+        assert_eq!(
+            fs.create(&root, "a:b", false).unwrap_err(),
+            FatError::InvalidName
+        );
+
+        // Names sharing their first characters get numbered short names.
+        for index in 0..40 {
+            fs.create(&dir, &format!("long file name {index}.txt"), false)
+                .unwrap();
+        }
+        let mut listed = names(&fs, &dir);
+        listed.sort();
+        assert_eq!(listed.len(), 41);
+        assert!(listed.contains(&String::from("long file name 39.txt")));
+        let mut root_names = names(&fs, &root);
+        root_names.sort();
+        assert_eq!(root_names, ["A directory with a long name", "kernel.bin"]);
+
+        fs.truncate(&mut file, 10).unwrap();
+        assert_eq!(
+            fs.read_to_end(&fs.open("kernel.bin").unwrap()).unwrap(),
+            data[..10]
+        );
+        fs.truncate(&mut file, 20).unwrap();
+        assert_eq!(fs.read_to_end(&file).unwrap()[10..], [0; 10]);
+        fs.truncate(&mut file, 0).unwrap();
+        assert_eq!(fs.open("kernel.bin").unwrap().size(), 0);
+
+        assert_eq!(
+            fs.remove(&root, "A directory with a long name"),
+            Err(FatError::DirectoryNotEmpty)
+        );
+        for index in 0..40 {
+            fs.remove(&dir, &format!("long file name {index}.txt"))
+                .unwrap();
+        }
+        fs.remove(&sub, "nested file.text").unwrap();
+        fs.remove(&dir, "sub").unwrap();
+        fs.remove(&root, "a directory with a long name").unwrap();
+        fs.remove(&root, "kernel.bin").unwrap();
+        assert_eq!(fs.remove(&root, "kernel.bin"), Err(FatError::NotFound));
+        assert!(names(&fs, &root).is_empty());
+        // Only the clusters the root directory grew by stay in use.
+        let root_clusters = match fs.fat_type() {
+            FatType::Fat32 => fs.table().chain(root.first_cluster).unwrap().len() as u32 - 1,
+            _ => 0,
+        };
+        assert_eq!(fs.free_clusters().unwrap() + root_clusters, free);
+
+        fs.flush().unwrap();
+        let fs = FatFs::new(MemoryDevice::from_bytes(512, fs.device().to_bytes())).unwrap();
+        let mut file = fs.create(&fs.root(), "after", false).unwrap();
+        fs.write(&mut file, 0, b"remounted").unwrap();
+        assert_eq!(
+            fs.read_to_end(&fs.open("AFTER").unwrap()).unwrap(),
+            b"remounted"
+        );
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Runs `command` from dosfstools or mtools, and returns its output.
+    fn run(command: &mut Command) -> Vec<u8> {
+        let output = command
+            .env("MTOOLS_SKIP_CHECK", "1")
+            .output()
+            .expect("dosfstools and mtools are needed to build and check the test images");
+        assert!(
+            output.status.success(),
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output.stdout
+    }
+
+    /// Builds a `fat_type` image of `sectors` sectors with mkfs.fat and copies files to it with mtools, then checks
+    /// that they read back, and that the changes made by the driver read back with mtools and pass fsck.fat.
+    fn interoperate(name: &str, fat_type: FatType, sectors: u64) {
+        let root = std::env::temp_dir().join(format!("kfs-fat-{}-{name}", std::process::id()));
+        let source = root.join("source");
+        let image = root.join("image");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(source.join("dir/nested dir")).unwrap();
+        fs::write(source.join("A long file name.txt"), "hello fat\n").unwrap();
+        fs::write(source.join("dir/nested dir/deep.txt"), "deep").unwrap();
+        fs::write(source.join("big.bin"), pattern(300_000)).unwrap();
+        // Enough long names for the directory to span several clusters.
+        fs::create_dir(source.join("many")).unwrap();
+        for index in 0..100 {
+            fs::write(
+                source.join(format!("many/file number {index}")),
+                index.to_string(),
+            )
+            .unwrap();
+        }
+
+        let bits = match fat_type {
+            FatType::Fat12 => "12",
+            FatType::Fat16 => "16",
+            FatType::Fat32 => "32",
+        };
+        run(Command::new("mkfs.fat")
+            .args(["-C", "-F", bits, "-s", "1", "-n", "KFS-TEST"])
+            .arg(&image)
+            .arg((sectors / 2).to_string()));
+        let mut mcopy = Command::new("mcopy");
+        mcopy.args(["-s", "-i"]).arg(&image);
+        for file in ["A long file name.txt", "big.bin", "dir", "many"] {
+            mcopy.arg(source.join(file));
+        }
+        run(mcopy.arg("::/"));
+        run(Command::new("mmd")
+            .arg("-i")
+            .arg(&image)
+            .arg("::/Empty Directory"));
+
+        let fat = FatFs::new(MemoryDevice::from_bytes(512, fs::read(&image).unwrap())).unwrap();
+        assert_eq!(fat.fat_type(), fat_type);
+        assert_eq!(fat.label().as_deref(), Some("KFS-TEST"));
+        let root_dir = fat.root();
+        let mut listed = names(&fat, &root_dir);
+        listed.sort();
+        assert_eq!(
+            listed,
+            [
+                "A long file name.txt",
+                "Empty Directory",
+                "big.bin",
+                "dir",
+                "many"
+            ]
+        );
+        assert_eq!(
+            fat.read_to_end(&fat.open("a long file name.TXT").unwrap())
+                .unwrap(),
+            b"hello fat\n"
+        );
+        assert_eq!(
+            fat.read_to_end(&fat.open("/dir/nested dir/deep.txt").unwrap())
+                .unwrap(),
+            b"deep"
+        );
+        assert_eq!(
+            fat.read_to_end(&fat.open("big.bin").unwrap()).unwrap(),
+            pattern(300_000)
+        );
+        let many = fat.open("many").unwrap();
+        assert!(many.is_dir());
+        assert_eq!(names(&fat, &many).len(), 100);
+        let file = fat.lookup(&many, "FILE NUMBER 99").unwrap();
+        assert_eq!(fat.read_to_end(&file).unwrap(), b"99");
+        assert!(names(&fat, &fat.open("Empty Directory").unwrap()).is_empty());
+
+        let data = pattern(5000);
+        let mut written = fat
+            .create(&fat.open("dir").unwrap(), "Written by kfs.bin", false)
+            .unwrap();
+        fat.write(&mut written, 0, &data).unwrap();
+        let new = fat.create(&root_dir, "New directory", true).unwrap();
+        fat.create(&new, "empty", false).unwrap();
+        fat.remove(&many, "file number 50").unwrap();
+        fat.remove(&root_dir, "big.bin").unwrap();
+        fat.flush().unwrap();
+        fs::write(&image, fat.device().to_bytes()).unwrap();
+
+        let mtype = |path: &str| {
+            Command::new("mtype")
+                .env("MTOOLS_SKIP_CHECK", "1")
+                .arg("-i")
+                .arg(&image)
+                .arg(path)
+                .output()
+                .unwrap()
+        };
+        assert_eq!(mtype("::/dir/Written by kfs.bin").stdout, data);
+        assert!(mtype("::/New directory/empty").status.success());
+        assert!(!mtype("::/big.bin").status.success());
+        assert!(!mtype("::/many/file number 50").status.success());
+        run(Command::new("fsck.fat").arg("-n").arg(&image));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn fat12() {
+        exercise(volume(FatType::Fat12, 4096));
+    }
+
+    #[test]
+    fn fat16() {
+        exercise(volume(FatType::Fat16, 16384));
+    }
+
+    #[test]
+    fn fat32() {
+        exercise(volume(FatType::Fat32, 70000));
+    }
+
+    #[test]
+    fn mkfs_fat12() {
+        interoperate("fat12", FatType::Fat12, 4096);
+    }
+
+    #[test]
+    fn mkfs_fat16() {
+        interoperate("fat16", FatType::Fat16, 16384);
+    }
+
+    #[test]
+    fn mkfs_fat32() {
+        interoperate("fat32", FatType::Fat32, 70000);
+    }
+
+    #[test]
+    fn full_volume() {
+        let fs = volume(FatType::Fat12, 128);
+        let root = fs.root();
+        let mut file = fs.create(&root, "big", false).unwrap();
+        let free = fs.free_clusters().unwrap() as usize;
+        let data = vec![1; (free + 1) * fs.cluster_size() as usize];
+        assert_eq!(fs.write(&mut file, 0, &data), Err(FatError::NoSpace));
+        assert_eq!(fs.free_clusters().unwrap(), 0);
+        // The clusters allocated before running out stay with the file, and are freed with it.
+        fs.remove(&root, "big").unwrap();
+        assert_eq!(fs.free_clusters().unwrap() as usize, free);
+
+        // The root directory of FAT12 volumes can't grow.
+        let result = (0..1000)
+            .try_for_each(|index| fs.create(&root, &format!("{index}"), false).map(|_| ()));
+        assert_eq!(result, Err(FatError::NoSpace));
+
+        let read_only =
+            FatFs::new(MemoryDevice::from_bytes(512, fs.device().to_bytes()).read_only()).unwrap();
+        assert_eq!(
+            read_only.create(&root, "new", false),
+            Err(FatError::ReadOnly)
+        );
+        assert_eq!(MemoryDevice::new(512, 16).read_at(0, &mut [0; 4]), Ok(()));
+        assert!(matches!(
+            FatFs::new(MemoryDevice::new(512, 16)),
+            Err(FatError::InvalidBootSector(_))
+        ));
+    }
+}
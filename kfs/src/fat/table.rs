@@ -0,0 +1,211 @@
+//! The file allocation table, which chains the clusters of every file and marks the free ones.
+use alloc::vec::Vec;
+
+use super::{FatError, FatType, boot::Layout};
+use crate::device::SectorDevice;
+
+/// The value of free clusters.
+pub const FREE: u32 = 0;
+
+impl FatType {
+    /// Returns the value marking the end of a chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// Returns `true` if `value` ends a chain. Any value from the first one reserved for it does.
+    fn is_end_of_chain(self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+}
+
+/// The FATs of a volume.
+#[derive(Debug)]
+pub struct Table<'a, D> {
+    device: &'a D,
+    layout: &'a Layout,
+}
+
+impl<'a, D: SectorDevice> Table<'a, D> {
+    pub fn new(device: &'a D, layout: &'a Layout) -> Self {
+        Table { device, layout }
+    }
+
+    /// Returns the offset of the entry of `cluster` within a FAT.
+    fn entry_offset(&self, cluster: u32) -> u64 {
+        let cluster = u64::from(cluster);
+        match self.layout.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// Returns the FAT read from, the active one if mirroring is disabled and the first one otherwise.
+    fn main_fat(&self) -> u32 {
+        self.layout.active_fat.unwrap_or(0)
+    }
+
+    /// Returns the entry of `cluster`.
+    pub fn get(&self, cluster: u32) -> Result<u32, FatError> {
+        let offset = self.layout.fat_offset(self.main_fat()) + self.entry_offset(cluster);
+        Ok(match self.layout.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.device.read_at(offset, &mut bytes)?;
+                let value = u32::from(u16::from_le_bytes(bytes));
+                // Entries are 12 bits long, so odd ones start in the middle of a byte.
+                if cluster % 2 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.device.read_at(offset, &mut bytes)?;
+                u32::from(u16::from_le_bytes(bytes))
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.device.read_at(offset, &mut bytes)?;
+                // The top 4 bits are reserved.
+                u32::from_le_bytes(bytes) & 0x0fff_ffff
+            }
+        })
+    }
+
+    /// Sets the entry of `cluster` to `value` in every FAT in use.
+    pub fn set(&self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let fats = match self.layout.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.layout.fat_count,
+        };
+        for fat in fats {
+            let offset = self.layout.fat_offset(fat) + self.entry_offset(cluster);
+            match self.layout.fat_type {
+                FatType::Fat12 => {
+                    let mut bytes = [0; 2];
+                    self.device.read_at(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let value = value as u16 & 0xfff;
+                    let new = if cluster % 2 == 1 {
+                        (old & 0x000f) | (value << 4)
+                    } else {
+                        (old & 0xf000) | value
+                    };
+                    self.device.write_at(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self
+                    .device
+                    .write_at(offset, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    let mut bytes = [0; 4];
+                    self.device.read_at(offset, &mut bytes)?;
+                    let new = (u32::from_le_bytes(bytes) & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.device.write_at(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the cluster after `cluster` in its chain, or `None` if it is the last one.
+    pub fn next(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let value = self.get(cluster)?;
+        if self.layout.fat_type.is_end_of_chain(value) {
+            Ok(None)
+        } else if self.layout.is_cluster(value) {
+            Ok(Some(value))
+        } else {
+            Err(FatError::Corrupted("cluster chain"))
+        }
+    }
+
+    /// Returns the clusters of the chain starting at `first`.
+    pub fn chain(&self, first: u32) -> Result<Vec<u32>, FatError> {
+        if !self.layout.is_cluster(first) {
+            return Err(FatError::Corrupted("cluster chain"));
+        }
+        let mut chain = Vec::from([first]);
+        let mut cluster = first;
+        while let Some(next) = self.next(cluster)? {
+            // A chain longer than the volume loops.
+            if chain.len() as u32 >= self.layout.cluster_count {
+                return Err(FatError::Corrupted("cluster chain"));
+            }
+            chain.push(next);
+            cluster = next;
+        }
+        Ok(chain)
+    }
+
+    /// Allocates a free cluster, searching from `hint`, and appends it to the chain ending with `last` if there is
+    /// one. `hint` is moved past the cluster.
+    pub fn allocate(&self, hint: &mut u32, last: Option<u32>) -> Result<u32, FatError> {
+        let count = self.layout.cluster_count;
+        let start = if self.layout.is_cluster(*hint) {
+            *hint
+        } else {
+            2
+        };
+        for index in 0..count {
+            let cluster = 2 + (start - 2 + index) % count;
+            if self.get(cluster)? != FREE {
+                continue;
+            }
+            self.set(cluster, self.layout.fat_type.end_of_chain())?;
+            if let Some(last) = last {
+                self.set(last, cluster)?;
+            }
+            *hint = cluster + 1;
+            return Ok(cluster);
+        }
+        Err(FatError::NoSpace)
+    }
+
+    /// Makes `cluster` the last of its chain, freeing the clusters after it.
+    pub fn truncate(&self, cluster: u32) -> Result<(), FatError> {
+        let next = self.next(cluster)?;
+        self.set(cluster, self.layout.fat_type.end_of_chain())?;
+        if let Some(next) = next {
+            self.free(next)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the chain starting at `first`.
+    pub fn free(&self, first: u32) -> Result<(), FatError> {
+        for cluster in self.chain(first)? {
+            self.set(cluster, FREE)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of free clusters.
+    pub fn free_count(&self) -> Result<u32, FatError> {
+        let mut count = 0;
+        for cluster in 2..self.layout.cluster_count + 2 {
+            if self.get(cluster)? == FREE {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Writes the first two entries of the FATs of a new volume, plus the root directory of FAT32 volumes.
+pub fn init(device: &impl SectorDevice, layout: &Layout, media: u8) -> Result<(), FatError> {
+    let table = Table::new(device, layout);
+    let end_of_chain = layout.fat_type.end_of_chain();
+    table.set(0, (end_of_chain & !0xff) | u32::from(media))?;
+    table.set(1, end_of_chain)?;
+    if layout.fat_type == FatType::Fat32 {
+        table.set(layout.root_cluster, end_of_chain)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,14 @@
+//! Kfs - Filesystem drivers shared by the kernel and host tools
+//!
+//! The filesystems only need a [SectorDevice] to live on, so the kernel runs them over its block devices while tests
+//! run them over a [MemoryDevice].
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub use device::{DeviceError, MemoryDevice, SectorDevice, Slice};
+
+pub mod device;
+pub mod ext2;
+pub mod fat;
+pub mod partition;
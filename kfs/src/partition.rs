@@ -0,0 +1,173 @@
+//! Partition tables, both MBR and GPT, to find the filesystems of a disk.
+use alloc::{vec, vec::Vec};
+
+use crate::device::{DeviceError, SectorDevice};
+
+/// The type of MBR partitions holding an EFI system partition.
+const MBR_ESP: u8 = 0xef;
+/// The type of the MBR partition protecting a GPT from tools that don't know it.
+const MBR_PROTECTIVE: u8 = 0xee;
+/// The type GUID of EFI system partitions, as stored on disk.
+const GPT_ESP: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+/// The signature of a GPT header.
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+/// A partition of a disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// The first sector of the partition.
+    pub start: u64,
+    /// The number of sectors of the partition.
+    pub sectors: u64,
+    /// Whether the partition is an EFI system partition.
+    pub is_esp: bool,
+}
+
+/// Returns the partitions of `device`, from its GPT if it has one and its MBR otherwise. Disks without a partition
+/// table have none.
+pub fn partitions(device: &impl SectorDevice) -> Result<Vec<Partition>, DeviceError> {
+    let sector_size = device.sector_size();
+    if sector_size < 512 || device.sector_count() < 2 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = vec![0; sector_size];
+    device.read_sectors(0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xaa] {
+        return Ok(Vec::new());
+    }
+    let entries: Vec<_> = mbr[0x1be..0x1fe].chunks(16).collect();
+    if entries.iter().any(|entry| entry[4] == MBR_PROTECTIVE)
+        && let Some(partitions) = gpt_partitions(device)?
+    {
+        return Ok(partitions);
+    }
+    // A FAT boot sector also ends with the signature, but its partition entries overlap code and aren't plausible.
+    let mut partitions = Vec::new();
+    for entry in entries {
+        let kind = entry[4];
+        let start = u64::from(u32::from_le_bytes(entry[8..12].try_into().unwrap()));
+        let sectors = u64::from(u32::from_le_bytes(entry[12..16].try_into().unwrap()));
+        if kind == 0 || sectors == 0 || entry[0] & 0x7f != 0 {
+            continue;
+        }
+        if start == 0 || start + sectors > device.sector_count() {
+            return Ok(Vec::new());
+        }
+        partitions.push(Partition {
+            start,
+            sectors,
+            is_esp: kind == MBR_ESP,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Returns the partitions of the GPT of `device`, or `None` if its header is invalid.
+fn gpt_partitions(device: &impl SectorDevice) -> Result<Option<Vec<Partition>>, DeviceError> {
+    let sector_size = device.sector_size();
+    let mut header = vec![0; sector_size];
+    device.read_sectors(1, &mut header)?;
+    if !header.starts_with(GPT_SIGNATURE) {
+        return Ok(None);
+    }
+    let read_u64 =
+        |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let entries_start = read_u64(72);
+    let entry_count = read_u32(80) as usize;
+    let entry_size = read_u32(84) as usize;
+    if entry_size < 128 || entry_count > 1024 {
+        return Ok(None);
+    }
+    let len = (entry_count * entry_size).next_multiple_of(sector_size);
+    if entries_start
+        .checked_add((len / sector_size) as u64)
+        .is_none_or(|end| end > device.sector_count())
+    {
+        return Ok(None);
+    }
+    let mut entries = vec![0; len];
+    device.read_sectors(entries_start, &mut entries)?;
+    let mut partitions = Vec::new();
+    for entry in entries.chunks(entry_size).take(entry_count) {
+        let kind = &entry[0..16];
+        if kind.iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        // The last sector is included.
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if first == 0 || last < first || last >= device.sector_count() {
+            continue;
+        }
+        partitions.push(Partition {
+            start: first,
+            sectors: last - first + 1,
+            is_esp: kind == GPT_ESP,
+        });
+    }
+    Ok(Some(partitions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryDevice;
+
+    fn mbr_entry(disk: &mut [u8], index: usize, kind: u8, start: u32, sectors: u32) {
+        let entry = &mut disk[0x1be + index * 16..0x1be + (index + 1) * 16];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    #[test]
+    fn mbr_and_gpt() {
+        let mut disk = vec![0; 512 * 64];
+        disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+        mbr_entry(&mut disk, 0, 0x83, 2, 10);
+        mbr_entry(&mut disk, 2, MBR_ESP, 20, 8);
+        let device = MemoryDevice::from_bytes(512, disk.clone());
+        assert_eq!(
+            partitions(&device).unwrap(),
+            [
+                Partition {
+                    start: 2,
+                    sectors: 10,
+                    is_esp: false
+                },
+                Partition {
+                    start: 20,
+                    sectors: 8,
+                    is_esp: true
+                }
+            ]
+        );
+
+        mbr_entry(&mut disk, 0, MBR_PROTECTIVE, 1, 63);
+        mbr_entry(&mut disk, 2, 0, 0, 0);
+        let header = &mut disk[512..1024];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let esp = &mut disk[1024 + 128..1024 + 256];
+        esp[..16].copy_from_slice(&GPT_ESP);
+        esp[32..40].copy_from_slice(&34u64.to_le_bytes());
+        esp[40..48].copy_from_slice(&63u64.to_le_bytes());
+        let device = MemoryDevice::from_bytes(512, disk);
+        assert_eq!(
+            partitions(&device).unwrap(),
+            [Partition {
+                start: 34,
+                sectors: 30,
+                is_esp: true
+            }]
+        );
+
+        assert!(partitions(&MemoryDevice::new(512, 4)).unwrap().is_empty());
+    }
+}
@@ -4,7 +4,10 @@ use std::{
     sync::OnceLock,
 };
 
-use crate::qemu::debug::{flag_is_trace, flag_is_valid_standard, flag_is_valid_trace};
+use crate::qemu::{
+    DiskInterface, NetworkBackend,
+    debug::{flag_is_trace, flag_is_valid_standard, flag_is_valid_trace},
+};
 
 /// Environment variable to control debugger attachment.
 /// If set to "1", "true", or "yes", QEMU will wait for GDB to attach.
@@ -28,6 +31,16 @@ pub const VERBOSE_ENV_FLAG: &str = "VERBOSE";
 pub const NO_SPAWN_GDB_ENV_FLAG: &str = "NO_SPAWN_GDB";
 /// Environment variable to specify a custom QEMU binary path.
 pub const QEMU_BINARY_ENV_FLAG: &str = "QEMU_PATH";
+/// Environment variable to specify the QEMU machine type, such as `q35` for a PCI Express chipset.
+pub const MACHINE_ENV_FLAG: &str = "QEMU_MACHINE";
+/// Environment variable to specify a raw disk image to attach as a virtio block device.
+pub const DISK_ENV_FLAG: &str = "QEMU_DISK";
+/// Environment variable to specify the controller the disk image of [DISK_ENV_FLAG] is attached to. Either `virtio`,
+/// the default, `ahci` for a SATA drive or `nvme` for the namespace of an NVMe controller.
+pub const DISK_INTERFACE_ENV_FLAG: &str = "QEMU_DISK_IF";
+/// Environment variable to attach a virtio network card. Either `user` for QEMU's user networking, or
+/// `listen:HOST:PORT` or `connect:HOST:PORT` for a socket backend linking two VMs.
+pub const NET_ENV_FLAG: &str = "QEMU_NET";
 /// Environment variable to specify the number of SMP cores.
 pub const SMP_CORES_ENV_FLAG: &str = "SMP_CORES";
 /// Environment variable to specify additional QEMU debug flags. Flags should be comma-separated.
@@ -168,6 +181,54 @@ pub fn qemu_path() -> &'static Path {
     ))
 }
 
+/// Returns the QEMU machine type to emulate, if specified. QEMU defaults to the i440FX based `pc` machine otherwise.
+pub fn machine() -> Option<String> {
+    read_env(MACHINE_ENV_FLAG).filter(|machine| !machine.is_empty())
+}
+
+/// Returns the raw disk image to attach as a virtio block device, if specified.
+pub fn disk_image() -> Option<PathBuf> {
+    let image = PathBuf::from(read_env(DISK_ENV_FLAG).filter(|path| !path.is_empty())?);
+    if !image.exists() {
+        panic!(
+            "Disk image specified in {} does not exist: {}",
+            DISK_ENV_FLAG,
+            image.display()
+        );
+    }
+    Some(image)
+}
+
+/// Returns the controller to attach the disk image to, defaulting to virtio.
+pub fn disk_interface() -> DiskInterface {
+    let interface = read_env(DISK_INTERFACE_ENV_FLAG).unwrap_or_default();
+    match interface.as_str() {
+        "" | "virtio" => DiskInterface::VirtioBlk,
+        "ahci" => DiskInterface::Ahci,
+        "nvme" => DiskInterface::Nvme,
+        _ => panic!(
+            "Invalid value for {}: {}. Must be virtio, ahci or nvme.",
+            DISK_INTERFACE_ENV_FLAG, interface
+        ),
+    }
+}
+
+/// Returns the backend of the network card to attach, if specified.
+pub fn network() -> Option<NetworkBackend> {
+    let network = read_env(NET_ENV_FLAG).filter(|network| !network.is_empty())?;
+    if network == "user" {
+        return Some(NetworkBackend::User);
+    }
+    match network.split_once(':') {
+        Some(("listen", addr)) => Some(NetworkBackend::SocketListen(addr.to_string())),
+        Some(("connect", addr)) => Some(NetworkBackend::SocketConnect(addr.to_string())),
+        _ => panic!(
+            "Invalid value for {}: {}. Must be user, listen:HOST:PORT or connect:HOST:PORT.",
+            NET_ENV_FLAG, network
+        ),
+    }
+}
+
 /// Returns the number of SMP cores to use, if specified.
 pub fn smp_cores() -> Option<usize> {
     let cores_str = read_env(SMP_CORES_ENV_FLAG)?;
@@ -4,6 +4,7 @@ mod gdb;
 mod qemu;
 
 pub use qemu::{
-    QemuConfig,
+    Disk, DiskInterface, NetworkBackend, QemuConfig,
     chardev::{CharDev, CharDevRef},
+    uefi_images,
 };
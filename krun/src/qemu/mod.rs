@@ -38,6 +38,8 @@ pub struct QemuConfig {
     pub display: bool,
     /// Amount of memory to allocate to the VM.
     pub memory: String,
+    /// The machine type to emulate, such as `q35`. Uses QEMU's default if None.
+    pub machine: Option<String>,
     /// Number of CPU cores to allocate to the VM. Defaults to single core if None.
     pub core_count: Option<usize>,
     debug_flags: Option<Vec<String>>,
@@ -51,10 +53,114 @@ pub struct QemuConfig {
     pub dev_exit: bool,
     /// Path to the UEFI code and variables images
     pub uefi_img: Option<(PathBuf, PathBuf)>,
+    /// Disk images to attach.
+    pub disks: Vec<Disk>,
+    /// The backend of the virtio network card to attach, if any.
+    pub network: Option<NetworkBackend>,
     /// Extra arguments to pass to QEMU.
     pub extra_args: Vec<String>,
 }
 
+/// A raw disk image attached to the VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disk {
+    /// Path to the image.
+    pub path: PathBuf,
+    /// The controller the disk is attached to.
+    pub interface: DiskInterface,
+    /// Whether the guest may only read the disk.
+    pub read_only: bool,
+}
+
+/// The controller a [Disk] is attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskInterface {
+    /// A `virtio-blk-pci` device.
+    VirtioBlk,
+    /// A SATA drive on an AHCI controller of its own.
+    Ahci,
+    /// The only namespace of an NVMe controller of its own.
+    Nvme,
+}
+
+impl Disk {
+    /// Creates a writable disk attached as a virtio block device.
+    pub fn virtio(path: impl Into<PathBuf>) -> Self {
+        Disk {
+            path: path.into(),
+            interface: DiskInterface::VirtioBlk,
+            read_only: false,
+        }
+    }
+
+    /// Creates a writable disk attached as a SATA drive to an AHCI controller.
+    pub fn ahci(path: impl Into<PathBuf>) -> Self {
+        Disk {
+            interface: DiskInterface::Ahci,
+            ..Disk::virtio(path)
+        }
+    }
+
+    /// Creates a writable disk attached as the namespace of an NVMe controller.
+    pub fn nvme(path: impl Into<PathBuf>) -> Self {
+        Disk {
+            interface: DiskInterface::Nvme,
+            ..Disk::virtio(path)
+        }
+    }
+
+    fn add_args(&self, id: &str, args: &mut Vec<String>) {
+        let mut drive = format!("file={},format=raw,if=none,id={}", self.path.display(), id);
+        if self.read_only {
+            drive.push_str(",readonly=on");
+        }
+        args.push("-drive".to_string());
+        args.push(drive);
+        match self.interface {
+            DiskInterface::VirtioBlk => {
+                args.push("-device".to_string());
+                args.push(format!("virtio-blk-pci,drive={}", id));
+            }
+            DiskInterface::Ahci => {
+                // The `pc` machine has no AHCI controller, and the one of `q35` is shared with the CD-ROM.
+                args.push("-device".to_string());
+                args.push(format!("ahci,id={}-ahci", id));
+                args.push("-device".to_string());
+                args.push(format!("ide-hd,drive={},bus={}-ahci.0", id, id));
+            }
+            DiskInterface::Nvme => {
+                // QEMU refuses NVMe controllers without a serial number.
+                args.push("-device".to_string());
+                args.push(format!("nvme,drive={},serial={}", id, id));
+            }
+        }
+    }
+}
+
+/// Where the packets of the network card go, see [QemuConfig::network].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkBackend {
+    /// QEMU's user networking, which needs no privileges. The guest is at 10.0.2.15 behind a gateway at 10.0.2.2.
+    User,
+    /// A socket listening on the given `host:port` for another VM to connect to.
+    SocketListen(String),
+    /// A socket connecting to another VM listening on the given `host:port`.
+    SocketConnect(String),
+}
+
+impl NetworkBackend {
+    fn add_args(&self, id: &str, args: &mut Vec<String>) {
+        args.push("-netdev".to_string());
+        args.push(match self {
+            NetworkBackend::User => format!("user,id={}", id),
+            NetworkBackend::SocketListen(addr) => format!("socket,id={},listen={}", id, addr),
+            NetworkBackend::SocketConnect(addr) => format!("socket,id={},connect={}", id, addr),
+        });
+        args.push("-device".to_string());
+        args.push(format!("virtio-net-pci,netdev={}", id));
+    }
+}
+
 lazy_static! {
     /// Directory to store UEFI images.
     pub static ref UEFI_IMAGE_CACHE_DIR: &'static Path = Path::new("target/uefi");
@@ -66,18 +172,32 @@ lazy_static! {
 impl QemuConfig {
     /// Run QEMU with the current configuration.
     pub fn run(&mut self) {
-        let mut args = vec!["-cdrom".to_string(), self.iso.display().to_string()];
+        let mut args = Vec::new();
+        self.boot_image(&mut args);
         self.text_devices(&mut args);
         self.add_debug_flags(&mut args);
         self.add_memory(&mut args);
         self.uefi(&mut args);
 
+        for (i, disk) in self.disks.iter().enumerate() {
+            disk.add_args(&format!("disk{}", i), &mut args);
+        }
+
+        if let Some(network) = &self.network {
+            network.add_args("net0", &mut args);
+        }
+
         if let Some(flags) = &self.debug_flags {
             if let Some(flags) = debug::qemu_args_from_flags(flags) {
                 args.extend(flags);
             }
         }
 
+        if let Some(machine) = &self.machine {
+            args.push("-machine".to_string());
+            args.push(machine.clone());
+        }
+
         if let Some(cores) = self.core_count {
             args.push("-smp".to_string());
             args.push(cores.to_string());
@@ -156,7 +276,10 @@ impl QemuConfig {
             character_devices: Vec::new(),
             dev_exit: false,
             extra_args: Vec::new(),
+            disks: Vec::new(),
+            network: None,
             uefi_img: None,
+            machine: None,
             core_count: None,
         }
     }
@@ -223,6 +346,23 @@ impl QemuConfig {
         }
     }
 
+    fn boot_image(&self, args: &mut Vec<String>) {
+        if self.uefi_img.is_none() {
+            args.push("-cdrom".to_string());
+            args.push(self.iso.display().to_string());
+            return;
+        }
+        // The ISO is hybrid, so UEFI boots it as a disk just as well. The kernel has no CD-ROM driver, and needs a
+        // disk to find the EFI system partition on.
+        args.push("-drive".to_string());
+        args.push(format!(
+            "file={},format=raw,if=none,id=boot,readonly=on",
+            self.iso.display()
+        ));
+        args.push("-device".to_string());
+        args.push("virtio-blk-pci,drive=boot,bootindex=0".to_string());
+    }
+
     fn uefi(&mut self, args: &mut Vec<String>) {
         if let Some(uefi_img) = &self.uefi_img {
             args.push("-drive".to_string());
@@ -247,7 +387,7 @@ impl Default for QemuConfig {
         cfg.debugger = DebuggerStatus::from_env();
 
         if env::uefi_enabled() {
-            cfg.uefi_img = Some(get_uefi_images());
+            cfg.uefi_img = Some(uefi_images());
         }
 
         if !env::display_enabled() {
@@ -258,14 +398,25 @@ impl Default for QemuConfig {
         cfg.memory = env::memory_config();
         cfg.dev_exit = env::dev_exit_enabled();
         cfg.extra_args = env::extra_arguments();
+        cfg.machine = env::machine();
+        cfg.disks = env::disk_image()
+            .map(|path| Disk {
+                interface: env::disk_interface(),
+                ..Disk::virtio(path)
+            })
+            .into_iter()
+            .collect();
+        cfg.network = env::network();
         cfg.core_count = env::smp_cores();
         cfg.debug_flags = env::qemu_debug_flags();
         cfg
     }
 }
 
-fn get_uefi_images() -> (PathBuf, PathBuf) {
-    let pre = Prebuilt::fetch(Source::LATEST, "target/uefi").unwrap();
+/// Returns the paths to the UEFI code and variables images, downloading them to [UEFI_IMAGE_CACHE_DIR] first if
+/// needed.
+pub fn uefi_images() -> (PathBuf, PathBuf) {
+    let pre = Prebuilt::fetch(Source::LATEST, *UEFI_IMAGE_CACHE_DIR).unwrap();
     (
         pre.get_file(Arch::X64, FileType::Code),
         pre.get_file(Arch::X64, FileType::Vars),
@@ -59,6 +59,8 @@ pub(crate) use arch_impl::PTE_FREE_BIT0;
 
 pub(crate) use arch_impl::pml4_phys;
 
+pub(crate) use arch_impl::space;
+
 pub(crate) use arch_impl::RecursivePageTable;
 
 pub(crate) use arch_impl::RECURSIVE_SLOT0;
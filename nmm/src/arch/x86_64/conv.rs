@@ -169,7 +169,9 @@ into_arch_frame!(
 impl From<MapFlags> for PageTableFlags {
     fn from(value: MapFlags) -> Self {
         let mut flags = Self::PRESENT;
-        if value.contains(MapFlags::WRITABLE) {
+        if value.contains(MapFlags::COPY_ON_WRITE) {
+            flags |= Self::COPY_ON_WRITE;
+        } else if value.contains(MapFlags::WRITABLE) {
             flags |= Self::WRITABLE;
         }
         if value.contains(MapFlags::USER_ACCESSIBLE) {
@@ -206,6 +208,9 @@ impl From<PageTableFlags> for MapFlags {
         if value.contains(PageTableFlags::NO_CACHE) {
             flags |= MapFlags::CACHE_DISABLE;
         }
+        if value.contains(PageTableFlags::COPY_ON_WRITE) {
+            flags |= MapFlags::WRITABLE | MapFlags::COPY_ON_WRITE;
+        }
         flags
     }
 }
@@ -6,7 +6,7 @@ use crate::{
     },
     paging::{
         EntryMappingFlags, FragmentManager, FragmentSize, Frame, Page, PageTable, PageTableIndex,
-        Small,
+        PhysAddr, Small,
         map::{Flush, SizedMemoryMapper, Unmapped},
     },
 };
@@ -41,12 +41,27 @@ impl Mapper {
         Self::Recursive(unsafe { RecursivePageTable::new(root, recursive_index) })
     }
 
+    /// Returns `true` if the mapper can only reach its page tables while its root table is loaded.
+    pub fn requires_active(&self) -> bool {
+        matches!(self, Mapper::Recursive(_))
+    }
+
     pub fn root_table(&self) -> &PageTable {
         match self {
             Mapper::Offset(mapper) => mapper.p4(),
             Mapper::Recursive(mapper) => mapper.p4(),
         }
     }
+
+    /// Translates a virtual address to the physical address it is mapped to, along with the flags of the mapping.
+    /// Returns `None` if the address is not mapped.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, MapFlags)> {
+        let (phys, flags) = match self {
+            Mapper::Offset(mapper) => mapper.translate(addr),
+            Mapper::Recursive(mapper) => mapper.translate(addr),
+        }?;
+        Some((phys, flags.into()))
+    }
 }
 
 impl<S> SizedMemoryMapper<S> for Mapper
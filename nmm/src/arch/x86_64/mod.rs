@@ -5,6 +5,7 @@ mod conv;
 mod mapper;
 mod offset;
 mod recursive;
+pub(crate) mod space;
 
 use cfg_if::cfg_if;
 pub use mapper::Mapper;
@@ -72,6 +73,9 @@ bitflags! {
         const HUGE_PAGE       = 1 << 7;
         /// The page is global and not flushed from TLB on CR3 reload.
         const GLOBAL          = 1 << 8;
+        /// Available to software: the page is logically writable, but its frame is shared and gets copied on the first
+        /// write. Never set together with `WRITABLE`.
+        const COPY_ON_WRITE   = PTE_FREE_BIT1;
         /// No-execute flag; if set, code execution is not allowed from this page.
         const NO_EXECUTE      = 1 << 63;
     }
@@ -140,6 +144,8 @@ pub(crate) fn pml4_phys() -> Frame<Small> {
 
 /// The first free available-to-software bit in a page table entry.
 pub const PTE_FREE_BIT0: u64 = 1 << 9;
+/// The second free available-to-software bit in a page table entry.
+pub const PTE_FREE_BIT1: u64 = 1 << 10;
 
 /// The first slot in the pml4 table that is reserved for recursive mapping, specifically reserved for mapping of the current address space.
 pub const RECURSIVE_SLOT0: PageTableIndex = PageTableIndex::new(510);
@@ -246,4 +252,17 @@ mod tests {
             NO_EXECUTE
         ));
     }
+
+    #[test]
+    fn copy_on_write_maps_read_only() {
+        use super::PageTableFlags;
+        use crate::MapFlags;
+
+        let flags: PageTableFlags = (MapFlags::WRITABLE | MapFlags::COPY_ON_WRITE).into();
+        assert!(flags.contains(PageTableFlags::COPY_ON_WRITE));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+        let flags: MapFlags = flags.into();
+        assert!(flags.contains(MapFlags::WRITABLE | MapFlags::COPY_ON_WRITE));
+    }
 }
@@ -6,7 +6,8 @@ use crate::{
     MapFlags, MemError,
     arch::x86_64::{PageTableFlags, XFrameAllocator, impl_memory_mapper_for},
     paging::{
-        EntryMappingFlags, FragmentManager, Frame, Large, Medium, Page, PageTable, Small, VirtAddr,
+        EntryMappingFlags, FragmentManager, Frame, Large, Medium, Page, PageTable, PhysAddr, Small,
+        VirtAddr,
         map::{Flush, SizedMemoryMapper, Unmapped},
     },
 };
@@ -48,6 +49,17 @@ impl<'a> OffsetPageTable<'a> {
     pub fn p4_mut(&mut self) -> &mut PageTable {
         PageTable::from_arch_mut(self.inner.level_4_table_mut())
     }
+
+    /// Translates a virtual address to the physical address and flags it is mapped with.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.inner.translate(addr.into()) {
+            arch_lib::TranslateResult::Mapped { frame, offset, flags } => Some((
+                PhysAddr::from(frame.start_address()) + offset,
+                PageTableFlags::from_bits_retain(flags.bits()),
+            )),
+            _ => None,
+        }
+    }
 }
 
 impl Debug for OffsetPageTable<'_> {
@@ -12,7 +12,7 @@ use crate::{
     arch::x86_64::{PageTableFlags, XFrameAllocator, impl_memory_mapper_for},
     paging::{
         EntryMappingFlags, FragmentManager, Frame, Large, Medium, Page, PageTable, PageTableIndex,
-        Small,
+        PhysAddr, Small, VirtAddr,
         map::{Flush, SizedMemoryMapper, Unmapped},
     },
 };
@@ -164,6 +164,17 @@ impl<'a> RecursivePageTable<'a> {
         let p4 = self.inner.level_4_table_mut();
         PageTable::from_arch_mut(p4)
     }
+
+    /// Translates a virtual address to the physical address and flags it is mapped with.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.inner.translate(addr.into()) {
+            arch_lib::TranslateResult::Mapped { frame, offset, flags } => Some((
+                PhysAddr::from(frame.start_address()) + offset,
+                PageTableFlags::from_bits_retain(flags.bits()),
+            )),
+            _ => None,
+        }
+    }
 }
 
 impl Debug for RecursivePageTable<'_> {
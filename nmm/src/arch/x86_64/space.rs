@@ -0,0 +1,295 @@
+//! Support for per-process address spaces on x86_64: sharing the kernel half, PCIDs, loading CR3, and freeing the page
+//! tables of the user half.
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use cake::Once;
+use x86_64::{
+    instructions::tlb::Pcid,
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::PhysFrame,
+};
+
+use crate::{
+    MemError,
+    arch::{ENTRY_COUNT, RECURSIVE_SLOT0, RECURSIVE_SLOT1, pml4_phys},
+    bitmap::PhysicalMemoryManager,
+    paging::{
+        Address, AddressExt, EntryMappingFlags, FragmentManager, FragmentSize, Frame, Large,
+        Medium, MemoryFragment, PageTable, PageTableEntry, Small, asm,
+    },
+};
+
+use super::PageTableFlags;
+
+/// The PML4 entries that map the user half of an address space.
+pub(crate) const USER_HALF: Range<usize> = 0..ENTRY_COUNT / 2;
+/// The PML4 entries that map the kernel half of an address space.
+pub(crate) const KERNEL_HALF: Range<usize> = ENTRY_COUNT / 2..ENTRY_COUNT;
+
+/// One bit per PCID. PCID 0 is never handed out, it tags the kernel address space.
+static PCIDS: [AtomicU64; 4096 / 64] = [const { AtomicU64::new(0) }; 4096 / 64];
+static PCID_SUPPORTED: Once<bool> = Once::new();
+
+fn pcid_supported() -> bool {
+    *PCID_SUPPORTED.call_once(|| core::arch::x86_64::__cpuid(1).ecx & (1 << 17) != 0)
+}
+
+/// Allocates a PCID for a new address space, or returns `None` if PCIDs are unsupported or all of them are in use.
+pub(crate) fn allocate_pcid() -> Option<u16> {
+    if !pcid_supported() {
+        return None;
+    }
+
+    for (i, word) in PCIDS.iter().enumerate() {
+        let mut current = word.load(Ordering::Relaxed);
+        loop {
+            let taken = if i == 0 { current | 1 } else { current };
+            if taken == u64::MAX {
+                break;
+            }
+            let bit = (!taken).trailing_zeros();
+            match word.compare_exchange_weak(
+                current,
+                current | (1 << bit),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(i as u16 * 64 + bit as u16),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+    None
+}
+
+/// Returns a PCID allocated by [allocate_pcid].
+pub(crate) fn free_pcid(pcid: u16) {
+    PCIDS[pcid as usize / 64].fetch_and(!(1 << (pcid % 64)), Ordering::AcqRel);
+}
+
+/// Loads `root` into CR3, tagged with `pcid` if there is one.
+///
+/// The load always drops the TLB entries tagged with `pcid`, so entries left behind by changes made while the space
+/// wasn't loaded on this core are never used. Entries of other PCIDs survive the switch.
+///
+/// # Safety
+/// `root` must be a valid PML4 that maps the kernel half, and must stay valid for as long as it is loaded.
+pub(crate) unsafe fn load_root(root: Frame<Small>, pcid: Option<u16>) {
+    if pml4_phys() == root {
+        return;
+    }
+
+    let frame = PhysFrame::containing_address(x86_64::PhysAddr::new(root.start_address().as_u64()));
+    let pcid_enabled = Cr4::read().contains(Cr4Flags::PCID);
+    if !pcid_enabled && pcid_supported() && Cr3::read_raw().1 == 0 {
+        // SAFETY: CR4.PCIDE may only be set while the current PCID is 0, which was checked above.
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+    }
+
+    match pcid {
+        Some(pcid) if pcid_supported() => {
+            // SAFETY: The caller guarantees that `root` is a valid PML4 that maps the kernel.
+            unsafe { Cr3::write_pcid(frame, Pcid::new(pcid).expect("PCID out of range")) };
+        }
+        // SAFETY: The caller guarantees that `root` is a valid PML4 that maps the kernel.
+        _ => unsafe { Cr3::write(frame, Cr3Flags::empty()) },
+    }
+}
+
+/// Makes sure every entry of the kernel half of `kernel` points to a page table, so that copying the kernel half into
+/// another PML4 keeps sharing every kernel mapping made afterwards.
+///
+/// The recursive slots are left alone. This costs one frame for each entry that was empty, and only ever has to run
+/// once.
+///
+/// # Safety
+/// `kernel` must be the PML4 of the kernel address space, and the caller must hold its mapper lock.
+pub(crate) unsafe fn populate_kernel_half(
+    kernel: &mut PageTable,
+    pmm: &mut PhysicalMemoryManager,
+) -> Result<(), MemError> {
+    // SAFETY: New entries point to zeroed tables, which map nothing.
+    let entries = unsafe { kernel.entries_mut() };
+    for index in KERNEL_HALF {
+        if index == RECURSIVE_SLOT0.value() as usize || index == RECURSIVE_SLOT1.value() as usize {
+            continue;
+        }
+        if entries[index]
+            .arch_flags()
+            .contains(PageTableFlags::PRESENT)
+        {
+            continue;
+        }
+        let frame: Frame<Small> = pmm.allocate_fragment()?;
+        asm::zero_frame(frame);
+        entries[index] =
+            PageTableEntry::new(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    Ok(())
+}
+
+/// Frees every page table of the user half of `root`, along with every anonymous frame mapped by it.
+///
+/// # Safety
+/// `root` must not be loaded on any core, and nothing may access the memory it maps anymore.
+pub(crate) unsafe fn free_user_half(root: &mut PageTable, pmm: &mut PhysicalMemoryManager) {
+    // SAFETY: The user half is cleared entry by entry as its tables are freed.
+    let entries = unsafe { root.entries_mut() };
+    for entry in &mut entries[USER_HALF] {
+        if entry.arch_flags().contains(PageTableFlags::PRESENT) {
+            // SAFETY: The entry is present and not huge, so it points to a page directory pointer table.
+            unsafe { free_table(*entry, 3, pmm) };
+        }
+        *entry = PageTableEntry::empty();
+    }
+}
+
+/// Copies the user half of `root` into `clone`, whose user half must be empty, sharing every anonymous small frame
+/// copy-on-write between both.
+///
+/// Each table of `clone` is linked in before it is filled, so if this fails, freeing the user half of `clone` releases
+/// everything that was shared or copied so far.
+///
+/// # Safety
+/// The caller must hold the mapper lock of `root`, `clone` must not be loaded on any core, and the TLB entries of the
+/// user half of `root` must be flushed afterwards.
+pub(crate) unsafe fn clone_user_half(
+    root: &mut PageTable,
+    clone: &mut PageTable,
+    pmm: &mut PhysicalMemoryManager,
+) -> Result<(), MemError> {
+    // SAFETY: Entries only lose write access, and the caller flushes them.
+    let entries = unsafe { root.entries_mut() };
+    // SAFETY: Every entry is a copy of a valid entry of `root`, or points to a copy of one of its tables.
+    let clone_entries = unsafe { clone.entries_mut() };
+    for index in USER_HALF {
+        if entries[index]
+            .arch_flags()
+            .contains(PageTableFlags::PRESENT)
+        {
+            // SAFETY: The entry is present and not huge, so it points to a page directory pointer table.
+            unsafe { clone_table(&mut entries[index], &mut clone_entries[index], 3, pmm)? };
+        }
+    }
+    Ok(())
+}
+
+/// Copies the table `entry` points to, which is at `level` (3 for a PDPT, 1 for a PT), and everything below it into a
+/// new table, which `clone` is made to point to.
+unsafe fn clone_table(
+    entry: &mut PageTableEntry,
+    clone: &mut PageTableEntry,
+    level: u8,
+    pmm: &mut PhysicalMemoryManager,
+) -> Result<(), MemError> {
+    // SAFETY: The caller guarantees that the entry points to a page table, which is reachable through the direct map.
+    let table =
+        unsafe { &mut *asm::direct_map(entry_frame::<Small>(*entry)).as_mut_ptr::<PageTable>() };
+    let clone_frame: Frame<Small> = pmm.allocate_fragment()?;
+    asm::zero_frame(clone_frame);
+    *clone = PageTableEntry::new(clone_frame, entry.arch_flags());
+    // SAFETY: The table was just allocated, and is only reachable through `clone`.
+    let copy = unsafe { &mut *asm::direct_map(clone_frame).as_mut_ptr::<PageTable>() };
+
+    // SAFETY: Entries only lose write access, and the caller flushes them.
+    let entries = unsafe { table.entries_mut() };
+    // SAFETY: Every entry is shared with or copied from `table`.
+    let clone_entries = unsafe { copy.entries_mut() };
+    for (entry, clone) in entries.iter_mut().zip(clone_entries.iter_mut()) {
+        let flags = entry.arch_flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let anon = entry.mapping_flags().contains(EntryMappingFlags::MAP_ANON);
+        match level {
+            1 if anon => {
+                pmm.share(entry_frame::<Small>(*entry))?;
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    entry.set_arch_flags(
+                        (flags - PageTableFlags::WRITABLE) | PageTableFlags::COPY_ON_WRITE,
+                    );
+                }
+                *clone = *entry;
+            }
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => {
+                *clone = if anon {
+                    copy_huge::<Medium>(*entry, pmm)?
+                } else {
+                    *entry
+                };
+            }
+            3 if flags.contains(PageTableFlags::HUGE_PAGE) => {
+                *clone = if anon {
+                    copy_huge::<Large>(*entry, pmm)?
+                } else {
+                    *entry
+                };
+            }
+            // SAFETY: A present, non-huge entry above level 1 points to the next level table.
+            2 | 3 => unsafe { clone_table(entry, clone, level - 1, pmm)? },
+            _ => *clone = *entry,
+        }
+    }
+    Ok(())
+}
+
+/// Returns an entry like `entry` that maps a copy of the huge frame `entry` maps.
+fn copy_huge<S: FragmentSize>(
+    entry: PageTableEntry,
+    pmm: &mut PhysicalMemoryManager,
+) -> Result<PageTableEntry, MemError> {
+    let frame: Frame<S> = pmm.allocate_fragment()?;
+    asm::copy_frame(entry_frame::<S>(entry), frame);
+    Ok(entry.with_frame(frame))
+}
+
+/// Flushes the TLB entries of the active address space, except for global ones.
+///
+/// # Safety
+/// The active root table must stay valid.
+pub(crate) unsafe fn flush_user_half() {
+    x86_64::instructions::tlb::flush_all();
+}
+
+/// Frees the table `entry` points to, which is at `level` (3 for a PDPT, 1 for a PT), and everything below it.
+unsafe fn free_table(entry: PageTableEntry, level: u8, pmm: &mut PhysicalMemoryManager) {
+    let table_frame: Frame<Small> = entry_frame(entry);
+    // SAFETY: The caller guarantees that the entry points to a page table, which is reachable through the direct map.
+    let table = unsafe { &*asm::direct_map(table_frame).as_ptr::<PageTable>() };
+
+    for entry in table.entries() {
+        let flags = entry.arch_flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let anon = entry.mapping_flags().contains(EntryMappingFlags::MAP_ANON);
+        match level {
+            1 if anon => pmm.deallocate_fragment(entry_frame::<Small>(*entry)),
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => {
+                if anon {
+                    pmm.deallocate_fragment(entry_frame::<Medium>(*entry));
+                }
+            }
+            3 if flags.contains(PageTableFlags::HUGE_PAGE) => {
+                if anon {
+                    pmm.deallocate_fragment(entry_frame::<Large>(*entry));
+                }
+            }
+            // SAFETY: A present, non-huge entry above level 1 points to the next level table.
+            2 | 3 => unsafe { free_table(*entry, level - 1, pmm) },
+            _ => {}
+        }
+    }
+
+    pmm.deallocate_fragment(table_frame);
+}
+
+fn entry_frame<S: FragmentSize>(entry: PageTableEntry) -> Frame<S> {
+    // Entries pointing at huge frames keep the PAT bit at bit 12, which is below their alignment.
+    Frame::containing_address(entry.addr())
+}
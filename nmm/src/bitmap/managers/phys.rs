@@ -126,9 +126,29 @@ impl PhysicalMemoryManager {
             )
         };
 
+        let shares_layout = Layout::array::<u16>(bits as usize).unwrap();
+        let shares_start = vmm.allocate(shares_layout).ok_or(MemError::OutOfMemory)?;
+        // SAFETY: The virtual range was just allocated, so nothing else is mapped there.
+        unsafe {
+            map_from(
+                shares_start,
+                shares_layout.size() as u64,
+                MapFlags::WRITABLE,
+                Default::default(),
+                walker,
+            )?
+        };
+
+        // SAFETY: The range was mapped writable above and holds `bits` counters.
+        let shares = unsafe {
+            core::slice::from_raw_parts_mut(shares_start.as_mut_ptr::<u16>(), bits as usize)
+        };
+        shares.fill(0);
+
         Ok(BitmapEntry {
             start: range.start(),
             bitmap: Bitmap::init(bitmap_slice, (bits % 64) as u8),
+            shares,
             bit_alignment: align_in_bits(alignment_of(range.start())),
             free: range.size() / Small::SIZE,
         })
@@ -165,6 +185,58 @@ impl PhysicalMemoryManager {
             .find(|b| range.start() >= b.start && range.end() <= b.end())
     }
 
+    /// Adds a reference to an allocated frame, so that it is only freed once [deallocate_fragment] was called for it
+    /// once more than [share] was. Used to share a frame between several mappings, e.g. for copy-on-write.
+    ///
+    /// [deallocate_fragment]: FragmentManager::deallocate_fragment
+    /// [share]: Self::share
+    pub fn share<S: FragmentSize>(&mut self, frame: Frame<S>) -> Result<(), MemError> {
+        let shares = self.shares_of(frame);
+        *shares = shares
+            .checked_add(1)
+            .ok_or(MemError::TooManyReferences(frame.start_address()))?;
+        Ok(())
+    }
+
+    /// Returns the number of references to an allocated frame: one, plus one for every outstanding [share](Self::share).
+    pub fn references<S: FragmentSize>(&mut self, frame: Frame<S>) -> u64 {
+        *self.shares_of(frame) as u64 + 1
+    }
+
+    // Returns the number of extra references to the given frame, which are tracked per small frame. Frames larger than
+    // a small frame use the counter of their first small frame.
+    fn shares_of<S: FragmentSize>(&mut self, frame: Frame<S>) -> &mut u16 {
+        let bitmap = self
+            .bitmap_containing(frame)
+            .expect("frame is not within any managed physical memory range");
+        let bitptr = address_as_bit_index(frame.start_address(), bitmap.start)
+            .expect("frame must be within the managed physical address space and properly aligned");
+        debug_assert!(bitmap.bitmap.all_are_set(bitptr, S::BITS));
+        &mut bitmap.shares[bitptr.bit_index() as usize]
+    }
+
+    /// Allocates `n_frames` physically contiguous small frames and returns the address of the first one. Used for memory
+    /// that devices access directly, which can't be scattered across frames.
+    pub fn allocate_contiguous(&mut self, n_frames: u64) -> Result<PhysAddr, MemError> {
+        for bitmap in self.bitmaps.iter_mut().filter(|b| b.free >= n_frames) {
+            if let Some(bitptr) = bitmap.bitmap.allocate(n_frames, Alignment::MIN) {
+                bitmap.free -= n_frames;
+                return Ok(bit_index_as_address(bitptr.bit_index(), bitmap.start));
+            }
+        }
+        Err(MemError::OutOfMemory)
+    }
+
+    /// Frees `n_frames` contiguous small frames starting at `start`, previously allocated with
+    /// [allocate_contiguous](Self::allocate_contiguous).
+    ///
+    /// # Safety
+    /// The frames must have been allocated together and must not be used anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysAddr, n_frames: u64) {
+        // SAFETY: Guaranteed by the caller.
+        unsafe { self.mark_unallocated(MemoryRange::new_len(start, n_frames * Small::SIZE)) };
+    }
+
     unsafe fn mark_allocated(&mut self, range: MemoryRange<PhysAddr>) {
         if let Some(bmp) = self.bitmap_for_range(range) {
             info!("using bitmap for range: {:?} {:?}", bmp, range);
@@ -219,6 +291,12 @@ where
             let bitptr = address_as_bit_index(primitive.start_address(), bitmap.start)
                 .expect("deallocated address must be within the managed physical address space and properly aligned");
             debug_assert!(bitmap.bitmap.all_are_set(bitptr, S::BITS));
+            let shares = &mut bitmap.shares[bitptr.bit_index() as usize];
+            if *shares > 0 {
+                // the frame is still referenced elsewhere, so only drop this reference
+                *shares -= 1;
+                return;
+            }
             bitmap.bitmap.clear(bitptr, S::BITS);
             bitmap.free += S::BITS;
         } else {
@@ -232,6 +310,8 @@ impl FullManager<FrameClass> for PhysicalMemoryManager {}
 struct BitmapEntry {
     // the bitmap that tracks the allocation of frames in this range
     bitmap: Bitmap<'static>,
+    /// the number of extra references to each frame in this range, see [PhysicalMemoryManager::share].
+    shares: &'static mut [u16],
     // the start of this entry
     start: PhysAddr,
     /// the max alignment that this bitmap can guarantee for it's allocations.
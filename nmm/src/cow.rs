@@ -0,0 +1,99 @@
+//! Copy-on-write mappings.
+//!
+//! A page mapped with [MapFlags::COPY_ON_WRITE] is writable, but mapped read-only while its frame may be shared with
+//! other mappings, e.g. the same page in a cloned [AddressSpace](crate::AddressSpace). The first write faults, and
+//! [resolve_fault](crate::resolve_fault) (or [AddressSpace::resolve_fault](crate::AddressSpace::resolve_fault)) maps a
+//! private copy of the frame in its place. If the faulting mapping holds the last reference to an anonymous frame, the
+//! page is made writable in place instead.
+use crate::{
+    FaultReason, MapFlags, MemError,
+    arch::Mapper,
+    bitmap::PhysicalMemoryManager,
+    paging::{
+        EntryMappingFlags, FragmentManager, Frame, MemoryFragment, Page, Small, VirtAddr, asm,
+        map::SizedMemoryMapper,
+    },
+};
+
+/// Resolves a fault at `addr` if the page containing it is mapped, by copying it if the fault was the first write to a
+/// copy-on-write page. Returns `None` if the page isn't mapped.
+///
+/// A fault on a page whose mapping allows `access` already succeeds as well, since that happens when another core
+/// resolved the same fault first.
+pub(crate) fn resolve_mapped(
+    mapper: &mut Mapper,
+    pmm: &mut PhysicalMemoryManager,
+    addr: VirtAddr,
+    access: MapFlags,
+) -> Option<Result<(), MemError>> {
+    let (_, flags) = mapper.translate(addr)?;
+    Some(if !flags.contains(access) {
+        Err(MemError::UnresolvedFault {
+            addr,
+            reason: FaultReason::AccessDenied { allowed: flags },
+        })
+    } else if access.contains(MapFlags::WRITABLE) && flags.contains(MapFlags::COPY_ON_WRITE) {
+        copy_page(mapper, pmm, Page::containing_address(addr), flags)
+    } else {
+        Ok(())
+    })
+}
+
+/// Replaces the copy-on-write mapping of `page`, which is mapped with `flags`, by a writable mapping of a private
+/// frame.
+fn copy_page(
+    mapper: &mut Mapper,
+    pmm: &mut PhysicalMemoryManager,
+    page: Page<Small>,
+    flags: MapFlags,
+) -> Result<(), MemError> {
+    let writable = flags - MapFlags::COPY_ON_WRITE;
+    // SAFETY: The page is mapped again right below, with the same contents.
+    let old = unsafe { mapper.unmap_primitive(page)? };
+    let anon = old.mapping_flags.contains(EntryMappingFlags::MAP_ANON);
+
+    if anon && pmm.references(old.frame) == 1 {
+        // Every other mapping of the frame is gone already, so this one can keep it.
+        return remap(mapper, pmm, page, old.frame, writable, old.mapping_flags);
+    }
+
+    let frame: Frame<Small> = match pmm.allocate_fragment() {
+        Ok(frame) => frame,
+        Err(e) => {
+            remap(mapper, pmm, page, old.frame, flags, old.mapping_flags)?;
+            return Err(e);
+        }
+    };
+    asm::copy_frame(old.frame, frame);
+    if let Err(e) = remap(
+        mapper,
+        pmm,
+        page,
+        frame,
+        writable,
+        EntryMappingFlags::MAP_ANON,
+    ) {
+        pmm.deallocate_fragment(frame);
+        remap(mapper, pmm, page, old.frame, flags, old.mapping_flags)?;
+        return Err(e);
+    }
+    if anon {
+        // Drops the reference this mapping held.
+        pmm.deallocate_fragment(old.frame);
+    }
+    Ok(())
+}
+
+fn remap(
+    mapper: &mut Mapper,
+    pmm: &mut PhysicalMemoryManager,
+    page: Page<Small>,
+    frame: Frame<Small>,
+    flags: MapFlags,
+    mapping_flags: EntryMappingFlags,
+) -> Result<(), MemError> {
+    mapper
+        .map_primitive(page, frame, flags, mapping_flags, pmm)?
+        .flush();
+    Ok(())
+}
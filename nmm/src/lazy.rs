@@ -0,0 +1,317 @@
+//! Lazily backed mappings.
+//!
+//! Mapping a range with [MapSource::Lazy](crate::MapSource::Lazy) only records it as a [LazyRegion]. The first access to
+//! each page faults, and the page fault handler hands the address to [resolve_fault](crate::resolve_fault) (or
+//! [AddressSpace::resolve_fault](crate::AddressSpace::resolve_fault)), which backs the page with a zeroed frame.
+//!
+//! The regions are copied along with the address space by [AddressSpace::try_clone](crate::AddressSpace::try_clone).
+use arrayvec::ArrayVec;
+
+use crate::{
+    InvalidRangeReason, MapFlags, MemError,
+    arch::{L1_PAGE_SIZE, Mapper},
+    bitmap::PhysicalMemoryManager,
+    paging::{
+        Address, EntryMappingFlags, FragmentManager, Frame, MemoryFragment, Page, Small, VirtAddr,
+        asm, map::SizedMemoryMapper,
+    },
+};
+
+/// The maximum number of lazy regions in a single address space.
+const MAX_LAZY_REGIONS: usize = 0x40;
+
+/// A range of virtual memory whose pages are backed by zeroed frames on first access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyRegion {
+    /// The first address of the region.
+    pub start: VirtAddr,
+    /// The size of the region in bytes.
+    pub size: u64,
+    /// The flags pages of the region are mapped with.
+    pub flags: MapFlags,
+}
+
+impl LazyRegion {
+    /// Returns the first address after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Returns `true` if `addr` lies in the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+/// The reason why a page fault couldn't be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FaultReason {
+    /// The address doesn't belong to any lazy region.
+    NoRegion,
+    /// The address belongs to a lazy region, but the region doesn't allow the access.
+    AccessDenied {
+        /// The flags the page is (or would be) mapped with.
+        allowed: MapFlags,
+    },
+}
+
+impl core::fmt::Display for FaultReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FaultReason::NoRegion => write!(f, "not part of any lazy region"),
+            FaultReason::AccessDenied { allowed } => {
+                write!(f, "access not allowed by the mapping ({})", allowed)
+            }
+        }
+    }
+}
+
+/// The lazy regions of an address space, sorted by their start address.
+#[derive(Debug, Clone)]
+pub(crate) struct LazyRegions {
+    regions: ArrayVec<LazyRegion, MAX_LAZY_REGIONS>,
+}
+
+impl LazyRegions {
+    pub(crate) const fn new() -> Self {
+        Self {
+            regions: ArrayVec::new_const(),
+        }
+    }
+
+    /// Records a new region. The region must be page aligned and must not overlap an existing region.
+    pub(crate) fn insert(&mut self, region: LazyRegion) -> Result<(), MemError> {
+        let invalid = |reason| MemError::InvalidVirtRange {
+            reason,
+            begin: region.start,
+            size: region.size,
+        };
+        if !region.start.as_u64().is_multiple_of(L1_PAGE_SIZE)
+            || !region.size.is_multiple_of(L1_PAGE_SIZE)
+        {
+            return Err(invalid(InvalidRangeReason::Unaligned));
+        }
+
+        let index = self.regions.partition_point(|r| r.start < region.start);
+        let overlaps_prev = index > 0 && self.regions[index - 1].end() > region.start;
+        let overlaps_next = index < self.regions.len() && self.regions[index].start < region.end();
+        if overlaps_prev || overlaps_next {
+            return Err(invalid(InvalidRangeReason::Overlapping));
+        }
+
+        self.regions
+            .try_insert(index, region)
+            .map_err(|_| MemError::TooManyLazyRegions)
+    }
+
+    /// Returns the region containing `addr`.
+    pub(crate) fn find(&self, addr: VirtAddr) -> Option<LazyRegion> {
+        let index = self.regions.partition_point(|r| r.start <= addr);
+        let region = *self.regions.get(index.checked_sub(1)?)?;
+        region.contains(addr).then_some(region)
+    }
+
+    /// Removes `start..start + size` from every region it overlaps, splitting regions as needed. Returns `true` if any
+    /// region was overlapped.
+    pub(crate) fn remove(&mut self, start: VirtAddr, size: u64) -> Result<bool, MemError> {
+        let end = start + size;
+        let mut kept = ArrayVec::<LazyRegion, MAX_LAZY_REGIONS>::new();
+        let mut overlapped = false;
+
+        for region in &self.regions {
+            if region.end() <= start || end <= region.start {
+                kept.try_push(*region)
+                    .map_err(|_| MemError::TooManyLazyRegions)?;
+                continue;
+            }
+            overlapped = true;
+            if region.start < start {
+                kept.try_push(LazyRegion {
+                    size: (start - region.start).as_u64(),
+                    ..*region
+                })
+                .map_err(|_| MemError::TooManyLazyRegions)?;
+            }
+            if end < region.end() {
+                kept.try_push(LazyRegion {
+                    start: end,
+                    size: (region.end() - end).as_u64(),
+                    ..*region
+                })
+                .map_err(|_| MemError::TooManyLazyRegions)?;
+            }
+        }
+
+        self.regions = kept;
+        Ok(overlapped)
+    }
+
+    /// Changes the flags of the parts of every region that lie in `start..start + size` to `flags`. Returns `true` if any
+    /// region was overlapped.
+    pub(crate) fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: MapFlags,
+    ) -> Result<bool, MemError> {
+        let end = start + size;
+        let overlapping = self
+            .regions
+            .iter()
+            .filter(|r| r.start < end && start < r.end())
+            .map(|r| {
+                let piece_start = r.start.max(start);
+                LazyRegion {
+                    start: piece_start,
+                    size: (r.end().min(end) - piece_start).as_u64(),
+                    flags,
+                }
+            })
+            .collect::<ArrayVec<_, MAX_LAZY_REGIONS>>();
+
+        if overlapping.is_empty() {
+            return Ok(false);
+        }
+        self.remove(start, size)?;
+        for region in overlapping {
+            self.insert(region)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Backs the page containing `addr` in `region` with a zeroed frame, if `region` allows `access`. The page must not be
+/// mapped yet, see [cow::resolve_mapped](crate::cow::resolve_mapped) for faults on mapped pages.
+pub(crate) fn populate(
+    mapper: &mut Mapper,
+    pmm: &mut PhysicalMemoryManager,
+    region: LazyRegion,
+    addr: VirtAddr,
+    access: MapFlags,
+) -> Result<(), MemError> {
+    if !region.flags.contains(access) {
+        return Err(MemError::UnresolvedFault {
+            addr,
+            reason: FaultReason::AccessDenied {
+                allowed: region.flags,
+            },
+        });
+    }
+
+    let page = Page::<Small>::containing_address(addr);
+    let frame: Frame<Small> = pmm.allocate_fragment()?;
+    asm::zero_frame(frame);
+    match mapper.map_primitive(page, frame, region.flags, EntryMappingFlags::MAP_ANON, pmm) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(e) => {
+            pmm.deallocate_fragment(frame);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: u64, size: u64) -> LazyRegion {
+        LazyRegion {
+            start: VirtAddr::new(start),
+            size,
+            flags: MapFlags::WRITABLE,
+        }
+    }
+
+    #[test]
+    fn insert_and_find() {
+        let mut regions = LazyRegions::new();
+        regions.insert(region(0x4000, 0x2000)).unwrap();
+        regions.insert(region(0x1000, 0x1000)).unwrap();
+
+        assert_eq!(
+            regions.find(VirtAddr::new(0x1800)),
+            Some(region(0x1000, 0x1000))
+        );
+        assert_eq!(
+            regions.find(VirtAddr::new(0x5fff)),
+            Some(region(0x4000, 0x2000))
+        );
+        assert_eq!(regions.find(VirtAddr::new(0x2000)), None);
+        assert_eq!(regions.find(VirtAddr::new(0x6000)), None);
+        assert_eq!(regions.find(VirtAddr::new(0x0)), None);
+    }
+
+    #[test]
+    fn insert_rejects_overlap_and_unaligned() {
+        let mut regions = LazyRegions::new();
+        regions.insert(region(0x4000, 0x2000)).unwrap();
+
+        assert!(matches!(
+            regions.insert(region(0x5000, 0x2000)),
+            Err(MemError::InvalidVirtRange {
+                reason: InvalidRangeReason::Overlapping,
+                ..
+            })
+        ));
+        assert!(matches!(
+            regions.insert(region(0x3000, 0x2000)),
+            Err(MemError::InvalidVirtRange {
+                reason: InvalidRangeReason::Overlapping,
+                ..
+            })
+        ));
+        assert!(matches!(
+            regions.insert(region(0x8100, 0x1000)),
+            Err(MemError::InvalidVirtRange {
+                reason: InvalidRangeReason::Unaligned,
+                ..
+            })
+        ));
+        regions.insert(region(0x6000, 0x1000)).unwrap();
+    }
+
+    #[test]
+    fn remove_splits_regions() {
+        let mut regions = LazyRegions::new();
+        regions.insert(region(0x1000, 0x4000)).unwrap();
+
+        assert!(regions.remove(VirtAddr::new(0x2000), 0x1000).unwrap());
+        assert_eq!(
+            regions.find(VirtAddr::new(0x1000)),
+            Some(region(0x1000, 0x1000))
+        );
+        assert_eq!(regions.find(VirtAddr::new(0x2000)), None);
+        assert_eq!(
+            regions.find(VirtAddr::new(0x3000)),
+            Some(region(0x3000, 0x2000))
+        );
+
+        assert!(!regions.remove(VirtAddr::new(0x8000), 0x1000).unwrap());
+        assert!(regions.remove(VirtAddr::new(0x0), 0x8000).unwrap());
+        assert_eq!(regions.find(VirtAddr::new(0x3000)), None);
+    }
+
+    #[test]
+    fn protect_changes_flags_of_overlap_only() {
+        let mut regions = LazyRegions::new();
+        regions.insert(region(0x1000, 0x3000)).unwrap();
+
+        assert!(
+            regions
+                .protect(VirtAddr::new(0x2000), 0x4000, MapFlags::empty())
+                .unwrap()
+        );
+        assert_eq!(
+            regions.find(VirtAddr::new(0x1000)).unwrap().flags,
+            MapFlags::WRITABLE
+        );
+        let protected = regions.find(VirtAddr::new(0x2000)).unwrap();
+        assert_eq!(protected.flags, MapFlags::empty());
+        assert_eq!(protected.size, 0x2000);
+        assert_eq!(regions.find(VirtAddr::new(0x4000)), None);
+    }
+}
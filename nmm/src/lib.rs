@@ -35,9 +35,15 @@ compile_error!("Only x86_64 architecture is currently supported.");
 
 pub mod arch;
 pub mod bitmap;
+mod cow;
 pub mod entry_walker;
 pub mod kernel_map;
+mod lazy;
 pub mod paging;
+mod space;
+
+pub use lazy::{FaultReason, LazyRegion};
+pub use space::AddressSpace;
 
 /// The minimum size of the managed range required for the memory manager to function correctly.
 pub const MIN_MANAGED_RANGE_SIZE: u64 = arch::L3_PAGE_SIZE + arch::L2_PAGE_SIZE;
@@ -51,7 +57,8 @@ pub struct InitConfig {
     /// This range is used for virtual address allocation (e.g., for `alloc_virtspace`) and physical memory mapping (e.g., for `alloc_paged`),
     /// as well as internal memory management state.
     pub managed_range: MemoryRange<VirtAddr>,
-    /// A page that is reserved for zeroing out frames before they are returned to the caller (if requested).
+    /// A page that is kept out of the managed range. Frames are zeroed through the direct map at `offset`, so the page
+    /// is currently unused.
     pub zero_page: Page<Large>,
     /// The physical memory map of the system, typically provided by the bootloader.
     ///
@@ -116,7 +123,7 @@ impl InitConfig {
 ///   This range is used for virtual address allocation (e.g., for `alloc_virtspace`) and physical memory mapping (e.g., for `alloc_paged`),
 ///   as well as internal memory management state.
 pub unsafe fn init(init_config: InitConfig) -> Result<(), MemError> {
-    asm::init_zero_page(init_config.zero_page);
+    asm::init_physical_memory_offset(init_config.offset);
     unsafe { arch::init_unchecked(EntryWalker::new(init_config.memory_map)?, init_config) }
 }
 
@@ -133,6 +140,9 @@ pub enum MapSource {
         /// If this is not set, it is undefined behavior to read from the mapped virtual address before writing to it.
         zero: bool,
     },
+    /// Only reserve the range. Each page is backed by a zeroed frame on its first access, once the page fault handler
+    /// passes the faulting address to [resolve_fault].
+    Lazy,
 }
 
 /// Maps a virtual address range to a physical address range with the specified size
@@ -162,6 +172,35 @@ pub fn map(
     unsafe { paging::map_unchecked(dest, src, byte_size, flags) }
 }
 
+/// Backs the page containing `addr` with a zeroed frame if it belongs to a range mapped with [MapSource::Lazy] that
+/// allows `access`, or with a private copy if `access` is the first write to a [MapFlags::COPY_ON_WRITE] page. This is
+/// meant to be called by the page fault handler for faults in the kernel half.
+///
+/// `access` holds the flags the faulting access needs: [MapFlags::WRITABLE] for writes, [MapFlags::USER_ACCESSIBLE] for
+/// accesses from user mode and [MapFlags::EXECUTABLE] for instruction fetches. Returns
+/// [MemError::UnresolvedFault] if the fault wasn't caused by a lazy or copy-on-write mapping.
+pub fn resolve_fault(addr: VirtAddr, access: MapFlags) -> Result<(), MemError> {
+    let mut pmm = asm::physical_memory_manager();
+    let c_as = asm::active();
+    let region = c_as.lazy_regions().find(addr);
+    let mut mapper = c_as
+        .mapper()
+        .ok_or(MemError::Uninit("kernel address space"))?;
+    if let Some(result) = cow::resolve_mapped(&mut mapper, &mut pmm, addr, access) {
+        return result;
+    }
+    let region = region.ok_or(MemError::UnresolvedFault {
+        addr,
+        reason: FaultReason::NoRegion,
+    })?;
+    lazy::populate(&mut mapper, &mut pmm, region, addr, access)
+}
+
+/// Returns the [MapSource::Lazy] range of the kernel address space that contains `addr`, if there is one.
+pub fn lazy_region(addr: VirtAddr) -> Option<LazyRegion> {
+    asm::active().lazy_regions().find(addr)
+}
+
 /// Unmaps a virtual address range of the specified size starting from the given virtual base address
 ///
 /// - `virt_base` is the starting virtual address of the range to be unmapped.
@@ -175,6 +214,16 @@ pub unsafe fn unmap(virt_base: VirtAddr, byte_size: usize) -> Result<(), MemErro
     unsafe { paging::unmap_unchecked(virt_base, byte_size) }
 }
 
+/// Translates a virtual address in the kernel address space to the physical address it is mapped to,
+/// along with the flags of the mapping. Returns `None` if the address is not mapped.
+///
+/// The lower half of an [AddressSpace] is only visible through [AddressSpace::translate].
+pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, MapFlags)> {
+    let c_as = asm::active();
+    let mapper = c_as.mapper()?;
+    mapper.translate(addr)
+}
+
 /// Allocates a virtual address range of the specified size without mapping it to any physical memory.
 #[must_use = "The returned virtual address must be freed with `free_virtspace` when it is no longer needed to avoid memory leaks and ensure proper resource management."]
 pub fn reserve_virtual(layout: Layout) -> Result<VirtAddr, MemError> {
@@ -226,6 +275,39 @@ pub fn free_frame<S: FragmentSize>(frame: Frame<S>) {
     pmm.deallocate_fragment(frame);
 }
 
+/// Returns the number of references to an allocated frame, which is more than one while copy-on-write mappings share
+/// it.
+pub fn frame_references<S: FragmentSize>(frame: Frame<S>) -> u64 {
+    let mut pmm = asm::physical_memory_manager();
+
+    pmm.references(frame)
+}
+
+/// Reserves enough physically contiguous frames to hold `byte_size` bytes and returns the address of the first one.
+/// This is meant for memory accessed by devices through DMA, which doesn't go through the page tables.
+pub fn reserve_contiguous(byte_size: usize) -> Result<PhysAddr, MemError> {
+    let mut pmm = asm::physical_memory_manager();
+
+    pmm.allocate_contiguous(frames_for(byte_size))
+}
+
+/// Frees the frames holding `byte_size` bytes starting at `phys_base`, previously reserved with [reserve_contiguous].
+///
+/// # Safety
+/// The frames must have been reserved by a single call to [reserve_contiguous] with the same size, and must not be
+/// accessed anymore, by the CPU or by a device.
+pub unsafe fn free_contiguous(phys_base: PhysAddr, byte_size: usize) {
+    let mut pmm = asm::physical_memory_manager();
+
+    // SAFETY: Guaranteed by caller.
+    unsafe { pmm.deallocate_contiguous(phys_base, frames_for(byte_size)) };
+}
+
+/// The number of small frames [reserve_contiguous] reserves for `byte_size` bytes, at least one.
+fn frames_for(byte_size: usize) -> u64 {
+    (byte_size as u64).div_ceil(arch::L1_PAGE_SIZE).max(1)
+}
+
 /// A structure representing a mapping between a virtual address range and a physical address range, along with the size of the mapping in bytes.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MemoryMapping {
@@ -309,6 +391,10 @@ pub enum InvalidRangeReason {
     NonCanonical,
     /// The address range is not aligned to the required page size (i.e., `virt_base` is not a multiple of the page size, or `byte_size` is not a multiple of the page size).
     Unaligned,
+    /// The address range reaches outside of the part of the address space the operation may touch (e.g., the lower half of an [AddressSpace]).
+    OutOfBounds,
+    /// The address range overlaps a range that is already in use (e.g., an existing [MapSource::Lazy] range).
+    Overlapping,
 }
 
 /// An error that can occur during memory mapping operations, such as invalid addresses, insufficient resources, or permission issues. The specific variants of this error type can be defined based on the needs of the memory manager implementation.
@@ -381,6 +467,23 @@ pub enum MemError {
         /// The physical address that is not managed by the memory manager.
         PhysAddr,
     ),
+    /// The address space already holds the maximum number of [MapSource::Lazy] ranges.
+    #[error("Too many lazily mapped ranges in the address space")]
+    TooManyLazyRegions,
+    /// A frame is already shared by the maximum number of mappings.
+    #[error("The frame at {0:?} has too many references")]
+    TooManyReferences(
+        /// The start of the shared frame.
+        PhysAddr,
+    ),
+    /// A page fault could not be resolved by backing a lazily mapped page or copying a copy-on-write page.
+    #[error("The page fault at {addr:?} could not be resolved: {reason}")]
+    UnresolvedFault {
+        /// The faulting address.
+        addr: VirtAddr,
+        /// Why the fault couldn't be resolved.
+        reason: FaultReason,
+    },
     /// An error that originated from architecture-specific operations in the memory manager.
     #[error("An architecture-specific error occurred during memory management operations: {0}")]
     ArchError(#[from] arch::ArchError),
@@ -447,6 +550,9 @@ bitflags! {
         const EXECUTABLE = 1 << 3;
         /// Disable caching for this page
         const CACHE_DISABLE = 1 << 4;
+        /// The page is writable, but shares its frame until the first write, which maps a private copy instead. The
+        /// page is mapped read-only until then. Implies [MapFlags::WRITABLE].
+        const COPY_ON_WRITE = 1 << 5;
     }
 }
 
@@ -476,6 +582,10 @@ impl Display for MapFlags {
             write!(f, " C")?;
         };
 
+        if self.contains(MapFlags::COPY_ON_WRITE) {
+            write!(f, " COW")?;
+        }
+
         Ok(())
     }
 }
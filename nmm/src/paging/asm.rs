@@ -1,18 +1,15 @@
 //! Address Space Management (ASM) module for nmm.
 
-use core::mem::transmute;
-
 use cake::{
     MappedMutexGuard, Mutex, MutexGuard, Once, OnceMutex, OnceMutexGuard, OnceRwLock,
     OnceRwReadGuard,
 };
 
 use crate::{
-    arch::{self, Mapper},
+    arch,
     bitmap::{PhysicalMemoryManager, VirtualMemoryManager},
-    paging::{
-        FragmentSize, Frame, Large, MemoryFragment, Page, PhysAddr, Small, map::SizedMemoryMapper,
-    },
+    lazy::LazyRegions,
+    paging::{AddressExt, FragmentSize, Frame, Page, Small, VirtAddr},
 };
 
 static ADDRESS_SPACE: OnceRwLock<AddressSpace> = OnceRwLock::new();
@@ -23,6 +20,7 @@ pub(crate) struct AddressSpace {
     l4_table_frame: Frame<Small>,
     l4_table: Page<Small>,
     vmm: Mutex<Option<VirtualMemoryManager<'static>>>,
+    lazy: Mutex<LazyRegions>,
 }
 
 impl AddressSpace {
@@ -37,6 +35,7 @@ impl AddressSpace {
             l4_table_frame,
             l4_table,
             vmm: Mutex::new(vmm),
+            lazy: Mutex::new(LazyRegions::new()),
         }
     }
 
@@ -47,12 +46,17 @@ impl AddressSpace {
             l4_table_frame,
             l4_table,
             vmm: Mutex::new(None),
+            lazy: Mutex::new(LazyRegions::new()),
         }
     }
 
+    /// Returns the mapper of this address space. A recursive mapper can only reach its tables while they are loaded in
+    /// CR3, so it is only handed out while active. An offset mapper works through the direct map and is always usable,
+    /// which lets the kernel half be edited while a user address space is loaded.
     pub(crate) fn mapper(&self) -> Option<MutexGuard<'_, arch::Mapper>> {
-        if self.l4_table_frame == arch::pml4_phys() {
-            Some(self.mapper.lock())
+        let mapper = self.mapper.lock();
+        if !mapper.requires_active() || self.l4_table_frame == arch::pml4_phys() {
+            Some(mapper)
         } else {
             None
         }
@@ -70,6 +74,11 @@ impl AddressSpace {
         MutexGuard::try_map(self.vmm.lock(), |vmm| vmm.as_mut()).ok()
     }
 
+    /// Returns the lazily mapped ranges of this address space. No other lock may be taken while holding the guard.
+    pub(crate) fn lazy_regions(&self) -> MutexGuard<'_, LazyRegions> {
+        self.lazy.lock()
+    }
+
     /// Sets the virtual memory manager for this address space. This function should only be called once during system initialization.
     pub(crate) fn set_vmm(&self, vmm: VirtualMemoryManager<'static>) {
         let mut vmm_guard = self.vmm.lock();
@@ -105,38 +114,40 @@ pub(crate) fn physical_memory_manager() -> OnceMutexGuard<'static, PhysicalMemor
     PHYSICAL_MEMORY_MANAGER.get()
 }
 
-// A page that is used to zero out frames when they are allocated and zeroing is requested.
-// This page is mapped to a known virtual address and is used to write zeros to the frame before it is returned to the caller.
-static ZERO_PAGE: Once<Page<Large>> = Once::new();
+// The virtual address at which all of physical memory is mapped. Frames are zeroed through this mapping, so zeroing
+// never needs to take the mapper or physical memory manager locks that the caller may already be holding.
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+pub(crate) fn init_physical_memory_offset(offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| offset);
+}
+
+pub(crate) fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("The physical memory offset has not been initialized")
+}
 
-pub(crate) fn init_zero_page(zero_page: Page<Large>) {
-    ZERO_PAGE.call_once(|| zero_page);
+/// Returns the address through which `frame` can be accessed in the direct map of physical memory.
+pub(crate) fn direct_map<S: FragmentSize>(frame: Frame<S>) -> VirtAddr {
+    frame
+        .translate_offset(physical_memory_offset())
+        .expect("Frame is outside of the direct map")
 }
 
-pub(crate) fn zero_frame<S>(frame: Frame<S>)
-where
-    S: FragmentSize,
-    arch::Mapper: SizedMemoryMapper<S>,
-{
-    let active_as = active();
-    let page = *ZERO_PAGE.get().unwrap();
-    let lower_page: Page<S> = unsafe { transmute(page) };
-    let mut mapper = active_as.mapper().unwrap();
+pub(crate) fn zero_frame<S: FragmentSize>(frame: Frame<S>) {
+    // SAFETY: The direct map covers all of physical memory and is writable, and the frame is owned by the caller.
+    unsafe { core::ptr::write_bytes(direct_map(frame).as_mut_ptr::<u8>(), 0, S::SIZE as usize) };
+}
+
+pub(crate) fn copy_frame<S: FragmentSize>(src: Frame<S>, dst: Frame<S>) {
+    // SAFETY: The direct map covers all of physical memory and is writable, `dst` is owned by the caller, and two
+    // distinct frames never overlap.
     unsafe {
-        mapper
-            .map_primitive(
-                lower_page,
-                frame,
-                crate::paging::MapFlags::WRITABLE,
-                Default::default(),
-                &mut *physical_memory_manager(),
-            )
-            .expect("Failed to map zero page to frame")
-            .flush();
-        lower_page.zero();
-        mapper
-            .unmap_primitive(lower_page)
-            .expect("Failed to unmap zero page from frame")
-            .flush();
-    }
+        core::ptr::copy_nonoverlapping(
+            direct_map(src).as_ptr::<u8>(),
+            direct_map(dst).as_mut_ptr::<u8>(),
+            S::SIZE as usize,
+        )
+    };
 }
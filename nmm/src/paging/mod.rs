@@ -15,8 +15,9 @@ use cake::log::trace;
 pub use index::PageTableIndex;
 
 use crate::{
-    MapFlags, MapSource, MemError,
-    arch::{self, Mapper, PageEntryType},
+    LazyRegion, MapFlags, MapSource, MemError,
+    arch::{self, L1_PAGE_SIZE, Mapper, PageEntryType},
+    bitmap::PhysicalMemoryManager,
     paging::{
         fragment::GreedyFragmentMapper,
         map::{Flush, MemoryMapper, SizedMemoryMapper, Unmapped},
@@ -256,6 +257,19 @@ pub(crate) unsafe fn map_unchecked(
                 );
             }
         },
+        MapSource::Lazy => {
+            trace!(
+                "Reserving lazy memory at virtual address {:#x} with size {} bytes and flags {:?}",
+                dest.as_u64(),
+                byte_size,
+                flags
+            );
+            asm::active().lazy_regions().insert(LazyRegion {
+                start: dest,
+                size: byte_size as u64,
+                flags,
+            })?;
+        }
     }
 
     Ok(())
@@ -265,31 +279,68 @@ pub(crate) unsafe fn unmap_unchecked(
     virt_base: VirtAddr,
     byte_size: usize,
 ) -> Result<(), MemError> {
-    let mapper = GreedyFragmentMapper::<PageClass>::new(virt_base, byte_size as u64);
+    let mut pmm = asm::physical_memory_manager();
+    let active_as = asm::active();
+    let sparse = active_as
+        .lazy_regions()
+        .remove(virt_base, byte_size as u64)?;
+    let mut mapper = active_as.mapper().unwrap();
+
+    // SAFETY: Guaranteed by the caller.
+    unsafe { unmap_with(&mut mapper, &mut pmm, virt_base, byte_size, sparse) }
+}
+
+/// Unmaps a range with `mapper`, returning the frames of anonymous mappings to `pmm`.
+///
+/// If `sparse` is set, the range overlaps a lazy range: it is walked in small pages and pages that were never backed
+/// are skipped.
+pub(crate) unsafe fn unmap_with(
+    mapper: &mut Mapper,
+    pmm: &mut PhysicalMemoryManager,
+    virt_base: VirtAddr,
+    byte_size: usize,
+    sparse: bool,
+) -> Result<(), MemError> {
+    if sparse {
+        for page in small_pages(virt_base, byte_size) {
+            if mapper.translate(page.start_address()).is_none() {
+                continue;
+            }
+            // SAFETY: The caller guarantees that the unmapped memory isn't accessed anymore.
+            let mut ent = unsafe { mapper.unmap_primitive(page)? };
+            ent.flush();
+            if ent.mapping_flags.contains(EntryMappingFlags::MAP_ANON) {
+                pmm.deallocate_fragment(ent.frame);
+            }
+        }
+        return Ok(());
+    }
+
+    let fragments = GreedyFragmentMapper::<PageClass>::new(virt_base, byte_size as u64);
 
-    for frag in mapper {
+    for frag in fragments {
         match frag {
             AnyFragment::Small(page_prim) => {
-                let mut ent = unsafe { unmap_primitive(page_prim)? };
+                // SAFETY: The caller guarantees that the unmapped memory isn't accessed anymore.
+                let mut ent = unsafe { mapper.unmap_primitive(page_prim)? };
                 ent.flush();
                 if ent.mapping_flags.contains(EntryMappingFlags::MAP_ANON) {
-                    let mut pmm = asm::physical_memory_manager();
                     pmm.deallocate_fragment(ent.frame);
                 }
             }
             AnyFragment::Medium(page_prim) => {
-                let mut ent = unsafe { unmap_primitive(page_prim)? };
+                // SAFETY: The caller guarantees that the unmapped memory isn't accessed anymore.
+                let mut ent = unsafe { mapper.unmap_primitive(page_prim)? };
                 ent.flush();
                 if ent.mapping_flags.contains(EntryMappingFlags::MAP_ANON) {
-                    let mut pmm = asm::physical_memory_manager();
                     pmm.deallocate_fragment(ent.frame);
                 }
             }
             AnyFragment::Large(page_prim) => {
-                let mut ent = unsafe { unmap_primitive(page_prim)? };
+                // SAFETY: The caller guarantees that the unmapped memory isn't accessed anymore.
+                let mut ent = unsafe { mapper.unmap_primitive(page_prim)? };
                 ent.flush();
                 if ent.mapping_flags.contains(EntryMappingFlags::MAP_ANON) {
-                    let mut pmm = asm::physical_memory_manager();
                     pmm.deallocate_fragment(ent.frame);
                 }
             }
@@ -298,3 +349,81 @@ pub(crate) unsafe fn unmap_unchecked(
 
     Ok(())
 }
+
+/// Replaces the flags of every mapping in a range with `flags`, keeping the frames and mapping flags. `sparse` works
+/// like it does for [unmap_with].
+pub(crate) unsafe fn protect_with(
+    mapper: &mut Mapper,
+    pmm: &mut PhysicalMemoryManager,
+    virt_base: VirtAddr,
+    byte_size: usize,
+    flags: MapFlags,
+    sparse: bool,
+) -> Result<(), MemError> {
+    if sparse {
+        for page in small_pages(virt_base, byte_size) {
+            if mapper.translate(page.start_address()).is_none() {
+                continue;
+            }
+            // SAFETY: The caller guarantees that the memory is only accessed as the new flags allow.
+            unsafe { reprotect(mapper, pmm, page, flags)? };
+        }
+        return Ok(());
+    }
+
+    let fragments = GreedyFragmentMapper::<PageClass>::new(virt_base, byte_size as u64);
+
+    // Remapping goes through `map_to`, which also widens the intermediate tables when the new flags need it.
+    for frag in fragments {
+        // SAFETY: See above.
+        unsafe {
+            match frag {
+                AnyFragment::Small(page_prim) => reprotect(mapper, pmm, page_prim, flags)?,
+                AnyFragment::Medium(page_prim) => reprotect(mapper, pmm, page_prim, flags)?,
+                AnyFragment::Large(page_prim) => reprotect(mapper, pmm, page_prim, flags)?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps the frame `page` is mapped to again, with `flags`. A page that shares its frame stays copy-on-write if `flags`
+/// make it writable.
+///
+/// # Safety
+/// Nothing may access the page in a way `flags` don't allow anymore.
+unsafe fn reprotect<S: FragmentSize>(
+    mapper: &mut Mapper,
+    pmm: &mut PhysicalMemoryManager,
+    page: Page<S>,
+    flags: MapFlags,
+) -> Result<(), MemError>
+where
+    Mapper: SizedMemoryMapper<S>,
+{
+    let old_flags = mapper
+        .translate(page.start_address())
+        .map(|(_, flags)| flags)
+        .unwrap_or(MapFlags::empty());
+    // SAFETY: The page is mapped again right below, with the same frame.
+    let ent = unsafe { mapper.unmap_primitive(page)? };
+    let shared = old_flags.contains(MapFlags::COPY_ON_WRITE)
+        || (ent.mapping_flags.contains(EntryMappingFlags::MAP_ANON)
+            && pmm.references(ent.frame) > 1);
+    let flags = if flags.contains(MapFlags::WRITABLE) && shared {
+        flags | MapFlags::COPY_ON_WRITE
+    } else {
+        flags - MapFlags::COPY_ON_WRITE
+    };
+    mapper
+        .map_primitive(page, ent.frame, flags, ent.mapping_flags, pmm)?
+        .flush();
+    Ok(())
+}
+
+/// Returns the small pages in a range.
+fn small_pages(virt_base: VirtAddr, byte_size: usize) -> impl Iterator<Item = Page<Small>> {
+    (0..byte_size as u64 / L1_PAGE_SIZE)
+        .map(move |i| Page::containing_address(virt_base + i * L1_PAGE_SIZE))
+}
@@ -3,7 +3,8 @@ use core::fmt::{Debug, Display};
 use crate::{
     MapFlags, arch,
     paging::{
-        Address, AddressExt, FragmentSize, Frame, MemoryFragment, Page, PhysAddr, Small, VirtAddr,
+        Address, AddressExt, EntryMappingFlags, FragmentSize, Frame, MemoryFragment, Page,
+        PhysAddr, Small, VirtAddr,
     },
 };
 
@@ -57,6 +58,11 @@ pub struct PageTableEntry {
 }
 
 impl PageTableEntry {
+    /// Creates an empty page table entry, which maps nothing.
+    pub const fn empty() -> Self {
+        Self { value: 0 }
+    }
+
     /// Creates a new page table entry with the given physical frame and flags.
     pub fn new<S: FragmentSize>(phys: Frame<S>, flags: arch::ArchEntryFlags) -> Self {
         let addr = phys.start_address().as_u64();
@@ -75,15 +81,34 @@ impl PageTableEntry {
         arch::ArchEntryFlags::from_bits_truncate(self.value).into()
     }
 
+    /// The flags nmm keeps in the software-available bits of this page table entry.
+    pub fn mapping_flags(&self) -> EntryMappingFlags {
+        EntryMappingFlags::from_bits_truncate(self.value)
+    }
+
     /// Sets the flags of this page table entry to the given `MapFlags`, while preserving the address bits.
     pub fn set_flags(&mut self, flags: MapFlags) {
         let arch_flags: arch::ArchEntryFlags = flags.into();
         self.value = (self.value & !arch_flags.bits()) | arch_flags.bits();
     }
 
+    /// Replaces the arch specific flags of this page table entry, while preserving the address bits and the mapping
+    /// flags.
+    pub fn set_arch_flags(&mut self, flags: arch::ArchEntryFlags) {
+        self.value = (self.value & !arch::ArchEntryFlags::all().bits()) | flags.bits();
+    }
+
+    /// Returns a copy of this page table entry that points to `phys` instead, with the same flags.
+    pub fn with_frame<S: FragmentSize>(&self, phys: Frame<S>) -> Self {
+        let addr_bits = arch::PHYSICAL_ADDRESS_MAX & !(S::SIZE - 1);
+        Self {
+            value: (self.value & !addr_bits) | phys.start_address().as_u64(),
+        }
+    }
+
     /// Returns the physical address contained in this page table entry, if it is present and valid.
     pub fn addr(&self) -> PhysAddr {
-        PhysAddr::new(self.value & arch::PHYSICAL_ADDRESS_MAX)
+        PhysAddr::new(self.value & arch::PHYSICAL_ADDRESS_MAX & !(arch::L1_PAGE_SIZE - 1))
     }
 }
 
@@ -0,0 +1,304 @@
+//! Per-process address spaces.
+//!
+//! An [AddressSpace] owns the lower half of its own root page table. The higher half refers to the same page tables as
+//! the kernel address space, so kernel mappings made at any point are visible in every address space.
+use core::fmt::Debug;
+
+use cake::{Mutex, Once, log::trace};
+
+use crate::{
+    FaultReason, InvalidRangeReason, LazyRegion, MapFlags, MapSource, MemError,
+    arch::{self, Mapper, space},
+    check_range_phys, check_range_virt, cow,
+    lazy::{self, LazyRegions},
+    paging::{
+        self, Address, AddressExt, EntryMappingFlags, FragmentManager, Frame, PageTable, PhysAddr,
+        Small, VirtAddr, asm, map::MemoryMapper,
+    },
+};
+
+/// The first address above the lower half of the address space.
+const LOWER_HALF_END: u64 = 1 << (arch::VIRTUAL_ADDRESS_WIDTH - 1);
+
+static KERNEL_HALF_POPULATED: Once<Result<(), MemError>> = Once::new();
+
+/// An address space with its own lower half, sharing the higher half with the kernel.
+///
+/// Dropping the address space frees every page table of its lower half, along with every frame that was mapped from
+/// [MapSource::Anon]. It must not be active on any core when that happens.
+pub struct AddressSpace {
+    root: Frame<Small>,
+    pcid: Option<u16>,
+    mapper: Mutex<Mapper>,
+    lazy: Mutex<LazyRegions>,
+}
+
+impl Debug for AddressSpace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AddressSpace")
+            .field("root", &self.root)
+            .field("pcid", &self.pcid)
+            .finish()
+    }
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty lower half.
+    ///
+    /// The first call makes sure every higher half entry of the kernel root table points to a page table, which costs
+    /// up to a page per entry, so that the higher half can be shared by reference from then on.
+    pub fn new() -> Result<Self, MemError> {
+        let mut pmm = asm::physical_memory_manager();
+        let kernel = asm::active();
+        let kernel_root = {
+            let mapper = kernel
+                .mapper()
+                .ok_or(MemError::Uninit("kernel address space"))?;
+            let root = asm::direct_map(kernel.l4_frame()).as_mut_ptr::<PageTable>();
+            KERNEL_HALF_POPULATED
+                // SAFETY: `root` is the kernel root table, and its mapper lock is held.
+                .call_once(|| unsafe { space::populate_kernel_half(&mut *root, &mut pmm) })
+                .as_ref()
+                .map_err(|e| *e)?;
+            drop(mapper);
+            // SAFETY: The kernel half of the root table never changes after it has been populated.
+            unsafe { &*root }
+        };
+
+        let root: Frame<Small> = pmm.allocate_fragment()?;
+        asm::zero_frame(root);
+        // SAFETY: The frame was just allocated, so this is the only reference to it.
+        let table = unsafe { &mut *asm::direct_map(root).as_mut_ptr::<PageTable>() };
+        // SAFETY: The copied entries point to the kernel's page tables, which outlive every address space.
+        unsafe {
+            table.entries_mut()[space::KERNEL_HALF]
+                .copy_from_slice(&kernel_root.entries()[space::KERNEL_HALF])
+        };
+
+        trace!("Created address space with root {:?}", root);
+        Ok(Self {
+            root,
+            pcid: space::allocate_pcid(),
+            // SAFETY: The table is owned by this address space and reachable through the direct map.
+            mapper: Mutex::new(unsafe { Mapper::new_offset(table, asm::physical_memory_offset()) }),
+            lazy: Mutex::new(LazyRegions::new()),
+        })
+    }
+
+    /// Creates a copy of this address space that shares every frame of the lower half with it.
+    ///
+    /// Anonymous pages become [MapFlags::COPY_ON_WRITE] in both address spaces, so the first write to a page on either
+    /// side maps a private copy of it. Other pages, such as device memory mapped from [MapSource::Direct], refer to the
+    /// same frames afterwards, and anonymous huge pages are copied right away. [MapSource::Lazy] ranges are copied as
+    /// well, pages of them that aren't backed yet are backed separately in each address space.
+    ///
+    /// Pages of this address space only lose write access on the current core right away, so it must not be active on
+    /// any other core.
+    pub fn try_clone(&self) -> Result<Self, MemError> {
+        let clone = Self::new()?;
+        *clone.lazy.lock() = self.lazy.lock().clone();
+
+        let mut pmm = asm::physical_memory_manager();
+        let _mapper = self.mapper.lock();
+        // SAFETY: Both tables are reachable through the direct map, the mapper lock of this address space is held, and
+        // the clone isn't loaded anywhere yet.
+        unsafe {
+            space::clone_user_half(
+                &mut *asm::direct_map(self.root).as_mut_ptr::<PageTable>(),
+                &mut *asm::direct_map(clone.root).as_mut_ptr::<PageTable>(),
+                &mut pmm,
+            )?
+        };
+        if self.is_active() {
+            // SAFETY: Only the protection of pages of the active address space changed.
+            unsafe { space::flush_user_half() };
+        }
+
+        trace!("Cloned address space {:?} into {:?}", self.root, clone.root);
+        Ok(clone)
+    }
+
+    /// Returns the frame holding the root page table of this address space.
+    pub fn root_frame(&self) -> Frame<Small> {
+        self.root
+    }
+
+    /// Maps `byte_size` bytes of the lower half at `dest`, like [crate::map].
+    pub fn map(
+        &self,
+        dest: VirtAddr,
+        src: MapSource,
+        byte_size: usize,
+        flags: MapFlags,
+    ) -> Result<(), MemError> {
+        check_lower_half(dest, byte_size)?;
+        if let MapSource::Direct(phys_base) = src {
+            check_range_phys(phys_base, byte_size)?;
+        }
+
+        let mut pmm = asm::physical_memory_manager();
+        let mut mapper = self.mapper.lock();
+        match src {
+            // SAFETY: The range lies in the lower half, which belongs to this address space alone.
+            MapSource::Direct(phys_base) => unsafe {
+                mapper.map(
+                    dest,
+                    phys_base,
+                    byte_size,
+                    flags,
+                    EntryMappingFlags::empty(),
+                    &mut *pmm,
+                )
+            },
+            // SAFETY: See above.
+            MapSource::Anon { zero: false } => unsafe {
+                mapper.map_from(
+                    dest,
+                    byte_size as u64,
+                    flags,
+                    EntryMappingFlags::MAP_ANON,
+                    &mut *pmm,
+                )
+            },
+            // SAFETY: See above.
+            MapSource::Anon { zero: true } => unsafe {
+                mapper.map_from_zeroed(
+                    dest,
+                    byte_size as u64,
+                    flags,
+                    EntryMappingFlags::MAP_ANON,
+                    &mut *pmm,
+                )
+            },
+            MapSource::Lazy => self.lazy.lock().insert(LazyRegion {
+                start: dest,
+                size: byte_size as u64,
+                flags,
+            }),
+        }
+    }
+
+    /// Unmaps `byte_size` bytes of the lower half at `virt_base`, like [crate::unmap].
+    ///
+    /// # Safety
+    /// Nothing may access the unmapped memory afterwards.
+    pub unsafe fn unmap(&self, virt_base: VirtAddr, byte_size: usize) -> Result<(), MemError> {
+        check_lower_half(virt_base, byte_size)?;
+        let sparse = self.lazy.lock().remove(virt_base, byte_size as u64)?;
+        let mut pmm = asm::physical_memory_manager();
+        let mut mapper = self.mapper.lock();
+        // SAFETY: The caller guarantees that the unmapped memory isn't accessed anymore.
+        unsafe { paging::unmap_with(&mut mapper, &mut pmm, virt_base, byte_size, sparse) }
+    }
+
+    /// Changes the flags of the mappings in `byte_size` bytes of the lower half at `virt_base` to `flags`. Every page of
+    /// the range must be mapped, or lie in a [MapSource::Lazy] range.
+    ///
+    /// # Safety
+    /// Nothing may access the memory in a way the new flags don't allow anymore.
+    pub unsafe fn protect(
+        &self,
+        virt_base: VirtAddr,
+        byte_size: usize,
+        flags: MapFlags,
+    ) -> Result<(), MemError> {
+        check_lower_half(virt_base, byte_size)?;
+        let sparse = self
+            .lazy
+            .lock()
+            .protect(virt_base, byte_size as u64, flags)?;
+        let mut pmm = asm::physical_memory_manager();
+        let mut mapper = self.mapper.lock();
+        // SAFETY: The caller guarantees that the memory is only accessed as the new flags allow.
+        unsafe { paging::protect_with(&mut mapper, &mut pmm, virt_base, byte_size, flags, sparse) }
+    }
+
+    /// Backs the page containing `addr` with a zeroed frame if it belongs to a [MapSource::Lazy] range of this address
+    /// space that allows `access`, or with a private copy if `access` is the first write to a
+    /// [MapFlags::COPY_ON_WRITE] page, like [crate::resolve_fault] does for the kernel half.
+    pub fn resolve_fault(&self, addr: VirtAddr, access: MapFlags) -> Result<(), MemError> {
+        let region = self.lazy_region(addr);
+        let mut pmm = asm::physical_memory_manager();
+        let mut mapper = self.mapper.lock();
+        if let Some(result) = cow::resolve_mapped(&mut mapper, &mut pmm, addr, access) {
+            return result;
+        }
+        let region = region.ok_or(MemError::UnresolvedFault {
+            addr,
+            reason: FaultReason::NoRegion,
+        })?;
+        lazy::populate(&mut mapper, &mut pmm, region, addr, access)
+    }
+
+    /// Returns the [MapSource::Lazy] range of this address space that contains `addr`, if there is one.
+    pub fn lazy_region(&self, addr: VirtAddr) -> Option<LazyRegion> {
+        self.lazy.lock().find(addr)
+    }
+
+    /// Translates a virtual address in this address space to the physical address it is mapped to, along with the
+    /// flags of the mapping. Returns `None` if the address is not mapped.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, MapFlags)> {
+        self.mapper.lock().translate(addr)
+    }
+
+    /// Returns `true` if this address space is loaded on the current core.
+    pub fn is_active(&self) -> bool {
+        arch::pml4_phys() == self.root
+    }
+
+    /// Loads this address space on the current core, tagged with its own PCID where the CPU supports them.
+    ///
+    /// # Safety
+    /// The address space must not be dropped while it is loaded on any core.
+    pub unsafe fn activate(&self) {
+        // SAFETY: The root table maps the kernel half, and the caller keeps it alive while it is loaded.
+        unsafe { space::load_root(self.root, self.pcid) };
+    }
+
+    /// Loads the kernel address space on the current core.
+    ///
+    /// # Safety
+    /// Nothing running on this core may access the lower half of the address space that was loaded before.
+    pub unsafe fn activate_kernel() {
+        let root = asm::active().l4_frame();
+        // SAFETY: The kernel root table is always valid.
+        unsafe { space::load_root(root, None) };
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(
+            !self.is_active(),
+            "Dropped address space {:?} while it is active",
+            self.root
+        );
+
+        let mut pmm = asm::physical_memory_manager();
+        // SAFETY: The address space isn't loaded on this core, and the caller of `activate` guarantees that it isn't
+        // loaded anywhere else either.
+        unsafe {
+            space::free_user_half(
+                &mut *asm::direct_map(self.root).as_mut_ptr::<PageTable>(),
+                &mut pmm,
+            )
+        };
+        pmm.deallocate_fragment(self.root);
+        if let Some(pcid) = self.pcid {
+            space::free_pcid(pcid);
+        }
+        trace!("Freed address space with root {:?}", self.root);
+    }
+}
+
+fn check_lower_half(virt_base: VirtAddr, byte_size: usize) -> Result<(), MemError> {
+    check_range_virt(virt_base, byte_size)?;
+    if virt_base.as_u64() + byte_size as u64 > LOWER_HALF_END {
+        return Err(MemError::InvalidVirtRange {
+            reason: InvalidRangeReason::OutOfBounds,
+            begin: virt_base,
+            size: byte_size as u64,
+        });
+    }
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use std::{
-    env,
+    env, fs,
     io::{self, BufRead},
     path::PathBuf,
     process::Stdio,
@@ -7,7 +7,10 @@ use std::{
 
 use jzon::JsonValue;
 use kbuild::config::Config as KConfig;
-use krun::QemuConfig;
+use krun::{Disk, NetworkBackend, QemuConfig};
+
+/// The size of the scratch disks the tests of the storage drivers write to.
+const SCRATCH_DISK_SIZE: u64 = 16 * 1024 * 1024;
 
 // TODO: Figure out how to pass test args to the test build command to be able to run specific tests
 fn main() {
@@ -24,12 +27,19 @@ fn main() {
     }
     println!("Running tests");
 
-    let mut cfg = QemuConfig::default();
-    cfg.iso = "boot_images/kernel_tests.iso".to_string();
+    let mut cfg = QemuConfig::default().with_default_chardevs();
+    // The tests of ECAM need the MCFG table of a PCI Express chipset, which the default `pc` machine lacks.
+    cfg.machine.get_or_insert_with(|| "q35".to_string());
+    // The tests of the EFI system partition need a UEFI boot.
+    cfg.uefi_img.get_or_insert_with(krun::uefi_images);
+    cfg.iso = "boot_images/kernel_tests.iso".into();
     cfg.dev_exit = true;
     cfg.display = false;
-    cfg.serial.clear();
-    cfg.serial.push("chardev:output".to_string());
+    cfg.disks.push(Disk::virtio(scratch_disk("virtio-scratch.img")));
+    cfg.disks.push(Disk::ahci(scratch_disk("ahci-scratch.img")));
+    cfg.disks.push(Disk::nvme(scratch_disk("nvme-scratch.img")));
+    // The network tests ping the gateway of QEMU's user networking.
+    cfg.network = Some(NetworkBackend::User);
     //cfg.wait_for_debugger = true;
     cfg.run();
 }
@@ -84,6 +94,14 @@ fn build_tests(test_args: Vec<String>) -> (PathBuf, bool) {
     panic!("Failed to build tests");
 }
 
+/// Creates a zeroed disk image named `name` in the target directory, and returns its path.
+fn scratch_disk(name: &str) -> PathBuf {
+    let path = PathBuf::from("target").join(name);
+    let file = fs::File::create(&path).expect("Failed to create a scratch disk");
+    file.set_len(SCRATCH_DISK_SIZE).expect("Failed to size a scratch disk");
+    path
+}
+
 fn make_test_iso(kernel_path: PathBuf) {
     let cfg = KConfig::new(
         "target/artifacts".parse().unwrap(),
@@ -91,7 +109,8 @@ fn make_test_iso(kernel_path: PathBuf) {
         "boot_cfg/test.conf".parse().unwrap(),
         "boot_images".parse().unwrap(),
         "kernel_tests.iso".parse().unwrap(),
-    );
+    )
+    .with_initramfs("initramfs".parse().unwrap());
 
     kbuild::build(&cfg);
 }